@@ -29,23 +29,110 @@ use crate::rdo::rdo;
 use crate::transform::{forward_transforms, inverse_transforms};
 
 use criterion::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaChaRng;
 use std::time::Duration;
 
 fn write_b(c: &mut Criterion) {
   for &tx_size in &[TxSize::TX_4X4, TxSize::TX_8X8] {
     for &qi in &[20, 55] {
       let n = format!("write_b_bench({:?}, {})", tx_size, qi);
-      c.bench_function(&n, move |b| write_b_bench(b, tx_size, qi));
+      c.bench_function(&n, move |b| write_b_bench(b, tx_size, qi, false));
     }
   }
 }
 
-fn write_b_bench(b: &mut Bencher, tx_size: TxSize, qindex: usize) {
+fn write_b_rdoq(c: &mut Criterion) {
+  for &tx_size in &[TxSize::TX_4X4, TxSize::TX_8X8] {
+    for &qi in &[20, 55] {
+      let n = format!("write_b_rdoq_bench({:?}, {})", tx_size, qi);
+      c.bench_function(&n, move |b| write_b_bench(b, tx_size, qi, true));
+    }
+  }
+}
+
+fn encode_rdoq(c: &mut Criterion) {
+  for &quantizer in &[60, 120, 180] {
+    for &rdoq in &[false, true] {
+      // Print the rate and quality of each point, to compare the speed
+      // against the BD-rate of the two curves.
+      let (bits, psnr) = encode_rdoq_frames(quantizer, rdoq);
+      println!(
+        "encode_rdoq({}, {}): {} bits, {:.3} dB PSNR-Y",
+        quantizer, rdoq, bits, psnr
+      );
+      let n = format!("encode_rdoq({}, {})", quantizer, rdoq);
+      c.bench_function(&n, move |b| {
+        b.iter(|| encode_rdoq_frames(quantizer, rdoq))
+      });
+    }
+  }
+}
+
+fn encode_rdoq_frames(quantizer: usize, rdoq: bool) -> (usize, f64) {
+  let (w, h) = (128, 128);
+  let limit = 4;
+  let config = Config::new().with_encoder_config(EncoderConfig {
+    width: w,
+    height: h,
+    quantizer,
+    speed_settings: SpeedSettings { rdoq, ..SpeedSettings::from_preset(10) },
+    ..Default::default()
+  });
+  let mut ctx: Context<u8> = config.new_context().unwrap();
+  let mut ra = ChaChaRng::from_seed([0; 32]);
+
+  // A moving gradient with some noise, so that every frame has residual.
+  for i in 0..limit {
+    let mut frame = ctx.new_frame();
+    for p in &mut frame.planes {
+      let (pw, ph) = (p.cfg.width, p.cfg.height);
+      let data: Vec<u8> = (0..pw * ph)
+        .map(|j| {
+          let v = (j % pw) * 2 + j / pw + i * 3;
+          ((v & 0xff) as u8).saturating_add(ra.gen_range(0, 16))
+        })
+        .collect();
+      p.copy_from_raw_u8(&data, pw, 1);
+    }
+    ctx.send_frame(frame).unwrap();
+  }
+  ctx.flush();
+
+  let mut bits = 0;
+  let mut sse = 0u64;
+  let mut frames = 0;
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => {
+        bits += pkt.data.len() * 8;
+        if let (Some(rec), Some(source)) = (pkt.rec, pkt.source) {
+          let (rec, source) = (&rec.planes[0], &source.planes[0]);
+          for y in 0..h {
+            for x in 0..w {
+              let d = i64::from(rec.p(x, y)) - i64::from(source.p(x, y));
+              sse += (d * d) as u64;
+            }
+          }
+          frames += 1;
+        }
+      }
+      Err(EncoderStatus::LimitReached) => break,
+      Err(EncoderStatus::Encoded) | Err(EncoderStatus::NeedMoreData) => {}
+      Err(e) => panic!("{:?}", e),
+    }
+  }
+
+  let mse = sse as f64 / (frames * w * h) as f64;
+  (bits, 10. * (255. * 255. / mse).log10())
+}
+
+fn write_b_bench(b: &mut Bencher, tx_size: TxSize, qindex: usize, rdoq: bool) {
   let config = EncoderConfig {
     width: 1024,
     height: 1024,
     quantizer: qindex,
-    speed_settings: SpeedSettings::from_preset(10),
+    speed_settings: SpeedSettings { rdoq, ..SpeedSettings::from_preset(10) },
     ..Default::default()
   };
   let sequence = Sequence::new(&Default::default());
@@ -192,7 +279,12 @@ criterion_group!(intra_prediction, predict::pred_bench,);
 
 criterion_group!(cfl, cfl_rdo);
 criterion_group!(cdef, cdef_frame);
-criterion_group!(write_block, write_b);
+criterion_group!(write_block, write_b, write_b_rdoq);
+criterion_group! {
+  name = rdoq;
+  config = Criterion::default().sample_size(10);
+  targets = encode_rdoq
+}
 criterion_group! {
   name = dist;
   config = Criterion::default().warm_up_time(Duration::new(1,0));
@@ -203,6 +295,7 @@ criterion_group!(ec, ec_bench);
 
 criterion_main!(
   write_block,
+  rdoq,
   intra_prediction,
   cdef,
   cfl,
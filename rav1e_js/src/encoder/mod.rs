@@ -41,14 +41,18 @@ pub trait Encoder {
 
   /// Allocates and returns a new frame.
   fn new_frame(&self) -> Frame {
-    Frame { f: self.ctx().new_frame() }
+    Frame { f: self.ctx().new_frame(), qp_offsets: None }
   }
 
   /// Sends the frame for encoding.
   ///
   /// This method adds the frame into the frame queue and runs the first passes of the look-ahead computation.
   fn send_frame(&mut self, frame: &Frame) -> Result<(), JsValue> {
-    match self.ctx_mut().send_frame(frame.f.clone()) {
+    let params = FrameParameters {
+      qp_offsets: frame.qp_offsets.clone(),
+      ..Default::default()
+    };
+    match self.ctx_mut().send_frame((frame.f.clone(), params)) {
       Ok(_) => Ok(()),
       Err(e) => match e {
         EncoderStatus::EnoughData => Err(construct_js_err(
@@ -17,6 +17,7 @@ use crate::web::Canvas;
 #[wasm_bindgen]
 pub struct Frame {
   pub(crate) f: rav1e::Frame<u8>,
+  pub(crate) qp_offsets: Option<Box<[i16]>>,
 }
 
 #[wasm_bindgen]
@@ -29,17 +30,30 @@ impl Frame {
   pub fn from_img(img: &HtmlImageElement) -> Self {
     let canvas = Canvas::new(img.width(), img.height());
     canvas.draw_image(img);
-    Frame { f: canvas.create_frame() }
+    Frame { f: canvas.create_frame(), qp_offsets: None }
   }
 
   pub fn from_canvas(canvas: &HtmlCanvasElement) -> Self {
     let canvas = Canvas::from(canvas);
-    Frame { f: canvas.create_frame() }
+    Frame { f: canvas.create_frame(), qp_offsets: None }
   }
 
   pub fn from_video(video: &HtmlVideoElement) -> Self {
     let canvas = Canvas::new(video.video_width(), video.video_height());
     canvas.draw_video_frame(video);
-    Frame { f: canvas.create_frame() }
+    Frame { f: canvas.create_frame(), qp_offsets: None }
+  }
+
+  /// Set the region of interest map of the frame.
+  ///
+  /// The map holds offsets to the quantizer index of each 64x64 block of the frame, in raster order over the configured frame size, within -255..=255.
+  /// Negative offsets raise the quality of the block.
+  pub fn set_roi(&mut self, qp_offsets: Box<[i16]>) {
+    self.qp_offsets = Some(qp_offsets);
+  }
+
+  /// Clear the region of interest map of the frame.
+  pub fn clear_roi(&mut self) {
+    self.qp_offsets = None;
   }
 }
@@ -0,0 +1,175 @@
+// Copyright (c) 2020, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::api::color::*;
+use crate::api::config::EncoderConfig;
+use crate::api::isobmff::*;
+use crate::encoder::TEMPORAL_DELIMITER;
+
+use std::io;
+
+/// Type of the auxiliary image holding the alpha channel.
+const AUX_TYPE_ALPHA: &[u8] = b"urn:mpeg:mpegB:cicp:systems:auxiliary:alpha\0";
+
+/// An encoded image, stored as an item of an AVIF file.
+#[derive(Clone, Copy, Debug)]
+pub struct AvifImage<'a> {
+  /// Configuration the image was encoded with.
+  pub config: &'a EncoderConfig,
+  /// Codec configuration record, as returned by
+  /// [`Context::container_sequence_header`].
+  ///
+  /// [`Context::container_sequence_header`]:
+  /// struct.Context.html#method.container_sequence_header
+  pub codec_config: &'a [u8],
+  /// Data of the packet holding the encoded frame.
+  pub data: &'a [u8],
+}
+
+impl<'a> AvifImage<'a> {
+  /// The frame data, without the temporal delimiter which items leave out.
+  fn item_data(&self) -> &'a [u8] {
+    if self.data.starts_with(&TEMPORAL_DELIMITER) {
+      &self.data[TEMPORAL_DELIMITER.len()..]
+    } else {
+      self.data
+    }
+  }
+}
+
+/// Image spatial extents.
+fn ispe(buf: &mut Vec<u8>, config: &EncoderConfig) {
+  let mut data = Vec::new();
+  data.extend_from_slice(&(config.width as u32).to_be_bytes());
+  data.extend_from_slice(&(config.height as u32).to_be_bytes());
+  write_full_box(buf, b"ispe", 0, 0, &data);
+}
+
+/// Pixel information, the bit depth of each channel.
+fn pixi(buf: &mut Vec<u8>, config: &EncoderConfig) {
+  let channels =
+    if config.chroma_sampling == ChromaSampling::Cs400 { 1 } else { 3 };
+  let mut data = vec![channels];
+  data.resize(1 + channels as usize, config.bit_depth as u8);
+  write_full_box(buf, b"pixi", 0, 0, &data);
+}
+
+/// Metadata of the items, given the position in the file of their data.
+fn meta(images: &[&AvifImage], data_offset: u32) -> Vec<u8> {
+  let mut meta = Vec::new();
+
+  let mut hdlr = vec![0; 4];
+  hdlr.extend_from_slice(b"pict");
+  hdlr.resize(hdlr.len() + 12 + 1, 0);
+  write_full_box(&mut meta, b"hdlr", 0, 0, &hdlr);
+
+  // The color image is the primary item, with id 1.
+  write_full_box(&mut meta, b"pitm", 0, 0, &1u16.to_be_bytes());
+
+  // 32-bit offsets and lengths, with one extent per item.
+  let mut iloc = vec![0x44, 0];
+  iloc.extend_from_slice(&(images.len() as u16).to_be_bytes());
+  let mut offset = data_offset;
+  for (item_id, image) in (1u16..).zip(images.iter()) {
+    let len = image.item_data().len() as u32;
+    iloc.extend_from_slice(&item_id.to_be_bytes());
+    iloc.extend_from_slice(&0u16.to_be_bytes()); // data_reference_index
+    iloc.extend_from_slice(&1u16.to_be_bytes()); // extent_count
+    iloc.extend_from_slice(&offset.to_be_bytes());
+    iloc.extend_from_slice(&len.to_be_bytes());
+    offset += len;
+  }
+  write_full_box(&mut meta, b"iloc", 0, 0, &iloc);
+
+  let mut iinf = (images.len() as u16).to_be_bytes().to_vec();
+  for item_id in 1..=images.len() as u16 {
+    let mut infe = item_id.to_be_bytes().to_vec();
+    infe.extend_from_slice(&0u16.to_be_bytes()); // item_protection_index
+    infe.extend_from_slice(b"av01");
+    infe.push(0); // item_name
+    write_full_box(&mut iinf, b"infe", 2, 0, &infe);
+  }
+  write_full_box(&mut meta, b"iinf", 0, 0, &iinf);
+
+  if images.len() > 1 {
+    // The alpha image is an auxiliary image of the color image.
+    let mut auxl = Vec::new();
+    auxl.extend_from_slice(&2u16.to_be_bytes());
+    auxl.extend_from_slice(&1u16.to_be_bytes());
+    auxl.extend_from_slice(&1u16.to_be_bytes());
+    let mut iref = Vec::new();
+    write_box(&mut iref, b"auxl", &auxl);
+    write_full_box(&mut meta, b"iref", 0, 0, &iref);
+  }
+
+  // Each item has its own properties, av1C being essential.
+  let mut ipco = Vec::new();
+  let mut ipma = (images.len() as u32).to_be_bytes().to_vec();
+  let mut index = 1;
+  for (item_id, image) in (1u16..).zip(images.iter()) {
+    ispe(&mut ipco, image.config);
+    pixi(&mut ipco, image.config);
+    write_box(&mut ipco, b"av1C", image.codec_config);
+    if item_id == 1 {
+      colr(&mut ipco, image.config);
+    } else {
+      write_full_box(&mut ipco, b"auxC", 0, 0, AUX_TYPE_ALPHA);
+    }
+    let essential = [false, false, true, false];
+    ipma.extend_from_slice(&item_id.to_be_bytes());
+    ipma.push(essential.len() as u8);
+    for &essential in essential.iter() {
+      ipma.push(if essential { 0x80 } else { 0 } | index);
+      index += 1;
+    }
+  }
+  let mut iprp = Vec::new();
+  write_box(&mut iprp, b"ipco", &ipco);
+  write_full_box(&mut iprp, b"ipma", 0, 0, &ipma);
+  write_box(&mut meta, b"iprp", &iprp);
+
+  let mut buf = Vec::new();
+  write_full_box(&mut buf, b"meta", 0, 0, &meta);
+  buf
+}
+
+/// Writes an AVIF file holding a still picture.
+///
+/// The color image and the optional `alpha` image are single frames encoded
+/// with [`EncoderConfig::still_picture`], the alpha channel being the luma
+/// of the alpha image, ideally a monochrome (`Cs400`) encode.
+///
+/// [`EncoderConfig::still_picture`]:
+/// struct.EncoderConfig.html#structfield.still_picture
+pub fn write_avif(
+  output: &mut dyn io::Write, color: &AvifImage, alpha: Option<&AvifImage>,
+) -> io::Result<()> {
+  let mut images = vec![color];
+  images.extend(alpha);
+
+  let mut buf = Vec::new();
+  let mut ftyp = b"avif".to_vec();
+  ftyp.extend_from_slice(&0u32.to_be_bytes());
+  ftyp.extend_from_slice(b"avifmif1miaf");
+  write_box(&mut buf, b"ftyp", &ftyp);
+
+  // The size of the metadata does not depend on the offsets of the data.
+  let meta_len = meta(&images, 0).len();
+  let data_offset = buf.len() + meta_len + 8;
+  buf.extend_from_slice(&meta(&images, data_offset as u32));
+
+  let data_len: usize = images.iter().map(|i| i.item_data().len()).sum();
+  buf.extend_from_slice(&(8 + data_len as u32).to_be_bytes());
+  buf.extend_from_slice(b"mdat");
+  for image in images.iter() {
+    buf.extend_from_slice(image.item_data());
+  }
+
+  output.write_all(&buf)
+}
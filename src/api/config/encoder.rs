@@ -11,7 +11,7 @@ use itertools::*;
 
 use crate::api::color::*;
 use crate::api::{Rational, SpeedSettings};
-use crate::encoder::Tune;
+use crate::encoder::{SuperresMode, Tune};
 use crate::serialize::{Deserialize, Serialize};
 
 use std::fmt;
@@ -54,6 +54,10 @@ pub struct EncoderConfig {
   /// Still picture mode flag.
   pub still_picture: bool,
 
+  /// Output the packets in the length delimited bitstream format of Annex B,
+  /// instead of the low overhead bitstream format of Section 5.
+  pub annex_b: bool,
+
   /// Flag to force all frames to be error resilient.
   pub error_resilient: bool,
 
@@ -72,12 +76,41 @@ pub struct EncoderConfig {
   ///
   /// In this mode the frame reordering is disabled.
   pub low_latency: bool,
+  /// Depth of the frame re-ordering pyramid (0-4).
+  ///
+  /// Frames are coded in groups of `2^pyramid_depth` frames. Ignored in low
+  /// latency mode.
+  pub pyramid_depth: u64,
+  /// Choose the depth of each re-ordering group from the temporal complexity
+  /// measured by the lookahead, up to [`pyramid_depth`].
+  ///
+  /// [`pyramid_depth`]: #structfield.pyramid_depth
+  pub adaptive_pyramid_depth: bool,
   /// The base quantizer to use.
+  ///
+  /// Without a target bitrate, a quantizer of 0 codes every frame
+  /// losslessly.
   pub quantizer: usize,
   /// The minimum allowed base quantizer to use in bitrate mode.
   pub min_quantizer: u8,
   /// The target bitrate for the bitrate mode.
   pub bitrate: i32,
+  /// The maximum bitrate of the decoder buffer model, in bits per second
+  /// (0 disables the model).
+  ///
+  /// The quantizers of the frames are raised, re-encoding them if needed, so
+  /// that a decoder buffer of [`buffer_size`] bits that is filled at this
+  /// bitrate does not underflow. If a frame does not fit even with the
+  /// coarsest quantizers, encoding fails with [`EncoderStatus::Failure`].
+  /// The model is signaled in the sequence header, along with the lowest
+  /// level supporting this bitrate.
+  ///
+  /// [`buffer_size`]: #structfield.buffer_size
+  /// [`EncoderStatus::Failure`]: enum.EncoderStatus.html#variant.Failure
+  pub max_bitrate: i32,
+  /// The size of the decoder buffer model in bits, at most one second at the
+  /// maximum bitrate.
+  pub buffer_size: i32,
   /// Metric to tune the quality for.
   pub tune: Tune,
   /// Number of tiles horizontally. Must be a power of two.
@@ -100,8 +133,40 @@ pub struct EncoderConfig {
   /// [`tile_cols`]: #structfield.tile_cols
   /// [`tile_rows`]: #structfield.tile_rows
   pub tiles: usize,
+  /// Use 128x128 superblocks instead of 64x64 ones.
+  ///
+  /// This allows blocks up to 128x128 when the speed settings allow 64x64
+  /// blocks, which saves signaling on large flat areas of high resolution
+  /// content.
+  pub superblock_128: bool,
+  /// How the super-resolution denominator of each frame is chosen.
+  ///
+  /// A frame with a denominator `d` (9-16) is coded at `8/d` of its width
+  /// and upscaled back to it in the loop, which saves bits at low bitrates.
+  pub superres_mode: SuperresMode,
+  /// The super-resolution denominator (9-16) of the fixed mode.
+  pub superres_denom: u8,
+  /// The base quantizer above which the quantizer threshold mode codes
+  /// frames at a reduced width.
+  pub superres_qthresh: u8,
   /// Number of frames to read ahead for the RDO lookahead computation.
   pub rdo_lookahead_frames: usize,
+  /// Film grain synthesis strength (0-50, 0 disables it).
+  ///
+  /// The source is denoised with this strength before encoding, and the
+  /// parameters of the removed grain are signaled so that the decoder can
+  /// synthesize it back.
+  pub film_grain: u8,
+  /// Enable intra block copy on key frames using screen content tools.
+  ///
+  /// The in-loop filters are disabled on the frames where it is used.
+  pub intrabc: bool,
+  /// Adapt the quantizer of each superblock to its spatial activity and
+  /// temporal importance, with matching loop filter strength deltas.
+  pub delta_q: bool,
+  /// Weight the quantizer of the coefficients by their frequency with
+  /// quantization matrices, coding the high frequencies more coarsely.
+  pub quant_matrices: bool,
 
   /// Settings which affect the enconding speed vs. quality trade-off.
   pub speed_settings: SpeedSettings,
@@ -141,6 +206,7 @@ impl EncoderConfig {
       enable_timing_info: false,
 
       still_picture: false,
+      annex_b: false,
 
       error_resilient: false,
       switch_frame_interval: 0,
@@ -152,13 +218,25 @@ impl EncoderConfig {
       min_quantizer: 0,
       reservoir_frame_delay: None,
       low_latency: false,
+      pyramid_depth: 2,
+      adaptive_pyramid_depth: false,
       quantizer: 100,
       bitrate: 0,
+      max_bitrate: 0,
+      buffer_size: 0,
       tune: Tune::default(),
       tile_cols: 0,
       tile_rows: 0,
       tiles: 0,
+      superblock_128: false,
+      superres_mode: SuperresMode::default(),
+      superres_denom: 16,
+      superres_qthresh: 128,
       rdo_lookahead_frames: 40,
+      film_grain: 0,
+      intrabc: false,
+      delta_q: false,
+      quant_matrices: false,
       speed_settings: SpeedSettings::from_preset(speed),
     }
   }
@@ -209,12 +287,20 @@ impl fmt::Display for EncoderConfig {
       ("keyint_max", self.max_key_frame_interval.to_string()),
       ("quantizer", self.quantizer.to_string()),
       ("bitrate", self.bitrate.to_string()),
+      ("max_bitrate", self.max_bitrate.to_string()),
+      ("buffer_size", self.buffer_size.to_string()),
       ("min_quantizer", self.min_quantizer.to_string()),
       ("low_latency", self.low_latency.to_string()),
+      ("pyramid_depth", self.pyramid_depth.to_string()),
+      ("adaptive_pyramid_depth", self.adaptive_pyramid_depth.to_string()),
       ("tune", self.tune.to_string()),
       ("rdo_lookahead_frames", self.rdo_lookahead_frames.to_string()),
       ("min_block_size", self.speed_settings.partition_range.min.to_string()),
       ("max_block_size", self.speed_settings.partition_range.max.to_string()),
+      ("superblock_128", self.superblock_128.to_string()),
+      ("superres_mode", self.superres_mode.to_string()),
+      ("superres_denom", self.superres_denom.to_string()),
+      ("superres_qthresh", self.superres_qthresh.to_string()),
       (
         "multiref",
         (!self.low_latency || self.speed_settings.multiref).to_string(),
@@ -241,10 +327,27 @@ impl fmt::Display for EncoderConfig {
         self.speed_settings.non_square_partition.to_string(),
       ),
       ("enable_timing_info", self.enable_timing_info.to_string()),
+      ("annex_b", self.annex_b.to_string()),
+      ("film_grain", self.film_grain.to_string()),
+      ("intrabc", self.intrabc.to_string()),
+      ("delta_q", self.delta_q.to_string()),
+      ("quant_matrices", self.quant_matrices.to_string()),
       (
         "fine_directional_intra",
         self.speed_settings.fine_directional_intra.to_string(),
       ),
+      ("filter_intra", self.speed_settings.filter_intra.to_string()),
+      ("global_motion", self.speed_settings.global_motion.to_string()),
+      ("warped_motion", self.speed_settings.warped_motion.to_string()),
+      (
+        "ext_partition_types",
+        self.speed_settings.ext_partition_types.to_string(),
+      ),
+      ("rdoq", self.speed_settings.rdoq.to_string()),
+      (
+        "interp_filter_search",
+        self.speed_settings.interp_filter_search.to_string(),
+      ),
     ];
     write!(
       f,
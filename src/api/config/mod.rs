@@ -11,9 +11,13 @@ use thiserror::Error;
 
 use std::sync::Arc;
 
-use crate::api::{ChromaSampling, Context, ContextInner};
+use crate::api::{ChromaSampling, Context, ContextInner, MAX_PYRAMID_DEPTH};
 use crate::cpu_features::CpuFeatureLevel;
+use crate::denoise::MAX_DENOISE_STRENGTH;
+use crate::encoder::SuperresMode;
+use crate::rate::{decoder_buffer_delay, decoder_model_level};
 use crate::rayon::{ThreadPool, ThreadPoolBuilder};
+use crate::scale::{SUPERRES_DENOM_MAX, SUPERRES_DENOM_MIN};
 use crate::tiling::TilingInfo;
 use crate::util::Pixel;
 
@@ -87,10 +91,69 @@ pub enum InvalidConfig {
     "invalid switch frame interval {0} (must only be used with low latency mode)"
   )]
   InvalidSwitchFrameInterval(u64),
+  /// Pyramid depth is invalid.
+  #[error("invalid pyramid depth {actual} (expected <= {max})")]
+  InvalidPyramidDepth {
+    /// The actual value.
+    actual: u64,
+    /// The maximal supported value.
+    max: u64,
+  },
+
+  /// Film grain strength is invalid.
+  #[error("invalid film grain strength {actual} (expected <= {max})")]
+  InvalidFilmGrain {
+    /// The actual value.
+    actual: u8,
+    /// The maximal supported value.
+    max: u8,
+  },
+
+  /// Super-resolution denominator is invalid.
+  #[error(
+    "invalid super-resolution denominator {actual} (expected >= {min}, <= {max})"
+  )]
+  InvalidSuperresDenom {
+    /// The actual value.
+    actual: u8,
+    /// The minimal supported value.
+    min: u8,
+    /// The maximal supported value.
+    max: u8,
+  },
+
+  /// The decoder buffer model is invalid.
+  #[error(
+    "invalid decoder buffer model with max bitrate {max_bitrate} and buffer size {buffer_size}"
+  )]
+  InvalidBufferModel {
+    /// The maximum bitrate.
+    max_bitrate: i32,
+    /// The buffer size.
+    buffer_size: i32,
+  },
+
+  /// No level supports the maximum bitrate of the decoder buffer model at the
+  /// frame size and rate.
+  #[error("no level supports the max bitrate {0}")]
+  InvalidMaxBitrate(i32),
+
+  /// The target bitrate exceeds the maximum bitrate.
+  #[error("target bitrate {actual} exceeds the max bitrate {max}")]
+  InvalidBitrate {
+    /// The actual value.
+    actual: i32,
+    /// The maximal supported value.
+    max: i32,
+  },
 
   /// The rate control needs a target bitrate in order to produce results
   #[error("The rate control requires a target bitrate")]
   TargetBitrateNeeded,
+
+  /// The constant quality mode cannot be used with a target bitrate
+  #[error("The constant quality mode is incompatible with a target bitrate")]
+  ConstantQualityWithBitrate,
 }
 
 /// Contains the encoder configuration.
@@ -219,6 +282,10 @@ impl Config {
       inner.rc_state.init_first_pass(params.pass1_log_base_q);
     }
 
+    if let Some(crf) = self.rate_control.crf {
+      inner.rc_state.init_constant_quality(crf);
+    }
+
     if let Some(ref s) = self.rate_control.summary {
       inner.rc_state.init_second_pass();
       inner.rc_state.setup_second_pass(s);
@@ -286,10 +353,63 @@ impl Config {
       }
     }
 
+    if config.max_bitrate != 0 || config.buffer_size != 0 {
+      // Both delays signaled for the decoder model must be positive, and
+      // their sum cannot exceed the one second buffer of the level.
+      let total_delay = if config.max_bitrate > 0 && config.buffer_size > 0 {
+        90_000 * config.buffer_size as u64 / config.max_bitrate as u64
+      } else {
+        0
+      };
+      if total_delay == 0
+        || total_delay > 90_000
+        || decoder_buffer_delay(config.max_bitrate, config.buffer_size) == 0
+      {
+        return Err(InvalidBufferModel {
+          max_bitrate: config.max_bitrate,
+          buffer_size: config.buffer_size,
+        });
+      }
+      if decoder_model_level(config).is_none() {
+        return Err(InvalidMaxBitrate(config.max_bitrate));
+      }
+      if config.bitrate > config.max_bitrate {
+        return Err(InvalidBitrate {
+          actual: config.bitrate,
+          max: config.max_bitrate,
+        });
+      }
+    }
+
     if config.switch_frame_interval > 0 && !config.low_latency {
       return Err(InvalidSwitchFrameInterval(config.switch_frame_interval));
     }
 
+    if config.pyramid_depth > MAX_PYRAMID_DEPTH {
+      return Err(InvalidPyramidDepth {
+        actual: config.pyramid_depth,
+        max: MAX_PYRAMID_DEPTH,
+      });
+    }
+
+    if config.film_grain > MAX_DENOISE_STRENGTH {
+      return Err(InvalidFilmGrain {
+        actual: config.film_grain,
+        max: MAX_DENOISE_STRENGTH,
+      });
+    }
+
+    if config.superres_mode == SuperresMode::Fixed
+      && (config.superres_denom < SUPERRES_DENOM_MIN
+        || config.superres_denom > SUPERRES_DENOM_MAX)
+    {
+      return Err(InvalidSuperresDenom {
+        actual: config.superres_denom,
+        min: SUPERRES_DENOM_MIN,
+        max: SUPERRES_DENOM_MAX,
+      });
+    }
+
     // TODO: add more validation
     let rc = &self.rate_control;
 
@@ -297,6 +417,10 @@ impl Config {
       return Err(TargetBitrateNeeded);
     }
 
+    if rc.crf.is_some() && config.bitrate != 0 {
+      return Err(ConstantQualityWithBitrate);
+    }
+
     Ok(())
   }
 }
@@ -27,6 +27,7 @@ pub enum Error {
 pub struct RateControlConfig {
   pub(crate) emit_pass_data: bool,
   pub(crate) summary: Option<RateControlSummary>,
+  pub(crate) crf: Option<u8>,
 }
 
 pub use crate::rate::RCSummary as RateControlSummary;
@@ -64,6 +65,17 @@ impl RateControlConfig {
     self
   }
 
+  /// Use the constant quality mode
+  ///
+  /// The quality factor is a quantizer index (0-255, smaller values are
+  /// higher quality) that is modulated per frame by the complexity estimated
+  /// by the lookahead, except for 0 which is lossless. It cannot be combined
+  /// with a target bitrate.
+  pub fn with_crf(mut self, crf: u8) -> Self {
+    self.crf = Some(crf);
+    self
+  }
+
   /// Emit the current pass data
   ///
   /// The pass data will be used in a second pass encoding session
@@ -82,6 +82,39 @@ pub struct SpeedSettings {
   /// Use fine directional intra prediction
   pub fine_directional_intra: bool,
 
+  /// Enables the recursive filter intra prediction modes.
+  ///
+  /// Enabled is slower.
+  pub filter_intra: bool,
+
+  /// Estimates frame-level global motion (rotation, zoom and shear) from
+  /// the lookahead motion vectors.
+  pub global_motion: bool,
+
+  /// Enables local warped motion for blocks following the motion of their
+  /// neighbours.
+  ///
+  /// Enabled is slower.
+  pub warped_motion: bool,
+
+  /// Searches the extended partition types (HORZ_A, HORZ_B, VERT_A, VERT_B,
+  /// HORZ_4 and VERT_4) in addition to the NONE, HORZ, VERT and SPLIT ones.
+  ///
+  /// Enabled is slower.
+  pub ext_partition_types: bool,
+
+  /// Lowers the quantized coefficient levels where it reduces the
+  /// rate-distortion cost of the transform block.
+  ///
+  /// Enabled is slower.
+  pub rdoq: bool,
+
+  /// Interpolation filters searched for the motion compensation of inter
+  /// blocks.
+  ///
+  /// Switchable and dual filters are slower.
+  pub interp_filter_search: InterpFilterSearch,
+
   // NOTE: put enums and basic type fields above
   /// Range of partition sizes that can be used. Larger ranges are slower.
   ///
@@ -117,6 +150,12 @@ impl Default for SpeedSettings {
       enable_segmentation: true,
       enable_inter_tx_split: false,
       fine_directional_intra: false,
+      filter_intra: true,
+      global_motion: true,
+      warped_motion: true,
+      ext_partition_types: true,
+      rdoq: true,
+      interp_filter_search: InterpFilterSearch::Dual,
     }
   }
 }
@@ -130,16 +169,21 @@ impl SpeedSettings {
   /// - 8: min block size 8x8, reduced TX set, fast deblock.
   /// - 7: min block size 8x8, reduced TX set.
   /// - 6 (default): min block size 8x8, reduced TX set, complex pred modes for keyframes.
-  /// - 5: min block size 8x8, complex pred modes for keyframes, RDO TX decision.
-  /// - 4: min block size 8x8, complex pred modes for keyframes, RDO TX decision, full SGR search.
+  /// - 5: min block size 8x8, complex pred modes for keyframes, RDO TX decision, global motion.
+  /// - 4: min block size 8x8, complex pred modes for keyframes, RDO TX decision, full SGR search,
+  ///        filter intra, global motion.
   /// - 3: min block size 8x8, complex pred modes for keyframes, RDO TX decision, include near MVs,
-  ///        full SGR search.
+  ///        full SGR search, filter intra, global and warped motion, switchable interpolation filters.
   /// - 2: min block size 4x4, complex pred modes, RDO TX decision, include near MVs,
-  ///        full SGR search, coarse directions.
+  ///        full SGR search, coarse directions, filter intra, global and warped motion, RDOQ,
+  ///        switchable interpolation filters.
   /// - 1: min block size 4x4, complex pred modes, RDO TX decision, include near MVs,
-  ///        bottom-up encoding, full SGR search.
+  ///        bottom-up encoding with extended partitions, full SGR search, filter intra,
+  ///        global and warped motion, RDOQ, dual interpolation filters.
   /// - 0 (slowest): min block size 4x4, complex pred modes, RDO TX decision, include near MVs,
-  ///        bottom-up encoding with non-square partitions everywhere, full SGR search.
+  ///        bottom-up encoding with non-square partitions everywhere and extended partitions,
+  ///        full SGR search, filter intra, global and warped motion, RDOQ,
+  ///        dual interpolation filters.
   pub fn from_preset(speed: usize) -> Self {
     SpeedSettings {
       partition_range: Self::partition_range_preset(speed),
@@ -162,6 +206,12 @@ impl SpeedSettings {
       enable_segmentation: Self::enable_segmentation_preset(speed),
       enable_inter_tx_split: Self::enable_inter_tx_split_preset(speed),
       fine_directional_intra: Self::fine_directional_intra_preset(speed),
+      filter_intra: Self::filter_intra_preset(speed),
+      global_motion: Self::global_motion_preset(speed),
+      warped_motion: Self::warped_motion_preset(speed),
+      ext_partition_types: Self::ext_partition_types_preset(speed),
+      rdoq: Self::rdoq_preset(speed),
+      interp_filter_search: Self::interp_filter_search_preset(speed),
     }
   }
 
@@ -274,6 +324,36 @@ impl SpeedSettings {
   fn fine_directional_intra_preset(speed: usize) -> bool {
     speed != 2
   }
+
+  const fn filter_intra_preset(speed: usize) -> bool {
+    speed <= 4
+  }
+
+  const fn global_motion_preset(speed: usize) -> bool {
+    speed <= 5
+  }
+
+  const fn warped_motion_preset(speed: usize) -> bool {
+    speed <= 3
+  }
+
+  const fn ext_partition_types_preset(speed: usize) -> bool {
+    speed <= 1
+  }
+
+  const fn rdoq_preset(speed: usize) -> bool {
+    speed <= 2
+  }
+
+  fn interp_filter_search_preset(speed: usize) -> InterpFilterSearch {
+    if speed <= 1 {
+      InterpFilterSearch::Dual
+    } else if speed <= 3 {
+      InterpFilterSearch::Switchable
+    } else {
+      InterpFilterSearch::Regular
+    }
+  }
 }
 
 /// Range of block sizes to use.
@@ -362,3 +442,38 @@ impl fmt::Display for SGRComplexityLevel {
     )
   }
 }
+
+/// Interpolation filters searched for inter blocks.
+#[derive(
+  Clone,
+  Copy,
+  Debug,
+  PartialOrd,
+  PartialEq,
+  FromPrimitive,
+  Serialize,
+  Deserialize,
+)]
+pub enum InterpFilterSearch {
+  /// Only the regular filter, set for the whole frame.
+  Regular,
+  /// Search the regular, smooth and sharp filters for each block.
+  Switchable,
+  /// Search the filters for each block, with separate horizontal and
+  /// vertical filters.
+  Dual,
+}
+
+impl fmt::Display for InterpFilterSearch {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    write!(
+      f,
+      "{}",
+      match self {
+        InterpFilterSearch::Regular => "Regular",
+        InterpFilterSearch::Switchable => "Switchable",
+        InterpFilterSearch::Dual => "Dual",
+      }
+    )
+  }
+}
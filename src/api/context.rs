@@ -70,6 +70,16 @@ impl<T: Pixel> Context<T> {
   /// or the encoder internal limit is hit (`std::i32::MAX` frames) the
   /// [`EncoderStatus::EnoughData`] error is returned.
   ///
+  /// If the frame size requested in the parameters is larger than the
+  /// configured size or than the frame, or smaller than half of the
+  /// configured size or than 16 samples in either dimension, the
+  /// [`EncoderStatus::Failure`] error is returned. Still pictures must be of
+  /// the configured size.
+  ///
+  /// If the quantizer offsets supplied in the parameters do not cover each
+  /// 64x64 block of the configured size, or are outside of -255..=255, the
+  /// [`EncoderStatus::Failure`] error is returned.
+  ///
   /// # Examples
   ///
   /// ```
@@ -82,7 +92,7 @@ impl<T: Pixel> Context<T> {
   /// let f2 = f1.clone();
   /// let info = FrameParameters {
   ///   frame_type_override: FrameTypeOverride::Key,
-  ///   opaque: None,
+  ///   ..Default::default()
   /// };
   ///
   /// // Send the plain frame data
@@ -90,6 +100,13 @@ impl<T: Pixel> Context<T> {
   /// // Send the data and the per-frame parameters
   /// // In this case the frame is forced to be a keyframe.
   /// ctx.send_frame((f2, info))?;
+  /// // In this case the frame is coded at half the configured size.
+  /// let f3 = ctx.new_frame();
+  /// let info = FrameParameters {
+  ///   frame_size: Some((320, 240)),
+  ///   ..Default::default()
+  /// };
+  /// ctx.send_frame((f3, info))?;
   /// // Flush the encoder, it is equivalent to a call to `flush()`
   /// ctx.send_frame(None)?;
   /// # Ok(())
@@ -98,6 +115,7 @@ impl<T: Pixel> Context<T> {
   ///
   /// [`flush`]: #method.flush
   /// [`EncoderStatus::EnoughData`]: enum.EncoderStatus.html#variant.EnoughData
+  /// [`EncoderStatus::Failure`]: enum.EncoderStatus.html#variant.Failure
   #[inline]
   pub fn send_frame<F>(&mut self, frame: F) -> Result<(), EncoderStatus>
   where
@@ -105,6 +123,39 @@ impl<T: Pixel> Context<T> {
   {
     let (frame, params) = frame.into();
 
+    if let (Some(frame), Some((width, height))) =
+      (&frame, params.as_ref().and_then(|params| params.frame_size))
+    {
+      let PlaneConfig { width: frame_w, height: frame_h, .. } =
+        frame.planes[0].cfg;
+      // References may not be scaled down more than twice, which holds
+      // between any two frames in this range.
+      let fits = |len: usize, max: usize| len <= max && 2 * len >= max;
+      let resized = width != self.config.width || height != self.config.height;
+      if !fits(width, self.config.width)
+        || !fits(height, self.config.height)
+        || width < 16
+        || height < 16
+        || width > frame_w
+        || height > frame_h
+        || (resized && self.config.still_picture)
+      {
+        return Err(EncoderStatus::Failure);
+      }
+    }
+
+    if let Some(qp_offsets) =
+      params.as_ref().and_then(|params| params.qp_offsets.as_ref())
+    {
+      let cols = (self.config.width + 63) / 64;
+      let rows = (self.config.height + 63) / 64;
+      if qp_offsets.len() != cols * rows
+        || qp_offsets.iter().any(|&offset| !(-255..=255).contains(&offset))
+      {
+        return Err(EncoderStatus::Failure);
+      }
+    }
+
     if frame.is_none() {
       if self.is_flushing {
         return Ok(());
@@ -315,14 +366,24 @@ impl<T: Pixel> Context<T> {
         bw.write_bit(true)?; // marker
         bw.write(7, 1)?; // version
         bw.write(3, seq.profile)?;
-        bw.write(5, 31)?; // level
+        bw.write(5, seq.seq_level_idx(0) as u32)?; // level
         bw.write_bit(false)?; // tier
         bw.write_bit(seq.bit_depth > 8)?; // high_bitdepth
         bw.write_bit(seq.bit_depth == 12)?; // twelve_bit
-        bw.write_bit(seq.bit_depth == 1)?; // monochrome
+        bw.write_bit(seq.chroma_sampling == ChromaSampling::Cs400)?; // monochrome
         bw.write_bit(seq.chroma_sampling != ChromaSampling::Cs444)?; // chroma_subsampling_x
-        bw.write_bit(seq.chroma_sampling == ChromaSampling::Cs420)?; // chroma_subsampling_y
-        bw.write(2, 0)?; // sample_position
+        bw.write_bit(
+          seq.chroma_sampling == ChromaSampling::Cs420
+            || seq.chroma_sampling == ChromaSampling::Cs400,
+        )?; // chroma_subsampling_y
+        bw.write(
+          2,
+          if seq.chroma_sampling == ChromaSampling::Cs420 {
+            seq.chroma_sample_position as u32
+          } else {
+            0
+          },
+        )?; // chroma_sample_position
         bw.write(3, 0)?; // reserved
         bw.write_bit(false)?; // initial_presentation_delay_present
 
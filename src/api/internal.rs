@@ -13,15 +13,20 @@ use crate::api::lookahead::*;
 use crate::api::{EncoderConfig, EncoderStatus, FrameType, Packet};
 use crate::color::ChromaSampling::Cs400;
 use crate::cpu_features::CpuFeatureLevel;
+use crate::denoise::denoise_frame;
 use crate::dist::get_satd;
 use crate::encoder::*;
+use crate::film_grain::*;
 use crate::frame::*;
+use crate::global_motion::estimate_global_motion;
 use crate::hawktracer::*;
+use crate::palette::is_screen_content;
 use crate::partition::*;
 use crate::rate::{
-  RCState, FRAME_NSUBTYPES, FRAME_SUBTYPE_I, FRAME_SUBTYPE_P,
-  FRAME_SUBTYPE_SEF,
+  RCState, FRAME_NSUBTYPES, FRAME_SUBTYPE_B1, FRAME_SUBTYPE_I,
+  FRAME_SUBTYPE_P, FRAME_SUBTYPE_SEF,
 };
+use crate::scale::{crop_frame, resample_frame, SUPERRES_NUM};
 use crate::scenechange::SceneChangeDetector;
 use crate::stats::EncoderStats;
 use crate::tiling::Area;
@@ -35,6 +40,37 @@ use std::fs;
 use std::path::PathBuf;
 use std::sync::Arc;
 
+/// The maximum supported depth of the re-ordering pyramid.
+pub(crate) const MAX_PYRAMID_DEPTH: u64 = 4;
+
+/// The maximum number of output frames in a re-ordering group.
+const MAX_GROUP_OUTPUT_LEN: usize =
+  (1 << MAX_PYRAMID_DEPTH) + (1 << (MAX_PYRAMID_DEPTH - 1));
+
+/// A single output frame of a re-ordering group.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct GroupFrame {
+  /// Offset of the input frame from the start of the group (1-based).
+  offset: u64,
+  /// Level of the frame in the pyramid.
+  level: u64,
+  /// Whether the frame is shown.
+  show_frame: bool,
+  /// Whether the frame is a Show Existing Frame of an earlier hidden frame.
+  show_existing_frame: bool,
+}
+
+/// The re-ordering group an output frame belongs to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct ReorderGroup {
+  /// Index of the group in the current keyframe gop.
+  pub(crate) idx: u64,
+  /// Order-hint of the last input frame before the group.
+  pub(crate) order_hint_start: u64,
+  /// The depth of the re-ordering pyramid of the group.
+  pub(crate) pyramid_depth: u64,
+}
+
 /// The set of options that controls frame re-ordering and reference picture
 ///  selection.
 /// The options stored here are invariant over the whole encode.
@@ -45,13 +81,19 @@ pub struct InterConfig {
   /// Whether P-frames can use multiple references.
   pub(crate) multiref: bool,
   /// The depth of the re-ordering pyramid.
-  /// The current code cannot support values larger than 2.
+  /// When the depth is chosen adaptively, this is the maximum depth of a
+  ///  group.
+  /// This cannot be larger than MAX_PYRAMID_DEPTH.
   pub(crate) pyramid_depth: u64,
+  /// Whether the depth of each group is chosen from the lookahead.
+  pub(crate) adaptive_pyramid_depth: bool,
   /// Number of input frames in group.
   pub(crate) group_input_len: u64,
   /// Number of output frames in group.
   /// This includes both hidden frames and "show existing frame" frames.
   group_output_len: u64,
+  /// The output frames of a group of each depth, in coding order.
+  groups: [[GroupFrame; MAX_GROUP_OUTPUT_LEN]; MAX_PYRAMID_DEPTH as usize + 1],
   /// Interval between consecutive S-frames.
   /// Keyframes reset this interval.
   /// This MUST be a multiple of group_input_len.
@@ -61,8 +103,10 @@ pub struct InterConfig {
 impl InterConfig {
   pub(crate) fn new(enc_config: &EncoderConfig) -> InterConfig {
     let reorder = !enc_config.low_latency;
-    // A group always starts with (group_output_len - group_input_len) hidden
-    //  frames, followed by group_input_len shown frames.
+    // A group is coded depth-first: the frame ending the group is coded
+    //  first as a hidden frame, then each half of the group is coded
+    //  recursively, with the frame in its middle coded as a hidden frame at
+    //  the next level.
     // The shown frames iterate over the input frames in order, with frames
     //  already encoded as hidden frames now displayed with Show Existing
     //  Frame.
@@ -74,25 +118,104 @@ impl InterConfig {
     // level:                 0   1   2   1   2   0
     //                        ^^^^^   ^^^^^^^^^^^^^
     //                        hidden      shown
-    // TODO: This only works for pyramid_depth <= 2 --- after that we need
-    //  more hidden frames in the middle of the group.
-    let pyramid_depth = if reorder { 2 } else { 0 };
+    // For a pyramid depth of 3, hidden frames are also coded in the middle
+    //  of the group:
+    // input_frameno:         8   4   2   1  SEF  3  SEF  6   5  SEF  7  SEF
+    // level:                 0   1   2   3   2   3   1   2   3   2   3   0
+    let pyramid_depth = if reorder { enc_config.pyramid_depth } else { 0 };
+    assert!(pyramid_depth <= MAX_PYRAMID_DEPTH);
     let group_input_len = 1 << pyramid_depth;
-    let group_output_len = group_input_len + pyramid_depth;
+    let mut groups = [[GroupFrame::default(); MAX_GROUP_OUTPUT_LEN];
+      MAX_PYRAMID_DEPTH as usize + 1];
+    for (depth, padded_group) in groups.iter_mut().enumerate() {
+      let depth = depth as u64;
+      let len = 1 << depth;
+      let mut group = ArrayVec::<[GroupFrame; MAX_GROUP_OUTPUT_LEN]>::new();
+      if depth == 0 {
+        group.push(GroupFrame {
+          offset: 1,
+          level: 0,
+          show_frame: true,
+          show_existing_frame: false,
+        });
+      } else {
+        group.push(GroupFrame {
+          offset: len,
+          level: 0,
+          show_frame: false,
+          show_existing_frame: false,
+        });
+        Self::build_group(&mut group, 0, len, 0, 1);
+      }
+      debug_assert_eq!(group.len() as u64, Self::output_len(depth));
+      padded_group[..group.len()].copy_from_slice(&group);
+    }
     let switch_frame_interval = enc_config.switch_frame_interval;
     assert!(switch_frame_interval % group_input_len == 0);
     InterConfig {
       reorder,
       multiref: reorder || enc_config.speed_settings.multiref,
       pyramid_depth,
+      adaptive_pyramid_depth: reorder && enc_config.adaptive_pyramid_depth,
       group_input_len,
-      group_output_len,
+      group_output_len: Self::output_len(pyramid_depth),
+      groups,
       switch_frame_interval,
     }
   }
 
+  /// Append the output frames coding the input frames in `(lo, hi]` of a
+  ///  group, given that the frame at `hi` was already coded as a hidden
+  ///  frame at `hi_level` and the next free level is `level`.
+  fn build_group(
+    group: &mut ArrayVec<[GroupFrame; MAX_GROUP_OUTPUT_LEN]>, lo: u64,
+    hi: u64, hi_level: u64, level: u64,
+  ) {
+    if hi - lo == 1 {
+      group.push(GroupFrame {
+        offset: hi,
+        level: hi_level,
+        show_frame: true,
+        show_existing_frame: true,
+      });
+      return;
+    }
+    let mid = (lo + hi) / 2;
+    if mid - lo == 1 {
+      group.push(GroupFrame {
+        offset: mid,
+        level,
+        show_frame: true,
+        show_existing_frame: false,
+      });
+    } else {
+      group.push(GroupFrame {
+        offset: mid,
+        level,
+        show_frame: false,
+        show_existing_frame: false,
+      });
+      Self::build_group(group, lo, mid, level, level + 1);
+    }
+    Self::build_group(group, mid, hi, hi_level, level + 1);
+  }
+
+  /// Number of output frames in a group of the given pyramid depth.
+  const fn output_len(pyramid_depth: u64) -> u64 {
+    // Every other input frame is first coded as a hidden frame.
+    (1 << pyramid_depth) + ((1 << pyramid_depth) >> 1)
+  }
+
+  fn get_group_frame(
+    &self, group: &ReorderGroup, idx_in_group_output: u64,
+  ) -> &GroupFrame {
+    debug_assert!(idx_in_group_output < Self::output_len(group.pyramid_depth));
+    &self.groups[group.pyramid_depth as usize][idx_in_group_output as usize]
+  }
+
   /// Get the index of an output frame in its re-ordering group given the output
-  ///  frame number of the frame in the current keyframe gop.
+  ///  frame number of the frame in the current keyframe gop, assuming all the
+  ///  groups have the maximum depth.
   /// When re-ordering is disabled, this always returns 0.
   pub(crate) fn get_idx_in_group_output(
     &self, output_frameno_in_gop: u64,
@@ -103,85 +226,90 @@ impl InterConfig {
     (output_frameno_in_gop - 1) % self.group_output_len
   }
 
-  /// Get the order-hint of an output frame given the output frame number of the
-  ///  frame in the current keyframe gop and the index of that output frame
-  ///  in its re-ordering gorup.
-  pub(crate) fn get_order_hint(
-    &self, output_frameno_in_gop: u64, idx_in_group_output: u64,
-  ) -> u32 {
-    // The first frame in the GOP should be a keyframe, but currently this
-    //  function only handles inter frames.
-    // We could return 0 for keyframes if keyframe support is needed.
+  /// Get the re-ordering group of an output frame given the output frame
+  ///  number of the frame in the current keyframe gop, assuming all the
+  ///  groups have the maximum depth.
+  pub(crate) fn get_group(&self, output_frameno_in_gop: u64) -> ReorderGroup {
     debug_assert!(output_frameno_in_gop > 0);
     // Which P-frame group in the current gop is this output frame in?
     // Subtract 1 because the first frame in the gop is always a keyframe.
-    let group_idx = (output_frameno_in_gop - 1) / self.group_output_len;
+    let idx = (output_frameno_in_gop - 1) / self.group_output_len;
+    ReorderGroup {
+      idx,
+      order_hint_start: self.group_input_len * idx,
+      pyramid_depth: self.pyramid_depth,
+    }
+  }
+
+  /// Whether an output frame is the last one of its re-ordering group.
+  pub(crate) fn is_last_in_group(
+    &self, group: &ReorderGroup, idx_in_group_output: u64,
+  ) -> bool {
+    idx_in_group_output + 1 == Self::output_len(group.pyramid_depth)
+  }
+
+  /// Get the re-ordering group following the given one, or the first group
+  ///  of a gop if there is none, with the maximum depth.
+  pub(crate) fn get_next_group(
+    &self, group: Option<&ReorderGroup>,
+  ) -> ReorderGroup {
+    match group {
+      Some(group) => ReorderGroup {
+        idx: group.idx + 1,
+        order_hint_start: group.order_hint_start + (1 << group.pyramid_depth),
+        pyramid_depth: self.pyramid_depth,
+      },
+      None => ReorderGroup {
+        idx: 0,
+        order_hint_start: 0,
+        pyramid_depth: self.pyramid_depth,
+      },
+    }
+  }
+
+  /// Get the order-hint of an output frame given its re-ordering group and
+  ///  the index of that output frame in its re-ordering gorup.
+  pub(crate) fn get_order_hint(
+    &self, group: &ReorderGroup, idx_in_group_output: u64,
+  ) -> u32 {
     // Get the offset to the corresponding input frame.
-    // TODO: This only works with pyramid_depth <= 2.
-    let offset = if idx_in_group_output < self.pyramid_depth {
-      self.group_input_len >> idx_in_group_output
-    } else {
-      idx_in_group_output - self.pyramid_depth + 1
-    };
+    let offset = self.get_group_frame(group, idx_in_group_output).offset;
     // Construct the final order hint relative to the start of the group.
-    (self.group_input_len * group_idx + offset) as u32
+    (group.order_hint_start + offset) as u32
   }
 
   /// Get the level of the current frame in the pyramid.
-  pub(crate) fn get_level(&self, idx_in_group_output: u64) -> u64 {
-    if !self.reorder {
-      0
-    } else if idx_in_group_output < self.pyramid_depth {
-      // Hidden frames are output first (to be shown in the future).
-      idx_in_group_output
-    } else {
-      // Shown frames
-      // TODO: This only works with pyramid_depth <= 2.
-      pos_to_lvl(
-        idx_in_group_output - self.pyramid_depth + 1,
-        self.pyramid_depth,
-      )
-    }
+  pub(crate) fn get_level(
+    &self, group: &ReorderGroup, idx_in_group_output: u64,
+  ) -> u64 {
+    self.get_group_frame(group, idx_in_group_output).level
   }
 
-  pub(crate) fn get_slot_idx(&self, level: u64, order_hint: u32) -> u32 {
-    // Frames with level == 0 are stored in slots 0..4, and frames with higher
-    //  values of level in slots 4..8
+  pub(crate) fn get_slot_idx(
+    &self, group: &ReorderGroup, order_hint: u32,
+  ) -> u32 {
+    // The frames bounding a group (with level == 0) are stored in slots 0..4,
+    //  and frames with higher values of level in slots 4..8
+    let pos = order_hint as u64 - group.order_hint_start;
+    let level = pos_to_lvl(pos, group.pyramid_depth);
     if level == 0 {
-      (order_hint >> self.pyramid_depth) & 3
+      ((group.idx + (pos >> group.pyramid_depth)) & 3) as u32
     } else {
       // This only works with pyramid_depth <= 4.
       3 + level as u32
     }
   }
 
-  pub(crate) const fn get_show_frame(&self, idx_in_group_output: u64) -> bool {
-    idx_in_group_output >= self.pyramid_depth
+  pub(crate) fn get_show_frame(
+    &self, group: &ReorderGroup, idx_in_group_output: u64,
+  ) -> bool {
+    self.get_group_frame(group, idx_in_group_output).show_frame
   }
 
   pub(crate) fn get_show_existing_frame(
-    &self, idx_in_group_output: u64,
+    &self, group: &ReorderGroup, idx_in_group_output: u64,
   ) -> bool {
-    // The self.reorder test here is redundant, but short-circuits the rest,
-    //  avoiding a bunch of work when it's false.
-    self.reorder
-      && self.get_show_frame(idx_in_group_output)
-      && (idx_in_group_output - self.pyramid_depth + 1).count_ones() == 1
-      && idx_in_group_output != self.pyramid_depth
-  }
-
-  pub(crate) fn get_input_frameno(
-    &self, output_frameno_in_gop: u64, gop_input_frameno_start: u64,
-  ) -> u64 {
-    if output_frameno_in_gop == 0 {
-      gop_input_frameno_start
-    } else {
-      let idx_in_group_output =
-        self.get_idx_in_group_output(output_frameno_in_gop);
-      let order_hint =
-        self.get_order_hint(output_frameno_in_gop, idx_in_group_output);
-      gop_input_frameno_start + order_hint as u64
-    }
+    self.get_group_frame(group, idx_in_group_output).show_existing_frame
   }
 
   const fn max_reordering_latency(&self) -> u64 {
@@ -219,6 +347,14 @@ impl<T: Pixel> FrameData<T> {
   }
 }
 
+// A frame sent at another size than the configured one, cropped to the
+// size it is coded at.
+struct ScaledFrame<T: Pixel> {
+  frame: Arc<Frame<T>>,
+  width: usize,
+  height: usize,
+}
+
 // the fields pub(super) are accessed only by the tests
 pub(crate) struct ContextInner<T: Pixel> {
   pub(crate) frame_count: u64,
@@ -228,8 +364,21 @@ pub(crate) struct ContextInner<T: Pixel> {
   pub(super) frames_processed: u64,
   /// Maps *input_frameno* to frames
   pub(super) frame_q: BTreeMap<u64, Option<Arc<Frame<T>>>>, //    packet_q: VecDeque<Packet>
+  /// Maps *input_frameno* to the frames sent at another size than the
+  ///  configured one, along with their size, at which they are coded.
+  /// The frame queue holds them resampled to the configured size, for the
+  ///  lookahead.
+  scaled_frames: BTreeMap<u64, ScaledFrame<T>>,
+  /// Maps *input_frameno* to the quantizer offsets of the 64x64 blocks
+  ///  supplied with the frame.
+  qp_offsets: BTreeMap<u64, Box<[i16]>>,
   /// Maps *output_frameno* to frame data
   pub(super) frame_data: BTreeMap<u64, FrameData<T>>,
+  /// Maps *input_frameno* to the lookahead costs of the frame relative to
+  ///  the previous one.
+  /// Only filled in when the pyramid depth is chosen adaptively or in
+  ///  constant quality mode.
+  lookahead_costs: BTreeMap<u64, LookaheadCosts>,
   /// A list of the input_frameno for keyframes in this encode.
   /// Needed so that we don't need to keep all of the frame_invariants in
   ///  memory for the whole life of the encode.
@@ -272,7 +421,10 @@ impl<T: Pixel> ContextInner<T> {
       output_frameno: 0,
       frames_processed: 0,
       frame_q: BTreeMap::new(),
+      scaled_frames: BTreeMap::new(),
+      qp_offsets: BTreeMap::new(),
       frame_data: BTreeMap::new(),
+      lookahead_costs: BTreeMap::new(),
       keyframes: BTreeSet::new(),
       keyframes_forced: BTreeSet::new(),
       packet_data,
@@ -297,6 +449,8 @@ impl<T: Pixel> ContextInner<T> {
         enc.min_quantizer,
         enc.max_key_frame_interval as i32,
         enc.reservoir_frame_delay,
+        enc.max_bitrate,
+        enc.buffer_size,
       ),
       maybe_prev_log_base_q: None,
       next_lookahead_frame: 0,
@@ -314,6 +468,29 @@ impl<T: Pixel> ContextInner<T> {
     if !is_flushing {
       self.frame_count += 1;
     }
+    let frame_size = params.as_ref().and_then(|params| params.frame_size);
+    let frame = match (frame, frame_size) {
+      (Some(frame), Some((width, height)))
+        if width != self.config.width || height != self.config.height =>
+      {
+        let cs = self.config.chroma_sampling;
+        let resampled = resample_frame(
+          &frame,
+          width,
+          height,
+          self.config.width,
+          self.config.height,
+          cs,
+        );
+        let cropped = crop_frame(&frame, width, height, cs);
+        let frame = Arc::new(cropped);
+        self
+          .scaled_frames
+          .insert(input_frameno, ScaledFrame { frame, width, height });
+        Some(Arc::new(resampled))
+      }
+      (frame, _) => frame,
+    };
     self.frame_q.insert(input_frameno, frame);
 
     if let Some(params) = params {
@@ -323,6 +500,9 @@ impl<T: Pixel> ContextInner<T> {
       if let Some(op) = params.opaque {
         self.opaque_q.insert(input_frameno, op);
       }
+      if let Some(qp_offsets) = params.qp_offsets {
+        self.qp_offsets.insert(input_frameno, qp_offsets);
+      }
     }
 
     if self.config.still_picture || self.next_lookahead_frame == 0 {
@@ -346,9 +526,11 @@ impl<T: Pixel> ContextInner<T> {
             break;
           }
 
+          self.compute_lookahead_costs(cur_lookahead_frames);
           self.compute_keyframe_placement(cur_lookahead_frames);
         }
       } else {
+        self.compute_lookahead_costs(&lookahead_frames);
         self.compute_keyframe_placement(&lookahead_frames);
       }
     }
@@ -414,7 +596,7 @@ impl<T: Pixel> ContextInner<T> {
   fn set_frame_properties(
     &mut self, output_frameno: u64,
   ) -> Result<(), EncoderStatus> {
-    let fi = self.build_frame_properties(output_frameno)?;
+    let mut fi = self.build_frame_properties(output_frameno)?;
 
     if output_frameno == 0 && log_enabled!(Info) {
       if fi.tiling.tile_count() == 1 {
@@ -431,7 +613,56 @@ impl<T: Pixel> ContextInner<T> {
 
     let frame =
       self.frame_q.get(&fi.input_frameno).as_ref().unwrap().as_ref().unwrap();
-    self.frame_data.insert(output_frameno, FrameData::new(fi, frame.clone()));
+    let frame = if self.config.film_grain > 0
+      && !fi.invalid
+      && !fi.show_existing_frame
+    {
+      // Encode the denoised source and signal the removed grain instead.
+      let denoised = denoise_frame(
+        frame,
+        fi.width,
+        fi.height,
+        self.config.chroma_sampling,
+        self.config.film_grain,
+        self.config.bit_depth,
+      );
+      fi.film_grain_params = estimate_film_grain_params(
+        frame,
+        &denoised,
+        fi.width,
+        fi.height,
+        self.config.chroma_sampling,
+        self.config.pixel_range,
+        self.config.bit_depth,
+      )
+      .map(|params| FilmGrainParams {
+        random_seed: film_grain_seed(fi.input_frameno),
+        ..params
+      });
+      Arc::new(denoised)
+    } else {
+      frame.clone()
+    };
+    if fi.sequence.force_screen_content_tools == 2
+      && !fi.invalid
+      && !fi.show_existing_frame
+    {
+      fi.allow_screen_content_tools =
+        if self.config.tune == Tune::ScreenContent {
+          1
+        } else {
+          is_screen_content(
+            &frame.planes[0],
+            fi.width,
+            fi.height,
+            self.config.bit_depth,
+          ) as u32
+        };
+    }
+    fi.allow_intrabc = self.config.intrabc
+      && fi.allow_screen_content_tools != 0
+      && fi.intra_only;
+    self.frame_data.insert(output_frameno, FrameData::new(fi, frame));
 
     Ok(())
   }
@@ -473,10 +704,16 @@ impl<T: Pixel> ContextInner<T> {
 
     let output_frameno_in_gop =
       output_frameno - self.gop_output_frameno_start[&output_frameno];
-    let mut input_frameno = self.inter_cfg.get_input_frameno(
-      output_frameno_in_gop,
-      self.gop_input_frameno_start[&output_frameno],
-    );
+    let mut input_frameno = self.gop_input_frameno_start[&output_frameno];
+    let (group, idx_in_group_output) = if output_frameno_in_gop == 0 {
+      (ReorderGroup::default(), 0)
+    } else {
+      let (group, idx_in_group_output) =
+        self.get_reorder_group(output_frameno)?;
+      input_frameno +=
+        self.inter_cfg.get_order_hint(&group, idx_in_group_output) as u64;
+      (group, idx_in_group_output)
+    };
 
     if self.needs_more_frame_q_lookahead(input_frameno) {
       return Err(EncoderStatus::NeedMoreData);
@@ -491,8 +728,7 @@ impl<T: Pixel> ContextInner<T> {
         self.frame_data[&(output_frameno - 1)].fi.input_frameno;
       if input_frameno >= next_keyframe_input_frameno {
         if !self.inter_cfg.reorder
-          || ((output_frameno_in_gop - 1) % self.inter_cfg.group_output_len
-            == 0
+          || (idx_in_group_output == 0
             && prev_input_frameno == (next_keyframe_input_frameno - 1))
         {
           input_frameno = next_keyframe_input_frameno;
@@ -514,7 +750,8 @@ impl<T: Pixel> ContextInner<T> {
             &self.frame_data[&(output_frameno - 1)].fi,
             &self.inter_cfg,
             self.gop_input_frameno_start[&output_frameno],
-            output_frameno_in_gop,
+            group,
+            idx_in_group_output,
             next_keyframe_input_frameno,
             self.config.error_resilient,
           );
@@ -563,7 +800,8 @@ impl<T: Pixel> ContextInner<T> {
         &self.frame_data[&(output_frameno - 1)].fi,
         &self.inter_cfg,
         self.gop_input_frameno_start[&output_frameno],
-        output_frameno_in_gop,
+        group,
+        idx_in_group_output,
         next_keyframe_input_frameno,
         self.config.error_resilient,
       );
@@ -572,6 +810,83 @@ impl<T: Pixel> ContextInner<T> {
     }
   }
 
+  /// Get the re-ordering group and the index in that group of an output
+  ///  frame which is not the first frame of its gop.
+  /// The depth of the group is chosen when its first frame is reached.
+  fn get_reorder_group(
+    &self, output_frameno: u64,
+  ) -> Result<(ReorderGroup, u64), EncoderStatus> {
+    let prev_fi = &self.frame_data[&(output_frameno - 1)].fi;
+    let prev_group = if self.gop_output_frameno_start[&output_frameno]
+      == output_frameno - 1
+    {
+      // The previous frame is the keyframe starting the gop.
+      None
+    } else if self
+      .inter_cfg
+      .is_last_in_group(&prev_fi.group, prev_fi.idx_in_group_output)
+    {
+      Some(prev_fi.group)
+    } else {
+      return Ok((prev_fi.group, prev_fi.idx_in_group_output + 1));
+    };
+
+    let mut group = self.inter_cfg.get_next_group(prev_group.as_ref());
+    let input_frameno_start =
+      self.gop_input_frameno_start[&output_frameno] + group.order_hint_start;
+    if self.needs_more_frame_q_lookahead(
+      input_frameno_start + self.inter_cfg.group_input_len,
+    ) {
+      return Err(EncoderStatus::NeedMoreData);
+    }
+    group.pyramid_depth = self.get_pyramid_depth(input_frameno_start);
+    Ok((group, 0))
+  }
+
+  /// Get the depth of the re-ordering group following the input frame
+  ///  `input_frameno`.
+  fn get_pyramid_depth(&self, input_frameno: u64) -> u64 {
+    if !self.inter_cfg.adaptive_pyramid_depth {
+      return self.inter_cfg.pyramid_depth;
+    }
+    let complexities = (1..=self.inter_cfg.group_input_len)
+      .map(|i| {
+        // Frames past the end of the video do not have an estimate.
+        self
+          .lookahead_costs
+          .get(&(input_frameno + i))
+          .map(LookaheadCosts::temporal_complexity)
+          .unwrap_or(0)
+      })
+      .collect::<Vec<_>>();
+    select_pyramid_depth(&complexities, self.inter_cfg.pyramid_depth)
+  }
+
+  /// Returns the lookahead costs of the frame at `output_frameno`, if they
+  ///  have been estimated.
+  pub(crate) fn get_lookahead_costs(
+    &self, output_frameno: u64,
+  ) -> Option<LookaheadCosts> {
+    let fi = &self.frame_data.get(&output_frameno)?.fi;
+    self.lookahead_costs.get(&fi.input_frameno).cloned()
+  }
+
+  /// Returns the number of frames left to code in the temporal unit of the
+  ///  frame at `output_frameno`, including it and the shown frame ending it.
+  pub(crate) fn tu_frames_left(&self, output_frameno: u64) -> usize {
+    let mut frames = 0;
+    for (_, frame_data) in self.frame_data.range(output_frameno..) {
+      if frame_data.fi.invalid {
+        continue;
+      }
+      frames += 1;
+      if frame_data.fi.show_frame {
+        break;
+      }
+    }
+    frames.max(1)
+  }
+
   pub(crate) fn done_processing(&self) -> bool {
     self.limit.map(|limit| self.frames_processed == limit).unwrap_or(false)
   }
@@ -631,14 +946,19 @@ impl<T: Pixel> ContextInner<T> {
       // We do want to propagate the lookahead_rec_buffer though.
       let rfs = Arc::new(ReferenceFrame {
         order_hint: fi.order_hint,
+        base_q_idx: fi.base_q_idx,
         // Use the original frame contents.
         frame: fs.input.clone(),
+        width: fi.width,
+        height: fi.height,
         input_hres: fs.input_hres.clone(),
         input_qres: fs.input_qres.clone(),
         cdfs: fs.cdfs,
         frame_mvs: fs.frame_mvs.clone(),
         output_frameno,
         segmentation: fs.segmentation,
+        segment_map: fs.segment_map.clone(),
+        gm_params: fi.gm_params,
       });
       for i in 0..(REF_FRAMES as usize) {
         if (fi.refresh_frame_flags & (1 << i)) != 0 {
@@ -669,6 +989,14 @@ impl<T: Pixel> ContextInner<T> {
     // Save the motion vectors to FrameInvariants.
     fi.lookahead_mvs = fs.frame_mvs.clone();
 
+    // Estimate the global motion relative to each reference.
+    if fi.frame_type == FrameType::INTER
+      && fi.force_integer_mv == 0
+      && fi.config.speed_settings.global_motion
+    {
+      fi.gm_params = estimate_global_motion(fi, fs);
+    }
+
     #[cfg(feature = "dump_lookahead_data")]
     {
       use crate::partition::RefType::*;
@@ -710,14 +1038,19 @@ impl<T: Pixel> ContextInner<T> {
     // FrameInvariants to pick it up.
     let rfs = Arc::new(ReferenceFrame {
       order_hint: fi.order_hint,
+      base_q_idx: fi.base_q_idx,
       // Use the original frame contents.
       frame: fs.input.clone(),
+      width: fi.width,
+      height: fi.height,
       input_hres: fs.input_hres.clone(),
       input_qres: fs.input_qres.clone(),
       cdfs: fs.cdfs,
       frame_mvs: fs.frame_mvs.clone(),
       output_frameno,
       segmentation: fs.segmentation,
+      segment_map: fs.segment_map.clone(),
+      gm_params: fi.gm_params,
     });
     for i in 0..(REF_FRAMES as usize) {
       if (fi.refresh_frame_flags & (1 << i)) != 0 {
@@ -751,6 +1084,31 @@ impl<T: Pixel> ContextInner<T> {
     );
   }
 
+  /// Estimates the costs of the next frame to be processed by the lookahead,
+  ///  which are needed to choose the depth of the re-ordering groups
+  ///  adaptively and to modulate the quantizer in constant quality mode.
+  #[hawktracer(compute_lookahead_costs)]
+  fn compute_lookahead_costs(&mut self, lookahead_frames: &[Arc<Frame<T>>]) {
+    if !(self.inter_cfg.adaptive_pyramid_depth
+      || self.rc_state.is_constant_quality())
+      || lookahead_frames.len() < 2
+    {
+      return;
+    }
+
+    self.lookahead_costs.insert(
+      self.next_lookahead_frame,
+      estimate_lookahead_costs(
+        lookahead_frames[1].clone(),
+        lookahead_frames[0].clone(),
+        self.config.bit_depth,
+        CpuFeatureLevel::default(),
+        self.config,
+        self.seq,
+      ),
+    );
+  }
+
   #[hawktracer(compute_keyframe_placement)]
   pub fn compute_keyframe_placement(
     &mut self, lookahead_frames: &[Arc<Frame<T>>],
@@ -776,7 +1134,10 @@ impl<T: Pixel> ContextInner<T> {
     {
       self
         .compute_lookahead_motion_vectors(self.next_lookahead_output_frameno);
-      if self.config.temporal_rdo() {
+      if self.config.temporal_rdo()
+        || self.config.delta_q
+        || self.config.speed_settings.enable_segmentation
+      {
         self.compute_lookahead_intra_costs(self.next_lookahead_output_frameno);
       }
       self.next_lookahead_output_frameno += 1;
@@ -1116,7 +1477,12 @@ impl<T: Pixel> ContextInner<T> {
         false,
       );
       let (rec, source) = if frame_data.fi.show_frame {
-        (Some(frame_data.fs.rec.clone()), Some(frame_data.fs.input.clone()))
+        let source = self
+          .scaled_frames
+          .get(&frame_data.fi.input_frameno)
+          .map_or(&frame_data.fs.input, |scaled| &scaled.frame)
+          .clone();
+        (Some(frame_data.fs.rec.clone()), Some(source))
       } else {
         (None, None)
       };
@@ -1125,7 +1491,11 @@ impl<T: Pixel> ContextInner<T> {
 
       let input_frameno = frame_data.fi.input_frameno;
       let frame_type = frame_data.fi.frame_type;
-      let qp = frame_data.fi.base_q_idx;
+      // Report the quantizer the shown frame was coded with.
+      let qp = frame_data.fi.rec_buffer.frames
+        [frame_data.fi.frame_to_show_map_idx as usize]
+        .as_ref()
+        .map_or(frame_data.fi.base_q_idx, |rec| rec.base_q_idx);
       let enc_stats = frame_data.fs.enc_stats.clone();
       self.finalize_packet(
         rec,
@@ -1145,7 +1515,7 @@ impl<T: Pixel> ContextInner<T> {
       let mut frame_data =
         self.frame_data.get(&cur_output_frameno).cloned().unwrap();
       let fti = frame_data.fi.get_frame_subtype();
-      let qps = self.rc_state.select_qi(
+      let mut qps = self.rc_state.select_qi(
         self,
         cur_output_frameno,
         fti,
@@ -1153,6 +1523,72 @@ impl<T: Pixel> ContextInner<T> {
       );
       frame_data.fi.set_quantizers(&qps);
 
+      // Code the frame at the size it was sent at.
+      let scaled_input = self.scaled_frames.get(&frame_data.fi.input_frameno);
+      let scaled_input = scaled_input.map(|scaled| {
+        let (width, height) = (scaled.width, scaled.height);
+        let fi = &mut frame_data.fi;
+        fi.set_frame_size(width, height);
+        if fi.film_grain_params.is_some() {
+          // Keep the denoised input the grain was estimated from.
+          Arc::new(resample_frame(
+            &frame_data.fs.input,
+            fi.config.width,
+            fi.config.height,
+            width,
+            height,
+            fi.sequence.chroma_sampling,
+          ))
+        } else {
+          scaled.frame.clone()
+        }
+      });
+      if let Some(input) = scaled_input.clone() {
+        frame_data.fs = FrameState::new_with_frame(&frame_data.fi, input);
+      }
+
+      // Code the frame at a reduced width when super-resolution is picked,
+      // keeping the upscaled input for the references.
+      let denom = frame_data.fi.pick_superres_denom();
+      let upscaled_input = if denom != SUPERRES_NUM {
+        let fs = &frame_data.fs;
+        let upscaled_input =
+          (fs.input.clone(), fs.input_hres.clone(), fs.input_qres.clone());
+        frame_data.fi.set_superres_denom(denom);
+        let fi = &frame_data.fi;
+        let input = resample_frame(
+          &fs.input,
+          fi.upscaled_width,
+          fi.height,
+          fi.width,
+          fi.height,
+          fi.sequence.chroma_sampling,
+        );
+        frame_data.fs = FrameState::new_with_frame(fi, Arc::new(input));
+        Some(upscaled_input)
+      } else {
+        None
+      };
+
+      frame_data.fi.scale_refs();
+      if (scaled_input.is_some() || upscaled_input.is_some())
+        && !frame_data.fi.intra_only
+      {
+        // Redo the motion search of the lookahead at the coded size.
+        compute_motion_vectors(
+          &mut frame_data.fi,
+          &mut frame_data.fs,
+          &self.inter_cfg,
+        );
+      }
+
+      if self.config.delta_q || frame_data.fi.enable_segmentation {
+        frame_data.fi.activity_mask =
+          ActivityMask::from_plane(&frame_data.fs.input.planes[0]);
+      }
+      let qp_offsets = self.qp_offsets.get(&frame_data.fi.input_frameno);
+      frame_data.fi.set_delta_q_offsets(qp_offsets.map(|offsets| &offsets[..]));
+
       if self.rc_state.needs_trial_encode(fti) {
         let mut trial_fs = frame_data.fs.clone();
         let data =
@@ -1165,7 +1601,7 @@ impl<T: Pixel> ContextInner<T> {
           true,
           false,
         );
-        let qps = self.rc_state.select_qi(
+        qps = self.rc_state.select_qi(
           self,
           cur_output_frameno,
           fti,
@@ -1174,12 +1610,40 @@ impl<T: Pixel> ContextInner<T> {
         frame_data.fi.set_quantizers(&qps);
       }
 
-      // TODO: replace with ActivityMask::from_plane() when
-      // the activity mask is actually used.
-      frame_data.fi.activity_mask = ActivityMask::default();
-
-      let data =
+      let maybe_fs = if self.rc_state.has_buffer_model() {
+        Some(frame_data.fs.clone())
+      } else {
+        None
+      };
+      let mut data =
         encode_frame(&frame_data.fi, &mut frame_data.fs, &self.inter_cfg);
+      // Re-encode the frame with coarser quantizers until it is within its
+      // budget in the decoder buffer model.
+      if let Some(fs) = maybe_fs {
+        let tu_frames = self.tu_frames_left(cur_output_frameno);
+        while !self
+          .rc_state
+          .within_buffer_budget((data.len() * 8) as i64, tu_frames)
+          && frame_data.fi.base_q_idx < 255
+        {
+          qps = self.rc_state.select_buffer_limited_qi(
+            &qps,
+            (data.len() * 8) as i64,
+            fti,
+            tu_frames,
+            self.config.bit_depth,
+            self.config.chroma_sampling,
+          );
+          frame_data.fi.set_quantizers(&qps);
+          frame_data.fs = fs.clone();
+          data =
+            encode_frame(&frame_data.fi, &mut frame_data.fs, &self.inter_cfg);
+        }
+        // Even the coarsest quantizers would underflow the decoder buffer.
+        if !self.rc_state.fits_buffer((data.len() * 8) as i64, tu_frames) {
+          return Err(EncoderStatus::Failure);
+        }
+      }
       let enc_stats = frame_data.fs.enc_stats.clone();
       self.maybe_prev_log_base_q = Some(qps.log_base_q);
       // TODO: Add support for dropping frames.
@@ -1193,18 +1657,34 @@ impl<T: Pixel> ContextInner<T> {
       );
       self.packet_data.extend(data);
 
+      if let Some((input, input_hres, input_qres)) = upscaled_input {
+        frame_data.fs.input = input;
+        frame_data.fs.input_hres = input_hres;
+        frame_data.fs.input_qres = input_qres;
+      }
+
       let planes =
         if frame_data.fi.sequence.chroma_sampling == Cs400 { 1 } else { 3 };
 
       Arc::make_mut(&mut frame_data.fs.rec).pad(
-        frame_data.fi.width,
+        frame_data.fi.upscaled_width,
         frame_data.fi.height,
         planes,
       );
 
       // TODO avoid the clone by having rec Arc.
+      // The source is taken from the frame queue, since the input of the
+      // frame state may have been denoised.
       let (rec, source) = if frame_data.fi.show_frame {
-        (Some(frame_data.fs.rec.clone()), Some(frame_data.fs.input.clone()))
+        let input_frameno = frame_data.fi.input_frameno;
+        (
+          Some(frame_data.fs.rec.clone()),
+          self
+            .scaled_frames
+            .get(&input_frameno)
+            .map(|scaled| scaled.frame.clone())
+            .or_else(|| self.frame_q[&input_frameno].clone()),
+        )
       } else {
         (None, None)
       };
@@ -1214,6 +1694,13 @@ impl<T: Pixel> ContextInner<T> {
         &mut frame_data.fi,
         &frame_data.fs,
       );
+      // Later frames are derived from this one, at the configured size and
+      // from their own references.
+      if frame_data.fi.use_superres() || scaled_input.is_some() {
+        frame_data.fi.set_superres_denom(SUPERRES_NUM);
+        frame_data.fi.set_frame_size(self.config.width, self.config.height);
+      }
+      frame_data.fi.scaled_rec_buffer = None;
 
       // Copy persistent fields into subsequent FrameInvariants.
       let rec_buffer = frame_data.fi.rec_buffer.clone();
@@ -1291,7 +1778,10 @@ impl<T: Pixel> ContextInner<T> {
       return Err(EncoderStatus::LimitReached);
     }
 
-    if self.config.temporal_rdo() {
+    if self.config.temporal_rdo()
+      || self.config.delta_q
+      || self.config.speed_settings.enable_segmentation
+    {
       // Compute the block importances for the current output frame.
       self.compute_block_importances();
     }
@@ -1313,7 +1803,15 @@ impl<T: Pixel> ContextInner<T> {
     input_frameno: u64, frame_type: FrameType, qp: u8,
     enc_stats: EncoderStats,
   ) -> Result<Packet<T>, EncoderStatus> {
-    let data = self.packet_data.clone();
+    let data = if self.config.annex_b {
+      let mut data = Vec::new();
+      if write_annex_b_temporal_unit(&mut data, &self.packet_data).is_err() {
+        return Err(EncoderStatus::Failure);
+      }
+      data
+    } else {
+      self.packet_data.clone()
+    };
     self.packet_data.clear();
     if write_temporal_delimiter(&mut self.packet_data).is_err() {
       return Err(EncoderStatus::Failure);
@@ -1339,6 +1837,9 @@ impl<T: Pixel> ContextInner<T> {
     let frame_q_start = self.frame_q.keys().next().cloned().unwrap_or(0);
     for i in frame_q_start..cur_input_frameno {
       self.frame_q.remove(&i);
+      self.scaled_frames.remove(&i);
+      self.qp_offsets.remove(&i);
+      self.lookahead_costs.remove(&i);
     }
 
     if self.output_frameno < 2 {
@@ -1438,13 +1939,13 @@ impl<T: Pixel> ContextInner<T> {
         nframes_total += 1;
         continue;
       }
+      // The depth of future groups is not known yet when it is chosen
+      //  adaptively, so assume they all have the maximum depth.
+      let group = self.inter_cfg.get_group(output_frameno_in_gop);
       let idx_in_group_output =
         self.inter_cfg.get_idx_in_group_output(output_frameno_in_gop);
       let input_frameno = prev_keyframe_input_frameno
-        + self
-          .inter_cfg
-          .get_order_hint(output_frameno_in_gop, idx_in_group_output)
-          as u64;
+        + self.inter_cfg.get_order_hint(&group, idx_in_group_output) as u64;
       // For rate control purposes, ignore any limit on frame count that has
       //  been set.
       // We pretend that we will keep encoding frames forever to prevent the
@@ -1476,16 +1977,18 @@ impl<T: Pixel> ContextInner<T> {
         output_frameno += 1;
         continue;
       }
-      if self.inter_cfg.get_show_existing_frame(idx_in_group_output) {
+      if self.inter_cfg.get_show_existing_frame(&group, idx_in_group_output) {
         acc[FRAME_SUBTYPE_SEF] += 1;
       } else {
         // TODO: Implement golden P-frames.
-        let fti = FRAME_SUBTYPE_P
-          + (self.inter_cfg.get_level(idx_in_group_output) as usize);
+        // Deeper pyramid levels share the statistics of the last B-frame subtype.
+        let fti = (FRAME_SUBTYPE_P
+          + (self.inter_cfg.get_level(&group, idx_in_group_output) as usize))
+          .min(FRAME_SUBTYPE_B1);
         acc[fti] += 1;
         nframes_total += 1;
       }
-      if self.inter_cfg.get_show_frame(idx_in_group_output) {
+      if self.inter_cfg.get_show_frame(&group, idx_in_group_output) {
         ntus += 1;
       }
       output_frameno += 1;
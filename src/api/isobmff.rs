@@ -0,0 +1,46 @@
+// Copyright (c) 2020, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::api::color::*;
+use crate::api::config::EncoderConfig;
+
+/// Appends a box of the given type holding `data`.
+pub fn write_box(buf: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+  buf.extend_from_slice(&(8 + data.len() as u32).to_be_bytes());
+  buf.extend_from_slice(fourcc);
+  buf.extend_from_slice(data);
+}
+
+/// Appends a full box, a box with a version and flags, holding `data`.
+pub fn write_full_box(
+  buf: &mut Vec<u8>, fourcc: &[u8; 4], version: u8, flags: u32, data: &[u8],
+) {
+  buf.extend_from_slice(&(12 + data.len() as u32).to_be_bytes());
+  buf.extend_from_slice(fourcc);
+  buf.extend_from_slice(&(u32::from(version) << 24 | flags).to_be_bytes());
+  buf.extend_from_slice(data);
+}
+
+/// Appends the colour information of `config`, as an nclx `colr` box.
+pub fn colr(buf: &mut Vec<u8>, config: &EncoderConfig) {
+  let mut data = b"nclx".to_vec();
+  let (primaries, transfer, matrix) = match config.color_description {
+    Some(desc) => (
+      desc.color_primaries as u16,
+      desc.transfer_characteristics as u16,
+      desc.matrix_coefficients as u16,
+    ),
+    None => (2, 2, 2),
+  };
+  data.extend_from_slice(&primaries.to_be_bytes());
+  data.extend_from_slice(&transfer.to_be_bytes());
+  data.extend_from_slice(&matrix.to_be_bytes());
+  data.push(if config.pixel_range == PixelRange::Full { 0x80 } else { 0 });
+  write_box(buf, b"colr", &data);
+}
@@ -2,7 +2,7 @@ use crate::api::internal::InterConfig;
 use crate::config::EncoderConfig;
 use crate::context::{
   BlockOffset, FrameBlocks, PlaneSuperBlockOffset, SuperBlockOffset,
-  TileBlockOffset, TileSuperBlockOffset,
+  TileBlockOffset, TileSuperBlockOffset, SB_SIZE_LOG2,
 };
 use crate::cpu_features::CpuFeatureLevel;
 use crate::dist::get_satd;
@@ -12,7 +12,7 @@ use crate::encoder::{
 };
 use crate::frame::{AsRegion, PlaneOffset};
 use crate::hawktracer::*;
-use crate::partition::{get_intra_edges, BlockSize};
+use crate::partition::{get_intra_edges, BlockSize, PartitionType};
 use crate::predict::{IntraParam, PredictionMode};
 use crate::rayon::iter::*;
 use crate::tiling::{Area, TileRect, TileStateMut};
@@ -58,6 +58,8 @@ pub(crate) fn estimate_intra_costs<T: Pixel>(
         0,
         0,
         bsize,
+        PartitionType::PARTITION_NONE,
+        SB_SIZE_LOG2,
         PlaneOffset {
           x: (x * IMPORTANCE_BLOCK_SIZE) as isize,
           y: (y * IMPORTANCE_BLOCK_SIZE) as isize,
@@ -125,8 +127,10 @@ pub(crate) fn estimate_inter_costs<T: Pixel>(
   config.speed_settings.multiref = false;
   let inter_cfg = InterConfig::new(&config);
   let last_fi = FrameInvariants::new_key_frame(config, sequence, 0);
-  let mut fi =
-    FrameInvariants::new_inter_frame(&last_fi, &inter_cfg, 0, 1, 2, false);
+  let group = inter_cfg.get_group(1);
+  let mut fi = FrameInvariants::new_inter_frame(
+    &last_fi, &inter_cfg, 0, group, 0, 2, false,
+  );
 
   // Compute the motion vectors.
   let mut fs = FrameState::new_with_frame(&fi, frame.clone());
@@ -178,6 +182,77 @@ pub(crate) fn estimate_inter_costs<T: Pixel>(
   inter_costs.into_boxed_slice()
 }
 
+/// The scale of the temporal complexity estimates: a frame which is as
+/// expensive to code from the previous frame as on its own has a temporal
+/// complexity of `TEMPORAL_COMPLEXITY_SCALE`.
+pub(crate) const TEMPORAL_COMPLEXITY_SCALE: u32 = 256;
+
+/// The costs of a frame estimated by the lookahead, summed over its blocks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct LookaheadCosts {
+  /// The cost of coding the frame on its own.
+  pub intra: u64,
+  /// The cost of predicting the frame from the previous frame.
+  pub inter: u64,
+  /// The cost of coding each block with the cheaper of the two.
+  pub best: u64,
+}
+
+impl LookaheadCosts {
+  /// The temporal complexity of the frame relative to the previous one.
+  ///
+  /// The result saturates at `TEMPORAL_COMPLEXITY_SCALE`.
+  pub fn temporal_complexity(&self) -> u32 {
+    (self.inter * TEMPORAL_COMPLEXITY_SCALE as u64 / self.intra.max(1))
+      .min(TEMPORAL_COMPLEXITY_SCALE as u64) as u32
+  }
+}
+
+/// Estimates the costs of `frame` from its intra costs and from the
+/// motion-compensated inter costs relative to `ref_frame`.
+pub(crate) fn estimate_lookahead_costs<T: Pixel>(
+  frame: Arc<Frame<T>>, ref_frame: Arc<Frame<T>>, bit_depth: usize,
+  cpu_feature_level: CpuFeatureLevel, config: EncoderConfig,
+  sequence: Sequence,
+) -> LookaheadCosts {
+  let intra_costs =
+    estimate_intra_costs(&*frame, bit_depth, cpu_feature_level);
+  let inter_costs =
+    estimate_inter_costs(frame, ref_frame, bit_depth, config, sequence);
+
+  intra_costs.iter().zip(inter_costs.iter()).fold(
+    LookaheadCosts::default(),
+    |costs, (&intra_cost, &inter_cost)| LookaheadCosts {
+      intra: costs.intra + intra_cost as u64,
+      inter: costs.inter + inter_cost as u64,
+      best: costs.best + intra_cost.min(inter_cost) as u64,
+    },
+  )
+}
+
+/// Chooses the depth of a re-ordering group given the temporal complexity of
+/// the frames following its start.
+///
+/// The deepest pyramid (up to `max_pyramid_depth`) is used for which the
+/// temporal complexity accumulated over the group does not exceed that of a
+/// single frame without temporal redundancy, so static content gets long
+/// groups and high motion short ones.
+pub(crate) fn select_pyramid_depth(
+  complexities: &[u32], max_pyramid_depth: u64,
+) -> u64 {
+  (1..=max_pyramid_depth)
+    .rev()
+    .find(|&pyramid_depth| {
+      complexities
+        .iter()
+        .take(1 << pyramid_depth)
+        .map(|&complexity| complexity as u64)
+        .sum::<u64>()
+        <= TEMPORAL_COMPLEXITY_SCALE as u64
+    })
+    .unwrap_or(0)
+}
+
 #[hawktracer(compute_motion_vectors_per_tile)]
 fn compute_motion_vectors_per_tile<T: Pixel>(
   ts: &mut TileStateMut<T>, fi: &FrameInvariants<T>, inter_cfg: &InterConfig,
@@ -8,6 +8,8 @@
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 #![deny(missing_docs)]
 
+/// AVIF still image output
+pub mod avif;
 /// Color model information
 pub mod color;
 /// Encoder Configuration
@@ -16,6 +18,9 @@ pub mod config;
 pub mod context;
 /// Internal implementation
 pub(crate) mod internal;
+/// ISOBMFF box writing shared by the AVIF and MP4 outputs
+#[doc(hidden)]
+pub mod isobmff;
 /// Lookahead-specific methods
 pub(crate) mod lookahead;
 
@@ -24,6 +29,7 @@ mod util;
 #[cfg(test)]
 mod test;
 
+pub use avif::*;
 pub use color::*;
 pub use config::*;
 pub use context::*;
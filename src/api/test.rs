@@ -392,7 +392,7 @@ fn output_frameno_reorder_minus(missing: u64) {
     10,
   );
 
-  // TODO: when we support more pyramid depths, this test will need tweaks.
+  // This test assumes the default pyramid depth.
   assert_eq!(ctx.inner.inter_cfg.pyramid_depth, 2);
 
   let limit = 10 - missing;
@@ -521,7 +521,7 @@ fn pyramid_level_reorder_minus(missing: u64) {
     10,
   );
 
-  // TODO: when we support more pyramid depths, this test will need tweaks.
+  // This test assumes the default pyramid depth.
   assert_eq!(ctx.inner.inter_cfg.pyramid_depth, 2);
 
   let limit = 10 - missing;
@@ -624,6 +624,613 @@ fn pyramid_level_reorder_minus(missing: u64) {
   );
 }
 
+#[interpolate_test(0, 0)]
+#[interpolate_test(1, 1)]
+#[interpolate_test(3, 3)]
+#[interpolate_test(4, 4)]
+fn output_frameno_reorder_pyramid_depth(pyramid_depth: u64) {
+  // Test that every input frame is coded once and shown once, in order, for
+  // a non-default pyramid depth.
+
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = 64;
+  enc.height = 80;
+  enc.min_key_frame_interval = 40;
+  enc.max_key_frame_interval = 40;
+  enc.pyramid_depth = pyramid_depth;
+  enc.speed_settings.no_scene_detection = true;
+  enc.rdo_lookahead_frames = 10;
+  let cfg = Config::new().with_encoder_config(enc).with_threads(1);
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  assert_eq!(ctx.inner.inter_cfg.pyramid_depth, pyramid_depth);
+  let group_input_len = 1 << pyramid_depth;
+  assert_eq!(ctx.inner.inter_cfg.group_input_len, group_input_len);
+
+  let limit = 2 * group_input_len + 1;
+  send_frames(&mut ctx, limit, 0);
+  ctx.flush();
+
+  let fis =
+    get_frame_invariants(ctx).filter(|fi| !fi.invalid).collect::<Vec<_>>();
+
+  let coded = fis
+    .iter()
+    .filter(|fi| !fi.show_existing_frame)
+    .map(|fi| fi.input_frameno)
+    .collect::<Vec<_>>();
+  let mut sorted = coded.clone();
+  sorted.sort();
+  assert_eq!(sorted, (0..limit).collect::<Vec<_>>());
+
+  let shown = fis
+    .iter()
+    .filter(|fi| fi.show_frame || fi.show_existing_frame)
+    .map(|fi| fi.input_frameno)
+    .collect::<Vec<_>>();
+  assert_eq!(shown, (0..limit).collect::<Vec<_>>());
+
+  for fi in fis.iter().filter(|fi| fi.frame_type != FrameType::KEY) {
+    assert!(fi.pyramid_level <= pyramid_depth);
+    assert_eq!(
+      fi.pyramid_level,
+      crate::encoder::pos_to_lvl(fi.order_hint as u64, pyramid_depth)
+    );
+  }
+}
+
+fn encode_adaptive_pyramid_depth(
+  static_frames: u64, limit: u64,
+) -> Vec<FrameInvariants<u8>> {
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = 64;
+  enc.height = 64;
+  enc.min_key_frame_interval = 64;
+  enc.max_key_frame_interval = 64;
+  enc.pyramid_depth = 4;
+  enc.adaptive_pyramid_depth = true;
+  enc.speed_settings.no_scene_detection = true;
+  enc.rdo_lookahead_frames = 10;
+  let cfg = Config::new().with_encoder_config(enc).with_threads(1);
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  send_static_then_noise_frames(&mut ctx, static_frames, limit);
+  ctx.flush();
+
+  get_frame_invariants(ctx).filter(|fi| !fi.invalid).collect()
+}
+
+/// Sends `static_frames` static frames followed by frames of unrelated noise.
+fn send_static_then_noise_frames(
+  ctx: &mut Context<u8>, static_frames: u64, limit: u64,
+) {
+  let mut state = 1u32;
+  for i in 0..limit {
+    let mut input = ctx.new_frame();
+    if i < static_frames {
+      fill_frame_const(&mut input, 128);
+    } else {
+      for plane in input.planes.iter_mut() {
+        for pixel in plane.data.iter_mut() {
+          state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+          *pixel = (state >> 24) as u8;
+        }
+      }
+    }
+    let _ = ctx.send_frame(Arc::new(input));
+  }
+}
+
+#[test]
+fn adaptive_pyramid_depth() {
+  let limit = 33;
+  let fis = encode_adaptive_pyramid_depth(17, limit);
+
+  // Every input frame is still coded once and shown once, in order.
+  let mut coded = fis
+    .iter()
+    .filter(|fi| !fi.show_existing_frame)
+    .map(|fi| fi.input_frameno)
+    .collect::<Vec<_>>();
+  coded.sort();
+  assert_eq!(coded, (0..limit).collect::<Vec<_>>());
+  let shown = fis
+    .iter()
+    .filter(|fi| fi.show_frame || fi.show_existing_frame)
+    .map(|fi| fi.input_frameno)
+    .collect::<Vec<_>>();
+  assert_eq!(shown, (0..limit).collect::<Vec<_>>());
+
+  // The static frames are coded in a single group of the maximum depth,
+  // and the noise frames are not re-ordered.
+  for fi in fis.iter().filter(|fi| fi.frame_type != FrameType::KEY) {
+    if fi.input_frameno <= 16 {
+      assert_eq!(fi.group.pyramid_depth, 4);
+    } else if fi.input_frameno < limit - 1 {
+      assert_eq!(fi.group.pyramid_depth, 0);
+    }
+  }
+
+  // The decisions only depend on the input.
+  let groups = |fis: &[FrameInvariants<u8>]| {
+    fis
+      .iter()
+      .map(|fi| (fi.input_frameno, fi.group, fi.idx_in_group_output))
+      .collect::<Vec<_>>()
+  };
+  assert_eq!(groups(&fis), groups(&encode_adaptive_pyramid_depth(17, limit)));
+}
+
+fn encode_crf(
+  quantizer: usize, maybe_crf: Option<u8>, static_frames: u64, limit: u64,
+) -> Vec<(u64, FrameType, u8)> {
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = 64;
+  enc.height = 64;
+  enc.quantizer = quantizer;
+  enc.min_key_frame_interval = 64;
+  enc.max_key_frame_interval = 64;
+  enc.low_latency = true;
+  enc.speed_settings.no_scene_detection = true;
+  let mut rc = RateControlConfig::new();
+  if let Some(crf) = maybe_crf {
+    rc = rc.with_crf(crf);
+  }
+  let cfg = Config::new()
+    .with_encoder_config(enc)
+    .with_rate_control(rc)
+    .with_threads(1);
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  send_static_then_noise_frames(&mut ctx, static_frames, limit);
+  ctx.flush();
+
+  let mut packets = Vec::new();
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => packets.push((pkt.input_frameno, pkt.frame_type, pkt.qp)),
+      Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::LimitReached) => break,
+      Err(err) => panic!("Unexpected status {:?}", err),
+    }
+  }
+  packets
+}
+
+#[test]
+fn crf_modulates_quantizer() {
+  let limit = 16;
+  let fixed = encode_crf(100, None, 8, limit);
+  let crf = encode_crf(255, Some(100), 8, limit);
+  assert_eq!(crf.len(), limit as usize);
+
+  for (&(input_frameno, frame_type, fixed_qp), &(_, _, qp)) in
+    fixed.iter().zip(crf.iter())
+  {
+    if frame_type == FrameType::KEY {
+      // Keyframes use the quality factor as their quantizer.
+      assert_eq!(qp, fixed_qp);
+    } else if input_frameno < 8 {
+      // Static frames get a finer quantizer...
+      assert!(qp < fixed_qp);
+    } else {
+      // ...and noise a coarser one.
+      assert!(qp > fixed_qp);
+    }
+  }
+}
+
+fn encode_buffer_model(
+  quantizer: usize, bitrate: i32, max_bitrate: i32, buffer_size: i32,
+  limit: u64,
+) -> Vec<usize> {
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = 64;
+  enc.height = 64;
+  enc.quantizer = quantizer;
+  enc.bitrate = bitrate;
+  enc.max_bitrate = max_bitrate;
+  enc.buffer_size = buffer_size;
+  enc.speed_settings.no_scene_detection = true;
+  let cfg = Config::new().with_encoder_config(enc).with_threads(1);
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  send_static_then_noise_frames(&mut ctx, 0, limit);
+  ctx.flush();
+
+  let mut data = Vec::new();
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => data.extend_from_slice(&pkt.data),
+      Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::LimitReached) => break,
+      Err(err) => panic!("Unexpected status {:?}", err),
+    }
+  }
+  temporal_unit_sizes(&data)
+}
+
+/// Splits a low overhead bitstream in its temporal units, each starting with
+/// a temporal delimiter, and returns their sizes in bits.
+fn temporal_unit_sizes(mut data: &[u8]) -> Vec<usize> {
+  let mut sizes = Vec::new();
+  while !data.is_empty() {
+    assert_ne!(data[0] & 0x02, 0, "OBU without a size field");
+    let header_len = if data[0] & 0x04 != 0 { 2 } else { 1 };
+    let (len, len_size) = read_uleb128(&data[header_len..]);
+    // Temporal delimiter
+    if data[0] >> 3 & 0xf == 2 {
+      sizes.push(0);
+    }
+    let obu_len = header_len + len_size + len;
+    *sizes.last_mut().expect("No leading temporal delimiter") += obu_len * 8;
+    data = &data[obu_len..];
+  }
+  sizes
+}
+
+/// Replays the decoder buffer model on the sizes of the coded temporal units,
+/// each of which is removed one tick after the previous one, and returns
+/// whether the buffer underflowed.
+fn buffer_model_underflows(
+  sizes: &[usize], max_bitrate: i32, buffer_size: i32,
+) -> bool {
+  // The default time base is 1/30.
+  let bits_per_tu = max_bitrate as i64 / 30;
+  let mut fullness =
+    crate::rate::decoder_buffer_delay(max_bitrate, buffer_size) as i64
+      * max_bitrate as i64
+      / 90_000;
+  for &size in sizes {
+    fullness -= size as i64;
+    if fullness < 0 {
+      return true;
+    }
+    fullness = (fullness + bits_per_tu).min(buffer_size as i64);
+  }
+  false
+}
+
+#[interpolate_test(quantizer, 20, 0)]
+#[interpolate_test(bitrate, 255, 100_000)]
+fn buffer_model_no_underflow(quantizer: usize, bitrate: i32) {
+  let limit = 30;
+  let max_bitrate = 600_000;
+  let buffer_size = 600_000;
+
+  // The frames would underflow the decoder buffer unconstrained...
+  let sizes = encode_buffer_model(20, 0, 0, 0, limit);
+  assert!(buffer_model_underflows(&sizes, max_bitrate, buffer_size));
+
+  // ...but not with the buffer model.
+  let sizes =
+    encode_buffer_model(quantizer, bitrate, max_bitrate, buffer_size, limit);
+  assert_eq!(sizes.len(), limit as usize);
+  assert!(!buffer_model_underflows(&sizes, max_bitrate, buffer_size));
+}
+
+#[test]
+fn buffer_model_too_small() {
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = 64;
+  enc.height = 64;
+  enc.max_bitrate = 20_000;
+  enc.buffer_size = 20_000;
+  let cfg = Config::new().with_encoder_config(enc).with_threads(1);
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  send_static_then_noise_frames(&mut ctx, 0, 10);
+  ctx.flush();
+
+  // Noise does not fit in the buffer even with the coarsest quantizers, which
+  // fails the encode instead of underflowing the buffer.
+  loop {
+    match ctx.receive_packet() {
+      Ok(_) | Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::Failure) => break,
+      Err(err) => panic!("Unexpected status {:?}", err),
+    }
+  }
+}
+
+fn setup_frame_size_encoder(
+  low_latency: bool, superres_mode: SuperresMode,
+) -> Context<u8> {
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = 64;
+  enc.height = 64;
+  enc.quantizer = 100;
+  enc.low_latency = low_latency;
+  enc.superres_mode = superres_mode;
+  enc.speed_settings.no_scene_detection = true;
+  let cfg = Config::new().with_encoder_config(enc).with_threads(1);
+  cfg.new_context().unwrap()
+}
+
+#[interpolate_test(low_latency, true, SuperresMode::None)]
+#[interpolate_test(reorder, false, SuperresMode::None)]
+#[interpolate_test(superres, true, SuperresMode::Random)]
+fn frame_size_switch(low_latency: bool, superres_mode: SuperresMode) {
+  let mut ctx = setup_frame_size_encoder(low_latency, superres_mode);
+  let sizes = [(64, 64), (48, 40), (32, 32), (32, 32), (64, 64), (40, 56)];
+
+  let mut state = 1u32;
+  for &size in sizes.iter() {
+    let mut input = ctx.new_frame();
+    for plane in input.planes.iter_mut() {
+      for pixel in plane.data.iter_mut() {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        *pixel = (state >> 24) as u8;
+      }
+    }
+    let fp = FrameParameters { frame_size: Some(size), ..Default::default() };
+    ctx.send_frame((input, fp)).unwrap();
+  }
+  ctx.flush();
+
+  let mut count = 0;
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => {
+        // Each frame is reconstructed at the size it was sent at.
+        let size = sizes[pkt.input_frameno as usize];
+        for frame in [pkt.rec.unwrap(), pkt.source.unwrap()].iter() {
+          let cfg = &frame.planes[0].cfg;
+          assert_eq!((cfg.width, cfg.height), size);
+        }
+        // The size switches do not need key frames.
+        assert_eq!(pkt.frame_type == FrameType::KEY, pkt.input_frameno == 0);
+        count += 1;
+      }
+      Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::LimitReached) => break,
+      Err(err) => panic!("Unexpected status {:?}", err),
+    }
+  }
+  assert_eq!(count, sizes.len());
+}
+
+#[test]
+fn frame_size_out_of_range() {
+  let mut ctx = setup_frame_size_encoder(true, SuperresMode::None);
+  let mut send_frame = |size| {
+    let fp = FrameParameters { frame_size: Some(size), ..Default::default() };
+    ctx.send_frame((ctx.new_frame(), fp))
+  };
+  for &size in [(72, 64), (64, 72), (31, 64), (64, 31)].iter() {
+    assert_eq!(send_frame(size), Err(EncoderStatus::Failure));
+  }
+  assert_eq!(send_frame((32, 64)), Ok(()));
+}
+
+#[test]
+fn qp_offsets() {
+  let limit = 5;
+  let mut ctx = setup_encoder::<u8>(
+    200,
+    136,
+    10,
+    100,
+    8,
+    ChromaSampling::Cs420,
+    0,
+    15,
+    0,
+    true,
+    0,
+    true,
+    10,
+  );
+
+  {
+    let mut send_frame = |qp_offsets: Vec<i16>| {
+      let fp = FrameParameters {
+        qp_offsets: Some(qp_offsets.into_boxed_slice()),
+        ..Default::default()
+      };
+      ctx.send_frame((ctx.new_frame(), fp))
+    };
+    // The offsets cover 4x3 blocks of 64x64
+    assert_eq!(send_frame(vec![0; 11]), Err(EncoderStatus::Failure));
+    assert_eq!(send_frame(vec![256; 12]), Err(EncoderStatus::Failure));
+    assert_eq!(send_frame(vec![i16::MIN; 12]), Err(EncoderStatus::Failure));
+    for i in 0..limit as i16 {
+      let qp_offsets = (0..12).map(|j| (j - i) * 20).collect();
+      assert_eq!(send_frame(qp_offsets), Ok(()));
+    }
+  }
+  ctx.flush();
+
+  let mut count = 0;
+  loop {
+    match ctx.receive_packet() {
+      Ok(_) => count += 1,
+      Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::LimitReached) => break,
+      Err(err) => panic!("Unexpected status {:?}", err),
+    }
+  }
+  assert_eq!(count, limit);
+}
+
+#[interpolate_test(low_latency, true)]
+#[interpolate_test(reorder, false)]
+fn segmentation(low_latency: bool) {
+  let limit = 5;
+  let (w, h) = (128, 96);
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.quantizer = 100;
+  enc.max_key_frame_interval = 15;
+  enc.low_latency = low_latency;
+  enc.width = w;
+  enc.height = h;
+  enc.speed_settings.no_scene_detection = true;
+  enc.speed_settings.enable_segmentation = true;
+  let cfg = Config::new().with_encoder_config(enc).with_threads(1);
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  // A static textured background, busier on the right half, with a square
+  // moving over it.
+  for i in 0..limit {
+    let mut input = ctx.new_frame();
+    for plane in input.planes.iter_mut() {
+      let stride = plane.cfg.stride;
+      let width = plane.cfg.width;
+      let mut state = 1u32;
+      for (y, row) in plane.data.chunks_mut(stride).enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+          state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+          let amplitude = if x < width / 2 { 8 } else { 64 };
+          *pixel = 96 + ((state >> 24) % amplitude) as u8;
+          let (sx, sy) = (x as isize - 4 * i as isize, y as isize - 16);
+          if (0..16).contains(&sx) && (0..16).contains(&sy) {
+            *pixel = 32;
+          }
+        }
+      }
+    }
+    let qp_offsets = (0..4).map(|j| (j - 2) * 16).collect();
+    let fp =
+      FrameParameters { qp_offsets: Some(qp_offsets), ..Default::default() };
+    assert_eq!(ctx.send_frame((input, fp)), Ok(()));
+  }
+  ctx.flush();
+
+  let mut count = 0;
+  loop {
+    match ctx.receive_packet() {
+      Ok(_) => count += 1,
+      Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::LimitReached) => break,
+      Err(err) => panic!("Unexpected status {:?}", err),
+    }
+  }
+  assert_eq!(count, limit);
+}
+
+fn lossless<T: Pixel>(
+  speed: usize, bit_depth: usize, chroma_sampling: ChromaSampling,
+) {
+  let limit = 5;
+  let mut ctx = setup_encoder::<T>(
+    64,
+    80,
+    speed,
+    0,
+    bit_depth,
+    chroma_sampling,
+    0,
+    15,
+    0,
+    false,
+    0,
+    true,
+    10,
+  );
+
+  // Moving gradients with noise, to exercise both intra and inter coding.
+  let mut state = 1u32;
+  for i in 0..limit {
+    let mut input = ctx.new_frame();
+    for plane in input.planes.iter_mut() {
+      let stride = plane.cfg.stride;
+      for (y, row) in plane.data.chunks_mut(stride).enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+          state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+          let v = (x + 2 * i) * 3 + y + (state >> 29) as usize;
+          *pixel = T::cast_from(v as u16 & ((1 << bit_depth) - 1));
+        }
+      }
+    }
+    ctx.send_frame(input).unwrap();
+  }
+  ctx.flush();
+
+  let mut count = 0;
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => {
+        let (rec, source) = (pkt.rec.unwrap(), pkt.source.unwrap());
+        for (pli, (r, s)) in
+          rec.planes.iter().zip(source.planes.iter()).enumerate()
+        {
+          let width = s.cfg.width;
+          for (y, (r, s)) in r.rows_iter().zip(s.rows_iter()).enumerate() {
+            assert_eq!(
+              &r[..width],
+              &s[..width],
+              "frame {} plane {} row {}",
+              pkt.input_frameno,
+              pli,
+              y
+            );
+          }
+        }
+        count += 1;
+      }
+      Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::LimitReached) => break,
+      Err(err) => panic!("Unexpected status {:?}", err),
+    }
+  }
+  assert_eq!(count, limit);
+}
+
+#[interpolate_test(speed_10_8bit_420, 10, 8, ChromaSampling::Cs420)]
+#[interpolate_test(speed_10_10bit_422, 10, 10, ChromaSampling::Cs422)]
+#[interpolate_test(speed_10_12bit_444, 10, 12, ChromaSampling::Cs444)]
+#[interpolate_test(speed_10_8bit_400, 10, 8, ChromaSampling::Cs400)]
+#[interpolate_test(speed_2_8bit_420, 2, 8, ChromaSampling::Cs420)]
+fn lossless_reconstruction(
+  speed: usize, bit_depth: usize, chroma_sampling: ChromaSampling,
+) {
+  lossless::<u16>(speed, bit_depth, chroma_sampling);
+}
+
+#[test]
+fn rdoq_slow_preset() {
+  let limit = 3;
+  let mut enc = EncoderConfig::with_speed_preset(2);
+  enc.quantizer = 100;
+  enc.max_key_frame_interval = 15;
+  enc.width = 64;
+  enc.height = 80;
+  // As set up by the CLI, weighing the distortion with temporal RDO
+  enc.tune = Tune::Psychovisual;
+  enc.speed_settings.tx_domain_distortion = false;
+  assert!(enc.speed_settings.rdoq);
+  assert!(enc.temporal_rdo());
+  let cfg = Config::new().with_encoder_config(enc).with_threads(1);
+  let mut ctx: Context<u8> = cfg.new_context().unwrap();
+
+  let mut state = 1u32;
+  for i in 0..limit {
+    let mut input = ctx.new_frame();
+    for plane in input.planes.iter_mut() {
+      let stride = plane.cfg.stride;
+      for (y, row) in plane.data.chunks_mut(stride).enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+          state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+          *pixel = ((x + 2 * i) * 3 + y + (state >> 27) as usize) as u8;
+        }
+      }
+    }
+    ctx.send_frame(input).unwrap();
+  }
+  ctx.flush();
+
+  let mut count = 0;
+  loop {
+    match ctx.receive_packet() {
+      Ok(_) => count += 1,
+      Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::LimitReached) => break,
+      Err(err) => panic!("Unexpected status {:?}", err),
+    }
+  }
+  assert_eq!(count, limit);
+}
+
 #[interpolate_test(0, 0)]
 #[interpolate_test(1, 1)]
 #[interpolate_test(2, 2)]
@@ -649,7 +1256,7 @@ fn output_frameno_reorder_scene_change_at(scene_change_at: u64) {
     10,
   );
 
-  // TODO: when we support more pyramid depths, this test will need tweaks.
+  // This test assumes the default pyramid depth.
   assert_eq!(ctx.inner.inter_cfg.pyramid_depth, 2);
 
   let limit = 10;
@@ -756,7 +1363,7 @@ fn pyramid_level_reorder_scene_change_at(scene_change_at: u64) {
     10,
   );
 
-  // TODO: when we support more pyramid depths, this test will need tweaks.
+  // This test assumes the default pyramid depth.
   assert_eq!(ctx.inner.inter_cfg.pyramid_depth, 2);
 
   let limit = 10;
@@ -863,7 +1470,7 @@ fn output_frameno_incremental_reorder_minus(missing: u64) {
     10,
   );
 
-  // TODO: when we support more pyramid depths, this test will need tweaks.
+  // This test assumes the default pyramid depth.
   assert_eq!(ctx.inner.inter_cfg.pyramid_depth, 2);
 
   let limit = 10 - missing;
@@ -994,7 +1601,7 @@ fn output_frameno_incremental_reorder_scene_change_at(scene_change_at: u64) {
     10,
   );
 
-  // TODO: when we support more pyramid depths, this test will need tweaks.
+  // This test assumes the default pyramid depth.
   assert_eq!(ctx.inner.inter_cfg.pyramid_depth, 2);
 
   let limit = 10;
@@ -1086,7 +1693,8 @@ fn send_frame_kf<T: Pixel>(ctx: &mut Context<T>, keyframe: bool) {
 
   let opaque = Some(Box::new(keyframe) as Box<dyn std::any::Any + Send>);
 
-  let fp = FrameParameters { frame_type_override, opaque };
+  let fp =
+    FrameParameters { frame_type_override, opaque, ..Default::default() };
 
   let _ = ctx.send_frame((input, fp));
 }
@@ -1151,7 +1759,7 @@ fn output_frameno_incremental_reorder_keyframe_at(kf_at: u64) {
     10,
   );
 
-  // TODO: when we support more pyramid depths, this test will need tweaks.
+  // This test assumes the default pyramid depth.
   assert_eq!(ctx.inner.inter_cfg.pyramid_depth, 2);
 
   let limit = 5;
@@ -1266,7 +1874,7 @@ fn output_frameno_no_scene_change_at_short_flash(flash_at: u64) {
     10,
   );
 
-  // TODO: when we support more pyramid depths, this test will need tweaks.
+  // This test assumes the default pyramid depth.
   assert_eq!(ctx.inner.inter_cfg.pyramid_depth, 2);
 
   let limit = 5;
@@ -1319,7 +1927,7 @@ fn output_frameno_no_scene_change_at_max_len_flash() {
     10,
   );
 
-  // TODO: when we support more pyramid depths, this test will need tweaks.
+  // This test assumes the default pyramid depth.
   assert_eq!(ctx.inner.inter_cfg.pyramid_depth, 2);
   assert_eq!(ctx.inner.inter_cfg.group_input_len, 4);
 
@@ -1378,7 +1986,7 @@ fn output_frameno_scene_change_past_max_len_flash() {
     10,
   );
 
-  // TODO: when we support more pyramid depths, this test will need tweaks.
+  // This test assumes the default pyramid depth.
   assert_eq!(ctx.inner.inter_cfg.pyramid_depth, 2);
   assert_eq!(ctx.inner.inter_cfg.group_input_len, 4);
 
@@ -1443,7 +2051,7 @@ fn output_frameno_no_scene_change_at_multiple_flashes() {
     10,
   );
 
-  // TODO: when we support more pyramid depths, this test will need tweaks.
+  // This test assumes the default pyramid depth.
   assert_eq!(ctx.inner.inter_cfg.pyramid_depth, 2);
   assert_eq!(ctx.inner.inter_cfg.group_input_len, 4);
 
@@ -1788,6 +2396,79 @@ fn zero_width() {
   assert!(res.is_err());
 }
 
+#[test]
+fn pyramid_depth_overflow() {
+  let mut enc = EncoderConfig::default();
+  enc.pyramid_depth = 5;
+  let config = Config::new().with_encoder_config(enc);
+  let res: Result<Context<u8>, _> = config.new_context();
+  assert!(res.is_err());
+}
+
+#[test]
+fn crf_with_bitrate() {
+  let mut enc = EncoderConfig::default();
+  enc.bitrate = 1000;
+  let config = Config::new()
+    .with_encoder_config(enc)
+    .with_rate_control(RateControlConfig::new().with_crf(100));
+  let res: Result<Context<u8>, _> = config.new_context();
+  assert!(res.is_err());
+}
+
+#[test]
+fn buffer_size_without_max_bitrate() {
+  let mut enc = EncoderConfig::default();
+  enc.buffer_size = 1_000_000;
+  let config = Config::new().with_encoder_config(enc);
+  let res: Result<Context<u8>, _> = config.new_context();
+  assert!(res.is_err());
+}
+
+#[test]
+fn bitrate_above_max_bitrate() {
+  let mut enc = EncoderConfig::default();
+  enc.bitrate = 2_000_000;
+  enc.max_bitrate = 1_000_000;
+  enc.buffer_size = 1_000_000;
+  let config = Config::new().with_encoder_config(enc);
+  let res: Result<Context<u8>, _> = config.new_context();
+  assert!(res.is_err());
+}
+
+#[test]
+fn buffer_size_above_one_second() {
+  let mut enc = EncoderConfig::default();
+  enc.max_bitrate = 1_000_000;
+  enc.buffer_size = 2_000_000;
+  let config = Config::new().with_encoder_config(enc);
+  let res: Result<Context<u8>, _> = config.new_context();
+  assert!(res.is_err());
+}
+
+#[test]
+fn max_bitrate_above_levels() {
+  let mut enc = EncoderConfig::default();
+  enc.max_bitrate = 200_000_000;
+  enc.buffer_size = 200_000_000;
+  let config = Config::new().with_encoder_config(enc);
+  let res: Result<Context<u8>, _> = config.new_context();
+  assert!(res.is_err());
+}
+
+#[interpolate_test(level_3_0, 1_000_000, 4)]
+#[interpolate_test(level_3_1, 8_000_000, 5)]
+fn buffer_model_level(max_bitrate: i32, seq_level_idx: u8) {
+  let mut enc = EncoderConfig::default();
+  enc.max_bitrate = max_bitrate;
+  enc.buffer_size = max_bitrate;
+  let config = Config::new().with_encoder_config(enc);
+  let ctx: Context<u8> = config.new_context().unwrap();
+  // The 640x480 frames need at least level 3.0. The level follows the
+  // profile in the codec configuration record.
+  assert_eq!(ctx.container_sequence_header()[1] & 0x1f, seq_level_idx);
+}
+
 #[test]
 fn rdo_lookahead_frames_overflow() {
   let mut enc = EncoderConfig::default();
@@ -1811,6 +2492,7 @@ fn log_q_exp_overflow() {
     content_light: None,
     enable_timing_info: false,
     still_picture: false,
+    annex_b: false,
     error_resilient: false,
     switch_frame_interval: 0,
     time_base: Rational { num: 1, den: 25 },
@@ -1818,14 +2500,26 @@ fn log_q_exp_overflow() {
     max_key_frame_interval: 240,
     reservoir_frame_delay: None,
     low_latency: false,
+    pyramid_depth: 2,
+    adaptive_pyramid_depth: false,
     quantizer: 100,
     min_quantizer: 64,
     bitrate: 1,
+    max_bitrate: 0,
+    buffer_size: 0,
     tune: Tune::Psychovisual,
     tile_cols: 0,
     tile_rows: 0,
     tiles: 0,
+    superblock_128: false,
+    superres_mode: SuperresMode::None,
+    superres_denom: 16,
+    superres_qthresh: 128,
     rdo_lookahead_frames: 40,
+    film_grain: 0,
+    intrabc: false,
+    delta_q: false,
+    quant_matrices: false,
     speed_settings: SpeedSettings {
       partition_range: PartitionRange::new(
         BlockSize::BLOCK_64X64,
@@ -1875,6 +2569,7 @@ fn guess_frame_subtypes_assert() {
     content_light: None,
     enable_timing_info: false,
     still_picture: false,
+    annex_b: false,
     error_resilient: false,
     switch_frame_interval: 0,
     time_base: Rational { num: 1, den: 25 },
@@ -1882,14 +2577,26 @@ fn guess_frame_subtypes_assert() {
     max_key_frame_interval: 1,
     reservoir_frame_delay: None,
     low_latency: false,
+    pyramid_depth: 2,
+    adaptive_pyramid_depth: false,
     quantizer: 100,
     min_quantizer: 0,
     bitrate: 16384,
+    max_bitrate: 0,
+    buffer_size: 0,
     tune: Tune::Psychovisual,
     tile_cols: 0,
     tile_rows: 0,
     tiles: 0,
+    superblock_128: false,
+    superres_mode: SuperresMode::None,
+    superres_denom: 16,
+    superres_qthresh: 128,
     rdo_lookahead_frames: 40,
+    film_grain: 0,
+    intrabc: false,
+    delta_q: false,
+    quant_matrices: false,
     speed_settings: SpeedSettings {
       partition_range: PartitionRange::new(
         BlockSize::BLOCK_64X64,
@@ -1921,3 +2628,160 @@ fn guess_frame_subtypes_assert() {
 
   ctx.receive_packet().unwrap();
 }
+
+fn encode_still_picture(
+  chroma_sampling: ChromaSampling,
+) -> (EncoderConfig, Vec<u8>, Vec<u8>) {
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = 64;
+  enc.height = 48;
+  enc.chroma_sampling = chroma_sampling;
+  enc.still_picture = true;
+  let config = Config::new().with_encoder_config(enc).with_threads(1);
+
+  let mut ctx: Context<u8> = config.new_context().unwrap();
+  let mut input = ctx.new_frame();
+  fill_frame_const(&mut input, 128);
+  ctx.send_frame(input).unwrap();
+  ctx.flush();
+  let pkt = ctx.receive_packet().unwrap();
+
+  (enc, ctx.container_sequence_header(), pkt.data)
+}
+
+#[test]
+fn avif_with_alpha() {
+  let (color_config, color_codec_config, color_data) =
+    encode_still_picture(ChromaSampling::Cs420);
+  let (alpha_config, alpha_codec_config, alpha_data) =
+    encode_still_picture(ChromaSampling::Cs400);
+  let color = AvifImage {
+    config: &color_config,
+    codec_config: &color_codec_config,
+    data: &color_data,
+  };
+  let alpha = AvifImage {
+    config: &alpha_config,
+    codec_config: &alpha_codec_config,
+    data: &alpha_data,
+  };
+
+  let mut avif = Vec::new();
+  write_avif(&mut avif, &color, Some(&alpha)).unwrap();
+
+  assert_eq!(&avif[4..12], b"ftypavif");
+  for fourcc in [b"pitm", b"iloc", b"auxl", b"auxC", b"pixi", b"colr"].iter() {
+    assert!(avif.windows(4).any(|w| w == *fourcc));
+  }
+  // Both items are stored in the trailing mdat box, without the temporal
+  // delimiters.
+  let data_len = color_data.len() + alpha_data.len() - 4;
+  assert_eq!(&avif[avif.len() - data_len - 4..][..4], b"mdat");
+  assert!(avif.ends_with(&alpha_data[2..]));
+}
+
+fn read_uleb128(data: &[u8]) -> (usize, usize) {
+  let mut value = 0;
+  for (i, &byte) in data.iter().enumerate() {
+    value |= ((byte & 0x7f) as usize) << (7 * i);
+    if byte & 0x80 == 0 {
+      return (value, i + 1);
+    }
+  }
+  panic!("Unterminated leb128");
+}
+
+fn write_uleb128(buf: &mut Vec<u8>, mut value: usize) {
+  loop {
+    let byte = (value & 0x7f) as u8;
+    value >>= 7;
+    if value == 0 {
+      buf.push(byte);
+      return;
+    }
+    buf.push(byte | 0x80);
+  }
+}
+
+/// Splits `data` in its length delimited units.
+fn length_delimited_units(mut data: &[u8]) -> Vec<&[u8]> {
+  let mut units = Vec::new();
+  while !data.is_empty() {
+    let (len, len_size) = read_uleb128(data);
+    units.push(&data[len_size..len_size + len]);
+    data = &data[len_size + len..];
+  }
+  units
+}
+
+/// Parses an Annex B temporal unit, checking its framing, and returns its
+/// OBUs in the low overhead bitstream format with its number of frames.
+fn parse_annex_b_temporal_unit(data: &[u8]) -> (Vec<u8>, usize) {
+  let temporal_units = length_delimited_units(data);
+  assert_eq!(temporal_units.len(), 1);
+
+  let frame_units = length_delimited_units(temporal_units[0]);
+  let frames = frame_units.len();
+  let mut obus = Vec::new();
+  for (i, frame_unit) in frame_units.into_iter().enumerate() {
+    let mut frame_headers = 0;
+    for (j, obu) in length_delimited_units(frame_unit).into_iter().enumerate()
+    {
+      assert_eq!(obu[0] & 0x02, 0, "OBU with a size field");
+      let obu_type = obu[0] >> 3 & 0xf;
+      // Temporal delimiter
+      assert_eq!(obu_type == 2, i == 0 && j == 0);
+      // Frame header or frame
+      if obu_type == 3 || obu_type == 6 {
+        frame_headers += 1;
+      }
+      let header_len = if obu[0] & 0x04 != 0 { 2 } else { 1 };
+      obus.push(obu[0] | 0x02);
+      obus.extend_from_slice(&obu[1..header_len]);
+      write_uleb128(&mut obus, obu.len() - header_len);
+      obus.extend_from_slice(&obu[header_len..]);
+    }
+    assert_eq!(frame_headers, 1);
+  }
+  (obus, frames)
+}
+
+fn encode_packets(annex_b: bool) -> Vec<Vec<u8>> {
+  let mut enc = EncoderConfig::with_speed_preset(10);
+  enc.width = 64;
+  enc.height = 80;
+  enc.annex_b = annex_b;
+  let config = Config::new().with_encoder_config(enc).with_threads(1);
+
+  let mut ctx: Context<u8> = config.new_context().unwrap();
+  for i in 0..10 {
+    send_test_frame(&mut ctx, 16 * i);
+  }
+  ctx.flush();
+
+  let mut packets = Vec::new();
+  loop {
+    match ctx.receive_packet() {
+      Ok(pkt) => packets.push(pkt.data),
+      Err(EncoderStatus::Encoded) => {}
+      Err(EncoderStatus::LimitReached) => break,
+      Err(err) => panic!("Unexpected status {:?}", err),
+    }
+  }
+  packets
+}
+
+#[test]
+fn annex_b_framing() {
+  let packets = encode_packets(false);
+  let annex_b_packets = encode_packets(true);
+  assert_eq!(packets.len(), annex_b_packets.len());
+  let mut max_frames = 0;
+  for (data, annex_b_data) in packets.iter().zip(annex_b_packets.iter()) {
+    let (obus, frames) = parse_annex_b_temporal_unit(annex_b_data);
+    assert_eq!(data, &obus);
+    max_frames = max_frames.max(frames);
+  }
+  // Hidden frames share the temporal unit of the next shown frame.
+  assert!(max_frames > 1);
+}
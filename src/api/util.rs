@@ -175,7 +175,10 @@ pub struct Packet<T: Pixel> {
   pub input_frameno: u64,
   /// Type of the shown frame.
   pub frame_type: FrameType,
-  /// QP selected for the frame.
+  /// QP selected for the frame, i.e., its base quantizer index.
+  ///
+  /// For a frame shown again from the reference buffer, this is the QP the
+  /// frame was coded with.
   pub qp: u8,
   /// Block-level encoding stats for the frame
   pub enc_stats: EncoderStats,
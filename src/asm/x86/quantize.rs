@@ -38,15 +38,22 @@ cpu_function_lookup_table!(
 #[inline(always)]
 pub fn dequantize<T: Coefficient>(
   qindex: u8, coeffs: &[T], eob: usize, rcoeffs: &mut [T], tx_size: TxSize,
-  bit_depth: usize, dc_delta_q: i8, ac_delta_q: i8, cpu: CpuFeatureLevel,
+  bit_depth: usize, dc_delta_q: i8, ac_delta_q: i8, qm: Option<&[u8]>,
+  cpu: CpuFeatureLevel,
 ) {
   let call_rust = |rcoeffs: &mut [T]| {
     crate::quantize::rust::dequantize(
       qindex, coeffs, eob, rcoeffs, tx_size, bit_depth, dc_delta_q,
-      ac_delta_q, cpu,
+      ac_delta_q, qm, cpu,
     );
   };
 
+  // Quantization matrices are only supported by the Rust implementation
+  if qm.is_some() {
+    call_rust(rcoeffs);
+    return;
+  }
+
   #[cfg(any(feature = "check_asm", test))]
   let ref_rcoeffs = {
     let area = av1_get_coded_tx_size(tx_size).area();
@@ -206,6 +213,7 @@ mod test {
           bd,
           0,
           0,
+          None,
           CpuFeatureLevel::default(),
         );
       }
@@ -7,6 +7,8 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
+use crate::decoder::raw::{RawInput, RawLayout};
+use crate::decoder::VideoDetails;
 use crate::error::*;
 use crate::muxer::{create_muxer, Muxer};
 use crate::stats::MetricsEnabled;
@@ -16,9 +18,11 @@ use rav1e::prelude::*;
 use rav1e::version;
 use scan_fmt::scan_fmt;
 
+use std::ffi::OsStr;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
+use std::path::Path;
 
 pub struct EncoderIO {
   pub input: Box<dyn Read>,
@@ -35,6 +39,7 @@ pub enum Verbose {
 
 pub struct CliOptions {
   pub io: EncoderIO,
+  pub raw_input: Option<RawInput>,
   pub enc: EncoderConfig,
   pub limit: usize,
   pub color_range_specified: bool,
@@ -47,6 +52,7 @@ pub struct CliOptions {
   pub pass1file_name: Option<String>,
   pub pass2file_name: Option<String>,
   pub save_config: Option<String>,
+  pub crf: Option<u8>,
 }
 
 #[cfg(feature = "serialize")]
@@ -103,18 +109,63 @@ pub fn parse_cli() -> Result<CliOptions, CliError> {
     // INPUT/OUTPUT
     .arg(
       Arg::with_name("INPUT")
-        .help("Uncompressed YUV4MPEG2 video input")
+        .help("Uncompressed YUV4MPEG2 or raw video input")
         .required_unless("FULLHELP")
         .index(1)
     )
+    .arg(
+      Arg::with_name("INPUT_FORMAT")
+        .help("Format of the input, inferred from its extension when omitted\n\
+            yuv: raw planar frames\n\
+            nv12, p010: raw semi-planar 4:2:0 frames")
+        .long("input-format")
+        .possible_values(&["y4m", "yuv", "nv12", "p010"])
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("WIDTH")
+        .help("Width of the raw input")
+        .long("width")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("HEIGHT")
+        .help("Height of the raw input")
+        .long("height")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("INPUT_CHROMA")
+        .help("Chroma subsampling of the raw planar input")
+        .long("input-chroma")
+        .possible_values(&["420", "422", "444", "400"])
+        .default_value("420")
+    )
+    .arg(
+      Arg::with_name("INPUT_DEPTH")
+        .help("Bit depth of the raw planar input, with 2 byte little-endian samples above 8")
+        .long("input-depth")
+        .possible_values(&["8", "10", "12"])
+        .default_value("8")
+    )
     .arg(
       Arg::with_name("OUTPUT")
-        .help("Compressed AV1 in IVF video output")
+        .help("Compressed AV1 in IVF, WebM, Matroska or MP4 video output, AVIF still image output or raw OBU output")
         .short("o")
         .long("output")
         .required_unless("FULLHELP")
         .takes_value(true)
     )
+    .arg(
+      Arg::with_name("ANNEX_B")
+        .help("Write the length delimited bitstream format of Annex B, to a raw .obu output")
+        .long("annex-b")
+    )
+    .arg(
+      Arg::with_name("FRAGMENTED")
+        .help("Write fragmented MP4 (CMAF), with a fragment per key frame")
+        .long("fragmented")
+    )
     // ENCODING SETTINGS
     .arg(
       Arg::with_name("FIRST_PASS")
@@ -145,7 +196,7 @@ pub fn parse_cli() -> Result<CliOptions, CliError> {
     )
     .arg(
       Arg::with_name("QP")
-        .help("Quantizer (0-255), smaller values are higher quality [default: 100]")
+        .help("Quantizer (0-255), smaller values are higher quality, 0 is lossless [default: 100]")
         .long("quantizer")
         .takes_value(true)
     )
@@ -163,6 +214,27 @@ pub fn parse_cli() -> Result<CliOptions, CliError> {
         .long("bitrate")
         .takes_value(true)
     )
+    .arg(
+      Arg::with_name("MAX_BITRATE")
+        .help("Maximum bitrate (kbps) of the decoder buffer model, which frames are coded not to underflow")
+        .long("max-bitrate")
+        .alias("max_bitrate")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("BUFFER_SIZE")
+        .help("Size (kbits) of the decoder buffer model, at most one second at the maximum bitrate [default: one second]")
+        .long("buffer-size")
+        .alias("buffer_size")
+        .requires("MAX_BITRATE")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("CRF")
+        .help("Constant quality factor (0-255), modulated per frame by the lookahead complexity. Cannot be used with a target bitrate")
+        .long("crf")
+        .takes_value(true)
+    )
     .arg(
       Arg::with_name("SPEED")
         .help("Speed level (0 is best quality, 10 is fastest)\n\
@@ -212,6 +284,21 @@ pub fn parse_cli() -> Result<CliOptions, CliError> {
         .long("low-latency")
         .alias("low_latency")
     )
+    .arg(
+      Arg::with_name("PYRAMID_DEPTH")
+        .help("Depth of the frame re-ordering pyramid (0-4); frames are coded in groups of 2^depth [default: 2]\n\
+            Deeper pyramids benefit static content; ignored in low latency mode")
+        .long("pyramid-depth")
+        .alias("pyramid_depth")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("ADAPTIVE_PYRAMID_DEPTH")
+        .help("Choose the depth of each re-ordering group from the lookahead, up to the pyramid depth\n\
+            Static scenes get longer groups and high motion shorter ones")
+        .long("adaptive-pyramid-depth")
+        .alias("adaptive_pyramid_depth")
+    )
     .arg(
       Arg::with_name("RDO_LOOKAHEAD_FRAMES")
         .help("Number of frames encoder should lookahead for RDO purposes [default: 40]\n")
@@ -248,6 +335,62 @@ pub fn parse_cli() -> Result<CliOptions, CliError> {
         .takes_value(true)
         .default_value("0")
     )
+    .arg(
+      Arg::with_name("SUPERBLOCK_128")
+        .help("Use 128x128 superblocks, which allow 128x128 blocks on high resolution content")
+        .long("superblock-128")
+    )
+    .arg(
+      Arg::with_name("SUPERRES_MODE")
+        .help("How frames are chosen to be coded at a reduced width and upscaled back in the loop")
+        .long("superres-mode")
+        .alias("superres_mode")
+        .possible_values(&SuperresMode::variants())
+        .default_value("None")
+        .case_insensitive(true)
+    )
+    .arg(
+      Arg::with_name("SUPERRES_DENOM")
+        .help("Super-resolution denominator (9-16) of the fixed mode, coding frames at 8/denom of their width [default: 16]")
+        .long("superres-denom")
+        .alias("superres_denom")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("SUPERRES_QTHRESH")
+        .help("Base quantizer (0-255) above which the qthresh mode codes frames at a reduced width [default: 128]")
+        .long("superres-qthresh")
+        .alias("superres_qthresh")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("FILM_GRAIN")
+        .help("Film grain synthesis strength (0-50). The source is denoised and the removed grain\n\
+               is signaled so that the decoder can synthesize it back [default: 0]")
+        .long("film-grain")
+        .alias("film_grain")
+        .takes_value(true)
+    )
+    .arg(
+      Arg::with_name("INTRABC")
+        .help("Enable intra block copy on key frames with screen content\n\
+               Disables the in-loop filters on those frames")
+        .long("intrabc")
+    )
+    .arg(
+      Arg::with_name("DELTA_Q")
+        .help("Adapt the quantizer of each superblock to its activity and \
+               temporal importance")
+        .long("delta-q")
+        .alias("delta_q")
+    )
+    .arg(
+      Arg::with_name("QUANT_MATRICES")
+        .help("Weight the quantizer of the coefficients by their frequency \
+               with quantization matrices")
+        .long("quant-matrices")
+        .alias("quant_matrices")
+    )
     // MASTERING
     .arg(
       Arg::with_name("PIXEL_RANGE")
@@ -450,11 +593,30 @@ pub fn parse_cli() -> Result<CliOptions, CliError> {
     output: create_muxer(
       matches.value_of("OUTPUT").unwrap(),
       matches.is_present("OVERWRITE"),
+      matches.is_present("FRAGMENTED"),
     )?,
     rec,
   };
 
-  let enc = enc.map_or_else(|| parse_config(&matches), Ok)?;
+  let mut enc = enc.map_or_else(|| parse_config(&matches), Ok)?;
+
+  let output_ext = Path::new(matches.value_of("OUTPUT").unwrap())
+    .extension()
+    .and_then(OsStr::to_str)
+    .map(str::to_lowercase)
+    .unwrap_or_default();
+
+  // AVIF output holds a single still picture.
+  let still_image = output_ext == "avif";
+  if still_image {
+    enc.still_picture = true;
+  }
+
+  if enc.annex_b && output_ext != "obu" {
+    return Err(CliError::new("Annex B output requires a .obu output file"));
+  }
+
+  let raw_input = parse_raw_input(&matches, enc.time_base)?;
 
   let verbose = if matches.is_present("QUIET") {
     Verbose::Quiet
@@ -464,6 +626,20 @@ pub fn parse_cli() -> Result<CliOptions, CliError> {
     Verbose::Normal
   };
 
+  let crf = match matches.value_of_int("CRF") {
+    Some(crf) => {
+      let crf = crf?;
+      if enc.bitrate > 0 {
+        panic!("A constant quality factor cannot be used with a bitrate");
+      }
+      if !(0..=255).contains(&crf) {
+        panic!("Constant quality factor must be between 0-255");
+      }
+      Some(crf as u8)
+    }
+    None => None,
+  };
+
   let metrics_enabled = if matches.is_present("METRICS") {
     MetricsEnabled::All
   } else if matches.is_present("PSNR") {
@@ -474,8 +650,13 @@ pub fn parse_cli() -> Result<CliOptions, CliError> {
 
   Ok(CliOptions {
     io,
+    raw_input,
     enc,
-    limit: matches.value_of("LIMIT").unwrap().parse().unwrap(),
+    limit: if still_image {
+      1
+    } else {
+      matches.value_of("LIMIT").unwrap().parse().unwrap()
+    },
     // Use `occurrences_of()` because `is_present()` is always true
     // if a parameter has a default value.
     color_range_specified: matches.occurrences_of("PIXEL_RANGE") > 0,
@@ -488,6 +669,7 @@ pub fn parse_cli() -> Result<CliOptions, CliError> {
     pass1file_name: matches.value_of("FIRST_PASS").map(|s| s.to_owned()),
     pass2file_name: matches.value_of("SECOND_PASS").map(|s| s.to_owned()),
     save_config,
+    crf,
   })
 }
 
@@ -503,6 +685,62 @@ impl MatchGet for ArgMatches<'_> {
   }
 }
 
+fn parse_raw_input(
+  matches: &ArgMatches<'_>, time_base: Rational,
+) -> Result<Option<RawInput>, CliError> {
+  let format = match matches.value_of("INPUT_FORMAT") {
+    Some(format) => format.to_owned(),
+    None => Path::new(matches.value_of("INPUT").unwrap())
+      .extension()
+      .and_then(OsStr::to_str)
+      .map(str::to_lowercase)
+      .unwrap_or_default(),
+  };
+  let (layout, chroma_sampling, bit_depth) = match &format[..] {
+    "yuv" => {
+      let chroma_sampling = match matches.value_of("INPUT_CHROMA").unwrap() {
+        "422" => ChromaSampling::Cs422,
+        "444" => ChromaSampling::Cs444,
+        "400" => ChromaSampling::Cs400,
+        _ => ChromaSampling::Cs420,
+      };
+      let bit_depth =
+        matches.value_of("INPUT_DEPTH").unwrap().parse().unwrap();
+      (RawLayout::Planar, chroma_sampling, bit_depth)
+    }
+    "nv12" => (RawLayout::SemiPlanar, ChromaSampling::Cs420, 8),
+    "p010" => (RawLayout::SemiPlanar, ChromaSampling::Cs420, 10),
+    _ => return Ok(None),
+  };
+
+  // y4m has no colorspace for monochrome above 8 bits.
+  if chroma_sampling == ChromaSampling::Cs400
+    && bit_depth > 8
+    && matches.is_present("RECONSTRUCTION")
+  {
+    return Err(CliError::new(
+      "Reconstruction output of monochrome raw input requires 8-bit input",
+    ));
+  }
+
+  let dimension = |name| -> Result<usize, CliError> {
+    matches
+      .value_of(name)
+      .ok_or_else(|| CliError::new("Raw input requires --width and --height"))?
+      .parse()
+      .map_err(|_| CliError::new("Invalid raw input dimensions"))
+  };
+  let details = VideoDetails {
+    width: dimension("WIDTH")?,
+    height: dimension("HEIGHT")?,
+    bit_depth,
+    chroma_sampling,
+    chroma_sample_position: ChromaSamplePosition::Unknown,
+    time_base,
+  };
+  Ok(Some(RawInput { layout, details }))
+}
+
 fn parse_config(matches: &ArgMatches<'_>) -> Result<EncoderConfig, CliError> {
   let maybe_quantizer = matches.value_of_int("QP");
   let maybe_bitrate = matches.value_of_int("BITRATE");
@@ -523,10 +761,10 @@ fn parse_config(matches: &ArgMatches<'_>) -> Result<EncoderConfig, CliError> {
     panic!("A target bitrate must be specified when using passes");
   }
 
-  if quantizer == 0 {
-    unimplemented!("Lossless encoding not yet implemented");
-  } else if quantizer > 255 {
+  if quantizer > 255 {
     panic!("Quantizer must be between 0-255");
+  } else if quantizer == 0 && bitrate > 0 {
+    panic!("Lossless encoding cannot be used with a bitrate");
   }
 
   let speed = matches.value_of("SPEED").unwrap().parse().unwrap();
@@ -636,11 +874,20 @@ fn parse_config(matches: &ArgMatches<'_>) -> Result<EncoderConfig, CliError> {
   };
 
   cfg.still_picture = matches.is_present("STILL_PICTURE");
+  cfg.annex_b = matches.is_present("ANNEX_B");
 
   cfg.quantizer = quantizer;
   cfg.min_quantizer =
     matches.value_of("MINQP").unwrap_or("0").parse().unwrap();
   cfg.bitrate = bitrate.checked_mul(1000).expect("Bitrate too high");
+  let max_bitrate: i32 =
+    matches.value_of_int("MAX_BITRATE").unwrap_or(Ok(0))?;
+  let buffer_size: i32 =
+    matches.value_of_int("BUFFER_SIZE").unwrap_or(Ok(max_bitrate))?;
+  cfg.max_bitrate =
+    max_bitrate.checked_mul(1000).expect("Maximum bitrate too high");
+  cfg.buffer_size =
+    buffer_size.checked_mul(1000).expect("Buffer size too high");
   cfg.reservoir_frame_delay = matches
     .value_of("RESERVOIR_FRAME_DELAY")
     .map(|reservior_frame_delay| reservior_frame_delay.parse().unwrap());
@@ -656,6 +903,13 @@ fn parse_config(matches: &ArgMatches<'_>) -> Result<EncoderConfig, CliError> {
   cfg.tile_rows = matches.value_of("TILE_ROWS").unwrap().parse().unwrap();
 
   cfg.tiles = matches.value_of("TILES").unwrap().parse().unwrap();
+  cfg.superblock_128 = matches.is_present("SUPERBLOCK_128");
+  cfg.superres_mode =
+    matches.value_of("SUPERRES_MODE").unwrap().parse().unwrap();
+  cfg.superres_denom =
+    matches.value_of("SUPERRES_DENOM").unwrap_or("16").parse().unwrap();
+  cfg.superres_qthresh =
+    matches.value_of("SUPERRES_QTHRESH").unwrap_or("128").parse().unwrap();
 
   if cfg.tile_cols > 64 || cfg.tile_rows > 64 {
     panic!("Tile columns and rows may not be greater than 64");
@@ -669,6 +923,21 @@ fn parse_config(matches: &ArgMatches<'_>) -> Result<EncoderConfig, CliError> {
   }
 
   cfg.low_latency = matches.is_present("LOW_LATENCY");
+  cfg.pyramid_depth =
+    matches.value_of("PYRAMID_DEPTH").unwrap_or("2").parse().unwrap();
+  if cfg.pyramid_depth > 4 {
+    panic!("Pyramid depth must be between 0-4");
+  }
+  cfg.adaptive_pyramid_depth = matches.is_present("ADAPTIVE_PYRAMID_DEPTH");
+
+  cfg.film_grain =
+    matches.value_of("FILM_GRAIN").unwrap_or("0").parse().unwrap();
+  if cfg.film_grain > 50 {
+    panic!("Film grain strength must be between 0-50");
+  }
+  cfg.intrabc = matches.is_present("INTRABC");
+  cfg.delta_q = matches.is_present("DELTA_Q");
+  cfg.quant_matrices = matches.is_present("QUANT_MATRICES");
 
   Ok(cfg)
 }
@@ -11,6 +11,7 @@
 use rav1e::prelude::*;
 use std::io;
 
+pub mod raw;
 pub mod y4m;
 
 pub trait Decoder {
@@ -18,6 +19,8 @@ pub trait Decoder {
   fn read_frame<T: Pixel>(
     &mut self, ctx: &Context<T>, cfg: &VideoDetails,
   ) -> Result<Frame<T>, DecodeError>;
+  /// Reads a frame and discards it.
+  fn skip_frame(&mut self) -> Result<(), DecodeError>;
 }
 
 #[derive(Debug)]
@@ -0,0 +1,173 @@
+// Copyright (c) 2020, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use std::io;
+use std::io::Read;
+
+use crate::color::ChromaSampling::Cs400;
+use crate::decoder::{DecodeError, Decoder, VideoDetails};
+use crate::Frame;
+use rav1e::prelude::*;
+
+/// Layout of the planes of raw frames.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RawLayout {
+  /// The Y, U and V planes one after the other, as in I420.
+  Planar,
+  /// The Y plane followed by the interleaved U and V planes, as in NV12, with
+  /// high bit depth samples in the most significant bits, as in P010.
+  SemiPlanar,
+}
+
+/// Raw video input, whose details are given on the command line.
+#[derive(Clone, Copy, Debug)]
+pub struct RawInput {
+  pub layout: RawLayout,
+  pub details: VideoDetails,
+}
+
+fn bytes_per_sample(details: &VideoDetails) -> usize {
+  if details.bit_depth > 8 {
+    2
+  } else {
+    1
+  }
+}
+
+fn chroma_dimensions(details: &VideoDetails) -> (usize, usize) {
+  details.chroma_sampling.get_chroma_dimensions(details.width, details.height)
+}
+
+fn frame_size(details: &VideoDetails) -> usize {
+  let (chroma_width, chroma_height) = chroma_dimensions(details);
+  (details.width * details.height + 2 * chroma_width * chroma_height)
+    * bytes_per_sample(details)
+}
+
+/// Reads the data of a whole frame, the end of the input being reported as
+/// `DecodeError::EOF`.
+fn read_frame_data(
+  input: &mut dyn Read, buf: &mut [u8],
+) -> Result<(), DecodeError> {
+  input.read_exact(buf).map_err(|e| match e.kind() {
+    io::ErrorKind::UnexpectedEof => DecodeError::EOF,
+    _ => DecodeError::IoError(e),
+  })
+}
+
+/// Decoder of raw planar frames.
+pub struct PlanarDecoder {
+  input: Box<dyn Read>,
+  details: VideoDetails,
+  buf: Vec<u8>,
+}
+
+impl PlanarDecoder {
+  pub fn new(input: Box<dyn Read>, details: VideoDetails) -> Self {
+    PlanarDecoder { input, details, buf: vec![0; frame_size(&details)] }
+  }
+}
+
+impl Decoder for PlanarDecoder {
+  fn get_video_details(&self) -> VideoDetails {
+    self.details
+  }
+
+  fn read_frame<T: Pixel>(
+    &mut self, ctx: &Context<T>, cfg: &VideoDetails,
+  ) -> Result<Frame<T>, DecodeError> {
+    read_frame_data(&mut self.input, &mut self.buf)?;
+
+    let mut f = ctx.new_frame();
+    let bytes = bytes_per_sample(cfg);
+    let (chroma_width, chroma_height) = chroma_dimensions(cfg);
+    let (y, uv) = self.buf.split_at(cfg.width * cfg.height * bytes);
+    f.planes[0].copy_from_raw_u8(y, cfg.width * bytes, bytes);
+    if cfg.chroma_sampling != Cs400 {
+      let (u, v) = uv.split_at(chroma_width * chroma_height * bytes);
+      f.planes[1].copy_from_raw_u8(u, chroma_width * bytes, bytes);
+      f.planes[2].copy_from_raw_u8(v, chroma_width * bytes, bytes);
+    }
+    Ok(f)
+  }
+
+  fn skip_frame(&mut self) -> Result<(), DecodeError> {
+    read_frame_data(&mut self.input, &mut self.buf)
+  }
+}
+
+/// Decoder of raw semi-planar frames.
+pub struct SemiPlanarDecoder {
+  input: Box<dyn Read>,
+  details: VideoDetails,
+  buf: Vec<u8>,
+  u: Vec<u8>,
+  v: Vec<u8>,
+}
+
+impl SemiPlanarDecoder {
+  pub fn new(input: Box<dyn Read>, details: VideoDetails) -> Self {
+    let (chroma_width, chroma_height) = chroma_dimensions(&details);
+    let chroma_size =
+      chroma_width * chroma_height * bytes_per_sample(&details);
+    SemiPlanarDecoder {
+      input,
+      details,
+      buf: vec![0; frame_size(&details)],
+      u: vec![0; chroma_size],
+      v: vec![0; chroma_size],
+    }
+  }
+}
+
+impl Decoder for SemiPlanarDecoder {
+  fn get_video_details(&self) -> VideoDetails {
+    self.details
+  }
+
+  fn read_frame<T: Pixel>(
+    &mut self, ctx: &Context<T>, cfg: &VideoDetails,
+  ) -> Result<Frame<T>, DecodeError> {
+    read_frame_data(&mut self.input, &mut self.buf)?;
+
+    let bytes = bytes_per_sample(cfg);
+    if bytes == 2 {
+      // Move the samples from the most significant bits.
+      let shift = 16 - cfg.bit_depth;
+      for sample in self.buf.chunks_mut(2) {
+        let value = u16::from_le_bytes([sample[0], sample[1]]) >> shift;
+        sample.copy_from_slice(&value.to_le_bytes());
+      }
+    }
+
+    let mut f = ctx.new_frame();
+    let (chroma_width, _) = chroma_dimensions(cfg);
+    let (y, uv) = self.buf.split_at(cfg.width * cfg.height * bytes);
+    f.planes[0].copy_from_raw_u8(y, cfg.width * bytes, bytes);
+    if cfg.chroma_sampling != Cs400 {
+      for ((u, v), uv) in self
+        .u
+        .chunks_mut(bytes)
+        .zip(self.v.chunks_mut(bytes))
+        .zip(uv.chunks(2 * bytes))
+      {
+        let (uv_u, uv_v) = uv.split_at(bytes);
+        u.copy_from_slice(uv_u);
+        v.copy_from_slice(uv_v);
+      }
+      f.planes[1].copy_from_raw_u8(&self.u, chroma_width * bytes, bytes);
+      f.planes[2].copy_from_raw_u8(&self.v, chroma_width * bytes, bytes);
+    }
+    Ok(f)
+  }
+
+  fn skip_frame(&mut self) -> Result<(), DecodeError> {
+    read_frame_data(&mut self.input, &mut self.buf)
+  }
+}
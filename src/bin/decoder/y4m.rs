@@ -69,6 +69,10 @@ impl Decoder for y4m::Decoder<Box<dyn Read>> {
       })
       .map_err(Into::into)
   }
+
+  fn skip_frame(&mut self) -> Result<(), DecodeError> {
+    y4m::Decoder::read_frame(self).map(|_| ()).map_err(Into::into)
+  }
 }
 
 impl From<y4m::Error> for DecodeError {
@@ -101,3 +105,26 @@ pub fn map_y4m_color_space(
     C444 | C444p10 | C444p12 => (Cs444, Colocated),
   }
 }
+
+/// The y4m color space matching the details of a raw input.
+pub fn y4m_colorspace(details: &VideoDetails) -> y4m::Colorspace {
+  use crate::ChromaSamplePosition::*;
+  use crate::ChromaSampling::*;
+  use y4m::Colorspace::*;
+  match (details.chroma_sampling, details.bit_depth) {
+    (Cs400, _) => Cmono,
+    (Cs420, 8) => match details.chroma_sample_position {
+      Unknown => C420jpeg,
+      Vertical => C420mpeg2,
+      Colocated => C420,
+    },
+    (Cs420, 10) => C420p10,
+    (Cs420, _) => C420p12,
+    (Cs422, 8) => C422,
+    (Cs422, 10) => C422p10,
+    (Cs422, _) => C422p12,
+    (Cs444, 8) => C444,
+    (Cs444, 10) => C444p10,
+    (Cs444, _) => C444p12,
+  }
+}
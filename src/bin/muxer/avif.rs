@@ -0,0 +1,69 @@
+// Copyright (c) 2020, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use super::Muxer;
+use rav1e::prelude::*;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+
+use crate::error::*;
+
+/// AVIF muxer of a still picture, written once its single frame is encoded.
+pub struct AvifMuxer {
+  output: BufWriter<File>,
+  config: Option<EncoderConfig>,
+  codec_config: Vec<u8>,
+  data: Option<Vec<u8>>,
+}
+
+impl AvifMuxer {
+  pub fn open(path: &str) -> Result<Box<dyn Muxer>, CliError> {
+    let file =
+      File::create(path).map_err(|e| e.context("Cannot open output file"))?;
+    let avif = AvifMuxer {
+      output: BufWriter::new(file),
+      config: None,
+      codec_config: Vec::new(),
+      data: None,
+    };
+    Ok(Box::new(avif))
+  }
+}
+
+impl Muxer for AvifMuxer {
+  fn write_header(&mut self, config: &EncoderConfig, codec_config: &[u8]) {
+    self.config = Some(*config);
+    self.codec_config = codec_config.to_vec();
+  }
+
+  fn write_frame(&mut self, _pts: u64, data: &[u8], _frame_type: FrameType) {
+    assert!(self.data.is_none(), "AVIF output holds a single frame");
+    self.data = Some(data.to_vec());
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+
+  fn finish(&mut self) -> io::Result<()> {
+    let (config, data) = match (self.config.as_ref(), self.data.as_ref()) {
+      (Some(config), Some(data)) => (config, data),
+      _ => {
+        return Err(io::Error::new(
+          io::ErrorKind::UnexpectedEof,
+          "No frame was encoded",
+        ))
+      }
+    };
+    let color = AvifImage { config, codec_config: &self.codec_config, data };
+    write_avif(&mut self.output, &color, None)?;
+    self.output.flush()
+  }
+}
@@ -24,16 +24,13 @@ pub struct IvfMuxer {
 }
 
 impl Muxer for IvfMuxer {
-  fn write_header(
-    &mut self, width: usize, height: usize, framerate_num: usize,
-    framerate_den: usize,
-  ) {
+  fn write_header(&mut self, config: &EncoderConfig, _codec_config: &[u8]) {
     write_ivf_header(
       &mut self.output,
-      width,
-      height,
-      framerate_num,
-      framerate_den,
+      config.width,
+      config.height,
+      config.time_base.den as usize,
+      config.time_base.num as usize,
     );
   }
 
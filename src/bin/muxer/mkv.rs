@@ -0,0 +1,433 @@
+// Copyright (c) 2020, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use super::{strip_temporal_delimiter, Muxer};
+use rav1e::prelude::*;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+use crate::error::*;
+
+// EBML and Matroska element ids
+const EBML: u32 = 0x1A45_DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const SEGMENT: u32 = 0x1853_8067;
+const SEEK_HEAD: u32 = 0x114D_9B74;
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+const VOID: u32 = 0xEC;
+const INFO: u32 = 0x1549_A966;
+const TIMESTAMP_SCALE: u32 = 0x2A_D7B1;
+const DURATION: u32 = 0x4489;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const FLAG_LACING: u32 = 0x9C;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const DEFAULT_DURATION: u32 = 0x23_E383;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const COLOUR: u32 = 0x55B0;
+const MATRIX_COEFFICIENTS: u32 = 0x55B1;
+const BITS_PER_CHANNEL: u32 = 0x55B2;
+const CHROMA_SUBSAMPLING_HORZ: u32 = 0x55B3;
+const CHROMA_SUBSAMPLING_VERT: u32 = 0x55B4;
+const CHROMA_SITING_HORZ: u32 = 0x55B7;
+const CHROMA_SITING_VERT: u32 = 0x55B8;
+const RANGE: u32 = 0x55B9;
+const TRANSFER_CHARACTERISTICS: u32 = 0x55BA;
+const PRIMARIES: u32 = 0x55BB;
+const MAX_CLL: u32 = 0x55BC;
+const MAX_FALL: u32 = 0x55BD;
+const MASTERING_METADATA: u32 = 0x55D0;
+const PRIMARY_R_CHROMATICITY_X: u32 = 0x55D1;
+const WHITE_POINT_CHROMATICITY_X: u32 = 0x55D7;
+const WHITE_POINT_CHROMATICITY_Y: u32 = 0x55D8;
+const LUMINANCE_MAX: u32 = 0x55D9;
+const LUMINANCE_MIN: u32 = 0x55DA;
+const CLUSTER: u32 = 0x1F43_B675;
+const TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+const CUES: u32 = 0x1C53_BB6B;
+const CUE_POINT: u32 = 0xBB;
+const CUE_TIME: u32 = 0xB3;
+const CUE_TRACK_POSITIONS: u32 = 0xB7;
+const CUE_TRACK: u32 = 0xF7;
+const CUE_CLUSTER_POSITION: u32 = 0xF1;
+
+/// Timestamps are in milliseconds.
+const TIMESTAMP_SCALE_NS: u64 = 1_000_000;
+
+/// Space reserved after the Segment header for the SeekHead, written once
+/// the positions of the other top-level elements are known.
+const SEEK_HEAD_RESERVED: usize = 96;
+
+/// Size of an element of unknown size, patched when finishing the file.
+const UNKNOWN_SIZE: u64 = 0x00FF_FFFF_FFFF_FFFF;
+
+fn write_id(buf: &mut Vec<u8>, id: u32) {
+  let len = 4 - (id.leading_zeros() / 8) as usize;
+  buf.extend_from_slice(&id.to_be_bytes()[4 - len..]);
+}
+
+/// Writes `size` as an EBML variable size integer of `len` bytes.
+fn write_size_len(buf: &mut Vec<u8>, size: u64, len: usize) {
+  let marked = size | 1 << (7 * len);
+  buf.extend_from_slice(&marked.to_be_bytes()[8 - len..]);
+}
+
+fn write_size(buf: &mut Vec<u8>, size: u64) {
+  let len = (1..8).find(|&len| size < (1 << (7 * len)) - 1).unwrap_or(8);
+  write_size_len(buf, size, len);
+}
+
+fn write_binary(buf: &mut Vec<u8>, id: u32, data: &[u8]) {
+  write_id(buf, id);
+  write_size(buf, data.len() as u64);
+  buf.extend_from_slice(data);
+}
+
+fn write_uint(buf: &mut Vec<u8>, id: u32, value: u64) {
+  let len = (8 - (value.leading_zeros() / 8) as usize).max(1);
+  write_binary(buf, id, &value.to_be_bytes()[8 - len..]);
+}
+
+fn write_float(buf: &mut Vec<u8>, id: u32, value: f64) {
+  write_binary(buf, id, &value.to_bits().to_be_bytes());
+}
+
+fn write_string(buf: &mut Vec<u8>, id: u32, value: &str) {
+  write_binary(buf, id, value.as_bytes());
+}
+
+/// Writes a Void element filling exactly `len` bytes, which must be at least
+/// 2.
+fn write_void(buf: &mut Vec<u8>, len: usize) {
+  debug_assert!(len >= 2);
+  write_id(buf, VOID);
+  let size_len = if len - 1 > 127 { 8 } else { 1 };
+  write_size_len(buf, (len - 1 - size_len) as u64, size_len);
+  buf.resize(buf.len() + len - 1 - size_len, 0);
+}
+
+fn colour(config: &EncoderConfig) -> Vec<u8> {
+  let mut buf = Vec::new();
+  if let Some(desc) = config.color_description {
+    write_uint(&mut buf, MATRIX_COEFFICIENTS, desc.matrix_coefficients as u64);
+  }
+  write_uint(&mut buf, BITS_PER_CHANNEL, config.bit_depth as u64);
+  let (ss_x, ss_y) = config.chroma_sampling.get_decimation().unwrap_or((0, 0));
+  if config.chroma_sampling != ChromaSampling::Cs400 {
+    write_uint(&mut buf, CHROMA_SUBSAMPLING_HORZ, ss_x as u64);
+    write_uint(&mut buf, CHROMA_SUBSAMPLING_VERT, ss_y as u64);
+  }
+  // Left (or top) collocated is 1 and half-way is 2.
+  match config.chroma_sample_position {
+    ChromaSamplePosition::Vertical => {
+      write_uint(&mut buf, CHROMA_SITING_HORZ, 1);
+      write_uint(&mut buf, CHROMA_SITING_VERT, 2);
+    }
+    ChromaSamplePosition::Colocated => {
+      write_uint(&mut buf, CHROMA_SITING_HORZ, 1);
+      write_uint(&mut buf, CHROMA_SITING_VERT, 1);
+    }
+    ChromaSamplePosition::Unknown => {}
+  }
+  let range = match config.pixel_range {
+    PixelRange::Limited => 1,
+    PixelRange::Full => 2,
+  };
+  write_uint(&mut buf, RANGE, range);
+  if let Some(desc) = config.color_description {
+    write_uint(
+      &mut buf,
+      TRANSFER_CHARACTERISTICS,
+      desc.transfer_characteristics as u64,
+    );
+    write_uint(&mut buf, PRIMARIES, desc.color_primaries as u64);
+  }
+  if let Some(light) = config.content_light {
+    write_uint(&mut buf, MAX_CLL, light.max_content_light_level as u64);
+    write_uint(&mut buf, MAX_FALL, light.max_frame_average_light_level as u64);
+  }
+  if let Some(mastering) = config.mastering_display {
+    let mut metadata = Vec::new();
+    // The R, G and B chromaticity coordinates have consecutive ids.
+    let coordinate = |v: u16| v as f64 / (1 << 16) as f64;
+    for (i, point) in mastering.primaries.iter().enumerate() {
+      let id = PRIMARY_R_CHROMATICITY_X + 2 * i as u32;
+      write_float(&mut metadata, id, coordinate(point.x));
+      write_float(&mut metadata, id + 1, coordinate(point.y));
+    }
+    let white_point = mastering.white_point;
+    write_float(
+      &mut metadata,
+      WHITE_POINT_CHROMATICITY_X,
+      coordinate(white_point.x),
+    );
+    write_float(
+      &mut metadata,
+      WHITE_POINT_CHROMATICITY_Y,
+      coordinate(white_point.y),
+    );
+    write_float(
+      &mut metadata,
+      LUMINANCE_MAX,
+      mastering.max_luminance as f64 / (1 << 8) as f64,
+    );
+    write_float(
+      &mut metadata,
+      LUMINANCE_MIN,
+      mastering.min_luminance as f64 / (1 << 14) as f64,
+    );
+    write_binary(&mut buf, MASTERING_METADATA, &metadata);
+  }
+  buf
+}
+
+/// Matroska (or WebM) muxer of a single AV1 video track, written to a file
+/// so that the sizes, the duration and the seek head can be filled in when
+/// finishing it.
+pub struct MkvMuxer {
+  output: BufWriter<File>,
+  doc_type: &'static str,
+  time_base: Rational,
+  /// Position of the data of the Segment element, to which the positions of
+  /// the seek head and the cues are relative.
+  segment_pos: u64,
+  /// Position of the value of the Duration element.
+  duration_pos: u64,
+  info_pos: u64,
+  tracks_pos: u64,
+  /// Blocks of the cluster being written, with its timestamp.
+  cluster: Vec<u8>,
+  cluster_timestamp: u64,
+  /// Timestamp and position of the clusters starting with a key frame.
+  cues: Vec<(u64, u64)>,
+  /// End of the last frame, in milliseconds.
+  duration: f64,
+}
+
+impl MkvMuxer {
+  pub fn open(
+    path: &str, doc_type: &'static str,
+  ) -> Result<Box<dyn Muxer>, CliError> {
+    let file =
+      File::create(path).map_err(|e| e.context("Cannot open output file"))?;
+    let mkv = MkvMuxer {
+      output: BufWriter::new(file),
+      doc_type,
+      time_base: Rational::new(1, 30),
+      segment_pos: 0,
+      duration_pos: 0,
+      info_pos: 0,
+      tracks_pos: 0,
+      cluster: Vec::new(),
+      cluster_timestamp: 0,
+      cues: Vec::new(),
+      duration: 0.,
+    };
+    Ok(Box::new(mkv))
+  }
+
+  /// Converts a frame number to milliseconds.
+  fn timestamp(&self, frameno: u64) -> f64 {
+    (frameno * self.time_base.num) as f64 * 1_000_000_000.
+      / (self.time_base.den * TIMESTAMP_SCALE_NS) as f64
+  }
+
+  fn position(&mut self) -> io::Result<u64> {
+    Ok(self.output.stream_position()? - self.segment_pos)
+  }
+
+  fn write_cluster(&mut self) -> io::Result<()> {
+    if self.cluster.is_empty() {
+      return Ok(());
+    }
+    let mut buf = Vec::new();
+    let mut data = Vec::new();
+    write_uint(&mut data, TIMESTAMP, self.cluster_timestamp);
+    data.append(&mut self.cluster);
+    write_binary(&mut buf, CLUSTER, &data);
+    self.output.write_all(&buf)
+  }
+
+  fn try_write_header(
+    &mut self, config: &EncoderConfig, codec_config: &[u8],
+  ) -> io::Result<()> {
+    self.time_base = config.time_base;
+
+    let mut buf = Vec::new();
+    let mut ebml = Vec::new();
+    write_uint(&mut ebml, EBML_VERSION, 1);
+    write_uint(&mut ebml, EBML_READ_VERSION, 1);
+    write_uint(&mut ebml, EBML_MAX_ID_LENGTH, 4);
+    write_uint(&mut ebml, EBML_MAX_SIZE_LENGTH, 8);
+    write_string(&mut ebml, DOC_TYPE, self.doc_type);
+    write_uint(&mut ebml, DOC_TYPE_VERSION, 4);
+    write_uint(&mut ebml, DOC_TYPE_READ_VERSION, 2);
+    write_binary(&mut buf, EBML, &ebml);
+
+    write_id(&mut buf, SEGMENT);
+    write_size_len(&mut buf, UNKNOWN_SIZE, 8);
+    self.segment_pos = buf.len() as u64;
+    write_void(&mut buf, SEEK_HEAD_RESERVED);
+
+    let mut info = Vec::new();
+    write_uint(&mut info, TIMESTAMP_SCALE, TIMESTAMP_SCALE_NS);
+    let app = format!("rav1e {}", rav1e::version::full());
+    write_string(&mut info, MUXING_APP, &app);
+    write_string(&mut info, WRITING_APP, &app);
+    // The duration is written last, to find its value at the end of Info.
+    write_float(&mut info, DURATION, 0.);
+    self.info_pos = buf.len() as u64 - self.segment_pos;
+    write_binary(&mut buf, INFO, &info);
+    self.duration_pos = buf.len() as u64 - 8;
+
+    let mut video = Vec::new();
+    write_uint(&mut video, PIXEL_WIDTH, config.width as u64);
+    write_uint(&mut video, PIXEL_HEIGHT, config.height as u64);
+    write_binary(&mut video, COLOUR, &colour(config));
+
+    let mut track = Vec::new();
+    write_uint(&mut track, TRACK_NUMBER, 1);
+    write_uint(&mut track, TRACK_UID, 1);
+    write_uint(&mut track, TRACK_TYPE, 1); // video
+    write_uint(&mut track, FLAG_LACING, 0);
+    write_string(&mut track, CODEC_ID, "V_AV1");
+    write_binary(&mut track, CODEC_PRIVATE, codec_config);
+    write_uint(
+      &mut track,
+      DEFAULT_DURATION,
+      self.time_base.num * 1_000_000_000 / self.time_base.den,
+    );
+    write_binary(&mut track, VIDEO, &video);
+
+    let mut tracks = Vec::new();
+    write_binary(&mut tracks, TRACK_ENTRY, &track);
+    self.tracks_pos = buf.len() as u64 - self.segment_pos;
+    write_binary(&mut buf, TRACKS, &tracks);
+
+    self.output.write_all(&buf)
+  }
+
+  fn try_write_frame(
+    &mut self, pts: u64, data: &[u8], frame_type: FrameType,
+  ) -> io::Result<()> {
+    let timestamp = self.timestamp(pts).round() as u64;
+    let keyframe = frame_type == FrameType::KEY;
+    // Start a cluster at each key frame, or when the timestamp relative to
+    // the cluster no longer fits in 16 bits. Frames are stored in decoding
+    // order, so it may be negative.
+    let relative_timestamp = timestamp as i64 - self.cluster_timestamp as i64;
+    if keyframe
+      || self.cluster.is_empty()
+      || relative_timestamp < i16::min_value() as i64
+      || relative_timestamp > i16::max_value() as i64
+    {
+      self.write_cluster()?;
+      self.cluster_timestamp = timestamp;
+      if keyframe {
+        let position = self.position()?;
+        self.cues.push((timestamp, position));
+      }
+    }
+
+    let mut block = Vec::with_capacity(data.len() + 4);
+    write_size(&mut block, 1); // track number
+    let relative_timestamp =
+      (timestamp as i64 - self.cluster_timestamp as i64) as i16;
+    block.extend_from_slice(&relative_timestamp.to_be_bytes());
+    block.push(if keyframe { 0x80 } else { 0 });
+    block.extend_from_slice(strip_temporal_delimiter(data));
+    write_binary(&mut self.cluster, SIMPLE_BLOCK, &block);
+
+    self.duration = self.duration.max(self.timestamp(pts + 1));
+    Ok(())
+  }
+
+  fn try_finish(&mut self) -> io::Result<()> {
+    self.write_cluster()?;
+
+    let mut cues = Vec::new();
+    for &(timestamp, position) in self.cues.iter() {
+      let mut positions = Vec::new();
+      write_uint(&mut positions, CUE_TRACK, 1);
+      write_uint(&mut positions, CUE_CLUSTER_POSITION, position);
+      let mut point = Vec::new();
+      write_uint(&mut point, CUE_TIME, timestamp);
+      write_binary(&mut point, CUE_TRACK_POSITIONS, &positions);
+      write_binary(&mut cues, CUE_POINT, &point);
+    }
+    let cues_pos = self.position()?;
+    let mut buf = Vec::new();
+    write_binary(&mut buf, CUES, &cues);
+    self.output.write_all(&buf)?;
+    let segment_size = self.position()?;
+
+    let mut seek_head = Vec::new();
+    for &(id, position) in
+      [(INFO, self.info_pos), (TRACKS, self.tracks_pos), (CUES, cues_pos)]
+        .iter()
+    {
+      let mut seek = Vec::new();
+      let mut seek_id = Vec::new();
+      write_id(&mut seek_id, id);
+      write_binary(&mut seek, SEEK_ID, &seek_id);
+      write_uint(&mut seek, SEEK_POSITION, position);
+      write_binary(&mut seek_head, SEEK, &seek);
+    }
+    let mut buf = Vec::new();
+    write_binary(&mut buf, SEEK_HEAD, &seek_head);
+    let len = buf.len();
+    write_void(&mut buf, SEEK_HEAD_RESERVED - len);
+
+    self.output.seek(SeekFrom::Start(self.segment_pos - 8))?;
+    let mut size = Vec::new();
+    write_size_len(&mut size, segment_size, 8);
+    self.output.write_all(&size)?;
+    self.output.write_all(&buf)?;
+    self.output.seek(SeekFrom::Start(self.duration_pos))?;
+    self.output.write_all(&self.duration.to_bits().to_be_bytes())?;
+    self.output.seek(SeekFrom::End(0))?;
+    self.output.flush()
+  }
+}
+
+impl Muxer for MkvMuxer {
+  fn write_header(&mut self, config: &EncoderConfig, codec_config: &[u8]) {
+    self.try_write_header(config, codec_config).unwrap();
+  }
+
+  fn write_frame(&mut self, pts: u64, data: &[u8], frame_type: FrameType) {
+    self.try_write_frame(pts, data, frame_type).unwrap();
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.output.flush()
+  }
+
+  fn finish(&mut self) -> io::Result<()> {
+    self.try_finish()
+  }
+}
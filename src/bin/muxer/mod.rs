@@ -7,9 +7,21 @@
 // Media Patent License 1.0 was not distributed with this source code in the
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
+mod avif;
+use self::avif::AvifMuxer;
+
 mod ivf;
 use self::ivf::IvfMuxer;
 
+mod mkv;
+use self::mkv::MkvMuxer;
+
+mod mp4;
+use self::mp4::Mp4Muxer;
+
+mod obu;
+use self::obu::ObuMuxer;
+
 mod y4m;
 pub use self::y4m::write_y4m_frame;
 
@@ -22,18 +34,34 @@ use std::path::Path;
 use crate::error::*;
 
 pub trait Muxer {
-  fn write_header(
-    &mut self, width: usize, height: usize, framerate_num: usize,
-    framerate_den: usize,
-  );
+  /// Writes the container header, `codec_config` being the AV1 codec
+  /// configuration record of the stream.
+  fn write_header(&mut self, config: &EncoderConfig, codec_config: &[u8]);
 
   fn write_frame(&mut self, pts: u64, data: &[u8], frame_type: FrameType);
 
   fn flush(&mut self) -> io::Result<()>;
+
+  /// Completes the output once all the frames are written.
+  fn finish(&mut self) -> io::Result<()> {
+    self.flush()
+  }
+}
+
+/// Strips the temporal delimiter OBU starting a packet, as the Matroska and
+/// ISOBMFF mappings of AV1 leave it out of the stored samples.
+fn strip_temporal_delimiter(data: &[u8]) -> &[u8] {
+  // OBU header of a temporal delimiter with a size field, and its empty size.
+  const TEMPORAL_DELIMITER: [u8; 2] = [0x12, 0x00];
+  if data.starts_with(&TEMPORAL_DELIMITER) {
+    &data[TEMPORAL_DELIMITER.len()..]
+  } else {
+    data
+  }
 }
 
 pub fn create_muxer(
-  path: &str, overwrite: bool,
+  path: &str, overwrite: bool, fragmented: bool,
 ) -> Result<Box<dyn Muxer>, CliError> {
   if !overwrite {
     IvfMuxer::check_file(path)?;
@@ -51,8 +79,16 @@ pub fn create_muxer(
 
   match &ext[..] {
     "ivf" => IvfMuxer::open(path),
+    "webm" => MkvMuxer::open(path, "webm"),
+    "mkv" => MkvMuxer::open(path, "matroska"),
+    "mp4" => Mp4Muxer::open(path, fragmented),
+    "avif" => AvifMuxer::open(path),
+    "obu" => ObuMuxer::open(path),
     _e => {
-      panic!("{} is not a supported extension, please change to .ivf", ext);
+      panic!(
+        "{} is not a supported extension, please change to .ivf, .webm, .mkv, .mp4, .avif or .obu",
+        ext
+      );
     }
   }
 }
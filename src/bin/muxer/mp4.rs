@@ -0,0 +1,483 @@
+// Copyright (c) 2020, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use super::{strip_temporal_delimiter, Muxer};
+use rav1e::isobmff::*;
+use rav1e::prelude::*;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+use crate::error::*;
+
+/// Identity transformation matrix of the movie and track headers.
+const MATRIX: [u32; 9] =
+  [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// Sample flags of the track fragment runs: sample_depends_on and
+/// sample_is_non_sync_sample.
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+  buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+  buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+  buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn ftyp(fragmented: bool) -> Vec<u8> {
+  let (major_brand, compatible_brands): (&[u8; 4], &[&[u8; 4]]) = if fragmented
+  {
+    (b"iso6", &[b"iso6", b"cmfc", b"av01"])
+  } else {
+    (b"isom", &[b"isom", b"mp41", b"av01"])
+  };
+  let mut data = Vec::new();
+  data.extend_from_slice(major_brand);
+  put_u32(&mut data, 0);
+  for brand in compatible_brands {
+    data.extend_from_slice(*brand);
+  }
+  let mut buf = Vec::new();
+  write_box(&mut buf, b"ftyp", &data);
+  buf
+}
+
+/// Mastering display colour volume, in the units of SMPTE ST 2086.
+fn mdcv(mastering: &MasteringDisplay) -> Vec<u8> {
+  // Chromaticity coordinates go from 0.16 fixed point to units of 0.00002.
+  let coordinate = |v: u16| ((u32::from(v) * 50_000 + (1 << 15)) >> 16) as u16;
+  let mut data = Vec::new();
+  // The primaries are stored in G, B, R order.
+  for &i in [1, 2, 0].iter() {
+    put_u16(&mut data, coordinate(mastering.primaries[i].x));
+    put_u16(&mut data, coordinate(mastering.primaries[i].y));
+  }
+  put_u16(&mut data, coordinate(mastering.white_point.x));
+  put_u16(&mut data, coordinate(mastering.white_point.y));
+  // Luminances go from 24.8 and 18.14 fixed point to units of 0.0001 cd/m².
+  put_u32(
+    &mut data,
+    (u64::from(mastering.max_luminance) * 10_000 / (1 << 8)) as u32,
+  );
+  put_u32(
+    &mut data,
+    (u64::from(mastering.min_luminance) * 10_000 / (1 << 14)) as u32,
+  );
+  let mut buf = Vec::new();
+  write_box(&mut buf, b"mdcv", &data);
+  buf
+}
+
+fn clli(light: &ContentLight) -> Vec<u8> {
+  let mut data = Vec::new();
+  put_u16(&mut data, light.max_content_light_level);
+  put_u16(&mut data, light.max_frame_average_light_level);
+  let mut buf = Vec::new();
+  write_box(&mut buf, b"clli", &data);
+  buf
+}
+
+/// `av01` sample entry, carrying the codec configuration record.
+fn av01(config: &EncoderConfig, codec_config: &[u8]) -> Vec<u8> {
+  let mut data = vec![0; 6];
+  put_u16(&mut data, 1); // data_reference_index
+  data.resize(data.len() + 16, 0);
+  put_u16(&mut data, config.width as u16);
+  put_u16(&mut data, config.height as u16);
+  put_u32(&mut data, 0x0048_0000); // 72 dpi
+  put_u32(&mut data, 0x0048_0000);
+  put_u32(&mut data, 0);
+  put_u16(&mut data, 1); // frame_count
+  let mut compressor_name = [0; 32];
+  let name = b"AOM Coding";
+  compressor_name[0] = name.len() as u8;
+  compressor_name[1..=name.len()].copy_from_slice(name);
+  data.extend_from_slice(&compressor_name);
+  put_u16(&mut data, 0x0018); // depth
+  put_u16(&mut data, 0xffff);
+  write_box(&mut data, b"av1C", codec_config);
+  colr(&mut data, config);
+  if let Some(mastering) = config.mastering_display {
+    data.extend_from_slice(&mdcv(&mastering));
+  }
+  if let Some(light) = config.content_light {
+    data.extend_from_slice(&clli(&light));
+  }
+  let mut buf = Vec::new();
+  write_box(&mut buf, b"av01", &data);
+  buf
+}
+
+struct Sample {
+  pts: u64,
+  size: u32,
+  keyframe: bool,
+}
+
+/// Sample tables of the movie, empty for fragmented output.
+#[derive(Default)]
+struct SampleTables {
+  durations: Vec<u32>,
+  sizes: Vec<u32>,
+  sync_samples: Vec<u32>,
+  chunk_offset: u64,
+}
+
+fn stbl(
+  config: &EncoderConfig, codec_config: &[u8], tables: &SampleTables,
+) -> Vec<u8> {
+  let mut stbl = Vec::new();
+
+  let mut stsd = Vec::new();
+  put_u32(&mut stsd, 1);
+  stsd.extend_from_slice(&av01(config, codec_config));
+  write_full_box(&mut stbl, b"stsd", 0, 0, &stsd);
+
+  // Time to sample, with runs of equal durations
+  let mut runs: Vec<(u32, u32)> = Vec::new();
+  for &duration in tables.durations.iter() {
+    match runs.last_mut() {
+      Some((count, delta)) if *delta == duration => *count += 1,
+      _ => runs.push((1, duration)),
+    }
+  }
+  let mut stts = Vec::new();
+  put_u32(&mut stts, runs.len() as u32);
+  for &(count, delta) in runs.iter() {
+    put_u32(&mut stts, count);
+    put_u32(&mut stts, delta);
+  }
+  write_full_box(&mut stbl, b"stts", 0, 0, &stts);
+
+  if !tables.sizes.is_empty() {
+    let mut stss = Vec::new();
+    put_u32(&mut stss, tables.sync_samples.len() as u32);
+    for &sample in tables.sync_samples.iter() {
+      put_u32(&mut stss, sample);
+    }
+    write_full_box(&mut stbl, b"stss", 0, 0, &stss);
+  }
+
+  // All the samples are stored in a single chunk.
+  let mut stsc = Vec::new();
+  if tables.sizes.is_empty() {
+    put_u32(&mut stsc, 0);
+  } else {
+    put_u32(&mut stsc, 1);
+    put_u32(&mut stsc, 1);
+    put_u32(&mut stsc, tables.sizes.len() as u32);
+    put_u32(&mut stsc, 1);
+  }
+  write_full_box(&mut stbl, b"stsc", 0, 0, &stsc);
+
+  let mut stsz = Vec::new();
+  put_u32(&mut stsz, 0);
+  put_u32(&mut stsz, tables.sizes.len() as u32);
+  for &size in tables.sizes.iter() {
+    put_u32(&mut stsz, size);
+  }
+  write_full_box(&mut stbl, b"stsz", 0, 0, &stsz);
+
+  let mut co64 = Vec::new();
+  if tables.sizes.is_empty() {
+    put_u32(&mut co64, 0);
+  } else {
+    put_u32(&mut co64, 1);
+    put_u64(&mut co64, tables.chunk_offset);
+  }
+  write_full_box(&mut stbl, b"co64", 0, 0, &co64);
+
+  let mut buf = Vec::new();
+  write_box(&mut buf, b"stbl", &stbl);
+  buf
+}
+
+fn moov(
+  config: &EncoderConfig, codec_config: &[u8], tables: &SampleTables,
+  fragmented: bool,
+) -> Vec<u8> {
+  let timescale = config.time_base.den as u32;
+  let duration: u64 = tables.durations.iter().map(|&d| u64::from(d)).sum();
+  let mut moov = Vec::new();
+
+  let mut mvhd = Vec::new();
+  put_u64(&mut mvhd, 0); // creation time
+  put_u64(&mut mvhd, 0); // modification time
+  put_u32(&mut mvhd, timescale);
+  put_u64(&mut mvhd, duration);
+  put_u32(&mut mvhd, 0x0001_0000); // rate
+  put_u16(&mut mvhd, 0x0100); // volume
+  mvhd.resize(mvhd.len() + 10, 0);
+  MATRIX.iter().for_each(|&v| put_u32(&mut mvhd, v));
+  mvhd.resize(mvhd.len() + 24, 0);
+  put_u32(&mut mvhd, 2); // next_track_ID
+  write_full_box(&mut moov, b"mvhd", 1, 0, &mvhd);
+
+  let mut trak = Vec::new();
+
+  let mut tkhd = Vec::new();
+  put_u64(&mut tkhd, 0); // creation time
+  put_u64(&mut tkhd, 0); // modification time
+  put_u32(&mut tkhd, 1); // track_ID
+  put_u32(&mut tkhd, 0);
+  put_u64(&mut tkhd, duration);
+  tkhd.resize(tkhd.len() + 16, 0);
+  MATRIX.iter().for_each(|&v| put_u32(&mut tkhd, v));
+  put_u32(&mut tkhd, (config.width as u32) << 16);
+  put_u32(&mut tkhd, (config.height as u32) << 16);
+  // Enabled and in movie
+  write_full_box(&mut trak, b"tkhd", 1, 3, &tkhd);
+
+  let mut mdia = Vec::new();
+
+  let mut mdhd = Vec::new();
+  put_u64(&mut mdhd, 0); // creation time
+  put_u64(&mut mdhd, 0); // modification time
+  put_u32(&mut mdhd, timescale);
+  put_u64(&mut mdhd, duration);
+  put_u16(&mut mdhd, 0x55c4); // "und"
+  put_u16(&mut mdhd, 0);
+  write_full_box(&mut mdia, b"mdhd", 1, 0, &mdhd);
+
+  let mut hdlr = Vec::new();
+  put_u32(&mut hdlr, 0);
+  hdlr.extend_from_slice(b"vide");
+  hdlr.resize(hdlr.len() + 12, 0);
+  hdlr.extend_from_slice(b"VideoHandler\0");
+  write_full_box(&mut mdia, b"hdlr", 0, 0, &hdlr);
+
+  let mut minf = Vec::new();
+  write_full_box(&mut minf, b"vmhd", 0, 1, &[0; 8]);
+  let mut dref = Vec::new();
+  put_u32(&mut dref, 1);
+  // The media data is in the same file.
+  write_full_box(&mut dref, b"url ", 0, 1, &[]);
+  let mut dinf = Vec::new();
+  write_full_box(&mut dinf, b"dref", 0, 0, &dref);
+  write_box(&mut minf, b"dinf", &dinf);
+  minf.extend_from_slice(&stbl(config, codec_config, tables));
+  write_box(&mut mdia, b"minf", &minf);
+
+  write_box(&mut trak, b"mdia", &mdia);
+  write_box(&mut moov, b"trak", &trak);
+
+  if fragmented {
+    let mut trex = Vec::new();
+    put_u32(&mut trex, 1); // track_ID
+    put_u32(&mut trex, 1); // default_sample_description_index
+    put_u32(&mut trex, config.time_base.num as u32);
+    put_u32(&mut trex, 0);
+    put_u32(&mut trex, 0);
+    let mut mvex = Vec::new();
+    write_full_box(&mut mvex, b"trex", 0, 0, &trex);
+    write_box(&mut moov, b"mvex", &mvex);
+  }
+
+  let mut buf = Vec::new();
+  write_box(&mut buf, b"moov", &moov);
+  buf
+}
+
+/// ISOBMFF (MP4) muxer of a single AV1 video track.
+///
+/// The samples are stored in a single `mdat` box followed by the `moov` box,
+/// or with `fragmented` in one movie fragment per key frame, as CMAF.
+pub struct Mp4Muxer {
+  output: BufWriter<File>,
+  fragmented: bool,
+  config: Option<EncoderConfig>,
+  codec_config: Vec<u8>,
+  /// Samples of the movie, or of the fragment being written.
+  samples: Vec<Sample>,
+  /// Data of the fragment being written.
+  fragment: Vec<u8>,
+  sequence_number: u32,
+  /// Position of the `mdat` box of non-fragmented output.
+  mdat_pos: u64,
+}
+
+impl Mp4Muxer {
+  pub fn open(
+    path: &str, fragmented: bool,
+  ) -> Result<Box<dyn Muxer>, CliError> {
+    let file =
+      File::create(path).map_err(|e| e.context("Cannot open output file"))?;
+    let mp4 = Mp4Muxer {
+      output: BufWriter::new(file),
+      fragmented,
+      config: None,
+      codec_config: Vec::new(),
+      samples: Vec::new(),
+      fragment: Vec::new(),
+      sequence_number: 0,
+      mdat_pos: 0,
+    };
+    Ok(Box::new(mp4))
+  }
+
+  fn time_base(&self) -> Rational {
+    self.config.map(|config| config.time_base).unwrap_or(Rational::new(1, 30))
+  }
+
+  /// Durations of the samples, in units of the track timescale, given the
+  /// presentation time of the following sample if any.
+  fn durations(&self, next_pts: Option<u64>) -> Vec<u32> {
+    let num = self.time_base().num;
+    let next = self.samples.iter().skip(1).map(|s| s.pts).map(Some);
+    self
+      .samples
+      .iter()
+      .zip(next.chain(std::iter::once(next_pts)))
+      .map(|(sample, next_pts)| match next_pts {
+        Some(next_pts) => ((next_pts - sample.pts) * num) as u32,
+        None => num as u32,
+      })
+      .collect()
+  }
+
+  fn write_fragment(&mut self, next_pts: Option<u64>) -> io::Result<()> {
+    if self.samples.is_empty() {
+      return Ok(());
+    }
+    self.sequence_number += 1;
+    let durations = self.durations(next_pts);
+
+    let mut traf = Vec::new();
+    // default-base-is-moof
+    write_full_box(&mut traf, b"tfhd", 0, 0x02_0000, &1u32.to_be_bytes());
+    let decode_time = self.samples[0].pts * self.time_base().num;
+    write_full_box(&mut traf, b"tfdt", 1, 0, &decode_time.to_be_bytes());
+    let trun_len = 12 + 8 + 12 * self.samples.len();
+    // moof, mfhd, traf and mdat headers.
+    let data_offset = 8 + 16 + 8 + traf.len() + trun_len + 8;
+    let mut trun = Vec::new();
+    put_u32(&mut trun, self.samples.len() as u32);
+    put_u32(&mut trun, data_offset as u32);
+    for (sample, &duration) in self.samples.iter().zip(durations.iter()) {
+      put_u32(&mut trun, duration);
+      put_u32(&mut trun, sample.size);
+      put_u32(
+        &mut trun,
+        if sample.keyframe {
+          SYNC_SAMPLE_FLAGS
+        } else {
+          NON_SYNC_SAMPLE_FLAGS
+        },
+      );
+    }
+    // data-offset, sample-duration, sample-size and sample-flags present
+    write_full_box(&mut traf, b"trun", 0, 0x00_0701, &trun);
+
+    let mut moof = Vec::new();
+    write_full_box(
+      &mut moof,
+      b"mfhd",
+      0,
+      0,
+      &self.sequence_number.to_be_bytes(),
+    );
+    write_box(&mut moof, b"traf", &traf);
+    let mut buf = Vec::new();
+    write_box(&mut buf, b"moof", &moof);
+    write_box(&mut buf, b"mdat", &self.fragment);
+    self.output.write_all(&buf)?;
+
+    self.samples.clear();
+    self.fragment.clear();
+    Ok(())
+  }
+
+  fn try_write_header(
+    &mut self, config: &EncoderConfig, codec_config: &[u8],
+  ) -> io::Result<()> {
+    self.config = Some(*config);
+    self.codec_config = codec_config.to_vec();
+    let mut buf = ftyp(self.fragmented);
+    if self.fragmented {
+      let tables = SampleTables::default();
+      buf.extend_from_slice(&moov(config, codec_config, &tables, true));
+    } else {
+      // The size of the mdat box is filled in when finishing the file.
+      self.mdat_pos = buf.len() as u64;
+      put_u32(&mut buf, 1);
+      buf.extend_from_slice(b"mdat");
+      put_u64(&mut buf, 0);
+    }
+    self.output.write_all(&buf)
+  }
+
+  fn try_write_frame(
+    &mut self, pts: u64, data: &[u8], frame_type: FrameType,
+  ) -> io::Result<()> {
+    let data = strip_temporal_delimiter(data);
+    let keyframe = frame_type == FrameType::KEY;
+    if self.fragmented {
+      // Each fragment starts with a key frame.
+      if keyframe {
+        self.write_fragment(Some(pts))?;
+      }
+      self.fragment.extend_from_slice(data);
+    } else {
+      self.output.write_all(data)?;
+    }
+    self.samples.push(Sample { pts, size: data.len() as u32, keyframe });
+    Ok(())
+  }
+
+  fn try_finish(&mut self) -> io::Result<()> {
+    if self.fragmented {
+      self.write_fragment(None)?;
+      return self.output.flush();
+    }
+
+    let mdat_size: u64 =
+      16 + self.samples.iter().map(|s| s.size as u64).sum::<u64>();
+    let tables = SampleTables {
+      durations: self.durations(None),
+      sizes: self.samples.iter().map(|s| s.size).collect(),
+      sync_samples: (1..=self.samples.len() as u32)
+        .filter(|&i| self.samples[i as usize - 1].keyframe)
+        .collect(),
+      chunk_offset: self.mdat_pos + 16,
+    };
+    let config = self.config.unwrap();
+    let buf = moov(&config, &self.codec_config, &tables, false);
+    self.output.write_all(&buf)?;
+
+    self.output.seek(SeekFrom::Start(self.mdat_pos + 8))?;
+    self.output.write_all(&mdat_size.to_be_bytes())?;
+    self.output.seek(SeekFrom::End(0))?;
+    self.output.flush()
+  }
+}
+
+impl Muxer for Mp4Muxer {
+  fn write_header(&mut self, config: &EncoderConfig, codec_config: &[u8]) {
+    self.try_write_header(config, codec_config).unwrap();
+  }
+
+  fn write_frame(&mut self, pts: u64, data: &[u8], frame_type: FrameType) {
+    self.try_write_frame(pts, data, frame_type).unwrap();
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.output.flush()
+  }
+
+  fn finish(&mut self) -> io::Result<()> {
+    self.try_finish()
+  }
+}
@@ -0,0 +1,42 @@
+// Copyright (c) 2020, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use super::Muxer;
+use rav1e::prelude::*;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+
+use crate::error::*;
+
+/// Raw OBU stream, in the low overhead bitstream format or the length
+/// delimited one of Annex B depending on the encoder configuration.
+pub struct ObuMuxer {
+  output: BufWriter<File>,
+}
+
+impl ObuMuxer {
+  pub fn open(path: &str) -> Result<Box<dyn Muxer>, CliError> {
+    let file =
+      File::create(path).map_err(|e| e.context("Cannot open output file"))?;
+    Ok(Box::new(ObuMuxer { output: BufWriter::new(file) }))
+  }
+}
+
+impl Muxer for ObuMuxer {
+  fn write_header(&mut self, _config: &EncoderConfig, _codec_config: &[u8]) {}
+
+  fn write_frame(&mut self, _pts: u64, data: &[u8], _frame_type: FrameType) {
+    self.output.write_all(data).unwrap();
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.output.flush()
+  }
+}
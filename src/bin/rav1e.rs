@@ -43,6 +43,10 @@ use crate::error::*;
 use crate::stats::*;
 use rav1e::prelude::*;
 
+use crate::decoder::raw::{
+  PlanarDecoder, RawInput, RawLayout, SemiPlanarDecoder,
+};
+use crate::decoder::y4m::y4m_colorspace;
 use crate::decoder::{Decoder, VideoDetails};
 use crate::muxer::*;
 use std::fs::File;
@@ -239,15 +243,17 @@ fn process_frame<T: Pixel, D: Decoder>(
 }
 
 fn do_encode<T: Pixel, D: Decoder>(
-  cfg: Config, verbose: Verbose, mut progress: ProgressInfo,
-  output: &mut dyn Muxer, mut source: Source<D>, mut pass1file: Option<File>,
-  mut pass2file: Option<File>,
+  cfg: Config, enc: &EncoderConfig, verbose: Verbose,
+  mut progress: ProgressInfo, output: &mut dyn Muxer, mut source: Source<D>,
+  mut pass1file: Option<File>, mut pass2file: Option<File>,
   mut y4m_enc: Option<y4m::Encoder<Box<dyn Write>>>,
   metrics_enabled: MetricsEnabled,
 ) -> Result<(), CliError> {
   let mut ctx: Context<T> =
     cfg.new_context().map_err(|e| e.context("Invalid encoder settings"))?;
 
+  output.write_header(enc, &ctx.container_sequence_header());
+
   // Let's write down a placeholder.
   if let Some(passfile) = pass1file.as_mut() {
     let len = ctx.rc_summary_size();
@@ -285,6 +291,7 @@ fn do_encode<T: Pixel, D: Decoder>(
       output.flush().unwrap();
     }
   }
+  output.finish().map_err(|e| e.context("Unable to finish the output"))?;
   if verbose != Verbose::Quiet {
     if verbose == Verbose::Verbose {
       // Clear out the temporary progress indicator
@@ -384,22 +391,48 @@ cfg_if::cfg_if! {
 
 fn run() -> Result<(), error::CliError> {
   let mut cli = parse_cli()?;
-  // Maximum frame size by specification + maximum y4m header
-  let limit = y4m::Limits {
-    // Use saturating operations to gracefully handle 32-bit architectures
-    bytes: 64usize
-      .saturating_mul(64)
-      .saturating_mul(4096)
-      .saturating_mul(2304)
-      .saturating_add(1024),
-  };
-  let mut y4m_dec = match y4m::Decoder::new_with_limits(cli.io.input, limit) {
-    Err(_) => {
-      return Err(CliError::new("Could not input video. Is it a y4m file?"))
+  // Take the input out of the options, which are passed on to the encoding.
+  let input = std::mem::replace(&mut cli.io.input, Box::new(std::io::empty()));
+  match cli.raw_input {
+    Some(RawInput { layout: RawLayout::Planar, details }) => {
+      let rec_colorspace = y4m_colorspace(&details);
+      let decoder = PlanarDecoder::new(input, details);
+      encode_input(cli, decoder, "raw planar", rec_colorspace)
     }
-    Ok(d) => d,
-  };
-  let video_info = y4m_dec.get_video_details();
+    Some(RawInput { layout: RawLayout::SemiPlanar, details }) => {
+      let rec_colorspace = y4m_colorspace(&details);
+      let decoder = SemiPlanarDecoder::new(input, details);
+      encode_input(cli, decoder, "raw semi-planar", rec_colorspace)
+    }
+    None => {
+      // Maximum frame size by specification + maximum y4m header
+      let limit = y4m::Limits {
+        // Use saturating operations to gracefully handle 32-bit architectures
+        bytes: 64usize
+          .saturating_mul(64)
+          .saturating_mul(4096)
+          .saturating_mul(2304)
+          .saturating_add(1024),
+      };
+      let y4m_dec = match y4m::Decoder::new_with_limits(input, limit) {
+        Err(_) => {
+          return Err(CliError::new(
+            "Could not input video. Is it a y4m file?",
+          ))
+        }
+        Ok(d) => d,
+      };
+      let rec_colorspace = y4m_dec.get_colorspace();
+      encode_input(cli, y4m_dec, "y4m", rec_colorspace)
+    }
+  }
+}
+
+fn encode_input<D: Decoder>(
+  mut cli: CliOptions, mut decoder: D, decoder_name: &str,
+  rec_colorspace: y4m::Colorspace,
+) -> Result<(), error::CliError> {
+  let video_info = decoder.get_video_details();
   let y4m_enc = match cli.io.rec {
     Some(rec) => Some(
       y4m::encode(
@@ -410,7 +443,7 @@ fn run() -> Result<(), error::CliError> {
           video_info.time_base.num as usize,
         ),
       )
-      .with_colorspace(y4m_dec.get_colorspace())
+      .with_colorspace(rec_colorspace)
       .write_header(rec)
       .unwrap(),
     ),
@@ -468,6 +501,10 @@ fn run() -> Result<(), error::CliError> {
     None => None,
   };
 
+  if let Some(crf) = cli.crf {
+    rc = rc.with_crf(crf);
+  }
+
   let cfg = Config::new()
     .with_encoder_config(cli.enc)
     .with_threads(cli.threads)
@@ -485,15 +522,9 @@ fn run() -> Result<(), error::CliError> {
     }
   }
 
-  cli.io.output.write_header(
-    video_info.width,
-    video_info.height,
-    cli.enc.time_base.den as usize,
-    cli.enc.time_base.num as usize,
-  );
-
   info!(
-    "Using y4m decoder: {}x{}p @ {}/{} fps, {}, {}-bit",
+    "Using {} decoder: {}x{}p @ {}/{} fps, {}, {}-bit",
+    decoder_name,
     video_info.width,
     video_info.height,
     video_info.time_base.den,
@@ -510,19 +541,17 @@ fn run() -> Result<(), error::CliError> {
   );
 
   for _ in 0..cli.skip {
-    match y4m_dec.read_frame() {
-      Ok(f) => f,
-      Err(_) => {
-        return Err(CliError::new("Skipped more frames than in the input"))
-      }
-    };
+    if decoder.skip_frame().is_err() {
+      return Err(CliError::new("Skipped more frames than in the input"));
+    }
   }
 
-  let source = Source::new(cli.limit, y4m_dec);
+  let source = Source::new(cli.limit, decoder);
 
   if video_info.bit_depth == 8 {
-    do_encode::<u8, y4m::Decoder<Box<dyn Read>>>(
+    do_encode::<u8, D>(
       cfg,
+      &cli.enc,
       cli.verbose,
       progress,
       &mut *cli.io.output,
@@ -533,8 +562,9 @@ fn run() -> Result<(), error::CliError> {
       cli.metrics_enabled,
     )?
   } else {
-    do_encode::<u16, y4m::Decoder<Box<dyn Read>>>(
+    do_encode::<u16, D>(
       cfg,
+      &cli.enc,
       cli.verbose,
       progress,
       &mut *cli.io.output,
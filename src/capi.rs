@@ -91,6 +91,7 @@ pub struct Frame {
   fi: FrameInternal,
   frame_type: FrameTypeOverride,
   opaque: Option<FrameOpaque>,
+  qp_offsets: Option<Box<[i16]>>,
 }
 
 /// Status that can be returned by encoder functions.
@@ -181,9 +182,14 @@ impl EncContext {
   fn send_frame(
     &mut self, frame: Option<FrameInternal>, frame_type: FrameTypeOverride,
     opaque: Option<Box<dyn std::any::Any + Send>>,
+    qp_offsets: Option<Box<[i16]>>,
   ) -> Result<(), rav1e::EncoderStatus> {
-    let info =
-      rav1e::FrameParameters { frame_type_override: frame_type, opaque };
+    let info = rav1e::FrameParameters {
+      frame_type_override: frame_type,
+      opaque,
+      qp_offsets,
+      ..Default::default()
+    };
     if let Some(frame) = frame {
       match (self, frame) {
         (EncContext::U8(ctx), FrameInternal::U8(ref f)) => {
@@ -640,6 +646,12 @@ unsafe fn option_match(
     "threads" => (*cfg).cfg.threads = value.parse().map_err(|_| ())?,
 
     "tiles" => enc.tiles = value.parse().map_err(|_| ())?,
+    "superblock_128" => enc.superblock_128 = value.parse().map_err(|_| ())?,
+    "superres_mode" => enc.superres_mode = value.parse().map_err(|_| ())?,
+    "superres_denom" => enc.superres_denom = value.parse().map_err(|_| ())?,
+    "superres_qthresh" => {
+      enc.superres_qthresh = value.parse().map_err(|_| ())?
+    }
     "tile_rows" => {
       enc.tile_rows = check_tile_log2(value.parse().map_err(|_| ()))?
     }
@@ -651,6 +663,11 @@ unsafe fn option_match(
     "quantizer" => enc.quantizer = value.parse().map_err(|_| ())?,
     "min_quantizer" => enc.min_quantizer = value.parse().map_err(|_| ())?,
     "bitrate" => enc.bitrate = value.parse().map_err(|_| ())?,
+    "max_bitrate" => enc.max_bitrate = value.parse().map_err(|_| ())?,
+    "buffer_size" => enc.buffer_size = value.parse().map_err(|_| ())?,
+    "crf" => {
+      (*cfg).cfg.rate_control.crf = Some(value.parse().map_err(|_| ())?)
+    }
 
     "key_frame_interval" => {
       enc.set_key_frame_interval(
@@ -674,10 +691,19 @@ unsafe fn option_match(
       enc.rdo_lookahead_frames = value.parse().map_err(|_| ())?
     }
     "low_latency" => enc.low_latency = value.parse().map_err(|_| ())?,
+    "pyramid_depth" => enc.pyramid_depth = value.parse().map_err(|_| ())?,
+    "adaptive_pyramid_depth" => {
+      enc.adaptive_pyramid_depth = value.parse().map_err(|_| ())?
+    }
     "enable_timing_info" => {
       enc.enable_timing_info = value.parse().map_err(|_| ())?
     }
     "still_picture" => enc.still_picture = value.parse().map_err(|_| ())?,
+    "annex_b" => enc.annex_b = value.parse().map_err(|_| ())?,
+    "film_grain" => enc.film_grain = value.parse().map_err(|_| ())?,
+    "intrabc" => enc.intrabc = value.parse().map_err(|_| ())?,
+    "delta_q" => enc.delta_q = value.parse().map_err(|_| ())?,
+    "quant_matrices" => enc.quant_matrices = value.parse().map_err(|_| ())?,
 
     _ => return Err(()),
   }
@@ -692,21 +718,35 @@ unsafe fn option_match(
 /// - "height": height of the frame, default 480
 /// - "speed": 0-10, default 6
 /// - "threads": maximum number of threads to be used
-/// - "tune": "psnr"-"psychovisual", default "psychovisual"
-/// - "quantizer": 0-255, default 100
+/// - "tune": "psnr", "psychovisual" or "screencontent", default "psychovisual"
+/// - "quantizer": 0-255 (0 is lossless), default 100
 /// - "tiles": total number of tiles desired (0 denotes auto), default 0
 /// - "tile_rows": number of tiles horizontally (must be a power of two, overridden by tiles if present), default 0
 /// - "tile_cols": number of tiles vertically (must be a power of two, overridden by tiles if present), default 0
+/// - "superblock_128": flag to use 128x128 superblocks, default false
+/// - "superres_mode": "none", "fixed", "random" or "qthresh", how frames are chosen to be coded at a reduced width, default "none"
+/// - "superres_denom": super-resolution denominator (9-16) of the fixed mode, default 16
+/// - "superres_qthresh": base quantizer above which the qthresh mode codes frames at a reduced width, default 128
 /// - "min_quantizer": minimum allowed base quantizer to use in bitrate mode, default 0
 /// - "bitrate": target bitrate for the bitrate mode (required for two pass mode), default 0
+/// - "max_bitrate": maximum bitrate of the decoder buffer model (0 disables it), default 0
+/// - "buffer_size": size in bits of the decoder buffer model, at most one second at the max bitrate, default 0
+/// - "crf": 0-255, quality factor for the constant quality mode (incompatible with bitrate), default None
 /// - "key_frame_interval": maximum interval between two keyframes, default 240
 /// - "min_key_frame_interval": minimum interval between two keyframes, default 12
 /// - "switch_frame_interval": interval between switch frames, default 0
 /// - "reservoir_frame_delay": number of temporal units over which to distribute the reservoir usage, default None
 /// - "rdo_lookahead_frames": number of frames to read ahead for the RDO lookahead computation, default 40
 /// - "low_latency": flag to enable low latency mode, default false
+/// - "pyramid_depth": depth of the frame re-ordering pyramid (0-4), default 2
+/// - "adaptive_pyramid_depth": flag to choose the depth of each re-ordering group from the lookahead, default false
 /// - "enable_timing_info": flag to enable signaling timing info in the bitstream, default false
 /// - "still_picture": flag for still picture mode, default false
+/// - "annex_b": flag to output the packets in the length delimited format of Annex B, default false
+/// - "film_grain": film grain synthesis strength (0-50, 0 disables it), default 0
+/// - "intrabc": flag to enable intra block copy on screen content key frames, default false
+/// - "delta_q": flag to adapt the quantizer of each superblock to its activity, default false
+/// - "quant_matrices": flag to weight the quantizer of the coefficients by their frequency, default false
 ///
 /// Return a negative value on error or 0.
 #[no_mangle]
@@ -777,7 +817,7 @@ pub unsafe extern fn rav1e_context_unref(ctx: *mut Context) {
 pub unsafe extern fn rav1e_frame_new(ctx: *const Context) -> *mut Frame {
   let fi = (*ctx).ctx.new_frame();
   let frame_type = rav1e::FrameTypeOverride::No;
-  let f = Frame { fi, frame_type, opaque: None };
+  let f = Frame { fi, frame_type, opaque: None, qp_offsets: None };
   let frame = Box::new(f.into());
 
   Box::into_raw(frame)
@@ -826,6 +866,36 @@ pub unsafe extern fn rav1e_frame_set_opaque(
   }
 }
 
+/// Set the region of interest map of the frame
+///
+/// The map holds offsets to the quantizer index of each 64x64 block of the
+/// frame, in raster order over the configured frame size, within -255..=255.
+/// Negative offsets raise the quality of the block. Passing NULL clears it.
+///
+/// The offsets are coded with delta-q, or with segments when the "delta_q"
+/// option is off and the speed settings enable segmentation.
+///
+/// Must be called before rav1e_send_frame() if used, which fails if the map
+/// does not cover the configured frame size.
+///
+/// Return a negative value on error or 0.
+#[no_mangle]
+pub unsafe extern fn rav1e_frame_set_roi(
+  frame: *mut Frame, qp_offsets: *const i16, len: size_t,
+) -> c_int {
+  if qp_offsets.is_null() {
+    (*frame).qp_offsets = None;
+    return 0;
+  }
+  let qp_offsets = slice::from_raw_parts(qp_offsets, len as usize);
+  if qp_offsets.iter().any(|&offset| !(-255..=255).contains(&offset)) {
+    return -1;
+  }
+  (*frame).qp_offsets = Some(qp_offsets.into());
+
+  0
+}
+
 /// Retrieve the first-pass data of a two-pass encode for the frame that was
 /// just encoded. This should be called BEFORE every call to rav1e_receive_packet()
 /// (including the very first one), even if no packet was produced by the
@@ -1055,9 +1125,12 @@ pub unsafe extern fn rav1e_send_frame(
       .map(|o| Box::new(o) as Box<dyn std::any::Any + Send>)
   };
 
+  let qp_offsets =
+    if frame.is_null() { None } else { (*frame).qp_offsets.clone() };
+
   let ret = (*ctx)
     .ctx
-    .send_frame(frame_internal, frame_type, maybe_opaque)
+    .send_frame(frame_internal, frame_type, maybe_opaque, qp_offsets)
     .map(|_v| None)
     .unwrap_or_else(|e| Some(e));
 
@@ -1325,4 +1398,57 @@ mod test {
       rav1e_config_unref(rac);
     }
   }
+
+  #[test]
+  fn frame_roi() {
+    unsafe {
+      let rac = rav1e_config_default();
+      let w = CString::new("width").unwrap();
+      rav1e_config_parse_int(rac, w.as_ptr(), 128);
+      let h = CString::new("height").unwrap();
+      rav1e_config_parse_int(rac, h.as_ptr(), 64);
+      let s = CString::new("speed").unwrap();
+      rav1e_config_parse_int(rac, s.as_ptr(), 10);
+
+      let rax = rav1e_context_new(rac);
+
+      let f = rav1e_frame_new(rax);
+
+      let out_of_range = [0i16, 256];
+      assert_eq!(rav1e_frame_set_roi(f, out_of_range.as_ptr(), 2), -1);
+      let out_of_range = [i16::MIN, 0];
+      assert_eq!(rav1e_frame_set_roi(f, out_of_range.as_ptr(), 2), -1);
+      let too_short = [-40i16];
+      assert_eq!(rav1e_frame_set_roi(f, too_short.as_ptr(), 1), 0);
+      assert_eq!(rav1e_send_frame(rax, f), EncoderStatus::Failure);
+
+      let qp_offsets = [-40i16, 40];
+      assert_eq!(rav1e_frame_set_roi(f, qp_offsets.as_ptr(), 2), 0);
+      for _ in 0..5 {
+        assert_eq!(rav1e_send_frame(rax, f), EncoderStatus::Success);
+      }
+      rav1e_frame_set_roi(f, std::ptr::null(), 0);
+      assert_eq!(rav1e_send_frame(rax, f), EncoderStatus::Success);
+
+      rav1e_send_frame(rax, std::ptr::null_mut());
+
+      let mut count = 0;
+      loop {
+        let mut p: *mut Packet = std::ptr::null_mut();
+        let ret = rav1e_receive_packet(rax, &mut p);
+        if ret == EncoderStatus::Success {
+          count += 1;
+        }
+        rav1e_packet_unref(p);
+        if ret == EncoderStatus::LimitReached {
+          break;
+        }
+      }
+      assert_eq!(count, 6);
+
+      rav1e_frame_unref(f);
+      rav1e_context_unref(rax);
+      rav1e_config_unref(rac);
+    }
+  }
 }
@@ -590,7 +590,7 @@ pub fn cdef_filter_tile<T: Pixel>(
   output: &mut TileMut<'_, T>,
 ) {
   // Each filter block is 64x64, except right and/or bottom for non-multiple-of-64 sizes.
-  // FIXME: 128x128 SB support will break this, we need FilterBlockOffset etc.
+  // With 128x128 superblocks, CDEF indices are still stored per 64x64 unit.
 
   // No need to guard against having fewer actual coded blocks than
   // the output.rect() area.  Inner code already guards this case.
@@ -9,7 +9,10 @@
 
 use super::*;
 
-use crate::predict::PredictionMode;
+use crate::mc::FilterMode;
+use crate::palette::*;
+use crate::predict::{FilterIntraMode, MotionMode, PredictionMode};
+use crate::warp::*;
 
 pub const MAX_PLANES: usize = 3;
 
@@ -81,7 +84,7 @@ impl BlockOffset {
 
   #[inline]
   const fn y_in_sb(self) -> usize {
-    self.y % MIB_SIZE
+    self.y & MAX_MIB_MASK
   }
 
   #[inline]
@@ -173,11 +176,16 @@ pub struct Block {
   pub n4_w: u8, /* block width in the unit of mode_info */
   pub n4_h: u8, /* block height in the unit of mode_info */
   pub txsize: TxSize,
-  // The block-level deblock_deltas are left-shifted by
-  // fi.deblock.block_delta_shift and added to the frame-configured
-  // deltas
+  // The block-level deblock_deltas are added to the frame-configured
+  // levels
   pub deblock_deltas: [i8; FRAME_LF_COUNT],
   pub segmentation_idx: u8,
+  pub palette: PaletteParams,
+  pub filter_intra_mode: Option<FilterIntraMode>,
+  pub motion_mode: MotionMode,
+  /// Interpolation filters of the block, vertical then horizontal as in the
+  /// coding order of the spec.
+  pub interp_filter: [FilterMode; 2],
 }
 
 impl Block {
@@ -205,6 +213,10 @@ impl Default for Block {
       txsize: TX_64X64,
       deblock_deltas: [0, 0, 0, 0],
       segmentation_idx: 0,
+      palette: PaletteParams::default(),
+      filter_intra_mode: None,
+      motion_mode: MotionMode::SIMPLE_TRANSLATION,
+      interp_filter: [FilterMode::REGULAR; 2],
     }
   }
 }
@@ -212,26 +224,47 @@ impl Default for Block {
 #[derive(Clone)]
 pub struct BlockContextCheckpoint {
   cdef_coded: bool,
+  cdef_units: ArrayVec<[(TileSuperBlockOffset, WriterCheckpoint); 4]>,
+  code_deltas: bool,
+  current_qidx: u8,
+  current_deblock_deltas: [i8; FRAME_LF_COUNT],
   above_partition_context: [u8; PARTITION_CONTEXT_MAX_WIDTH],
   // left context is also at 8x8 granularity
-  left_partition_context: [u8; MIB_SIZE >> 1],
+  left_partition_context: [u8; MAX_MIB_SIZE >> 1],
   above_tx_context: [u8; COEFF_CONTEXT_MAX_WIDTH],
-  left_tx_context: [u8; MIB_SIZE],
+  left_tx_context: [u8; MAX_MIB_SIZE],
   above_coeff_context: [[u8; COEFF_CONTEXT_MAX_WIDTH]; MAX_PLANES],
-  left_coeff_context: [[u8; MIB_SIZE]; MAX_PLANES],
+  left_coeff_context: [[u8; MAX_MIB_SIZE]; MAX_PLANES],
+  above_seg_pred_context: [u8; COEFF_CONTEXT_MAX_WIDTH],
+  left_seg_pred_context: [u8; MAX_MIB_SIZE],
 }
 
 pub struct BlockContext<'a> {
   pub cdef_coded: bool,
+  /// 64x64 units of the superblock whose CDEF index is coded, in coding
+  /// order, with the position of the index in the post-CDEF writer. A
+  /// 128x128 superblock codes an index in each of its 64x64 units that
+  /// isn't covered by a larger block.
+  pub cdef_units: ArrayVec<[(TileSuperBlockOffset, WriterCheckpoint); 4]>,
   pub code_deltas: bool,
+  /// Quantizer index of the last coded delta-q, or `base_q_idx` at the
+  /// start of the tile.
+  pub current_qidx: u8,
+  /// Loop filter level deltas of the last coded delta-lf.
+  pub current_deblock_deltas: [i8; FRAME_LF_COUNT],
   pub update_seg: bool,
   pub preskip_segid: bool,
   pub above_partition_context: [u8; PARTITION_CONTEXT_MAX_WIDTH],
-  pub left_partition_context: [u8; MIB_SIZE >> 1],
+  pub left_partition_context: [u8; MAX_MIB_SIZE >> 1],
   pub above_tx_context: [u8; COEFF_CONTEXT_MAX_WIDTH],
-  pub left_tx_context: [u8; MIB_SIZE],
+  pub left_tx_context: [u8; MAX_MIB_SIZE],
   pub above_coeff_context: [[u8; COEFF_CONTEXT_MAX_WIDTH]; MAX_PLANES],
-  pub left_coeff_context: [[u8; MIB_SIZE]; MAX_PLANES],
+  pub left_coeff_context: [[u8; MAX_MIB_SIZE]; MAX_PLANES],
+  /// Whether the segment id of the blocks above was temporally predicted.
+  pub above_seg_pred_context: [u8; COEFF_CONTEXT_MAX_WIDTH],
+  /// Whether the segment id of the blocks to the left was temporally
+  /// predicted.
+  pub left_seg_pred_context: [u8; MAX_MIB_SIZE],
   pub blocks: &'a mut TileBlocksMut<'a>,
 }
 
@@ -239,43 +272,60 @@ impl<'a> BlockContext<'a> {
   pub fn new(blocks: &'a mut TileBlocksMut<'a>) -> Self {
     BlockContext {
       cdef_coded: false,
+      cdef_units: ArrayVec::new(),
       code_deltas: false,
+      current_qidx: 0,
+      current_deblock_deltas: [0; FRAME_LF_COUNT],
       update_seg: false,
       preskip_segid: false,
       above_partition_context: [0; PARTITION_CONTEXT_MAX_WIDTH],
-      left_partition_context: [0; MIB_SIZE >> 1],
+      left_partition_context: [0; MAX_MIB_SIZE >> 1],
       above_tx_context: [0; COEFF_CONTEXT_MAX_WIDTH],
-      left_tx_context: [0; MIB_SIZE],
+      left_tx_context: [0; MAX_MIB_SIZE],
       above_coeff_context: [
         [0; COEFF_CONTEXT_MAX_WIDTH],
         [0; COEFF_CONTEXT_MAX_WIDTH],
         [0; COEFF_CONTEXT_MAX_WIDTH],
       ],
-      left_coeff_context: [[0; MIB_SIZE]; MAX_PLANES],
+      left_coeff_context: [[0; MAX_MIB_SIZE]; MAX_PLANES],
+      above_seg_pred_context: [0; COEFF_CONTEXT_MAX_WIDTH],
+      left_seg_pred_context: [0; MAX_MIB_SIZE],
       blocks,
     }
   }
 
-  pub const fn checkpoint(&self) -> BlockContextCheckpoint {
+  pub fn checkpoint(&self) -> BlockContextCheckpoint {
     BlockContextCheckpoint {
       cdef_coded: self.cdef_coded,
+      cdef_units: self.cdef_units.clone(),
+      code_deltas: self.code_deltas,
+      current_qidx: self.current_qidx,
+      current_deblock_deltas: self.current_deblock_deltas,
       above_partition_context: self.above_partition_context,
       left_partition_context: self.left_partition_context,
       above_tx_context: self.above_tx_context,
       left_tx_context: self.left_tx_context,
       above_coeff_context: self.above_coeff_context,
       left_coeff_context: self.left_coeff_context,
+      above_seg_pred_context: self.above_seg_pred_context,
+      left_seg_pred_context: self.left_seg_pred_context,
     }
   }
 
   pub fn rollback(&mut self, checkpoint: &BlockContextCheckpoint) {
     self.cdef_coded = checkpoint.cdef_coded;
+    self.cdef_units = checkpoint.cdef_units.clone();
+    self.code_deltas = checkpoint.code_deltas;
+    self.current_qidx = checkpoint.current_qidx;
+    self.current_deblock_deltas = checkpoint.current_deblock_deltas;
     self.above_partition_context = checkpoint.above_partition_context;
     self.left_partition_context = checkpoint.left_partition_context;
     self.above_tx_context = checkpoint.above_tx_context;
     self.left_tx_context = checkpoint.left_tx_context;
     self.above_coeff_context = checkpoint.above_coeff_context;
     self.left_coeff_context = checkpoint.left_coeff_context;
+    self.above_seg_pred_context = checkpoint.above_seg_pred_context;
+    self.left_seg_pred_context = checkpoint.left_seg_pred_context;
   }
 
   #[inline]
@@ -348,6 +398,34 @@ impl<'a> BlockContext<'a> {
     }
   }
 
+  pub fn seg_pred_context(&self, bo: TileBlockOffset) -> usize {
+    (self.above_seg_pred_context[bo.0.x]
+      + self.left_seg_pred_context[bo.y_in_sb()]) as usize
+  }
+
+  pub fn update_seg_pred_context(
+    &mut self, bo: TileBlockOffset, bsize: BlockSize, predicted: bool,
+  ) {
+    let n4_w = bsize.width_mi();
+    let n4_h = bsize.height_mi();
+
+    for v in self.above_seg_pred_context[bo.0.x..bo.0.x + n4_w].iter_mut() {
+      *v = predicted as u8;
+    }
+
+    for v in self.left_seg_pred_context[bo.y_in_sb()..bo.y_in_sb() + n4_h]
+      .iter_mut()
+    {
+      *v = predicted as u8;
+    }
+  }
+
+  fn reset_left_seg_pred_context(&mut self) {
+    for c in &mut self.left_seg_pred_context {
+      *c = 0;
+    }
+  }
+
   pub fn reset_left_contexts(&mut self, planes: usize) {
     for p in 0..planes {
       BlockContext::reset_left_coeff_context(self, p);
@@ -355,6 +433,8 @@ impl<'a> BlockContext<'a> {
     BlockContext::reset_left_partition_context(self);
 
     BlockContext::reset_left_tx_context(self);
+
+    BlockContext::reset_left_seg_pred_context(self);
   }
 
   // The mode info data structure has a one element border above and to the
@@ -623,43 +703,44 @@ impl IndexMut<PlaneBlockOffset> for FrameBlocks {
 }
 
 impl<'a> ContextWriter<'a> {
-  pub fn get_cdf_intra_mode_kf(
-    &self, bo: TileBlockOffset,
-  ) -> &[u16; INTRA_MODES + 1] {
+  fn get_intra_mode_kf_ctx(&self, bo: TileBlockOffset) -> (usize, usize) {
     static intra_mode_context: [usize; INTRA_MODES] =
       [0, 1, 2, 3, 4, 4, 4, 4, 3, 0, 1, 2, 0];
+    // Intra block copy neighbors are treated as DC_PRED
+    let kf_mode = |b: &Block| {
+      if b.is_inter() {
+        PredictionMode::DC_PRED
+      } else {
+        b.mode
+      }
+    };
     let above_mode = if bo.0.y > 0 {
-      self.bc.blocks.above_of(bo).mode
+      kf_mode(self.bc.blocks.above_of(bo))
     } else {
       PredictionMode::DC_PRED
     };
     let left_mode = if bo.0.x > 0 {
-      self.bc.blocks.left_of(bo).mode
+      kf_mode(self.bc.blocks.left_of(bo))
     } else {
       PredictionMode::DC_PRED
     };
-    let above_ctx = intra_mode_context[above_mode as usize];
-    let left_ctx = intra_mode_context[left_mode as usize];
+    (
+      intra_mode_context[above_mode as usize],
+      intra_mode_context[left_mode as usize],
+    )
+  }
+
+  pub fn get_cdf_intra_mode_kf(
+    &self, bo: TileBlockOffset,
+  ) -> &[u16; INTRA_MODES + 1] {
+    let (above_ctx, left_ctx) = self.get_intra_mode_kf_ctx(bo);
     &self.fc.kf_y_cdf[above_ctx][left_ctx]
   }
 
   pub fn write_intra_mode_kf(
     &mut self, w: &mut dyn Writer, bo: TileBlockOffset, mode: PredictionMode,
   ) {
-    static intra_mode_context: [usize; INTRA_MODES] =
-      [0, 1, 2, 3, 4, 4, 4, 4, 3, 0, 1, 2, 0];
-    let above_mode = if bo.0.y > 0 {
-      self.bc.blocks.above_of(bo).mode
-    } else {
-      PredictionMode::DC_PRED
-    };
-    let left_mode = if bo.0.x > 0 {
-      self.bc.blocks.left_of(bo).mode
-    } else {
-      PredictionMode::DC_PRED
-    };
-    let above_ctx = intra_mode_context[above_mode as usize];
-    let left_ctx = intra_mode_context[left_mode as usize];
+    let (above_ctx, left_ctx) = self.get_intra_mode_kf_ctx(bo);
     let cdf = &mut self.fc.kf_y_cdf[above_ctx][left_ctx];
     symbol_with_update!(self, w, mode as u32, cdf);
   }
@@ -682,11 +763,10 @@ impl<'a> ContextWriter<'a> {
   #[inline]
   pub fn write_intra_uv_mode(
     &mut self, w: &mut dyn Writer, uv_mode: PredictionMode,
-    y_mode: PredictionMode, bs: BlockSize,
+    y_mode: PredictionMode, cfl_allowed: bool,
   ) {
-    let cdf =
-      &mut self.fc.uv_mode_cdf[bs.cfl_allowed() as usize][y_mode as usize];
-    if bs.cfl_allowed() {
+    let cdf = &mut self.fc.uv_mode_cdf[cfl_allowed as usize][y_mode as usize];
+    if cfl_allowed {
       symbol_with_update!(self, w, uv_mode as u32, cdf);
     } else {
       symbol_with_update!(self, w, uv_mode as u32, &mut cdf[..UV_INTRA_MODES]);
@@ -717,18 +797,38 @@ impl<'a> ContextWriter<'a> {
     );
   }
 
+  pub fn write_filter_intra_mode(
+    &mut self, w: &mut dyn Writer, mode: FilterIntraMode,
+  ) {
+    symbol_with_update!(
+      self,
+      w,
+      mode as u32,
+      &mut self.fc.filter_intra_mode_cdf
+    );
+  }
+
   pub fn write_use_palette_mode(
-    &mut self, w: &mut dyn Writer, enable: bool, bsize: BlockSize,
+    &mut self, w: &mut dyn Writer, palette: &PaletteParams, bsize: BlockSize,
     bo: TileBlockOffset, luma_mode: PredictionMode,
-    chroma_mode: PredictionMode, xdec: usize, ydec: usize, cs: ChromaSampling,
+    chroma_mode: PredictionMode, bit_depth: usize, xdec: usize, ydec: usize,
+    cs: ChromaSampling,
   ) {
-    if enable {
-      unimplemented!(); // TODO
-    }
-
-    let (ctx_luma, ctx_chroma) = (0, 0); // TODO: increase based on surrounding block info
+    let enable = palette.is_enabled();
+    debug_assert!(!enable || luma_mode == PredictionMode::DC_PRED);
 
     if luma_mode == PredictionMode::DC_PRED {
+      let above = if bo.0.y > 0 {
+        self.bc.blocks.above_of(bo).palette
+      } else {
+        PaletteParams::default()
+      };
+      let left = if bo.0.x > 0 {
+        self.bc.blocks.left_of(bo).palette
+      } else {
+        PaletteParams::default()
+      };
+      let ctx_luma = above.is_enabled() as usize + left.is_enabled() as usize;
       let bsize_ctx = bsize.width_mi_log2() + bsize.height_mi_log2() - 2;
       symbol_with_update!(
         self,
@@ -736,20 +836,134 @@ impl<'a> ContextWriter<'a> {
         enable as u32,
         &mut self.fc.palette_y_mode_cdfs[bsize_ctx][ctx_luma]
       );
+
+      if enable {
+        let n = palette.size as usize;
+        symbol_with_update!(
+          self,
+          w,
+          (n - PALETTE_MIN_SIZE) as u32,
+          &mut self.fc.palette_y_size_cdfs[bsize_ctx]
+        );
+
+        // The above palette is not used across 64x64 block rows.
+        let above_colors = if bo.0.y % (64 >> MI_SIZE_LOG2) != 0 {
+          above.colors()
+        } else {
+          &[]
+        };
+        let cache = palette_cache(above_colors, left.colors());
+        let colors = palette.colors();
+        let mut idx = 0;
+        let mut from_cache = ArrayVec::<[u16; PALETTE_MAX_SIZE]>::new();
+        for &c in cache.iter() {
+          if idx >= n {
+            break;
+          }
+          let used = colors.contains(&c);
+          w.literal(1, used as u32);
+          if used {
+            from_cache.push(c);
+            idx += 1;
+          }
+        }
+        self.write_palette_colors(
+          w,
+          colors.iter().filter(|c| !from_cache.contains(c)),
+          bit_depth,
+        );
+      }
     }
 
     if has_chroma(bo, bsize, xdec, ydec, cs)
       && chroma_mode == PredictionMode::DC_PRED
     {
+      // Chroma palettes are not used.
+      let ctx_chroma = enable as usize;
       symbol_with_update!(
         self,
         w,
-        enable as u32,
+        0,
         &mut self.fc.palette_uv_mode_cdfs[ctx_chroma]
       );
     }
   }
 
+  /// Writes the ascending palette `colors` that are not taken from the
+  /// cache: the first one as a literal and the others as deltas.
+  fn write_palette_colors<'b>(
+    &mut self, w: &mut dyn Writer, mut colors: impl Iterator<Item = &'b u16>,
+    bit_depth: usize,
+  ) {
+    let ceil_log2 =
+      |x: u32| if x < 2 { 0 } else { 32 - (x - 1).leading_zeros() };
+
+    let mut prev = match colors.next() {
+      Some(&c) => c,
+      None => return,
+    };
+    w.literal(bit_depth as u8, u32::from(prev));
+
+    let remaining: ArrayVec<[u16; PALETTE_MAX_SIZE]> =
+      colors.cloned().collect();
+    if remaining.is_empty() {
+      return;
+    }
+
+    let min_bits = bit_depth as u32 - 3;
+    let max_delta = remaining
+      .iter()
+      .scan(prev, |p, &c| {
+        let delta = c - *p - 1;
+        *p = c;
+        Some(u32::from(delta))
+      })
+      .max()
+      .unwrap();
+    let mut bits = ceil_log2(max_delta + 1).max(min_bits);
+    w.literal(2, bits - min_bits);
+
+    for &c in remaining.iter() {
+      w.literal(bits as u8, u32::from(c - prev - 1));
+      prev = c;
+      let range = (1u32 << bit_depth) - u32::from(c) - 1;
+      bits = bits.min(ceil_log2(range));
+    }
+  }
+
+  /// Writes the color index map of the visible `width`x`height` area of a
+  /// block coded with `palette`, whose source is `src`.
+  pub fn write_palette_tokens<T: Pixel>(
+    &mut self, w: &mut dyn Writer, palette: &PaletteParams,
+    src: &PlaneRegion<'_, T>, width: usize, height: usize,
+  ) {
+    let n = palette.size as usize;
+    let mut color_map = [0u8; 64 * 64];
+    for (y, row) in src.rows_iter().take(height).enumerate() {
+      for (x, &v) in row[..width].iter().enumerate() {
+        color_map[y * width + x] = palette.color_index(u16::cast_from(v));
+      }
+    }
+
+    w.write_quniform(n as u32, u32::from(color_map[0]));
+    // The indices are coded in wavefront order, along anti-diagonals.
+    for i in 1..width + height - 1 {
+      for j in (i.saturating_sub(height - 1)..=i.min(width - 1)).rev() {
+        let (r, c) = (i - j, j);
+        let (ctx, order) = palette_color_context(&color_map, width, r, c, n);
+        let idx = color_map[r * width + c];
+        let s = order.iter().position(|&o| o == idx).unwrap();
+        symbol_with_update!(
+          self,
+          w,
+          s as u32,
+          &mut self.fc.palette_y_color_index_cdfs[n - PALETTE_MIN_SIZE][ctx]
+            [..=n]
+        );
+      }
+    }
+  }
+
   fn find_valid_row_offs(
     row_offset: isize, mi_row: usize, mi_rows: usize,
   ) -> isize {
@@ -812,7 +1026,18 @@ impl<'a> ContextWriter<'a> {
     ref_frames: [RefType; 2], blk: &Block,
     mv_stack: &mut ArrayVec<[CandidateMV; 9]>, weight: u32,
     newmv_count: &mut usize, is_compound: bool,
+    global_mvs: [Option<MotionVector>; 2],
   ) -> bool {
+    // Blocks predicted with a global warp contribute the motion of the
+    // warp at the current block rather than their own.
+    let is_global = (blk.mode == PredictionMode::GLOBALMV
+      || blk.mode == PredictionMode::GLOBAL_GLOBALMV)
+      && blk.n4_w.min(blk.n4_h) >= 2;
+    let cand_mv = |list: usize, i: usize| match global_mvs[list] {
+      Some(mv) if is_global => mv,
+      _ => blk.mv[i],
+    };
+
     if !blk.is_inter() {
       /* For intrabc */
       false
@@ -820,13 +1045,13 @@ impl<'a> ContextWriter<'a> {
       if blk.ref_frames[0] == ref_frames[0]
         && blk.ref_frames[1] == ref_frames[1]
       {
-        let found_match = Self::find_matching_comp_mv_and_update_weight(
-          blk.mv, mv_stack, weight,
-        );
+        let mvs = [cand_mv(0, 0), cand_mv(1, 1)];
+        let found_match =
+          Self::find_matching_comp_mv_and_update_weight(mvs, mv_stack, weight);
 
         if !found_match && mv_stack.len() < MAX_REF_MV_STACK_SIZE {
           let mv_cand =
-            CandidateMV { this_mv: blk.mv[0], comp_mv: blk.mv[1], weight };
+            CandidateMV { this_mv: mvs[0], comp_mv: mvs[1], weight };
 
           mv_stack.push(mv_cand);
         }
@@ -843,13 +1068,13 @@ impl<'a> ContextWriter<'a> {
       let mut found = false;
       for i in 0..2 {
         if blk.ref_frames[i] == ref_frames[0] {
-          let found_match = Self::find_matching_mv_and_update_weight(
-            blk.mv[i], mv_stack, weight,
-          );
+          let mv = cand_mv(0, i);
+          let found_match =
+            Self::find_matching_mv_and_update_weight(mv, mv_stack, weight);
 
           if !found_match && mv_stack.len() < MAX_REF_MV_STACK_SIZE {
             let mv_cand = CandidateMV {
-              this_mv: blk.mv[i],
+              this_mv: mv,
               comp_mv: MotionVector::default(),
               weight,
             };
@@ -927,6 +1152,7 @@ impl<'a> ContextWriter<'a> {
     processed_rows: &mut isize, ref_frames: [RefType; 2],
     mv_stack: &mut ArrayVec<[CandidateMV; 9]>, newmv_count: &mut usize,
     bsize: BlockSize, is_compound: bool,
+    global_mvs: [Option<MotionVector>; 2],
   ) -> bool {
     let bc = &self.bc;
     let target_n4_w = bsize.width_mi();
@@ -978,6 +1204,7 @@ impl<'a> ContextWriter<'a> {
         len as u32 * weight,
         newmv_count,
         is_compound,
+        global_mvs,
       ) {
         found_match = true;
       }
@@ -993,6 +1220,7 @@ impl<'a> ContextWriter<'a> {
     processed_cols: &mut isize, ref_frames: [RefType; 2],
     mv_stack: &mut ArrayVec<[CandidateMV; 9]>, newmv_count: &mut usize,
     bsize: BlockSize, is_compound: bool,
+    global_mvs: [Option<MotionVector>; 2],
   ) -> bool {
     let bc = &self.bc;
 
@@ -1044,6 +1272,7 @@ impl<'a> ContextWriter<'a> {
         len as u32 * weight,
         newmv_count,
         is_compound,
+        global_mvs,
       ) {
         found_match = true;
       }
@@ -1057,7 +1286,7 @@ impl<'a> ContextWriter<'a> {
   fn scan_blk_mbmi(
     &self, bo: TileBlockOffset, ref_frames: [RefType; 2],
     mv_stack: &mut ArrayVec<[CandidateMV; 9]>, newmv_count: &mut usize,
-    is_compound: bool,
+    is_compound: bool, global_mvs: [Option<MotionVector>; 2],
   ) -> bool {
     if bo.0.x >= self.bc.blocks.cols() || bo.0.y >= self.bc.blocks.rows() {
       return false;
@@ -1072,6 +1301,7 @@ impl<'a> ContextWriter<'a> {
       weight,
       newmv_count,
       is_compound,
+      global_mvs,
     )
   }
 
@@ -1088,6 +1318,17 @@ impl<'a> ContextWriter<'a> {
   ) -> usize {
     let (_rf, _rf_num) = (INTRA_FRAME, 1);
 
+    let mut global_mvs = [MotionVector::default(); 2];
+    let mut warped_global_mvs = [None; 2];
+    for i in 0..1 + is_compound as usize {
+      global_mvs[i] = self.global_mv(fi, bo, bsize, ref_frames[i]);
+      if ref_frames[i] != INTRA_FRAME
+        && fi.gm_params[ref_frames[i].to_index()].is_warp()
+      {
+        warped_global_mvs[i] = Some(global_mvs[i]);
+      }
+    }
+
     let target_n4_h = bsize.height_mi();
     let target_n4_w = bsize.width_mi();
 
@@ -1144,6 +1385,7 @@ impl<'a> ContextWriter<'a> {
         &mut newmv_count,
         bsize,
         is_compound,
+        warped_global_mvs,
       );
       row_match |= found_match;
     }
@@ -1158,16 +1400,20 @@ impl<'a> ContextWriter<'a> {
         &mut newmv_count,
         bsize,
         is_compound,
+        warped_global_mvs,
       );
       col_match |= found_match;
     }
-    if has_tr(bo, bsize) && bo.0.y > 0 {
+    if has_tr(bo, bsize, self.bc.blocks[bo].partition, fi.sb_size_log2())
+      && bo.0.y > 0
+    {
       let found_match = self.scan_blk_mbmi(
         bo.with_offset(target_n4_w as isize, -1),
         ref_frames,
         mv_stack,
         &mut newmv_count,
         is_compound,
+        warped_global_mvs,
       );
       row_match |= found_match;
     }
@@ -1188,6 +1434,7 @@ impl<'a> ContextWriter<'a> {
         mv_stack,
         &mut far_newmv_count,
         is_compound,
+        warped_global_mvs,
       );
     row_match |= found_match;
 
@@ -1208,6 +1455,7 @@ impl<'a> ContextWriter<'a> {
           &mut far_newmv_count,
           bsize,
           is_compound,
+          warped_global_mvs,
         );
         row_match |= found_match;
       }
@@ -1225,6 +1473,7 @@ impl<'a> ContextWriter<'a> {
           &mut far_newmv_count,
           bsize,
           is_compound,
+          warped_global_mvs,
         );
         col_match |= found_match;
       }
@@ -1290,7 +1539,7 @@ impl<'a> ContextWriter<'a> {
       }
 
       if is_compound {
-        let mut combined_mvs = [[MotionVector::default(); 2]; 2];
+        let mut combined_mvs = [global_mvs; 2];
 
         for list in 0..2 {
           let mut comp_count = 0;
@@ -1377,21 +1626,323 @@ impl<'a> ContextWriter<'a> {
     mode_context
   }
 
+  /// Whether any of the blocks along the top and left edges is an inter
+  /// block, as required for the motion mode to be coded.
+  pub fn has_overlappable_candidates(
+    &self, bo: TileBlockOffset, bsize: BlockSize,
+  ) -> bool {
+    let blocks = &self.bc.blocks;
+    if bo.0.y > 0 {
+      let end = (bo.0.x + bsize.width_mi()).min(blocks.cols());
+      for x4 in (bo.0.x..end).step_by(2) {
+        let x5 = (x4 | 1).min(blocks.cols() - 1);
+        if blocks[bo.0.y - 1][x5].ref_frames[0] > INTRA_FRAME {
+          return true;
+        }
+      }
+    }
+    if bo.0.x > 0 {
+      let end = (bo.0.y + bsize.height_mi()).min(blocks.rows());
+      for y4 in (bo.0.y..end).step_by(2) {
+        let y5 = (y4 | 1).min(blocks.rows() - 1);
+        if blocks[y5][bo.0.x - 1].ref_frames[0] > INTRA_FRAME {
+          return true;
+        }
+      }
+    }
+    false
+  }
+
+  /// Collects the samples used to fit the local warp of a single reference
+  /// block from the motion of its neighbours (spec 7.10.4).
+  pub fn find_warp_samples(
+    &self, bo: TileBlockOffset, bsize: BlockSize, ref_frame: RefType,
+    mv: MotionVector, sb_size_log2: usize,
+  ) -> ArrayVec<[WarpSample; LEAST_SQUARES_SAMPLES_MAX]> {
+    let blocks = &self.bc.blocks;
+    let w4 = bsize.width_mi();
+    let h4 = bsize.height_mi();
+    let threshold = bsize.width().max(bsize.height()).max(16).min(112) as i32;
+
+    let mut samples = ArrayVec::new();
+    let mut first = None;
+    let mut num_scanned = 0;
+    let mut add_sample = |delta_row: isize, delta_col: isize| {
+      if num_scanned >= LEAST_SQUARES_SAMPLES_MAX {
+        return;
+      }
+      let y = bo.0.y as isize + delta_row;
+      let x = bo.0.x as isize + delta_col;
+      if y < 0
+        || x < 0
+        || y >= blocks.rows() as isize
+        || x >= blocks.cols() as isize
+      {
+        return;
+      }
+      let cand = &blocks[y as usize][x as usize];
+      if cand.ref_frames[0] != ref_frame || cand.ref_frames[1] != NONE_FRAME {
+        return;
+      }
+      let cand_w4 = cand.n4_w as usize;
+      let cand_h4 = cand.n4_h as usize;
+      // Sample positions are in frame coordinates.
+      let cand_row = blocks.y() + (y as usize & !(cand_h4 - 1));
+      let cand_col = blocks.x() + (x as usize & !(cand_w4 - 1));
+      let mid_y = (cand_row * MI_SIZE + cand_h4 * 2) as i32 - 1;
+      let mid_x = (cand_col * MI_SIZE + cand_w4 * 2) as i32 - 1;
+      let cand_mv = cand.mv[0];
+      let valid = (cand_mv.row as i32 - mv.row as i32).abs()
+        + (cand_mv.col as i32 - mv.col as i32).abs()
+        <= threshold;
+      let sample = [
+        mid_y * 8,
+        mid_x * 8,
+        mid_y * 8 + cand_mv.row as i32,
+        mid_x * 8 + cand_mv.col as i32,
+      ];
+      num_scanned += 1;
+      if valid {
+        samples.push(sample);
+      } else if num_scanned == 1 {
+        first = Some(sample);
+      }
+    };
+
+    let mut do_top_left = true;
+    let mut do_top_right = true;
+    if bo.0.y > 0 {
+      let src_w = blocks[bo.0.y - 1][bo.0.x].n4_w as usize;
+      if w4 <= src_w {
+        let col_offset = bo.0.x & (src_w - 1);
+        if col_offset > 0 {
+          do_top_left = false;
+        }
+        if src_w > w4 + col_offset {
+          do_top_right = false;
+        }
+        add_sample(-1, 0);
+      } else {
+        let mut i = 0;
+        while i < w4.min(blocks.cols() - bo.0.x) {
+          let src_w = blocks[bo.0.y - 1][bo.0.x + i].n4_w as usize;
+          add_sample(-1, i as isize);
+          i += src_w;
+        }
+      }
+    }
+    if bo.0.x > 0 {
+      let src_h = blocks[bo.0.y][bo.0.x - 1].n4_h as usize;
+      if h4 <= src_h {
+        if bo.0.y & (src_h - 1) > 0 {
+          do_top_left = false;
+        }
+        add_sample(0, -1);
+      } else {
+        let mut i = 0;
+        while i < h4.min(blocks.rows() - bo.0.y) {
+          let src_h = blocks[bo.0.y + i][bo.0.x - 1].n4_h as usize;
+          add_sample(i as isize, -1);
+          i += src_h;
+        }
+      }
+    }
+    if do_top_left {
+      add_sample(-1, -1);
+    }
+    if do_top_right
+      && w4.max(h4) <= 16
+      && has_tr(bo, bsize, blocks[bo].partition, sb_size_log2)
+    {
+      add_sample(-1, w4 as isize);
+    }
+
+    if samples.is_empty() {
+      if let Some(sample) = first {
+        samples.push(sample);
+      }
+    }
+    samples
+  }
+
+  /// Fits the local warp of a block to its neighbours, returning `None` if
+  /// the block cannot use WARPED_CAUSAL or the fit is not usable.
+  pub fn local_warp_params<T: Pixel>(
+    &self, fi: &FrameInvariants<T>, bo: TileBlockOffset, bsize: BlockSize,
+    luma_mode: PredictionMode, ref_frames: [RefType; 2], mv: MotionVector,
+  ) -> Option<WarpedMotionParams> {
+    if !fi.allow_warped_motion
+      || fi.force_integer_mv != 0
+      || !self.motion_mode_coded(fi, bo, bsize, luma_mode, ref_frames)
+    {
+      return None;
+    }
+    let samples =
+      self.find_warp_samples(bo, bsize, ref_frames[0], mv, fi.sb_size_log2());
+    if samples.is_empty() {
+      return None;
+    }
+    let frame_bo = PlaneBlockOffset(BlockOffset {
+      x: self.bc.blocks.x() + bo.0.x,
+      y: self.bc.blocks.y() + bo.0.y,
+    });
+    WarpedMotionParams::from_samples(&samples, frame_bo, bsize, mv)
+  }
+
+  /// Whether the motion mode of an inter block is coded, i.e. it may use
+  /// something other than SIMPLE_TRANSLATION.
+  pub fn motion_mode_coded<T: Pixel>(
+    &self, fi: &FrameInvariants<T>, bo: TileBlockOffset, bsize: BlockSize,
+    luma_mode: PredictionMode, ref_frames: [RefType; 2],
+  ) -> bool {
+    if !fi.is_motion_mode_switchable
+      || bsize.width().min(bsize.height()) < 8
+      || ref_frames[1] != NONE_FRAME
+    {
+      return false;
+    }
+    if fi.force_integer_mv == 0
+      && (luma_mode == PredictionMode::GLOBALMV
+        || luma_mode == PredictionMode::GLOBAL_GLOBALMV)
+      && fi.gm_params[ref_frames[0].to_index()].is_warp()
+    {
+      return false;
+    }
+    self.has_overlappable_candidates(bo, bsize)
+  }
+
+  pub fn write_motion_mode<T: Pixel>(
+    &mut self, w: &mut dyn Writer, fi: &FrameInvariants<T>,
+    bo: TileBlockOffset, bsize: BlockSize, ref_frame: RefType,
+    mv: MotionVector, mode: MotionMode,
+  ) {
+    let num_samples = self
+      .find_warp_samples(bo, bsize, ref_frame, mv, fi.sb_size_log2())
+      .len();
+    if fi.force_integer_mv != 0 || num_samples == 0 || !fi.allow_warped_motion
+    {
+      debug_assert!(mode != MotionMode::WARPED_CAUSAL);
+      symbol_with_update!(
+        self,
+        w,
+        (mode == MotionMode::OBMC_CAUSAL) as u32,
+        &mut self.fc.obmc_cdf[bsize as usize]
+      );
+    } else {
+      symbol_with_update!(
+        self,
+        w,
+        mode as u32,
+        &mut self.fc.motion_mode_cdf[bsize as usize]
+      );
+    }
+  }
+
+  /// Whether the interpolation filter of an inter block is coded, i.e. it
+  /// may be something other than the regular filter (spec
+  /// needs_interp_filter()).
+  pub fn interp_filter_coded<T: Pixel>(
+    &self, fi: &FrameInvariants<T>, bsize: BlockSize,
+    luma_mode: PredictionMode, ref_frames: [RefType; 2],
+    motion_mode: MotionMode,
+  ) -> bool {
+    if !fi.is_filter_switchable || motion_mode == MotionMode::WARPED_CAUSAL {
+      return false;
+    }
+    let is_translation = |r: RefType| {
+      fi.gm_params[r.to_index()].wmtype == GlobalMVMode::TRANSLATION
+    };
+    let large = bsize.width().min(bsize.height()) >= 8;
+    if large && luma_mode == PredictionMode::GLOBALMV {
+      is_translation(ref_frames[0])
+    } else if large && luma_mode == PredictionMode::GLOBAL_GLOBALMV {
+      is_translation(ref_frames[0]) || is_translation(ref_frames[1])
+    } else {
+      true
+    }
+  }
+
+  fn interp_filter_ctx(
+    &self, bo: TileBlockOffset, ref_frames: [RefType; 2], dir: usize,
+  ) -> usize {
+    let filter_type = |block: &Block| {
+      if block.ref_frames[0] == ref_frames[0]
+        || block.ref_frames[1] == ref_frames[0]
+      {
+        block.interp_filter[dir] as usize
+      } else {
+        SWITCHABLE_FILTERS
+      }
+    };
+    let left_type = if bo.0.x > 0 {
+      filter_type(self.bc.blocks.left_of(bo))
+    } else {
+      SWITCHABLE_FILTERS
+    };
+    let above_type = if bo.0.y > 0 {
+      filter_type(self.bc.blocks.above_of(bo))
+    } else {
+      SWITCHABLE_FILTERS
+    };
+    let filter_ctx = if left_type == above_type {
+      left_type
+    } else if left_type == SWITCHABLE_FILTERS {
+      above_type
+    } else if above_type == SWITCHABLE_FILTERS {
+      left_type
+    } else {
+      SWITCHABLE_FILTERS
+    };
+    let is_compound =
+      ref_frames[1] != INTRA_FRAME && ref_frames[1] != NONE_FRAME;
+    (dir * 2 + is_compound as usize) * (SWITCHABLE_FILTERS + 1) + filter_ctx
+  }
+
+  pub fn write_interp_filter<T: Pixel>(
+    &mut self, w: &mut dyn Writer, fi: &FrameInvariants<T>,
+    bo: TileBlockOffset, ref_frames: [RefType; 2],
+    interp_filter: [FilterMode; 2],
+  ) {
+    let dirs = if fi.sequence.enable_dual_filter { 2 } else { 1 };
+    debug_assert!(dirs == 2 || interp_filter[0] == interp_filter[1]);
+    for dir in 0..dirs {
+      let ctx = self.interp_filter_ctx(bo, ref_frames, dir);
+      symbol_with_update!(
+        self,
+        w,
+        interp_filter[dir] as u32,
+        &mut self.fc.switchable_interp_cdf[ctx]
+      );
+    }
+  }
+
+  /// Motion vector given by the global motion of `ref_frame` for the block
+  /// (spec 7.10.2.1), used for GLOBALMV and to fill short candidate lists.
+  pub fn global_mv<T: Pixel>(
+    &self, fi: &FrameInvariants<T>, bo: TileBlockOffset, bsize: BlockSize,
+    ref_frame: RefType,
+  ) -> MotionVector {
+    if ref_frame == INTRA_FRAME || ref_frame == NONE_FRAME {
+      return MotionVector::default();
+    }
+    let frame_bo = PlaneBlockOffset(BlockOffset {
+      x: self.bc.blocks.x() + bo.0.x,
+      y: self.bc.blocks.y() + bo.0.y,
+    });
+    fi.gm_params[ref_frame.to_index()].global_mv(
+      frame_bo,
+      bsize,
+      fi.allow_high_precision_mv,
+    )
+  }
+
   pub fn find_mvrefs<T: Pixel>(
     &self, bo: TileBlockOffset, ref_frames: [RefType; 2],
     mv_stack: &mut ArrayVec<[CandidateMV; 9]>, bsize: BlockSize,
     fi: &FrameInvariants<T>, is_compound: bool,
   ) -> usize {
     assert!(ref_frames[0] != NONE_FRAME);
-    if ref_frames[0] != NONE_FRAME {
-      // TODO: If ref_frames[0] != INTRA_FRAME, convert global mv to an mv;
-      // otherwise, set the global mv ref to invalid.
-    }
-
-    if ref_frames[0] != INTRA_FRAME {
-      /* TODO: Set zeromv ref to the converted global motion vector */
-    } else {
-      /* TODO: Set the zeromv ref to 0 */
+    if ref_frames[0] == INTRA_FRAME && !fi.allow_intrabc {
       return 0;
     }
 
@@ -1687,63 +2238,75 @@ impl<'a> ContextWriter<'a> {
     &mut self, w: &mut dyn Writer, mv: MotionVector, ref_mv: MotionVector,
     mv_precision: MvSubpelPrecision,
   ) {
-    let diff =
-      MotionVector { row: mv.row - ref_mv.row, col: mv.col - ref_mv.col };
-    let j: MvJointType = av1_get_mv_joint(diff);
-
-    w.symbol_with_update(j as u32, &mut self.fc.nmv_context.joints_cdf);
+    encode_mv(w, &mut self.fc.nmv_context, mv, ref_mv, mv_precision);
+  }
 
-    if mv_joint_vertical(j) {
-      encode_mv_component(
-        w,
-        diff.row as i32,
-        &mut self.fc.nmv_context.comps[0],
-        mv_precision,
-      );
-    }
-    if mv_joint_horizontal(j) {
-      encode_mv_component(
-        w,
-        diff.col as i32,
-        &mut self.fc.nmv_context.comps[1],
-        mv_precision,
-      );
-    }
+  pub fn write_intrabc(&mut self, w: &mut dyn Writer, use_intrabc: bool) {
+    symbol_with_update!(self, w, use_intrabc as u32, &mut self.fc.intrabc_cdf);
   }
 
-  pub fn write_block_deblock_deltas(
-    &mut self, w: &mut dyn Writer, bo: TileBlockOffset, multi: bool,
-    planes: usize,
+  /// Displacement vectors use their own CDFs and are always integer.
+  pub fn write_dv(
+    &mut self, w: &mut dyn Writer, dv: MotionVector, ref_dv: MotionVector,
   ) {
-    fn write_block_delta(w: &mut dyn Writer, cdf: &mut [u16], delta: i8) {
-      let abs = delta.abs() as u32;
+    encode_mv(
+      w,
+      &mut self.fc.dv_context,
+      dv,
+      ref_dv,
+      MvSubpelPrecision::MV_SUBPEL_NONE,
+    );
+  }
 
-      w.symbol_with_update(cmp::min(abs, DELTA_LF_SMALL), cdf);
+  /// Codes the difference of a quantizer index or loop filter level from
+  /// the previous one of the tile, in units of the delta resolution.
+  fn write_delta(w: &mut dyn Writer, cdf: &mut [u16], delta: i32) {
+    // DELTA_Q_SMALL and DELTA_LF_SMALL are equal
+    let abs = delta.abs() as u32;
 
-      if abs >= DELTA_LF_SMALL {
-        let bits = msb(abs as i32 - 1) as u32;
-        w.literal(3, bits - 1);
-        w.literal(bits as u8, abs - (1 << bits) - 1);
-      }
-      if abs > 0 {
-        w.bool(delta < 0, 16384);
-      }
+    w.symbol_with_update(cmp::min(abs, DELTA_LF_SMALL), cdf);
+
+    if abs >= DELTA_LF_SMALL {
+      let bits = msb(abs as i32 - 1) as u32;
+      w.literal(3, bits - 1);
+      w.literal(bits as u8, abs - (1 << bits) - 1);
     }
+    if abs > 0 {
+      w.bool(delta < 0, 16384);
+    }
+  }
+
+  pub fn write_delta_qindex(
+    &mut self, w: &mut dyn Writer, qidx: u8, delta_q_res: u8,
+  ) {
+    let delta = qidx as i32 - self.bc.current_qidx as i32;
+    debug_assert_eq!(delta & ((1 << delta_q_res) - 1), 0);
+    Self::write_delta(w, &mut self.fc.delta_q_cdf, delta >> delta_q_res);
+    self.bc.current_qidx = qidx;
+  }
 
-    let block = &self.bc.blocks[bo];
+  pub fn write_block_deblock_deltas(
+    &mut self, w: &mut dyn Writer, deltas: [i8; FRAME_LF_COUNT], multi: bool,
+    shift: u8, planes: usize,
+  ) {
+    let current = self.bc.current_deblock_deltas;
+    let reduced = |i: usize| {
+      let delta = deltas[i] as i32 - current[i] as i32;
+      debug_assert_eq!(delta & ((1 << shift) - 1), 0);
+      delta >> shift
+    };
     if multi {
       let deltas_count = FRAME_LF_COUNT + planes - 3;
-      let deltas = &block.deblock_deltas[..deltas_count];
       let cdfs = &mut self.fc.deblock_delta_multi_cdf[..deltas_count];
 
-      for (&delta, cdf) in deltas.iter().zip(cdfs.iter_mut()) {
-        write_block_delta(w, cdf, delta);
+      for (i, cdf) in cdfs.iter_mut().enumerate() {
+        Self::write_delta(w, cdf, reduced(i));
       }
     } else {
-      let delta = block.deblock_deltas[0];
       let cdf = &mut self.fc.deblock_delta_cdf;
-      write_block_delta(w, cdf, delta);
+      Self::write_delta(w, cdf, reduced(0));
     }
+    self.bc.current_deblock_deltas = deltas;
   }
 
   pub fn write_is_inter(
@@ -1761,8 +2324,8 @@ impl<'a> ContextWriter<'a> {
   pub fn write_coeffs_lv_map<T: Coefficient>(
     &mut self, w: &mut dyn Writer, plane: usize, bo: TileBlockOffset,
     coeffs_in: &[T], eob: usize, pred_mode: PredictionMode, tx_size: TxSize,
-    tx_type: TxType, plane_bsize: BlockSize, xdec: usize, ydec: usize,
-    use_reduced_tx_set: bool, frame_clipped_txw: usize,
+    tx_type: TxType, qidx: u8, plane_bsize: BlockSize, xdec: usize,
+    ydec: usize, use_reduced_tx_set: bool, frame_clipped_txw: usize,
     frame_clipped_txh: usize,
   ) -> bool {
     debug_assert!(frame_clipped_txw != 0);
@@ -1814,13 +2377,18 @@ impl<'a> ContextWriter<'a> {
     let tx_class = tx_type_to_class[tx_type as usize];
     let plane_type = if plane == 0 { 0 } else { 1 } as usize;
 
-    // Signal tx_type for luma plane only
-    if plane == 0 {
+    // Signal tx_type for luma plane only, lossless blocks always use the
+    // Walsh-Hadamard transform
+    if plane == 0 && qidx > 0 {
+      let intra_dir = match self.bc.blocks[bo].filter_intra_mode {
+        Some(mode) if !is_inter => mode.intra_dir(),
+        _ => pred_mode,
+      };
       self.write_tx_type(
         w,
         tx_size,
         tx_type,
-        pred_mode,
+        intra_dir,
         is_inter,
         use_reduced_tx_set,
       );
@@ -1829,7 +2397,8 @@ impl<'a> ContextWriter<'a> {
     // Encode EOB
     let mut eob_extra = 0 as u32;
     let eob_pt = Self::get_eob_pos_token(eob, &mut eob_extra);
-    let eob_multi_size: usize = tx_size.area_log2() - 4;
+    // 64-point transforms only code their top-left 32x32 coefficients
+    let eob_multi_size: usize = av1_get_coded_tx_size(tx_size).area_log2() - 4;
     let eob_multi_ctx: usize = if tx_class == TX_CLASS_2D { 0 } else { 1 };
 
     symbol_with_update!(
@@ -8,6 +8,7 @@
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
 use super::*;
+use crate::predict::{FilterIntraMode, MotionMode, PaletteColor, PaletteSize};
 
 #[derive(Clone, Copy)]
 pub struct CDFContext {
@@ -31,9 +32,21 @@ pub struct CDFContext {
   pub intra_inter_cdfs: [[u16; 3]; INTRA_INTER_CONTEXTS],
   pub angle_delta_cdf: [[u16; 2 * MAX_ANGLE_DELTA + 1 + 1]; DIRECTIONAL_MODES],
   pub filter_intra_cdfs: [[u16; 3]; BlockSize::BLOCK_SIZES_ALL],
+  pub filter_intra_mode_cdf:
+    [u16; FilterIntraMode::FILTER_INTRA_MODES as usize + 1],
   pub palette_y_mode_cdfs:
     [[[u16; 3]; PALETTE_Y_MODE_CONTEXTS]; PALETTE_BSIZE_CTXS],
   pub palette_uv_mode_cdfs: [[u16; 3]; PALETTE_UV_MODE_CONTEXTS],
+  pub palette_y_size_cdfs:
+    [[u16; PaletteSize::PALETTE_SIZES as usize + 1]; PALETTE_BSIZE_CTXS],
+  pub palette_y_color_index_cdfs:
+    [[[u16; PaletteColor::PALETTE_COLORS as usize + 1];
+      PALETTE_COLOR_INDEX_CONTEXTS]; PaletteSize::PALETTE_SIZES as usize],
+  pub motion_mode_cdf:
+    [[u16; MotionMode::MOTION_MODES as usize + 1]; BlockSize::BLOCK_SIZES_ALL],
+  pub obmc_cdf: [[u16; 3]; BlockSize::BLOCK_SIZES_ALL],
+  pub switchable_interp_cdf:
+    [[u16; SWITCHABLE_FILTERS + 1]; SWITCHABLE_FILTER_CONTEXTS],
   pub comp_mode_cdf: [[u16; 3]; COMP_INTER_CONTEXTS],
   pub comp_ref_type_cdf: [[u16; 3]; COMP_REF_TYPE_CONTEXTS],
   pub comp_ref_cdf: [[[u16; 3]; FWD_REFS - 1]; REF_CONTEXTS],
@@ -43,9 +56,13 @@ pub struct CDFContext {
   pub compound_mode_cdf:
     [[u16; INTER_COMPOUND_MODES + 1]; INTER_MODE_CONTEXTS],
   pub nmv_context: NMVContext,
+  pub intrabc_cdf: [u16; 2 + 1],
+  pub dv_context: NMVContext,
+  pub delta_q_cdf: [u16; DELTA_Q_PROBS + 1 + 1],
   pub deblock_delta_multi_cdf: [[u16; DELTA_LF_PROBS + 1 + 1]; FRAME_LF_COUNT],
   pub deblock_delta_cdf: [u16; DELTA_LF_PROBS + 1 + 1],
   pub spatial_segmentation_cdfs: [[u16; 8 + 1]; 3],
+  pub segment_pred_cdfs: [[u16; 2 + 1]; 3],
   pub lrf_switchable_cdf: [u16; 3 + 1],
   pub lrf_sgrproj_cdf: [u16; 2 + 1],
   pub lrf_wiener_cdf: [u16; 2 + 1],
@@ -98,8 +115,14 @@ impl CDFContext {
       intra_inter_cdfs: default_intra_inter_cdf,
       angle_delta_cdf: default_angle_delta_cdf,
       filter_intra_cdfs: default_filter_intra_cdfs,
+      filter_intra_mode_cdf: default_filter_intra_mode_cdf,
       palette_y_mode_cdfs: default_palette_y_mode_cdfs,
       palette_uv_mode_cdfs: default_palette_uv_mode_cdfs,
+      palette_y_size_cdfs: default_palette_y_size_cdf,
+      palette_y_color_index_cdfs: default_palette_y_color_index_cdf,
+      motion_mode_cdf: default_motion_mode_cdf,
+      obmc_cdf: default_obmc_cdf,
+      switchable_interp_cdf: default_switchable_interp_cdf,
       comp_mode_cdf: default_comp_mode_cdf,
       comp_ref_type_cdf: default_comp_ref_type_cdf,
       comp_ref_cdf: default_comp_ref_cdf,
@@ -108,9 +131,13 @@ impl CDFContext {
       drl_cdfs: default_drl_cdf,
       compound_mode_cdf: default_compound_mode_cdf,
       nmv_context: default_nmv_context,
+      intrabc_cdf: default_intrabc_cdf,
+      dv_context: default_nmv_context,
+      delta_q_cdf: default_delta_q_cdf,
       deblock_delta_multi_cdf: default_delta_lf_multi_cdf,
       deblock_delta_cdf: default_delta_lf_cdf,
       spatial_segmentation_cdfs: default_spatial_pred_seg_tree_cdf,
+      segment_pred_cdfs: default_segment_pred_cdf,
       lrf_switchable_cdf: default_switchable_restore_cdf,
       lrf_sgrproj_cdf: default_sgrproj_restore_cdf,
       lrf_wiener_cdf: default_wiener_restore_cdf,
@@ -211,8 +238,19 @@ impl CDFContext {
     reset_2d!(self.intra_inter_cdfs);
     reset_2d!(self.angle_delta_cdf);
     reset_2d!(self.filter_intra_cdfs);
+    reset_1d!(self.filter_intra_mode_cdf);
     reset_3d!(self.palette_y_mode_cdfs);
     reset_2d!(self.palette_uv_mode_cdfs);
+    reset_2d!(self.palette_y_size_cdfs);
+    // Palettes of n colors only use the first n + 1 entries.
+    for i in 0..PaletteSize::PALETTE_SIZES as usize {
+      for j in 0..PALETTE_COLOR_INDEX_CONTEXTS {
+        self.palette_y_color_index_cdfs[i][j][i + 2] = 0;
+      }
+    }
+    reset_2d!(self.motion_mode_cdf);
+    reset_2d!(self.obmc_cdf);
+    reset_2d!(self.switchable_interp_cdf);
     reset_2d!(self.comp_mode_cdf);
     reset_2d!(self.comp_ref_type_cdf);
     reset_3d!(self.comp_ref_cdf);
@@ -220,23 +258,29 @@ impl CDFContext {
     reset_3d!(self.single_ref_cdfs);
     reset_2d!(self.drl_cdfs);
     reset_2d!(self.compound_mode_cdf);
+    reset_1d!(self.delta_q_cdf);
     reset_2d!(self.deblock_delta_multi_cdf);
     reset_1d!(self.deblock_delta_cdf);
     reset_2d!(self.spatial_segmentation_cdfs);
+    reset_2d!(self.segment_pred_cdfs);
     reset_1d!(self.lrf_switchable_cdf);
     reset_1d!(self.lrf_sgrproj_cdf);
     reset_1d!(self.lrf_wiener_cdf);
 
-    reset_1d!(self.nmv_context.joints_cdf);
-    for i in 0..2 {
-      reset_1d!(self.nmv_context.comps[i].classes_cdf);
-      reset_2d!(self.nmv_context.comps[i].class0_fp_cdf);
-      reset_1d!(self.nmv_context.comps[i].fp_cdf);
-      reset_1d!(self.nmv_context.comps[i].sign_cdf);
-      reset_1d!(self.nmv_context.comps[i].class0_hp_cdf);
-      reset_1d!(self.nmv_context.comps[i].hp_cdf);
-      reset_1d!(self.nmv_context.comps[i].class0_cdf);
-      reset_2d!(self.nmv_context.comps[i].bits_cdf);
+    reset_1d!(self.intrabc_cdf);
+    for mv_context in [&mut self.nmv_context, &mut self.dv_context].iter_mut()
+    {
+      reset_1d!(mv_context.joints_cdf);
+      for i in 0..2 {
+        reset_1d!(mv_context.comps[i].classes_cdf);
+        reset_2d!(mv_context.comps[i].class0_fp_cdf);
+        reset_1d!(mv_context.comps[i].fp_cdf);
+        reset_1d!(mv_context.comps[i].sign_cdf);
+        reset_1d!(mv_context.comps[i].class0_hp_cdf);
+        reset_1d!(mv_context.comps[i].hp_cdf);
+        reset_1d!(mv_context.comps[i].class0_cdf);
+        reset_2d!(mv_context.comps[i].bits_cdf);
+      }
     }
 
     // lv_map
@@ -300,6 +344,10 @@ impl CDFContext {
       self.filter_intra_cdfs.first().unwrap().as_ptr() as usize;
     let filter_intra_cdfs_end =
       filter_intra_cdfs_start + size_of_val(&self.filter_intra_cdfs);
+    let filter_intra_mode_cdf_start =
+      self.filter_intra_mode_cdf.as_ptr() as usize;
+    let filter_intra_mode_cdf_end =
+      filter_intra_mode_cdf_start + size_of_val(&self.filter_intra_mode_cdf);
     let palette_y_mode_cdfs_start =
       self.palette_y_mode_cdfs.first().unwrap().as_ptr() as usize;
     let palette_y_mode_cdfs_end =
@@ -308,6 +356,24 @@ impl CDFContext {
       self.palette_uv_mode_cdfs.first().unwrap().as_ptr() as usize;
     let palette_uv_mode_cdfs_end =
       palette_uv_mode_cdfs_start + size_of_val(&self.palette_uv_mode_cdfs);
+    let palette_y_size_cdfs_start =
+      self.palette_y_size_cdfs.first().unwrap().as_ptr() as usize;
+    let palette_y_size_cdfs_end =
+      palette_y_size_cdfs_start + size_of_val(&self.palette_y_size_cdfs);
+    let palette_y_color_index_cdfs_start =
+      self.palette_y_color_index_cdfs.first().unwrap().as_ptr() as usize;
+    let palette_y_color_index_cdfs_end = palette_y_color_index_cdfs_start
+      + size_of_val(&self.palette_y_color_index_cdfs);
+    let motion_mode_cdf_start =
+      self.motion_mode_cdf.first().unwrap().as_ptr() as usize;
+    let motion_mode_cdf_end =
+      motion_mode_cdf_start + size_of_val(&self.motion_mode_cdf);
+    let obmc_cdf_start = self.obmc_cdf.first().unwrap().as_ptr() as usize;
+    let obmc_cdf_end = obmc_cdf_start + size_of_val(&self.obmc_cdf);
+    let switchable_interp_cdf_start =
+      self.switchable_interp_cdf.first().unwrap().as_ptr() as usize;
+    let switchable_interp_cdf_end =
+      switchable_interp_cdf_start + size_of_val(&self.switchable_interp_cdf);
     let comp_mode_cdf_start =
       self.comp_mode_cdf.first().unwrap().as_ptr() as usize;
     let comp_mode_cdf_end =
@@ -324,6 +390,10 @@ impl CDFContext {
       self.comp_bwd_ref_cdf.first().unwrap().as_ptr() as usize;
     let comp_bwd_ref_cdf_end =
       comp_bwd_ref_cdf_start + size_of_val(&self.comp_bwd_ref_cdf);
+    let intrabc_cdf_start = self.intrabc_cdf.as_ptr() as usize;
+    let intrabc_cdf_end = intrabc_cdf_start + size_of_val(&self.intrabc_cdf);
+    let delta_q_cdf_start = self.delta_q_cdf.as_ptr() as usize;
+    let delta_q_cdf_end = delta_q_cdf_start + size_of_val(&self.delta_q_cdf);
     let deblock_delta_multi_cdf_start =
       self.deblock_delta_multi_cdf.first().unwrap().as_ptr() as usize;
     let deblock_delta_multi_cdf_end = deblock_delta_multi_cdf_start
@@ -335,6 +405,10 @@ impl CDFContext {
       self.spatial_segmentation_cdfs.first().unwrap().as_ptr() as usize;
     let spatial_segmentation_cdfs_end = spatial_segmentation_cdfs_start
       + size_of_val(&self.spatial_segmentation_cdfs);
+    let segment_pred_cdfs_start =
+      self.segment_pred_cdfs.first().unwrap().as_ptr() as usize;
+    let segment_pred_cdfs_end =
+      segment_pred_cdfs_start + size_of_val(&self.segment_pred_cdfs);
     let lrf_switchable_cdf_start = self.lrf_switchable_cdf.as_ptr() as usize;
     let lrf_switchable_cdf_end =
       lrf_switchable_cdf_start + size_of_val(&self.lrf_switchable_cdf);
@@ -410,6 +484,11 @@ impl CDFContext {
       ("intra_inter_cdfs", intra_inter_cdfs_start, intra_inter_cdfs_end),
       ("angle_delta_cdf", angle_delta_cdf_start, angle_delta_cdf_end),
       ("filter_intra_cdfs", filter_intra_cdfs_start, filter_intra_cdfs_end),
+      (
+        "filter_intra_mode_cdf",
+        filter_intra_mode_cdf_start,
+        filter_intra_mode_cdf_end,
+      ),
       (
         "palette_y_mode_cdfs",
         palette_y_mode_cdfs_start,
@@ -420,10 +499,29 @@ impl CDFContext {
         palette_uv_mode_cdfs_start,
         palette_uv_mode_cdfs_end,
       ),
+      (
+        "palette_y_size_cdfs",
+        palette_y_size_cdfs_start,
+        palette_y_size_cdfs_end,
+      ),
+      (
+        "palette_y_color_index_cdfs",
+        palette_y_color_index_cdfs_start,
+        palette_y_color_index_cdfs_end,
+      ),
+      ("motion_mode_cdf", motion_mode_cdf_start, motion_mode_cdf_end),
+      ("obmc_cdf", obmc_cdf_start, obmc_cdf_end),
+      (
+        "switchable_interp_cdf",
+        switchable_interp_cdf_start,
+        switchable_interp_cdf_end,
+      ),
       ("comp_mode_cdf", comp_mode_cdf_start, comp_mode_cdf_end),
       ("comp_ref_type_cdf", comp_ref_type_cdf_start, comp_ref_type_cdf_end),
       ("comp_ref_cdf", comp_ref_cdf_start, comp_ref_cdf_end),
       ("comp_bwd_ref_cdf", comp_bwd_ref_cdf_start, comp_bwd_ref_cdf_end),
+      ("intrabc_cdf", intrabc_cdf_start, intrabc_cdf_end),
+      ("delta_q_cdf", delta_q_cdf_start, delta_q_cdf_end),
       (
         "deblock_delta_multi_cdf",
         deblock_delta_multi_cdf_start,
@@ -435,6 +533,7 @@ impl CDFContext {
         spatial_segmentation_cdfs_start,
         spatial_segmentation_cdfs_end,
       ),
+      ("segment_pred_cdfs", segment_pred_cdfs_start, segment_pred_cdfs_end),
       ("lrf_switchable_cdf", lrf_switchable_cdf_start, lrf_switchable_cdf_end),
       ("lrf_sgrproj_cdf", lrf_sgrproj_cdf_start, lrf_sgrproj_cdf_end),
       ("lrf_wiener_cdf", lrf_wiener_cdf_start, lrf_wiener_cdf_end),
@@ -512,7 +611,7 @@ impl<'a> ContextWriter<'a> {
     (if element > 0 { cdf[element - 1] } else { 32768 }) - cdf[element]
   }
 
-  pub const fn checkpoint(&self) -> ContextWriterCheckpoint {
+  pub fn checkpoint(&self) -> ContextWriterCheckpoint {
     ContextWriterCheckpoint { fc: *self.fc, bc: self.bc.checkpoint() }
   }
 
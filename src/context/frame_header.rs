@@ -222,8 +222,7 @@ impl<'a> ContextWriter<'a> {
     &mut self, w: &mut dyn Writer, fi: &FrameInvariants<T>,
     rs: &mut TileRestorationStateMut, sbo: TileSuperBlockOffset, pli: usize,
   ) {
-    if !fi.allow_intrabc {
-      // TODO: also disallow if lossless
+    if !fi.allow_intrabc && !fi.lossless {
       let rp = &mut rs.planes[pli];
       if let Some(filter) = rp.restoration_unit(sbo, true).map(|ru| ru.filter)
       {
@@ -12,7 +12,7 @@
 #![allow(non_camel_case_types)]
 
 use crate::color::ChromaSampling;
-use crate::ec::{Writer, OD_BITRES};
+use crate::ec::{Writer, WriterCheckpoint, OD_BITRES};
 use crate::encoder::FrameInvariants;
 use crate::entropymode::*;
 use crate::frame::*;
@@ -129,8 +129,8 @@ const MV_MAX: usize = (1 << MV_MAX_BITS) - 1;
 const MV_VALS: usize = (MV_MAX << 1) + 1;
 
 const MV_IN_USE_BITS: usize = 14;
-const MV_UPP: i32 = 1 << MV_IN_USE_BITS;
-const MV_LOW: i32 = -(1 << MV_IN_USE_BITS);
+pub const MV_UPP: i32 = 1 << MV_IN_USE_BITS;
+pub const MV_LOW: i32 = -(1 << MV_IN_USE_BITS);
 
 #[inline(always)]
 pub fn av1_get_mv_joint(mv: MotionVector) -> MvJointType {
@@ -183,6 +183,24 @@ pub fn get_mv_class(z: u32, offset: &mut u32) -> usize {
   c
 }
 
+pub fn encode_mv(
+  w: &mut dyn Writer, ctx: &mut NMVContext, mv: MotionVector,
+  ref_mv: MotionVector, precision: MvSubpelPrecision,
+) {
+  let diff =
+    MotionVector { row: mv.row - ref_mv.row, col: mv.col - ref_mv.col };
+  let j: MvJointType = av1_get_mv_joint(diff);
+
+  w.symbol_with_update(j as u32, &mut ctx.joints_cdf);
+
+  if mv_joint_vertical(j) {
+    encode_mv_component(w, diff.row as i32, &mut ctx.comps[0], precision);
+  }
+  if mv_joint_horizontal(j) {
+    encode_mv_component(w, diff.col as i32, &mut ctx.comps[1], precision);
+  }
+}
+
 pub fn encode_mv_component(
   w: &mut dyn Writer, comp: i32, mvcomp: &mut NMVComponent,
   precision: MvSubpelPrecision,
@@ -168,7 +168,7 @@ mod test {
 
 impl<'a> ContextWriter<'a> {
   fn partition_gather_horz_alike(
-    out: &mut [u16; 2], cdf_in: &[u16], _bsize: BlockSize,
+    out: &mut [u16; 2], cdf_in: &[u16], bsize: BlockSize,
   ) {
     out[0] = 32768;
     out[0] -= ContextWriter::cdf_element_prob(
@@ -191,16 +191,18 @@ impl<'a> ContextWriter<'a> {
       cdf_in,
       PartitionType::PARTITION_VERT_A as usize,
     );
-    out[0] -= ContextWriter::cdf_element_prob(
-      cdf_in,
-      PartitionType::PARTITION_HORZ_4 as usize,
-    );
+    if bsize != BlockSize::BLOCK_128X128 {
+      out[0] -= ContextWriter::cdf_element_prob(
+        cdf_in,
+        PartitionType::PARTITION_HORZ_4 as usize,
+      );
+    }
     out[0] = 32768 - out[0];
     out[1] = 0;
   }
 
   fn partition_gather_vert_alike(
-    out: &mut [u16; 2], cdf_in: &[u16], _bsize: BlockSize,
+    out: &mut [u16; 2], cdf_in: &[u16], bsize: BlockSize,
   ) {
     out[0] = 32768;
     out[0] -= ContextWriter::cdf_element_prob(
@@ -223,10 +225,12 @@ impl<'a> ContextWriter<'a> {
       cdf_in,
       PartitionType::PARTITION_VERT_B as usize,
     );
-    out[0] -= ContextWriter::cdf_element_prob(
-      cdf_in,
-      PartitionType::PARTITION_VERT_4 as usize,
-    );
+    if bsize != BlockSize::BLOCK_128X128 {
+      out[0] -= ContextWriter::cdf_element_prob(
+        cdf_in,
+        PartitionType::PARTITION_VERT_4 as usize,
+      );
+    }
     out[0] = 32768 - out[0];
     out[1] = 0;
   }
@@ -301,6 +305,9 @@ impl<'a> ContextWriter<'a> {
   ) {
     debug_assert!(bsize.is_sqr());
     assert!(bsize >= BlockSize::BLOCK_8X8);
+    // Intra edge and motion vector candidate availability depend on the
+    // partition that the leaf blocks were coded with.
+    self.bc.blocks.set_partition(bo, bsize, p);
     let hbs = bsize.width_mi() / 2;
     let has_cols = (bo.0.x + hbs) < self.bc.blocks.cols();
     let has_rows = (bo.0.y + hbs) < self.bc.blocks.rows();
@@ -308,6 +315,9 @@ impl<'a> ContextWriter<'a> {
     assert!(ctx < PARTITION_CONTEXTS);
     let partition_cdf = if bsize <= BlockSize::BLOCK_8X8 {
       &mut self.fc.partition_cdf[ctx][..=PARTITION_TYPES]
+    } else if bsize == BlockSize::BLOCK_128X128 {
+      // HORZ_4 and VERT_4 are not allowed for 128x128 blocks
+      &mut self.fc.partition_cdf[ctx][..=EXT_PARTITION_TYPES - 2]
     } else {
       &mut self.fc.partition_cdf[ctx]
     };
@@ -376,16 +386,36 @@ impl<'a> ContextWriter<'a> {
     }
   }
 
+  /// Writes the segment id of a block. `temporal_pred` is the id predicted
+  /// from the segment map of the primary reference frame, when temporal
+  /// updates of the map are enabled.
   pub fn write_segmentation(
     &mut self, w: &mut dyn Writer, bo: TileBlockOffset, bsize: BlockSize,
-    skip: bool, last_active_segid: u8,
+    skip: bool, last_active_segid: u8, temporal_pred: Option<u8>,
   ) {
     let (pred, cdf_index) = self.get_segment_pred(bo);
     if skip {
+      if temporal_pred.is_some() {
+        self.bc.update_seg_pred_context(bo, bsize, false);
+      }
       self.bc.blocks.set_segmentation_idx(bo, bsize, pred);
       return;
     }
     let seg_idx = self.bc.blocks[bo].segmentation_idx;
+    if let Some(temporal_pred) = temporal_pred {
+      let predicted = seg_idx == temporal_pred;
+      let ctx = self.bc.seg_pred_context(bo);
+      symbol_with_update!(
+        self,
+        w,
+        predicted as u32,
+        &mut self.fc.segment_pred_cdfs[ctx]
+      );
+      self.bc.update_seg_pred_context(bo, bsize, predicted);
+      if predicted {
+        return;
+      }
+    }
     let coded_id = Self::neg_interleave(
       seg_idx as i32,
       pred as i32,
@@ -459,9 +489,6 @@ impl<'a> BlockContext<'a> {
   pub fn update_partition_context(
     &mut self, bo: TileBlockOffset, subsize: BlockSize, bsize: BlockSize,
   ) {
-    #[allow(dead_code)]
-    assert!(bsize.is_sqr());
-
     let bw = bsize.width_mi();
     let bh = bsize.height_mi();
 
@@ -481,4 +508,53 @@ impl<'a> BlockContext<'a> {
       *left = partition_context_lookup[subsize as usize][1];
     }
   }
+
+  /// Updates the partition context after coding a square block of size
+  /// `bsize` with `partition`, as the last blocks coded over each column
+  /// and row of the extended partitions are of different sizes.
+  pub fn update_ext_partition_context(
+    &mut self, bo: TileBlockOffset, partition: PartitionType, bsize: BlockSize,
+  ) {
+    debug_assert!(bsize.is_sqr());
+
+    let subsize = bsize.subsize(partition);
+    let split_size = bsize.subsize(PartitionType::PARTITION_SPLIT);
+    let hbs = (bsize.width_mi() / 2) as isize;
+
+    match partition {
+      PartitionType::PARTITION_HORZ_A => {
+        self.update_partition_context(bo, split_size, subsize);
+        self.update_partition_context(
+          bo.with_offset(0, hbs),
+          subsize,
+          subsize,
+        );
+      }
+      PartitionType::PARTITION_HORZ_B => {
+        self.update_partition_context(bo, subsize, subsize);
+        self.update_partition_context(
+          bo.with_offset(0, hbs),
+          split_size,
+          subsize,
+        );
+      }
+      PartitionType::PARTITION_VERT_A => {
+        self.update_partition_context(bo, split_size, subsize);
+        self.update_partition_context(
+          bo.with_offset(hbs, 0),
+          subsize,
+          subsize,
+        );
+      }
+      PartitionType::PARTITION_VERT_B => {
+        self.update_partition_context(bo, subsize, subsize);
+        self.update_partition_context(
+          bo.with_offset(hbs, 0),
+          split_size,
+          subsize,
+        );
+      }
+      _ => self.update_partition_context(bo, subsize, bsize),
+    }
+  }
 }
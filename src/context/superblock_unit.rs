@@ -10,13 +10,15 @@
 use super::*;
 
 pub const MAX_SB_SIZE_LOG2: usize = 7;
-const SB_SIZE_LOG2: usize = 6;
+pub const SB_SIZE_LOG2: usize = 6;
 pub const SB_SIZE: usize = 1 << SB_SIZE_LOG2;
 const SB_SQUARE: usize = SB_SIZE * SB_SIZE;
 
 pub const MI_SIZE_LOG2: usize = 2;
 pub const MI_SIZE: usize = 1 << MI_SIZE_LOG2;
 pub const MAX_MIB_SIZE_LOG2: usize = MAX_SB_SIZE_LOG2 - MI_SIZE_LOG2;
+pub const MAX_MIB_SIZE: usize = 1 << MAX_MIB_SIZE_LOG2;
+pub const MAX_MIB_MASK: usize = MAX_MIB_SIZE - 1;
 pub const MIB_SIZE_LOG2: usize = SB_SIZE_LOG2 - MI_SIZE_LOG2;
 pub const MIB_SIZE: usize = 1 << MIB_SIZE_LOG2;
 pub const MIB_MASK: usize = MIB_SIZE - 1;
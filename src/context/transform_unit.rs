@@ -546,10 +546,8 @@ impl<'a> ContextWriter<'a> {
             [square_tx_size as usize][..=num_tx_set[tx_set as usize]]
         );
       } else {
+        // With filter intra, y_mode is the matching intra direction
         let intra_dir = y_mode;
-        // TODO: Once use_filter_intra is enabled,
-        // intra_dir =
-        // fimode_to_intradir[mbmi->filter_intra_mode_info.filter_intra_mode];
 
         symbol_with_update!(
           self,
@@ -742,8 +740,8 @@ impl<'a> ContextWriter<'a> {
     } else {
       // if txfm_split == true, split one level only
       let split_tx_size = sub_tx_size_map[tx_size as usize];
-      let bw = bsize.width_mi() / split_tx_size.width_mi();
-      let bh = bsize.height_mi() / split_tx_size.height_mi();
+      let bw = tx_size.width_mi() / split_tx_size.width_mi();
+      let bh = tx_size.height_mi() / split_tx_size.height_mi();
 
       for by in 0..bh {
         for bx in 0..bw {
@@ -757,8 +755,8 @@ impl<'a> ContextWriter<'a> {
             bsize,
             split_tx_size,
             false,
-            bx,
-            by,
+            tbx * bw + bx,
+            tby * bh + by,
             depth + 1,
           );
         }
@@ -871,7 +869,7 @@ impl<'a> ContextWriter<'a> {
       }
   }
 
-  fn get_nz_map_ctx(
+  pub fn get_nz_map_ctx(
     levels: &[u8], coeff_idx: usize, bhl: usize, area: usize, scan_idx: usize,
     is_eob: bool, tx_size: TxSize, tx_class: TxClass,
   ) -> usize {
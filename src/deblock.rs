@@ -38,9 +38,9 @@ fn deblock_adjusted_level(
   let level = if deblock.block_deltas_enabled {
     // By-block filter strength delta, if the feature is active.
     let block_delta = if deblock.block_delta_multi {
-      block.deblock_deltas[idx] << deblock.block_delta_shift
+      block.deblock_deltas[idx]
     } else {
-      block.deblock_deltas[0] << deblock.block_delta_shift
+      block.deblock_deltas[0]
     };
 
     // Add to frame-specified filter strength (Y-vertical, Y-horizontal, U, V)
@@ -50,9 +50,10 @@ fn deblock_adjusted_level(
     deblock.levels[idx]
   };
 
-  // if fi.seg_feaure_active {
-  // rav1e does not yet support segments or segment features
-  // }
+  // Add the filter strength delta of the segment, if any.
+  let segment_delta = deblock.segment_deltas[block.segmentation_idx as usize];
+  let level =
+    clamp(level as i8 + segment_delta[idx], 0, MAX_LOOP_FILTER as i8) as u8;
 
   // Are delta modifiers for specific references and modes active?  If so, add them too.
   if deblock.deltas_enabled {
@@ -81,6 +82,18 @@ fn deblock_adjusted_level(
   }
 }
 
+#[inline]
+fn deblock_current<'a, T: Pixel>(
+  blocks: &'a TileBlocks, in_bo: TileBlockOffset, p: &PlaneRegion<T>,
+) -> &'a Block {
+  let xdec = p.plane_cfg.xdec;
+  let ydec = p.plane_cfg.ydec;
+
+  // subsampled chroma uses odd mi row/col, whose block may be in a
+  // different segment than the one at the even mi row/col
+  &blocks[in_bo.0.y | ydec][in_bo.0.x | xdec]
+}
+
 #[inline]
 fn deblock_left<'a, T: Pixel>(
   blocks: &'a TileBlocks, in_bo: TileBlockOffset, p: &PlaneRegion<T>,
@@ -1123,7 +1136,7 @@ fn filter_v_edge<T: Pixel>(
   deblock: &DeblockState, blocks: &TileBlocks, bo: TileBlockOffset,
   p: &mut PlaneRegionMut<T>, pli: usize, bd: usize, xdec: usize, ydec: usize,
 ) {
-  let block = &blocks[bo];
+  let block = deblock_current(blocks, bo, &p.as_const());
   let txsize = if pli == 0 {
     block.txsize
   } else {
@@ -1170,7 +1183,7 @@ fn sse_v_edge<T: Pixel>(
   src_plane: &PlaneRegion<T>, tally: &mut [i64; MAX_LOOP_FILTER + 2],
   pli: usize, bd: usize, xdec: usize, ydec: usize,
 ) {
-  let block = &blocks[bo];
+  let block = deblock_current(blocks, bo, rec_plane);
   let txsize = if pli == 0 {
     block.txsize
   } else {
@@ -1219,7 +1232,7 @@ fn filter_h_edge<T: Pixel>(
   deblock: &DeblockState, blocks: &TileBlocks, bo: TileBlockOffset,
   p: &mut PlaneRegionMut<T>, pli: usize, bd: usize, xdec: usize, ydec: usize,
 ) {
-  let block = &blocks[bo];
+  let block = deblock_current(blocks, bo, &p.as_const());
   let txsize = if pli == 0 {
     block.txsize
   } else {
@@ -1266,7 +1279,7 @@ fn sse_h_edge<T: Pixel>(
   src_plane: &PlaneRegion<T>, tally: &mut [i64; MAX_LOOP_FILTER + 2],
   pli: usize, bd: usize, xdec: usize, ydec: usize,
 ) {
-  let block = &blocks[bo];
+  let block = deblock_current(blocks, bo, rec_plane);
   let txsize = if pli == 0 {
     block.txsize
   } else {
@@ -1277,7 +1290,7 @@ fn sse_h_edge<T: Pixel>(
     let prev_block = deblock_up(blocks, bo, rec_plane);
     let block_edge = bo.0.y & (block.n4_h as usize - 1) == 0;
     let filter_size =
-      deblock_size(block, prev_block, rec_plane, pli, true, block_edge);
+      deblock_size(block, prev_block, rec_plane, pli, false, block_edge);
     if filter_size > 0 {
       let po = bo.plane_offset(rec_plane.plane_cfg); // rec and src have identical subsampling
       let rec_region = rec_plane.subregion(Area::Rect {
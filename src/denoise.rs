@@ -0,0 +1,153 @@
+// Copyright (c) 2020, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::color::ChromaSampling;
+use crate::frame::*;
+use crate::hawktracer::*;
+use crate::util::*;
+
+/// Radius of the window used to gather the local statistics, in pixels.
+const DENOISE_RADIUS: usize = 2;
+
+/// Maximum denoising strength accepted by the encoder configuration.
+pub const MAX_DENOISE_STRENGTH: u8 = 50;
+
+/// Returns the variance of the noise removed for a given `strength`,
+/// expressed in `bit_depth` sample units.
+pub fn noise_variance(strength: u8, bit_depth: usize) -> f64 {
+  // A strength of 4 corresponds to a standard deviation of one 8-bit code
+  // value, so the maximum strength removes grain up to sigma = 12.5.
+  let sigma = f64::from(strength) / 4.0 * (1 << (bit_depth - 8)) as f64;
+  sigma * sigma
+}
+
+/// Denoises the visible area of `frame` with an adaptive (Lee) Wiener
+/// filter and returns the filtered copy.
+///
+/// Each pixel is pulled towards its local mean by an amount that depends on
+/// how much of the local variance is explained by the assumed noise
+/// variance, so flat areas are smoothed while edges and textures are mostly
+/// preserved.
+#[hawktracer(denoise_frame)]
+pub fn denoise_frame<T: Pixel>(
+  frame: &Frame<T>, width: usize, height: usize,
+  chroma_sampling: ChromaSampling, strength: u8, bit_depth: usize,
+) -> Frame<T> {
+  let mut out = frame.clone();
+  let noise_var = noise_variance(strength, bit_depth);
+  let planes = if chroma_sampling == ChromaSampling::Cs400 { 1 } else { 3 };
+
+  for (src, dst) in frame.planes.iter().zip(out.planes.iter_mut()).take(planes)
+  {
+    let PlaneConfig { xdec, ydec, .. } = src.cfg;
+    let plane_width = (width + xdec) >> xdec;
+    let plane_height = (height + ydec) >> ydec;
+    denoise_plane(src, dst, plane_width, plane_height, noise_var, bit_depth);
+  }
+
+  out
+}
+
+fn denoise_plane<T: Pixel>(
+  src: &Plane<T>, dst: &mut Plane<T>, width: usize, height: usize,
+  noise_var: f64, bit_depth: usize,
+) {
+  // Summed-area tables of the samples and of their squares, with an extra
+  // leading row and column of zeros.
+  let stride = width + 1;
+  let mut sum = vec![0u64; stride * (height + 1)];
+  let mut sum_sq = vec![0u64; stride * (height + 1)];
+
+  for y in 0..height {
+    let mut row_sum = 0u64;
+    let mut row_sum_sq = 0u64;
+    for x in 0..width {
+      let v = u64::from(u16::cast_from(src.p(x, y)));
+      row_sum += v;
+      row_sum_sq += v * v;
+      let i = (y + 1) * stride + x + 1;
+      sum[i] = sum[i - stride] + row_sum;
+      sum_sq[i] = sum_sq[i - stride] + row_sum_sq;
+    }
+  }
+
+  let max_value = ((1 << bit_depth) - 1) as f64;
+  let PlaneConfig { stride: dst_stride, xorigin, yorigin, .. } = dst.cfg;
+
+  for y in 0..height {
+    let y0 = y.saturating_sub(DENOISE_RADIUS);
+    let y1 = (y + DENOISE_RADIUS + 1).min(height);
+    let row = &mut dst.data[(yorigin + y) * dst_stride + xorigin
+      ..(yorigin + y) * dst_stride + xorigin + width];
+    for (x, dst) in row.iter_mut().enumerate() {
+      let x0 = x.saturating_sub(DENOISE_RADIUS);
+      let x1 = (x + DENOISE_RADIUS + 1).min(width);
+      let count = ((y1 - y0) * (x1 - x0)) as f64;
+      let area = |t: &[u64]| {
+        t[y1 * stride + x1] + t[y0 * stride + x0]
+          - t[y0 * stride + x1]
+          - t[y1 * stride + x0]
+      };
+      let mean = area(&sum) as f64 / count;
+      let var = (area(&sum_sq) as f64 / count - mean * mean).max(0.);
+      let gain = if var > noise_var { (var - noise_var) / var } else { 0. };
+
+      let v = f64::from(u16::cast_from(src.p(x, y)));
+      let filtered = mean + gain * (v - mean);
+      *dst = T::cast_from(filtered.round().max(0.).min(max_value) as u16);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn denoise_flat_plane_is_unchanged() {
+    let mut frame = Frame::<u8>::new(16, 16, ChromaSampling::Cs420);
+    for plane in frame.planes.iter_mut() {
+      for p in plane.data.iter_mut() {
+        *p = 128;
+      }
+    }
+    let out = denoise_frame(&frame, 16, 16, ChromaSampling::Cs420, 20, 8);
+    for (src, dst) in frame.planes.iter().zip(out.planes.iter()) {
+      assert_eq!(&src.data[..], &dst.data[..]);
+    }
+  }
+
+  #[test]
+  fn denoise_reduces_variance() {
+    let mut frame = Frame::<u8>::new(32, 32, ChromaSampling::Cs400);
+    for y in 0..32 {
+      for x in 0..32 {
+        let off = frame.planes[0].cfg.yorigin + y;
+        let off =
+          off * frame.planes[0].cfg.stride + frame.planes[0].cfg.xorigin + x;
+        frame.planes[0].data[off] = if (x ^ y) & 1 == 0 { 124 } else { 132 };
+      }
+    }
+    let out = denoise_frame(&frame, 32, 32, ChromaSampling::Cs400, 20, 8);
+    let variance = |plane: &Plane<u8>| {
+      let n = 32. * 32.;
+      let mut s = 0.;
+      let mut s2 = 0.;
+      for y in 0..32 {
+        for x in 0..32 {
+          let v = f64::from(plane.p(x, y));
+          s += v;
+          s2 += v * v;
+        }
+      }
+      s2 / n - (s / n) * (s / n)
+    };
+    assert!(variance(&out.planes[0]) < variance(&frame.planes[0]) / 4.);
+  }
+}
@@ -430,6 +430,15 @@ impl WriterBase<WriterRecorder> {
     self.s.storage.truncate(0);
     self.s.bytes = 0;
   }
+
+  /// Splits the Recorder at a checkpoint taken from it, returning a new
+  /// Recorder holding the tokens recorded after the checkpoint.  The
+  /// returned Recorder is only meant to be replayed.
+  pub fn split_off(&mut self, checkpoint: &WriterCheckpoint) -> Self {
+    let mut tail = WriterRecorder::new();
+    tail.s.storage = self.s.storage.split_off(checkpoint.backend_var);
+    tail
+  }
 }
 
 /// Done implementation specific to the Encoder
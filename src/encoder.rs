@@ -13,28 +13,36 @@ use crate::cdef::*;
 use crate::context::*;
 use crate::deblock::*;
 use crate::ec::*;
+use crate::film_grain::FilmGrainParams;
 use crate::frame::*;
 use crate::header::*;
+use crate::intrabc::*;
 use crate::lrf::*;
 use crate::mc::{FilterMode, MotionVector};
 use crate::me::*;
+use crate::palette::*;
 use crate::partition::PartitionType::*;
 use crate::partition::RefType::*;
 use crate::partition::*;
 use crate::predict::{
-  AngleDelta, IntraEdgeFilterParameters, IntraParam, PredictionMode,
+  AngleDelta, FilterIntraMode, IntraEdgeFilterParameters, IntraParam,
+  MotionMode, PredictionMode,
 };
+use crate::qm_tables::{NUM_QM_LEVELS, QM_BITS};
 use crate::quantize::*;
 use crate::rate::{
-  bexp64, q57, QuantizerParameters, FRAME_SUBTYPE_I, FRAME_SUBTYPE_P, QSCALE,
+  bexp64, decoder_buffer_delay, decoder_model_level, q57, QuantizerParameters,
+  FRAME_SUBTYPE_B1, FRAME_SUBTYPE_I, FRAME_SUBTYPE_P, QSCALE,
 };
 use crate::rdo::*;
+use crate::scale::*;
 use crate::segmentation::*;
 use crate::serialize::{Deserialize, Serialize};
 use crate::stats::EncoderStats;
 use crate::tiling::*;
 use crate::transform::*;
 use crate::util::*;
+use crate::warp::*;
 use crate::wasm_bindgen::*;
 
 use arg_enum_proc_macro::ArgEnum;
@@ -77,13 +85,19 @@ pub const IMPORTANCE_BLOCK_SIZE: usize =
 #[derive(Debug, Clone)]
 pub struct ReferenceFrame<T: Pixel> {
   pub order_hint: u32,
+  pub base_q_idx: u8,
   pub frame: Arc<Frame<T>>,
+  /// Size of the frame, after super-resolution upscaling.
+  pub width: usize,
+  pub height: usize,
   pub input_hres: Arc<Plane<T>>,
   pub input_qres: Arc<Plane<T>>,
   pub cdfs: CDFContext,
   pub frame_mvs: Arc<Vec<FrameMotionVectors>>,
   pub output_frameno: u64,
   pub segmentation: SegmentationState,
+  pub segment_map: Arc<SegmentMap>,
+  pub gm_params: [WarpedMotionParams; INTER_REFS_PER_FRAME],
 }
 
 #[derive(Debug, Clone, Default)]
@@ -104,6 +118,7 @@ impl<T: Pixel> ReferenceFramesSet<T> {
 pub enum Tune {
   Psnr,
   Psychovisual,
+  ScreenContent,
 }
 
 impl Default for Tune {
@@ -112,6 +127,28 @@ impl Default for Tune {
   }
 }
 
+/// How the super-resolution denominator of each frame is chosen.
+#[wasm_bindgen]
+#[derive(ArgEnum, Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[repr(C)]
+pub enum SuperresMode {
+  /// Frames are coded at their full width.
+  None,
+  /// Frames are coded with the configured denominator.
+  Fixed,
+  /// Frames are coded with a pseudo-random denominator, if any.
+  Random,
+  /// Frames coded above the configured quantizer threshold are coded with a
+  /// denominator growing with their quantizer.
+  Qthresh,
+}
+
+impl Default for SuperresMode {
+  fn default() -> Self {
+    SuperresMode::None
+  }
+}
+
 const FRAME_ID_LENGTH: u32 = 15;
 const DELTA_FRAME_ID_LENGTH: u32 = 14;
 
@@ -172,6 +209,8 @@ pub struct Sequence {
   pub operating_point_idc: [u16; MAX_NUM_OPERATING_POINTS],
   pub display_model_info_present_flag: bool,
   pub decoder_model_info_present_flag: bool,
+  pub decoder_buffer_delay: u64, // in units of 1/90000 s
+  pub encoder_buffer_delay: u64, // in units of 1/90000 s
   pub level: [[usize; 2]; MAX_NUM_OPERATING_POINTS], // minor, major
   pub tier: [usize; MAX_NUM_OPERATING_POINTS], // seq_tier in the spec. One bit: 0
   // or 1.
@@ -196,16 +235,33 @@ impl Sequence {
       0
     };
 
+    // The decoder model cannot be signaled in the reduced still picture
+    // header.
+    let decoder_model_info_present_flag =
+      config.max_bitrate > 0 && !config.still_picture;
+
+    // Without the decoder model, the level is 7.3, which places no limits.
+    let seq_level_idx = if decoder_model_info_present_flag {
+      decoder_model_level(config).unwrap()
+    } else {
+      31
+    };
+
     let mut operating_point_idc = [0 as u16; MAX_NUM_OPERATING_POINTS];
     let mut level = [[1, 2 as usize]; MAX_NUM_OPERATING_POINTS];
     let mut tier = [0 as usize; MAX_NUM_OPERATING_POINTS];
 
     for i in 0..MAX_NUM_OPERATING_POINTS {
       operating_point_idc[i] = 0;
-      level[i][0] = 1; // minor
-      level[i][1] = 2; // major
+      level[i][0] = seq_level_idx & 3; // minor
+      level[i][1] = 2 + (seq_level_idx >> 2); // major
       tier[i] = 0;
     }
+    let decoder_buffer_delay = if decoder_model_info_present_flag {
+      decoder_buffer_delay(config.max_bitrate, config.buffer_size)
+    } else {
+      0
+    };
 
     // Restoration filters are not useful for very small frame sizes,
     // so disable them in that case.
@@ -227,22 +283,26 @@ impl Sequence {
       frame_id_numbers_present_flag: false,
       frame_id_length: FRAME_ID_LENGTH,
       delta_frame_id_length: DELTA_FRAME_ID_LENGTH,
-      use_128x128_superblock: false,
+      use_128x128_superblock: config.superblock_128,
       order_hint_bits_minus_1: 5,
-      force_screen_content_tools: if config.still_picture { 2 } else { 0 },
+      // Screen content tools are selected for each frame.
+      force_screen_content_tools: 2,
       force_integer_mv: 2,
       still_picture: config.still_picture,
       reduced_still_picture_hdr: config.still_picture,
-      enable_filter_intra: false,
+      enable_filter_intra: config.speed_settings.filter_intra,
       enable_intra_edge_filter: true,
       enable_interintra_compound: false,
       enable_masked_compound: false,
-      enable_dual_filter: false,
+      enable_dual_filter: config.speed_settings.interp_filter_search
+        == InterpFilterSearch::Dual
+        && !config.still_picture,
       enable_order_hint: !config.still_picture,
       enable_jnt_comp: false,
       enable_ref_frame_mvs: false,
-      enable_warped_motion: false,
-      enable_superres: false,
+      enable_warped_motion: config.speed_settings.warped_motion
+        && !config.still_picture,
+      enable_superres: config.superres_mode != SuperresMode::None,
       enable_cdef: config.speed_settings.cdef && enable_restoration_filters,
       enable_restoration: config.speed_settings.lrf
         && enable_restoration_filters,
@@ -251,14 +311,30 @@ impl Sequence {
       operating_points_cnt_minus_1: 0,
       operating_point_idc,
       display_model_info_present_flag: false,
-      decoder_model_info_present_flag: false,
+      decoder_model_info_present_flag,
+      decoder_buffer_delay,
+      // The whole buffer is filled between the arrival of the first bit of a
+      // frame and its removal.
+      encoder_buffer_delay: if decoder_model_info_present_flag {
+        90_000 * config.buffer_size as u64 / config.max_bitrate as u64
+          - decoder_buffer_delay
+      } else {
+        0
+      },
       level,
       tier,
-      film_grain_params_present: false,
-      timing_info_present: config.enable_timing_info,
+      film_grain_params_present: config.film_grain > 0,
+      // The decoder model can only be signaled along with timing info.
+      timing_info_present: config.enable_timing_info
+        || decoder_model_info_present_flag,
     }
   }
 
+  /// The seq_level_idx of an operating point.
+  pub const fn seq_level_idx(&self, op: usize) -> usize {
+    ((self.level[op][1] - 2) << 2) + self.level[op][0]
+  }
+
   pub const fn get_relative_dist(&self, a: u32, b: u32) -> i32 {
     let diff = a as i32 - b as i32;
     let m = 1 << self.order_hint_bits_minus_1;
@@ -354,11 +430,15 @@ pub struct FrameState<T: Pixel> {
   pub max_tile_size_bytes: u32,
   pub deblock: DeblockState,
   pub segmentation: SegmentationState,
+  /// Segment ids of the blocks of the frame, once coded with segmentation.
+  pub segment_map: Arc<SegmentMap>,
+  pub segment_classes: SegmentClasses,
   pub restoration: RestorationState,
   // Because we only reference these within a tile context,
   // these are stored per-tile for easier access.
   pub half_res_pmvs: Vec<(PlaneSuperBlockOffset, Vec<BlockPmv>)>,
   pub frame_mvs: Arc<Vec<FrameMotionVectors>>,
+  pub block_hashes: Option<BlockHashes>, // only built for intra block copy
   pub enc_stats: EncoderStats,
 }
 
@@ -396,6 +476,8 @@ impl<T: Pixel> FrameState<T> {
       max_tile_size_bytes: 0,
       deblock: Default::default(),
       segmentation: Default::default(),
+      segment_map: Default::default(),
+      segment_classes: Default::default(),
       restoration: rs,
       half_res_pmvs: Vec::with_capacity(fi.tiling.cols * fi.tiling.rows),
       frame_mvs: {
@@ -405,6 +487,7 @@ impl<T: Pixel> FrameState<T> {
         }
         Arc::new(vec)
       },
+      block_hashes: None,
       enc_stats: Default::default(),
     }
   }
@@ -428,6 +511,8 @@ pub struct DeblockState {
   pub block_deltas_enabled: bool,
   pub block_delta_shift: u8,
   pub block_delta_multi: bool,
+  /// Loop filter level deltas of the segments, from their ALT_LF features.
+  pub segment_deltas: [[i8; FRAME_LF_COUNT]; 8],
 }
 
 impl Default for DeblockState {
@@ -442,6 +527,7 @@ impl Default for DeblockState {
       block_deltas_enabled: false,
       block_delta_shift: 0,
       block_delta_multi: false,
+      segment_deltas: [[0; FRAME_LF_COUNT]; 8],
     }
   }
 }
@@ -451,6 +537,9 @@ pub struct SegmentationState {
   pub enabled: bool,
   pub update_data: bool,
   pub update_map: bool,
+  /// Code the segment ids as predicted from the segment map of the
+  /// primary reference frame, when possible.
+  pub temporal_update: bool,
   pub preskip: bool,
   pub last_active_segid: u8,
   pub features: [[bool; SegLvl::SEG_LVL_MAX as usize]; 8],
@@ -463,6 +552,7 @@ impl Default for SegmentationState {
       enabled: false,
       update_data: false,
       update_map: false,
+      temporal_update: false,
       preskip: false,
       last_active_segid: 0,
       features: [[false; SegLvl::SEG_LVL_MAX as usize]; 8],
@@ -478,6 +568,11 @@ pub struct FrameInvariants<T: Pixel> {
   pub sequence: Sequence,
   pub width: usize,
   pub height: usize,
+  /// Width of the frame after super-resolution upscaling.
+  pub upscaled_width: usize,
+  /// Super-resolution denominator, `SUPERRES_NUM` when the frame is coded
+  /// at its full width.
+  pub superres_denom: u8,
   pub sb_width: usize,
   pub sb_height: usize,
   pub w_in_b: usize,
@@ -497,7 +592,7 @@ pub struct FrameInvariants<T: Pixel> {
   pub reference_mode: ReferenceMode,
   pub use_prev_frame_mvs: bool,
   pub partition_range: PartitionRange,
-  pub globalmv_transformation_type: [GlobalMVMode; INTER_REFS_PER_FRAME],
+  pub gm_params: [WarpedMotionParams; INTER_REFS_PER_FRAME],
   pub num_tg: usize,
   pub large_scale_tile: bool,
   pub disable_cdf_update: bool,
@@ -519,24 +614,47 @@ pub struct FrameInvariants<T: Pixel> {
   pub cdef_y_strengths: [u8; 8],
   pub cdef_uv_strengths: [u8; 8],
   pub delta_q_present: bool,
+  /// Log2 of the step between the quantizer indices of the superblocks
+  /// with delta-q.
+  pub delta_q_res: u8,
+  /// Offsets to `base_q_idx` of each 64x64 block of the frame, in raster
+  /// order and in multiples of the delta-q step. Empty without delta-q.
+  pub delta_q_offsets: Box<[i16]>,
+  /// Offsets to `base_q_idx` of the segments adapting the quantizer of the
+  /// frame, in ascending order.
+  pub segment_q_levels: Box<[i16]>,
+  /// Index in `segment_q_levels` of the level of each 64x64 block of the
+  /// frame, in raster order. Empty when all blocks have the middle level.
+  pub segment_q_classes: Box<[u8]>,
   pub config: EncoderConfig,
   pub ref_frames: [u8; INTER_REFS_PER_FRAME],
   pub ref_frame_sign_bias: [bool; INTER_REFS_PER_FRAME],
   pub rec_buffer: ReferenceFramesSet<T>,
+  /// The references of `rec_buffer` resampled to the coded size of the
+  /// frame, for motion estimation, when it is coded at a reduced size.
+  pub scaled_rec_buffer: Option<ReferenceFramesSet<T>>,
   pub base_q_idx: u8,
   pub dc_delta_q: [i8; 3],
   pub ac_delta_q: [i8; 3],
+  /// Quantization matrix level of each plane, the last level being flat.
+  pub qm_levels: [u8; 3],
+  /// All the blocks of the frame are coded losslessly, with the 4x4
+  /// Walsh-Hadamard transform and no loop filtering.
+  pub lossless: bool,
   pub lambda: f64,
   pub me_lambda: f64,
   pub dist_scale: [f64; 3],
   pub me_range_scale: u8,
   pub use_tx_domain_distortion: bool,
   pub use_tx_domain_rate: bool,
+  pub(crate) group: ReorderGroup,
   pub idx_in_group_output: u64,
   pub pyramid_level: u64,
   pub enable_early_exit: bool,
   pub tx_mode_select: bool,
   pub enable_inter_txfm_split: bool,
+  /// Interpolation filter of the frame, also used by the blocks for which no
+  /// filter is coded when the filter is switchable.
   pub default_filter: FilterMode,
   /// If true, this `FrameInvariants` corresponds to an invalid frame and
   /// should be ignored. Invalid frames occur when a subgop is prematurely
@@ -568,6 +686,8 @@ pub struct FrameInvariants<T: Pixel> {
   pub cpu_feature_level: crate::cpu_features::CpuFeatureLevel,
   pub activity_mask: ActivityMask,
   pub enable_segmentation: bool,
+  /// Film grain synthesis parameters signaled for this frame, if any.
+  pub film_grain_params: Option<FilmGrainParams>,
 }
 
 pub(crate) const fn pos_to_lvl(pos: u64, pyramid_depth: u64) -> u64 {
@@ -582,25 +702,16 @@ pub(crate) const fn pos_to_lvl(pos: u64, pyramid_depth: u64) -> u64 {
 }
 
 impl<T: Pixel> FrameInvariants<T> {
-  #[allow(clippy::erasing_op, clippy::identity_op)]
-  pub fn new(config: EncoderConfig, sequence: Sequence) -> Self {
-    assert!(
-      sequence.bit_depth <= mem::size_of::<T>() * 8,
-      "bit depth cannot fit into u8"
-    );
-    let use_reduced_tx_set = config.speed_settings.reduced_tx_set;
-    let use_tx_domain_distortion =
-      config.tune == Tune::Psnr && config.speed_settings.tx_domain_distortion;
-    let use_tx_domain_rate = config.speed_settings.tx_domain_rate;
-
-    let w_in_b = 2 * config.width.align_power_of_two_and_shift(3); // MiCols, ((width+7)/8)<<3 >> MI_SIZE_LOG2
-    let h_in_b = 2 * config.height.align_power_of_two_and_shift(3); // MiRows, ((height+7)/8)<<3 >> MI_SIZE_LOG2
+  /// Chooses the tiling of a frame coded at `width`x`height` from the
+  /// configured tile counts.
+  fn tiling_info(
+    config: &EncoderConfig, sequence: &Sequence, width: usize, height: usize,
+  ) -> TilingInfo {
     let frame_rate = config.frame_rate();
-
     let mut tiling = TilingInfo::from_target_tiles(
       sequence.sb_size_log2(),
-      config.width,
-      config.height,
+      width,
+      height,
       frame_rate,
       TilingInfo::tile_log2(1, config.tile_cols).unwrap(),
       TilingInfo::tile_log2(1, config.tile_rows).unwrap(),
@@ -615,8 +726,8 @@ impl<T: Pixel> FrameInvariants<T> {
       {
         tiling = TilingInfo::from_target_tiles(
           sequence.sb_size_log2(),
-          config.width,
-          config.height,
+          width,
+          height,
           frame_rate,
           tile_cols_log2,
           tile_rows_log2,
@@ -638,6 +749,25 @@ impl<T: Pixel> FrameInvariants<T> {
       }
     }
 
+    tiling
+  }
+
+  #[allow(clippy::erasing_op, clippy::identity_op)]
+  pub fn new(config: EncoderConfig, sequence: Sequence) -> Self {
+    assert!(
+      sequence.bit_depth <= mem::size_of::<T>() * 8,
+      "bit depth cannot fit into u8"
+    );
+    let use_reduced_tx_set = config.speed_settings.reduced_tx_set;
+    let use_tx_domain_distortion = config.tune != Tune::Psychovisual
+      && config.speed_settings.tx_domain_distortion;
+    let use_tx_domain_rate = config.speed_settings.tx_domain_rate;
+
+    let w_in_b = 2 * config.width.align_power_of_two_and_shift(3); // MiCols, ((width+7)/8)<<3 >> MI_SIZE_LOG2
+    let h_in_b = 2 * config.height.align_power_of_two_and_shift(3); // MiRows, ((height+7)/8)<<3 >> MI_SIZE_LOG2
+    let tiling =
+      Self::tiling_info(&config, &sequence, config.width, config.height);
+
     // Width and height are padded to 8×8 block size.
     let w_in_imp_b = w_in_b / 2;
     let h_in_imp_b = h_in_b / 2;
@@ -646,6 +776,8 @@ impl<T: Pixel> FrameInvariants<T> {
       sequence,
       width: config.width,
       height: config.height,
+      upscaled_width: config.width,
+      superres_denom: SUPERRES_NUM,
       sb_width: config.width.align_power_of_two_and_shift(6),
       sb_height: config.height.align_power_of_two_and_shift(6),
       w_in_b,
@@ -664,13 +796,23 @@ impl<T: Pixel> FrameInvariants<T> {
       use_reduced_tx_set,
       reference_mode: ReferenceMode::SINGLE,
       use_prev_frame_mvs: false,
-      partition_range: config.speed_settings.partition_range,
-      globalmv_transformation_type: [GlobalMVMode::IDENTITY;
-        INTER_REFS_PER_FRAME],
+      // 128x128 blocks are only used when the speed settings allow all the
+      // block sizes of a 64x64 superblock.
+      partition_range: if sequence.use_128x128_superblock
+        && config.speed_settings.partition_range.max == BlockSize::BLOCK_64X64
+      {
+        PartitionRange::new(
+          config.speed_settings.partition_range.min,
+          BlockSize::BLOCK_128X128,
+        )
+      } else {
+        config.speed_settings.partition_range
+      },
+      gm_params: [WarpedMotionParams::default(); INTER_REFS_PER_FRAME],
       num_tg: 1,
       large_scale_tile: false,
       disable_cdf_update: false,
-      allow_screen_content_tools: sequence.force_screen_content_tools,
+      allow_screen_content_tools: 0,
       force_integer_mv: 1,
       primary_ref_frame: PRIMARY_REF_NONE,
       refresh_frame_flags: ALL_REF_FRAMES_MASK,
@@ -704,18 +846,26 @@ impl<T: Pixel> FrameInvariants<T> {
         13 * 4 + 3,
       ],
       delta_q_present: false,
+      delta_q_res: 2,
+      delta_q_offsets: Box::new([]),
+      segment_q_levels: segment_q_levels(&[], config.temporal_rdo()).0,
+      segment_q_classes: Box::new([]),
       ref_frames: [0; INTER_REFS_PER_FRAME],
       ref_frame_sign_bias: [false; INTER_REFS_PER_FRAME],
       rec_buffer: ReferenceFramesSet::new(),
+      scaled_rec_buffer: None,
       base_q_idx: config.quantizer as u8,
       dc_delta_q: [0; 3],
       ac_delta_q: [0; 3],
+      qm_levels: [NUM_QM_LEVELS as u8 - 1; 3],
+      lossless: false,
       lambda: 0.0,
       dist_scale: [1.0; 3],
       me_lambda: 0.0,
       me_range_scale: 1,
       use_tx_domain_distortion,
       use_tx_domain_rate,
+      group: ReorderGroup::default(),
       idx_in_group_output: 0,
       pyramid_level: 0,
       enable_early_exit: true,
@@ -746,6 +896,7 @@ impl<T: Pixel> FrameInvariants<T> {
       activity_mask: Default::default(),
       enable_segmentation: config.speed_settings.enable_segmentation,
       enable_inter_txfm_split: config.speed_settings.enable_inter_tx_split,
+      film_grain_params: None,
     }
   }
 
@@ -763,20 +914,20 @@ impl<T: Pixel> FrameInvariants<T> {
   /// FrameInvariants regardless of success or failure.
   pub(crate) fn new_inter_frame(
     previous_fi: &Self, inter_cfg: &InterConfig, gop_input_frameno_start: u64,
-    output_frameno_in_gop: u64, next_keyframe_input_frameno: u64,
-    error_resilient: bool,
+    group: ReorderGroup, idx_in_group_output: u64,
+    next_keyframe_input_frameno: u64, error_resilient: bool,
   ) -> Self {
     let mut fi = previous_fi.clone();
     fi.intra_only = false;
     fi.force_integer_mv = 0; // note: should be 1 if fi.intra_only is true
-    fi.idx_in_group_output =
-      inter_cfg.get_idx_in_group_output(output_frameno_in_gop);
+    fi.allow_intrabc = false;
+    fi.gm_params = [WarpedMotionParams::default(); INTER_REFS_PER_FRAME];
+    fi.group = group;
+    fi.idx_in_group_output = idx_in_group_output;
     fi.tx_mode_select = fi.enable_inter_txfm_split;
 
-    fi.order_hint =
-      inter_cfg.get_order_hint(output_frameno_in_gop, fi.idx_in_group_output);
-    let input_frameno = inter_cfg
-      .get_input_frameno(output_frameno_in_gop, gop_input_frameno_start);
+    fi.order_hint = inter_cfg.get_order_hint(&group, idx_in_group_output);
+    let input_frameno = gop_input_frameno_start + fi.order_hint as u64;
     if input_frameno >= next_keyframe_input_frameno {
       fi.frame_type = FrameType::INTER;
       fi.show_existing_frame = false;
@@ -787,10 +938,12 @@ impl<T: Pixel> FrameInvariants<T> {
       fi.invalid = false;
     }
 
-    fi.pyramid_level = inter_cfg.get_level(fi.idx_in_group_output);
+    fi.pyramid_level = inter_cfg.get_level(&group, idx_in_group_output);
 
+    // S-frames are only used without re-ordering, so the order-hint matches
+    //  the output frame number in the gop.
     fi.frame_type = if (inter_cfg.switch_frame_interval > 0)
-      && (output_frameno_in_gop % inter_cfg.switch_frame_interval == 0)
+      && (fi.order_hint as u64 % inter_cfg.switch_frame_interval == 0)
       && (fi.pyramid_level == 0)
     {
       FrameType::SWITCH
@@ -799,12 +952,17 @@ impl<T: Pixel> FrameInvariants<T> {
     };
     fi.error_resilient =
       if fi.frame_type == FrameType::SWITCH { true } else { error_resilient };
+    fi.allow_warped_motion =
+      fi.sequence.enable_warped_motion && !fi.error_resilient;
+    fi.is_motion_mode_switchable = fi.allow_warped_motion;
+    fi.is_filter_switchable = fi.config.speed_settings.interp_filter_search
+      != InterpFilterSearch::Regular;
 
     // this is the slot that the current frame is going to be saved into
-    let slot_idx = inter_cfg.get_slot_idx(fi.pyramid_level, fi.order_hint);
-    fi.show_frame = inter_cfg.get_show_frame(fi.idx_in_group_output);
+    let slot_idx = inter_cfg.get_slot_idx(&group, fi.order_hint);
+    fi.show_frame = inter_cfg.get_show_frame(&group, idx_in_group_output);
     fi.show_existing_frame =
-      inter_cfg.get_show_existing_frame(fi.idx_in_group_output);
+      inter_cfg.get_show_existing_frame(&group, idx_in_group_output);
     fi.frame_to_show_map_idx = slot_idx;
     fi.refresh_frame_flags = if fi.frame_type == FrameType::SWITCH {
       ALL_REF_FRAMES_MASK
@@ -846,27 +1004,16 @@ impl<T: Pixel> FrameInvariants<T> {
 
       // fill in defaults
       // default to backwards reference in lower level
-      fi.ref_frames = [{
-        let oh = fi.order_hint
-          - (inter_cfg.group_input_len as u32 >> fi.pyramid_level);
-        let lvl1 = pos_to_lvl(oh as u64, inter_cfg.pyramid_depth);
-        if lvl1 == 0 {
-          ((oh >> inter_cfg.pyramid_depth) % 4) as u8
-        } else {
-          3 + lvl1 as u8
-        }
-      }; INTER_REFS_PER_FRAME];
+      let group_input_len = 1u32 << group.pyramid_depth;
+      fi.ref_frames = [inter_cfg.get_slot_idx(
+        &group,
+        fi.order_hint - (group_input_len >> fi.pyramid_level),
+      ) as u8; INTER_REFS_PER_FRAME];
       // use forward reference in lower level as a second reference frame
-      fi.ref_frames[second_ref_frame.to_index()] = {
-        let oh = fi.order_hint
-          + (inter_cfg.group_input_len as u32 >> fi.pyramid_level);
-        let lvl2 = pos_to_lvl(oh as u64, inter_cfg.pyramid_depth);
-        if lvl2 == 0 {
-          ((oh >> inter_cfg.pyramid_depth) % 4) as u8
-        } else {
-          3 + lvl2 as u8
-        }
-      };
+      fi.ref_frames[second_ref_frame.to_index()] = inter_cfg.get_slot_idx(
+        &group,
+        fi.order_hint + (group_input_len >> fi.pyramid_level),
+      ) as u8;
       // use a reference to the previous frame in the same level
       // (horizontally) as a third reference
       fi.ref_frames[ref_in_previous_group.to_index()] = slot_idx as u8;
@@ -880,7 +1027,8 @@ impl<T: Pixel> FrameInvariants<T> {
       ReferenceMode::SINGLE
     };
     fi.input_frameno = input_frameno;
-    fi.me_range_scale = (inter_cfg.group_input_len >> fi.pyramid_level) as u8;
+    fi.me_range_scale =
+      ((1u64 << group.pyramid_depth) >> fi.pyramid_level) as u8;
     fi
   }
 
@@ -903,7 +1051,8 @@ impl<T: Pixel> FrameInvariants<T> {
     if self.frame_type == FrameType::KEY {
       FRAME_SUBTYPE_I
     } else {
-      FRAME_SUBTYPE_P + (self.pyramid_level as usize)
+      // Deeper pyramid levels share the statistics of the last B-frame subtype.
+      (FRAME_SUBTYPE_P + (self.pyramid_level as usize)).min(FRAME_SUBTYPE_B1)
     }
   }
 
@@ -938,6 +1087,19 @@ impl<T: Pixel> FrameInvariants<T> {
       self.dc_delta_q[pi] = (qps.dc_qi[pi] as i32 - base_q_idx) as i8;
       self.ac_delta_q[pi] = (qps.ac_qi[pi] as i32 - base_q_idx) as i8;
     }
+    self.lossless = self.base_q_idx == 0
+      && self.dc_delta_q.iter().all(|&d| d == 0)
+      && self.ac_delta_q.iter().all(|&d| d == 0);
+    for pi in 0..3 {
+      self.qm_levels[pi] = if self.config.quant_matrices && !self.lossless {
+        select_qm_level(
+          (base_q_idx + self.ac_delta_q[pi] as i32).max(0).min(255) as u8,
+        )
+      } else {
+        NUM_QM_LEVELS as u8 - 1
+      };
+    }
+    self.update_delta_q();
     self.lambda =
       qps.lambda * ((1 << (2 * (self.sequence.bit_depth - 8))) as f64);
     self.me_lambda = self.lambda.sqrt();
@@ -952,6 +1114,345 @@ impl<T: Pixel> FrameInvariants<T> {
     }
   }
 
+  /// Codes the frame at its width divided by `denom / SUPERRES_NUM`, for
+  /// the decoder to upscale it back to `upscaled_width`.
+  pub fn set_superres_denom(&mut self, denom: u8) {
+    self.superres_denom = denom;
+    self.width = superres_coded_width(self.upscaled_width, denom);
+    self.w_in_b = 2 * self.width.align_power_of_two_and_shift(3);
+    self.sb_width = self.width.align_power_of_two_and_shift(6);
+    self.tiling =
+      Self::tiling_info(&self.config, &self.sequence, self.width, self.height);
+    self.scaled_rec_buffer = None;
+  }
+
+  fn update_delta_q(&mut self) {
+    self.delta_q_present =
+      self.base_q_idx > 0 && self.delta_q_offsets.iter().any(|&o| o != 0);
+    // Segments with their own quantizer would not be coded losslessly, and
+    // the quantizer of the superblocks is already adapted with delta-q.
+    self.enable_segmentation = self.config.speed_settings.enable_segmentation
+      && !self.lossless
+      && !self.delta_q_present;
+  }
+
+  /// Sets the quantizer offsets of the 64x64 blocks of the frame, adapted
+  /// to their activity and temporal importance with
+  /// `EncoderConfig::delta_q` or segmentation, plus the ones supplied with
+  /// the frame. Without delta-q, they are coded with the segments.
+  pub fn set_delta_q_offsets(&mut self, qp_offsets: Option<&[i16]>) {
+    let (cols, rows) = (self.sb_width, self.sb_height);
+    let use_segments = !self.config.delta_q
+      && self.config.speed_settings.enable_segmentation
+      && !self.lossless;
+    let mut offsets = if self.config.delta_q || use_segments {
+      self.adaptive_delta_q_offsets()
+    } else {
+      vec![0; cols * rows]
+    };
+
+    if let Some(qp_offsets) = qp_offsets {
+      // The supplied offsets cover the configured size, of which the frame
+      // codes the top-left area, possibly at a reduced width.
+      let stride = self.config.width.align_power_of_two_and_shift(6);
+      for (y, row) in offsets.chunks_exact_mut(cols).enumerate() {
+        for (x, offset) in row.iter_mut().enumerate() {
+          let x = (x * self.upscaled_width / self.width).min(stride - 1);
+          *offset += qp_offsets[y * stride + x];
+        }
+      }
+    }
+
+    if self.sequence.use_128x128_superblock {
+      // Each 128x128 superblock codes a single quantizer.
+      for y in (0..rows).step_by(2) {
+        for x in (0..cols).step_by(2) {
+          let cells: ArrayVec<[usize; 4]> = [(x, y), (x + 1, y), (x, y + 1)]
+            .iter()
+            .chain([(x + 1, y + 1)].iter())
+            .filter(|&&(x, y)| x < cols && y < rows)
+            .map(|&(x, y)| y * cols + x)
+            .collect();
+          let sum: i32 = cells.iter().map(|&i| offsets[i] as i32).sum();
+          let mean = (sum as f32 / cells.len() as f32).round() as i16;
+          for &i in cells.iter() {
+            offsets[i] = mean;
+          }
+        }
+      }
+    }
+
+    if use_segments {
+      let (levels, classes) =
+        segment_q_levels(&offsets, self.config.temporal_rdo());
+      self.segment_q_levels = levels;
+      self.segment_q_classes = classes;
+      self.delta_q_offsets = Box::new([]);
+      self.update_delta_q();
+      return;
+    }
+
+    let step = 1 << self.delta_q_res;
+    for offset in offsets.iter_mut() {
+      let offset_steps = (*offset as f32 / step as f32).round() as i16;
+      *offset = clamp(offset_steps * step, -255, 255);
+    }
+
+    self.delta_q_offsets = if offsets.iter().any(|&o| o != 0) {
+      offsets.into_boxed_slice()
+    } else {
+      Box::new([])
+    };
+    self.update_delta_q();
+  }
+
+  /// Scales the quantizer of each 64x64 block by its spatial activity
+  /// relative to the frame, as the distortion of busy areas is masked, and
+  /// by the inverse square root of its temporal importance, unless the
+  /// importance already scales the distortion with temporal RDO.
+  fn adaptive_delta_q_offsets(&self) -> Vec<i16> {
+    let (cols, rows) = (self.sb_width, self.sb_height);
+    let bit_depth = self.sequence.bit_depth;
+    let activities: Vec<Option<f64>> = (0..rows)
+      .flat_map(|y| (0..cols).map(move |x| (x << 6, y << 6)))
+      .map(|(x, y)| {
+        let rect = Rect {
+          x: x as isize,
+          y: y as isize,
+          width: (self.width - x).min(64) & !7,
+          height: (self.height - y).min(64) & !7,
+        };
+        self.activity_mask.mean_activity_of(rect).map(|a| a.max(1.0).ln())
+      })
+      .collect();
+    let count = activities.iter().flatten().count().max(1);
+    let mean_activity = activities.iter().flatten().sum::<f64>() / count as f64;
+
+    let base_q = ac_q(self.base_q_idx, 0, bit_depth) as f64;
+    let base_q_idx = self.base_q_idx as i16;
+    activities
+      .iter()
+      .enumerate()
+      .map(|(i, activity)| {
+        let mut scale =
+          activity.map_or(1.0, |activity| (activity - mean_activity).exp());
+        if !self.config.temporal_rdo() {
+          scale /= self.mean_importance_scale(i % cols, i / cols).sqrt();
+        }
+        let q = base_q * scale.max(0.5).min(2.0);
+        select_ac_qi(q as i64, bit_depth).max(1) as i16 - base_q_idx
+      })
+      .collect()
+  }
+
+  /// Geometric mean of the temporal importance distortion scales of the
+  /// importance blocks of a 64x64 block.
+  fn mean_importance_scale(&self, x: usize, y: usize) -> f64 {
+    let mut sum = 0.;
+    let mut count = 0;
+    for by in ((y << 4)..((y + 1) << 4).min(self.h_in_b)).step_by(2) {
+      for bx in ((x << 4)..((x + 1) << 4).min(self.w_in_b)).step_by(2) {
+        let bo = PlaneBlockOffset(BlockOffset { x: bx, y: by });
+        sum += f64::from(importance_scale(self, bo)).ln();
+        count += 1;
+      }
+    }
+    if count == 0 {
+      1.
+    } else {
+      (sum / count as f64).exp()
+    }
+  }
+
+  /// Quantizer index of the superblock containing a block, with delta-q.
+  pub fn sb_qidx(&self, frame_bo: PlaneBlockOffset) -> u8 {
+    let (x, y) = (frame_bo.0.x >> 4, frame_bo.0.y >> 4);
+    let offset = self.delta_q_offsets[y * self.sb_width + x] as i32;
+    // Stay on the grid of the delta-q steps from base_q_idx, which also
+    // keeps the superblocks from being coded losslessly.
+    let step = 1 << self.delta_q_res;
+    let base_q_idx = self.base_q_idx as i32;
+    let min = base_q_idx - (base_q_idx - 1) / step * step;
+    let max = base_q_idx + (255 - base_q_idx) / step * step;
+    clamp(base_q_idx + offset, min, max) as u8
+  }
+
+  /// Index in `segment_q_levels` of the quantizer level of the 64x64 block
+  /// containing a block.
+  pub fn segment_q_level(&self, frame_bo: PlaneBlockOffset) -> usize {
+    let (x, y) = (frame_bo.0.x >> 4, frame_bo.0.y >> 4);
+    match self.segment_q_classes.get(y * self.sb_width + x) {
+      Some(&class) => class as usize,
+      None => self.segment_q_levels.len() / 2,
+    }
+  }
+
+  /// Loop filter level delta of the superblock containing a block, matching
+  /// its quantizer delta.
+  pub fn sb_deblock_delta(&self, frame_bo: PlaneBlockOffset) -> i8 {
+    let offset = self.sb_qidx(frame_bo) as i32 - self.base_q_idx as i32;
+    clamp(offset / 4, -(MAX_LOOP_FILTER as i32), MAX_LOOP_FILTER as i32) as i8
+  }
+
+  /// Codes the frame at `width`x`height` rather than at the configured size,
+  /// before super-resolution.
+  pub fn set_frame_size(&mut self, width: usize, height: usize) {
+    self.upscaled_width = width;
+    self.height = height;
+    self.h_in_b = 2 * height.align_power_of_two_and_shift(3);
+    self.sb_height = height.align_power_of_two_and_shift(6);
+    self.set_superres_denom(self.superres_denom);
+  }
+
+  /// Prepares the prediction from references of another size than the
+  /// coded size of the frame, once it is set.
+  pub fn scale_refs(&mut self) {
+    self.scaled_rec_buffer = None;
+    if self.has_scaled_refs() {
+      // The references are scaled, which rules out warped prediction.
+      self.gm_params = [WarpedMotionParams::default(); INTER_REFS_PER_FRAME];
+      self.allow_warped_motion = false;
+      self.is_motion_mode_switchable = false;
+      self.scaled_rec_buffer = Some(self.scale_rec_buffer());
+    }
+  }
+
+  /// Whether any reference of the frame has another size than its coded
+  /// size.
+  pub fn has_scaled_refs(&self) -> bool {
+    !self.intra_only
+      && self.ref_frames.iter().any(|&slot| {
+        self.rec_buffer.frames[slot as usize].as_ref().map_or(false, |rec| {
+          rec.width != self.width || rec.height != self.height
+        })
+      })
+  }
+
+  /// Whether every reference of the frame may be scaled to predict it when
+  /// coded at `width` (spec 7.11.3.3).
+  fn can_scale_refs(&self, width: usize) -> bool {
+    self.intra_only
+      || self.ref_frames.iter().all(|&slot| {
+        self.rec_buffer.frames[slot as usize].as_ref().map_or(true, |rec| {
+          2 * width >= rec.width
+            && 2 * self.height >= rec.height
+            && width <= 16 * rec.width
+            && self.height <= 16 * rec.height
+        })
+      })
+  }
+
+  /// Chooses the super-resolution denominator of the frame according to
+  /// the configured mode, once its quantizer is set.
+  pub fn pick_superres_denom(&self) -> u8 {
+    let denom = match self.config.superres_mode {
+      SuperresMode::None => SUPERRES_NUM,
+      SuperresMode::Fixed => self.config.superres_denom,
+      SuperresMode::Random => {
+        // A hash of the frame number keeps the encode reproducible.
+        let hash = self
+          .input_frameno
+          .wrapping_mul(6_364_136_223_846_793_005)
+          .wrapping_add(1_442_695_040_888_963_407);
+        let choices = (SUPERRES_DENOM_MAX - SUPERRES_NUM + 1) as u64;
+        SUPERRES_NUM + ((hash >> 33) % choices) as u8
+      }
+      SuperresMode::Qthresh => {
+        let qthresh = self.config.superres_qthresh;
+        if self.base_q_idx <= qthresh {
+          SUPERRES_NUM
+        } else {
+          // Scale down further as the quantizer approaches 255.
+          let steps = (SUPERRES_DENOM_MAX - SUPERRES_DENOM_MIN + 1) as usize;
+          let excess = (self.base_q_idx - qthresh - 1) as usize;
+          SUPERRES_DENOM_MIN
+            + (excess * steps / (255 - qthresh as usize)) as u8
+        }
+      }
+    };
+    // Intra block copy and lossless coding require the frame to be coded
+    // at its full width, decoders do not scale frames below 16 samples
+    // wide, and references may not be scaled down more than twice.
+    let width = superres_coded_width(self.upscaled_width, denom);
+    if self.allow_intrabc
+      || self.lossless
+      || width < 16
+      || !self.can_scale_refs(width)
+    {
+      SUPERRES_NUM
+    } else {
+      denom
+    }
+  }
+
+  /// Whether quantization matrices are signaled for the frame.
+  pub fn using_qmatrix(&self) -> bool {
+    self.qm_levels.iter().any(|&l| l < NUM_QM_LEVELS as u8 - 1)
+  }
+
+  #[inline(always)]
+  pub fn use_superres(&self) -> bool {
+    self.superres_denom != SUPERRES_NUM
+  }
+
+  /// Resamples the references of another size to the coded size of the
+  /// frame.
+  fn scale_rec_buffer(&self) -> ReferenceFramesSet<T> {
+    let mut scaled = self.rec_buffer.clone();
+    for i in 0..REF_FRAMES {
+      let rec = match &self.rec_buffer.frames[i] {
+        Some(rec) if rec.width != self.width || rec.height != self.height => {
+          rec
+        }
+        _ => continue,
+      };
+      let shared = (0..i).find(|&j| match &self.rec_buffer.frames[j] {
+        Some(other) => Arc::ptr_eq(other, rec),
+        None => false,
+      });
+      if let Some(j) = shared {
+        scaled.frames[i] = scaled.frames[j].clone();
+        continue;
+      }
+
+      let frame = resample_frame(
+        &rec.frame,
+        rec.width,
+        rec.height,
+        self.width,
+        self.height,
+        self.sequence.chroma_sampling,
+      );
+      // The source of the reference is gone, so the subsampled planes are
+      // taken from its reconstruction instead.
+      let hres = frame.planes[0].downsampled(self.width, self.height);
+      let qres = hres.downsampled(self.width, self.height);
+      scaled.frames[i] = Some(Arc::new(ReferenceFrame {
+        frame: Arc::new(frame),
+        width: self.width,
+        height: self.height,
+        input_hres: Arc::new(hres),
+        input_qres: Arc::new(qres),
+        frame_mvs: {
+          let mut vec = Vec::with_capacity(REF_FRAMES);
+          for _ in 0..REF_FRAMES {
+            vec.push(FrameMotionVectors::new(self.w_in_b, self.h_in_b));
+          }
+          Arc::new(vec)
+        },
+        ..(**rec).clone()
+      }));
+    }
+    scaled
+  }
+
+  /// References to search in motion estimation, at the coded size of the
+  /// frame.
+  #[inline(always)]
+  pub fn me_rec_buffer(&self) -> &ReferenceFramesSet<T> {
+    self.scaled_rec_buffer.as_ref().unwrap_or(&self.rec_buffer)
+  }
+
   #[inline(always)]
   pub fn sb_size_log2(&self) -> usize {
     self.sequence.sb_size_log2()
@@ -975,6 +1476,73 @@ pub fn write_temporal_delimiter(packet: &mut dyn io::Write) -> io::Result<()> {
   Ok(())
 }
 
+/// Writes a temporal unit of OBUs with size fields in the length delimited
+/// bitstream format of Annex B, leaving the size fields out.
+pub fn write_annex_b_temporal_unit(
+  packet: &mut dyn io::Write, mut data: &[u8],
+) -> io::Result<()> {
+  fn invalid_obu() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Invalid OBU")
+  }
+
+  fn read_uleb128(data: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0;
+    for (i, &byte) in data.iter().take(8).enumerate() {
+      value |= ((byte & 0x7f) as usize) << (7 * i);
+      if byte & 0x80 == 0 {
+        return Some((value, i + 1));
+      }
+    }
+    None
+  }
+
+  fn length_delimited(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    BitWriter::endian(&mut buf, BigEndian).write_uleb128(data.len() as u64)?;
+    buf.extend_from_slice(data);
+    Ok(buf)
+  }
+
+  // Each frame unit holds a single frame header, the first one also holding
+  // the temporal delimiter and sequence header preceding it.
+  let mut frame_units = vec![Vec::new()];
+  let mut has_frame_header = false;
+  while !data.is_empty() {
+    let header_len = if data[0] & 0x04 != 0 { 2 } else { 1 };
+    if data[0] & 0x02 == 0 || data.len() <= header_len {
+      return Err(invalid_obu());
+    }
+    let (size, size_len) =
+      read_uleb128(&data[header_len..]).ok_or_else(invalid_obu)?;
+    let obu_len = header_len + size_len + size;
+    if data.len() < obu_len {
+      return Err(invalid_obu());
+    }
+
+    let obu_type = data[0] >> 3 & 0xf;
+    if obu_type == ObuType::OBU_FRAME_HEADER as u8
+      || obu_type == ObuType::OBU_FRAME as u8
+    {
+      if has_frame_header {
+        frame_units.push(Vec::new());
+      }
+      has_frame_header = true;
+    }
+
+    let mut obu = data[..header_len].to_vec();
+    obu[0] &= !0x02; // obu_has_size_field
+    obu.extend_from_slice(&data[header_len + size_len..obu_len]);
+    frame_units.last_mut().unwrap().extend(length_delimited(&obu)?);
+    data = &data[obu_len..];
+  }
+
+  let mut temporal_unit = Vec::new();
+  for frame_unit in frame_units.iter() {
+    temporal_unit.extend(length_delimited(frame_unit)?);
+  }
+  packet.write_all(&length_delimited(&temporal_unit)?)
+}
+
 fn write_obus<T: Pixel>(
   packet: &mut dyn io::Write, fi: &FrameInvariants<T>, fs: &FrameState<T>,
   inter_cfg: &InterConfig,
@@ -1073,6 +1641,9 @@ fn get_qidx<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, cw: &ContextWriter,
   tile_bo: TileBlockOffset,
 ) -> u8 {
+  if fi.delta_q_present {
+    return fi.sb_qidx(ts.to_frame_block_offset(tile_bo));
+  }
   let mut qidx = fi.base_q_idx;
   let sidx = cw.bc.blocks[tile_bo].segmentation_idx as usize;
   if ts.segmentation.features[sidx][SegLvl::SEG_LVL_ALT_Q as usize] {
@@ -1151,33 +1722,70 @@ pub fn encode_tx_block<T: Pixel>(
   let frame_bo = ts.to_frame_block_offset(tx_bo);
   let rec = &mut ts.rec.planes[p];
 
-  if mode.is_intra() {
+  let palette = cw.bc.blocks[tile_partition_bo].palette;
+  if p == 0 && palette.is_enabled() {
+    let onscreen_width = ((ts.mi_width - tile_partition_bo.0.x)
+      << MI_SIZE_LOG2)
+      .min(bsize.width());
+    let onscreen_height = ((ts.mi_height - tile_partition_bo.0.y)
+      << MI_SIZE_LOG2)
+      .min(bsize.height());
+    predict_palette(
+      &palette,
+      &ts.input_tile.planes[0]
+        .subregion(Area::BlockStartingAt { bo: tile_partition_bo.0 }),
+      &mut rec.subregion_mut(area),
+      (tx_bo.0.x - tile_partition_bo.0.x) << MI_SIZE_LOG2,
+      (tx_bo.0.y - tile_partition_bo.0.y) << MI_SIZE_LOG2,
+      tx_size.width(),
+      tx_size.height(),
+      onscreen_width,
+      onscreen_height,
+    );
+  } else if mode.is_intra() {
     let bit_depth = fi.sequence.bit_depth;
+    let filter_intra_mode = if p == 0 {
+      cw.bc.blocks[tile_partition_bo].filter_intra_mode
+    } else {
+      None
+    };
     let edge_buf = get_intra_edges(
       &rec.as_const(),
       tile_partition_bo,
       bx,
       by,
       bsize,
+      cw.bc.blocks[tile_partition_bo].partition,
+      fi.sb_size_log2(),
       po,
       tx_size,
       bit_depth,
-      Some(mode),
+      // Filter intra needs all the edges
+      if filter_intra_mode.is_some() { None } else { Some(mode) },
       fi.sequence.enable_intra_edge_filter,
       pred_intra_param,
     );
 
-    mode.predict_intra(
-      tile_rect,
-      &mut rec.subregion_mut(area),
-      tx_size,
-      bit_depth,
-      ac,
-      pred_intra_param,
-      ief_params,
-      &edge_buf,
-      fi.cpu_feature_level,
-    );
+    if let Some(filter_intra_mode) = filter_intra_mode {
+      filter_intra_mode.predict_intra(
+        &mut rec.subregion_mut(area),
+        tx_size,
+        bit_depth,
+        &edge_buf,
+      );
+    } else {
+      mode.predict_intra(
+        tile_rect,
+        &mut rec.subregion_mut(area),
+        tx_size,
+        bit_depth,
+        ac,
+        pred_intra_param,
+        ief_params,
+        &edge_buf,
+        fi.cpu_feature_level,
+      );
+    }
   }
 
   if skip {
@@ -1221,28 +1829,68 @@ pub fn encode_tx_block<T: Pixel>(
     );
   }
 
-  forward_transform(
-    residual,
-    coeffs,
-    tx_size.width(),
-    tx_size,
-    tx_type,
-    fi.sequence.bit_depth,
-    fi.cpu_feature_level,
-  );
+  if fi.lossless {
+    debug_assert!(tx_size == TxSize::TX_4X4 && tx_type == TxType::DCT_DCT);
+    forward_wht4x4(residual, coeffs, tx_size.width());
+  } else {
+    forward_transform(
+      residual,
+      coeffs,
+      tx_size.width(),
+      tx_size,
+      tx_type,
+      fi.sequence.bit_depth,
+      fi.cpu_feature_level,
+    );
+  }
 
-  let eob = ts.qc.quantize(coeffs, qcoeffs, tx_size, tx_type);
+  let qm = qm_matrix(fi.qm_levels[p], p, tx_size, tx_type);
+  let mut eob = ts.qc.quantize(coeffs, qcoeffs, tx_size, tx_type, qm);
+
+  debug_assert!((((fi.w_in_b - frame_bo.0.x) << MI_SIZE_LOG2) >> xdec) >= 4);
+  debug_assert!((((fi.h_in_b - frame_bo.0.y) << MI_SIZE_LOG2) >> ydec) >= 4);
+  let frame_clipped_txw: usize =
+    (((fi.w_in_b - frame_bo.0.x) << MI_SIZE_LOG2) >> xdec)
+      .min(tx_size.width());
+  let frame_clipped_txh: usize =
+    (((fi.h_in_b - frame_bo.0.y) << MI_SIZE_LOG2) >> ydec)
+      .min(tx_size.height());
+
+  if fi.config.speed_settings.rdoq && !fi.lossless && eob > 0 {
+    // Temporal RDO only has importances for 8x8 blocks, so larger transforms
+    // are weighted with the one of the 8x8 block at their origin.
+    let tx_bsize = tx_size.block_size();
+    let bias = distortion_scale(
+      fi,
+      frame_bo,
+      if tx_bsize <= BlockSize::BLOCK_8X8 {
+        tx_bsize
+      } else {
+        BlockSize::BLOCK_8X8
+      },
+    );
+    eob = rdo_quantize(
+      fi,
+      cw,
+      p,
+      tx_bo,
+      coeffs,
+      qcoeffs,
+      eob,
+      tx_size,
+      tx_type,
+      qm,
+      plane_bsize,
+      xdec,
+      ydec,
+      qidx,
+      frame_clipped_txw,
+      frame_clipped_txh,
+      bias,
+    );
+  }
 
   let has_coeff = if need_recon_pixel || rdo_type.needs_coeff_rate() {
-    debug_assert!((((fi.w_in_b - frame_bo.0.x) << MI_SIZE_LOG2) >> xdec) >= 4);
-    debug_assert!((((fi.h_in_b - frame_bo.0.y) << MI_SIZE_LOG2) >> ydec) >= 4);
-    let frame_clipped_txw: usize =
-      (((fi.w_in_b - frame_bo.0.x) << MI_SIZE_LOG2) >> xdec)
-        .min(tx_size.width());
-    let frame_clipped_txh: usize =
-      (((fi.h_in_b - frame_bo.0.y) << MI_SIZE_LOG2) >> ydec)
-        .min(tx_size.height());
-
     cw.write_coeffs_lv_map(
       w,
       p,
@@ -1252,6 +1900,7 @@ pub fn encode_tx_block<T: Pixel>(
       mode,
       tx_size,
       tx_type,
+      qidx,
       plane_bsize,
       xdec,
       ydec,
@@ -1273,19 +1922,28 @@ pub fn encode_tx_block<T: Pixel>(
     fi.sequence.bit_depth,
     fi.dc_delta_q[p],
     fi.ac_delta_q[p],
+    qm,
     fi.cpu_feature_level,
   );
 
   if !fi.use_tx_domain_distortion || need_recon_pixel {
-    inverse_transform_add(
-      rcoeffs,
-      &mut rec.subregion_mut(area),
-      eob,
-      tx_size,
-      tx_type,
-      fi.sequence.bit_depth,
-      fi.cpu_feature_level,
-    );
+    if fi.lossless {
+      inverse_wht4x4_add(
+        rcoeffs,
+        &mut rec.subregion_mut(area),
+        fi.sequence.bit_depth,
+      );
+    } else {
+      inverse_transform_add(
+        rcoeffs,
+        &mut rec.subregion_mut(area),
+        eob,
+        tx_size,
+        tx_type,
+        fi.sequence.bit_depth,
+        fi.cpu_feature_level,
+      );
+    }
   }
 
   let tx_dist =
@@ -1296,12 +1954,19 @@ pub fn encode_tx_block<T: Pixel>(
       // over coeffs and rcoeffs for the first 32 rows/cols. For the
       // coefficients above 32 rows/cols, we iterate over the rest of coeffs
       // with the assumption that rcoeff coefficients are zero.
+      // With a quantization matrix, the error of each coefficient is
+      // weighted by the inverse of the weight of its quantizer.
       let mut raw_tx_dist = coeffs
         .iter()
         .zip(rcoeffs.iter())
-        .map(|(&a, &b)| {
+        .enumerate()
+        .map(|(i, (&a, &b))| {
           let c = i32::cast_from(a) - i32::cast_from(b);
-          (c * c) as u64
+          let dist = (c * c) as u64;
+          match qm {
+            Some(qm) => (dist << (2 * QM_BITS)) / (qm[i] as u64).pow(2),
+            None => dist,
+          }
         })
         .sum::<u64>()
         + coeffs[rcoeffs.len()..]
@@ -1334,6 +1999,39 @@ pub fn encode_tx_block<T: Pixel>(
   (has_coeff, tx_dist)
 }
 
+/// Selects the warp, if any, used instead of translation to predict each
+/// reference of an inter block: the global motion for GLOBALMV blocks and
+/// the local warp for WARPED_CAUSAL blocks.
+pub fn block_warps<T: Pixel>(
+  fi: &FrameInvariants<T>, cw: &ContextWriter, luma_mode: PredictionMode,
+  ref_frames: [RefType; 2], mvs: [MotionVector; 2], bsize: BlockSize,
+  tile_bo: TileBlockOffset,
+) -> [Option<WarpedMotionParams>; 2] {
+  let mut warps = [None; 2];
+  if bsize.width().min(bsize.height()) < 8 {
+    return warps;
+  }
+  if luma_mode == PredictionMode::GLOBALMV
+    || luma_mode == PredictionMode::GLOBAL_GLOBALMV
+  {
+    for (warp, &ref_frame) in warps.iter_mut().zip(ref_frames.iter()) {
+      if ref_frame != INTRA_FRAME && ref_frame != NONE_FRAME {
+        let params = fi.gm_params[ref_frame.to_index()];
+        if params.is_warp() && params.shear().is_some() {
+          *warp = Some(params);
+        }
+      }
+    }
+  }
+  if warps[0].is_none()
+    && cw.bc.blocks[tile_bo].motion_mode == MotionMode::WARPED_CAUSAL
+  {
+    warps[0] =
+      cw.local_warp_params(fi, tile_bo, bsize, luma_mode, ref_frames, mvs[0]);
+  }
+  warps
+}
+
 pub fn motion_compensate<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
   cw: &mut ContextWriter, luma_mode: PredictionMode, ref_frames: [RefType; 2],
@@ -1360,6 +2058,27 @@ pub fn motion_compensate<T: Pixel>(
       0
     };
 
+  if ref_frames[0] == INTRA_FRAME {
+    // Intra block copy from the already reconstructed part of the tile
+    for p in 0..num_planes {
+      let plane_bsize =
+        if p == 0 { bsize } else { bsize.subsampled_size(u_xdec, u_ydec) };
+      let rec = &mut ts.rec.planes[p];
+      let po = tile_bo.plane_offset(rec.plane_cfg);
+      predict_intrabc(
+        rec,
+        po,
+        mvs[0],
+        plane_bsize.width(),
+        plane_bsize.height(),
+      );
+    }
+    return;
+  }
+
+  let warps = block_warps(fi, cw, luma_mode, ref_frames, mvs, bsize, tile_bo);
+  let interp_filter = cw.bc.blocks[tile_bo].interp_filter;
+
   let luma_tile_rect = ts.tile_rect();
   let compound_buffer = &mut ts.inter_compound_buffers;
   for p in 0..num_planes {
@@ -1372,15 +2091,13 @@ pub fn motion_compensate<T: Pixel>(
     let tile_rect = luma_tile_rect.decimated(xdec, ydec);
 
     let area = Area::BlockStartingAt { bo: tile_bo.0 };
-    if p > 0 && bsize < BlockSize::BLOCK_8X8 {
+    if p > 0 && bsize.is_sub8x8(u_xdec, u_ydec) {
       let mut some_use_intra = false;
-      if bsize == BlockSize::BLOCK_4X4 || bsize == BlockSize::BLOCK_4X8 {
+      if bsize.width() == 4 {
         some_use_intra |=
           cw.bc.blocks[tile_bo.with_offset(-1, 0)].mode.is_intra();
       };
-      if !some_use_intra && bsize == BlockSize::BLOCK_4X4
-        || bsize == BlockSize::BLOCK_8X4
-      {
+      if !some_use_intra && bsize.height() == 4 {
         some_use_intra |=
           cw.bc.blocks[tile_bo.with_offset(0, -1)].mode.is_intra();
       };
@@ -1400,6 +2117,8 @@ pub fn motion_compensate<T: Pixel>(
           plane_bsize.height(),
           ref_frames,
           mvs,
+          [None; 2],
+          interp_filter,
           compound_buffer,
         );
       } else {
@@ -1408,12 +2127,16 @@ pub fn motion_compensate<T: Pixel>(
         if bsize == BlockSize::BLOCK_4X4 {
           let mv0 = cw.bc.blocks[tile_bo.with_offset(-1, -1)].mv;
           let rf0 = cw.bc.blocks[tile_bo.with_offset(-1, -1)].ref_frames;
+          let filter0 =
+            cw.bc.blocks[tile_bo.with_offset(-1, -1)].interp_filter;
           let mv1 = cw.bc.blocks[tile_bo.with_offset(0, -1)].mv;
           let rf1 = cw.bc.blocks[tile_bo.with_offset(0, -1)].ref_frames;
+          let filter1 = cw.bc.blocks[tile_bo.with_offset(0, -1)].interp_filter;
           let po1 = PlaneOffset { x: po.x + 2, y: po.y };
           let area1 = Area::StartingAt { x: po1.x, y: po1.y };
           let mv2 = cw.bc.blocks[tile_bo.with_offset(-1, 0)].mv;
           let rf2 = cw.bc.blocks[tile_bo.with_offset(-1, 0)].ref_frames;
+          let filter2 = cw.bc.blocks[tile_bo.with_offset(-1, 0)].interp_filter;
           let po2 = PlaneOffset { x: po.x, y: po.y + 2 };
           let area2 = Area::StartingAt { x: po2.x, y: po2.y };
           let po3 = PlaneOffset { x: po.x + 2, y: po.y + 2 };
@@ -1428,6 +2151,8 @@ pub fn motion_compensate<T: Pixel>(
             2,
             rf0,
             mv0,
+            [None; 2],
+            filter0,
             compound_buffer,
           );
           luma_mode.predict_inter(
@@ -1440,6 +2165,8 @@ pub fn motion_compensate<T: Pixel>(
             2,
             rf1,
             mv1,
+            [None; 2],
+            filter1,
             compound_buffer,
           );
           luma_mode.predict_inter(
@@ -1452,6 +2179,8 @@ pub fn motion_compensate<T: Pixel>(
             2,
             rf2,
             mv2,
+            [None; 2],
+            filter2,
             compound_buffer,
           );
           luma_mode.predict_inter(
@@ -1464,22 +2193,27 @@ pub fn motion_compensate<T: Pixel>(
             2,
             ref_frames,
             mvs,
+            [None; 2],
+            interp_filter,
             compound_buffer,
           );
         }
-        if bsize == BlockSize::BLOCK_8X4 {
+        if bsize == BlockSize::BLOCK_8X4 || bsize == BlockSize::BLOCK_16X4 {
           let mv1 = cw.bc.blocks[tile_bo.with_offset(0, -1)].mv;
           let rf1 = cw.bc.blocks[tile_bo.with_offset(0, -1)].ref_frames;
+          let filter1 = cw.bc.blocks[tile_bo.with_offset(0, -1)].interp_filter;
           luma_mode.predict_inter(
             fi,
             tile_rect,
             p,
             po,
             &mut rec.subregion_mut(area),
-            4,
+            plane_bsize.width(),
             2,
             rf1,
             mv1,
+            [None; 2],
+            filter1,
             compound_buffer,
           );
           let po3 = PlaneOffset { x: po.x, y: po.y + 2 };
@@ -1490,16 +2224,19 @@ pub fn motion_compensate<T: Pixel>(
             p,
             po3,
             &mut rec.subregion_mut(area3),
-            4,
+            plane_bsize.width(),
             2,
             ref_frames,
             mvs,
+            [None; 2],
+            interp_filter,
             compound_buffer,
           );
         }
-        if bsize == BlockSize::BLOCK_4X8 {
+        if bsize == BlockSize::BLOCK_4X8 || bsize == BlockSize::BLOCK_4X16 {
           let mv2 = cw.bc.blocks[tile_bo.with_offset(-1, 0)].mv;
           let rf2 = cw.bc.blocks[tile_bo.with_offset(-1, 0)].ref_frames;
+          let filter2 = cw.bc.blocks[tile_bo.with_offset(-1, 0)].interp_filter;
           luma_mode.predict_inter(
             fi,
             tile_rect,
@@ -1507,9 +2244,11 @@ pub fn motion_compensate<T: Pixel>(
             po,
             &mut rec.subregion_mut(area),
             2,
-            4,
+            plane_bsize.height(),
             rf2,
             mv2,
+            [None; 2],
+            filter2,
             compound_buffer,
           );
           let po3 = PlaneOffset { x: po.x + 2, y: po.y };
@@ -1521,14 +2260,24 @@ pub fn motion_compensate<T: Pixel>(
             po3,
             &mut rec.subregion_mut(area3),
             2,
-            4,
+            plane_bsize.height(),
             ref_frames,
             mvs,
+            [None; 2],
+            interp_filter,
             compound_buffer,
           );
         }
       }
     } else {
+      // Chroma blocks smaller than 8x8 are translated by the motion vector
+      // even when the luma block is warped.
+      let plane_warps =
+        if plane_bsize.width() >= 8 && plane_bsize.height() >= 8 {
+          warps
+        } else {
+          [None; 2]
+        };
       luma_mode.predict_inter(
         fi,
         tile_rect,
@@ -1539,6 +2288,8 @@ pub fn motion_compensate<T: Pixel>(
         plane_bsize.height(),
         ref_frames,
         mvs,
+        plane_warps,
+        interp_filter,
         compound_buffer,
       );
     }
@@ -1560,10 +2311,11 @@ pub fn save_block_motion<T: Pixel>(
 }
 
 pub fn encode_block_pre_cdef<T: Pixel>(
-  seq: &Sequence, ts: &TileStateMut<'_, T>, cw: &mut ContextWriter,
+  fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, cw: &mut ContextWriter,
   w: &mut dyn Writer, bsize: BlockSize, tile_bo: TileBlockOffset, skip: bool,
 ) -> bool {
   cw.bc.blocks.set_skip(tile_bo, bsize, skip);
+  let temporal_pred = temporal_segment_pred(fi, ts, tile_bo, bsize);
   if ts.segmentation.enabled
     && ts.segmentation.update_map
     && ts.segmentation.preskip
@@ -1574,9 +2326,18 @@ pub fn encode_block_pre_cdef<T: Pixel>(
       bsize,
       false,
       ts.segmentation.last_active_segid,
+      temporal_pred,
     );
   }
-  cw.write_skip(w, tile_bo, skip);
+  // Blocks of segments with the skip feature are implicitly skipped.
+  let sidx = cw.bc.blocks[tile_bo].segmentation_idx;
+  if ts.segmentation.preskip
+    && ts.segmentation.feature_active(sidx, SegLvl::SEG_LVL_SKIP)
+  {
+    assert!(skip);
+  } else {
+    cw.write_skip(w, tile_bo, skip);
+  }
   if ts.segmentation.enabled
     && ts.segmentation.update_map
     && !ts.segmentation.preskip
@@ -1587,9 +2348,16 @@ pub fn encode_block_pre_cdef<T: Pixel>(
       bsize,
       skip,
       ts.segmentation.last_active_segid,
+      temporal_pred,
     );
   }
-  if !skip && seq.enable_cdef {
+  if !skip && fi.sequence.enable_cdef && !fi.allow_intrabc && !fi.lossless {
+    // The CDEF index is coded in the first non-skip block of each 64x64
+    // unit, and also applies to the other units covered by the block.
+    let cdef_sbo = tile_bo.sb_offset();
+    if cw.bc.cdef_units.iter().all(|&(sbo, _)| sbo != cdef_sbo) {
+      cw.bc.cdef_units.push((cdef_sbo, w.checkpoint()));
+    }
     cw.bc.cdef_coded = true;
   }
   cw.bc.cdef_coded
@@ -1631,27 +2399,57 @@ pub fn encode_block_post_cdef<T: Pixel>(
   cw.bc.blocks.set_ref_frames(tile_bo, bsize, ref_frames);
   cw.bc.blocks.set_motion_vectors(tile_bo, bsize, mvs);
 
-  //write_q_deltas();
-  if cw.bc.code_deltas
-    && ts.deblock.block_deltas_enabled
-    && (bsize < sb_size || !skip)
-  {
-    cw.write_block_deblock_deltas(
-      w,
-      tile_bo,
-      ts.deblock.block_delta_multi,
-      planes,
-    );
+  // The deltas are coded in the first block of each superblock, unless it
+  // is a skipped block covering the whole superblock.
+  if cw.bc.code_deltas && (bsize < sb_size || !skip) {
+    let frame_bo = ts.to_frame_block_offset(tile_bo);
+    cw.write_delta_qindex(w, fi.sb_qidx(frame_bo), fi.delta_q_res);
+    if ts.deblock.block_deltas_enabled {
+      let deltas = [fi.sb_deblock_delta(frame_bo); FRAME_LF_COUNT];
+      cw.write_block_deblock_deltas(
+        w,
+        deltas,
+        ts.deblock.block_delta_multi,
+        ts.deblock.block_delta_shift,
+        planes,
+      );
+    }
   }
   cw.bc.code_deltas = false;
+  if ts.deblock.block_deltas_enabled {
+    let deltas = cw.bc.current_deblock_deltas;
+    cw.bc.blocks.set_deblock_deltas(tile_bo, bsize, deltas);
+  }
+
+  let palette = cw.bc.blocks[tile_bo].palette;
+  debug_assert!(!is_inter || !palette.is_enabled());
 
   if fi.frame_type.has_inter() {
-    cw.write_is_inter(w, tile_bo, is_inter);
+    // The features of the segment may imply the reference frame and the
+    // prediction mode of the block.
+    let sidx = cw.bc.blocks[tile_bo].segmentation_idx;
+    debug_assert!(ts.segmentation.allows(
+      sidx, bsize, luma_mode, ref_frames, skip
+    ));
+    let implied_ref_frame = ts.segmentation.implied_ref_frame(sidx);
+    let implied_global_mv = ts
+      .segmentation
+      .feature_active(sidx, SegLvl::SEG_LVL_SKIP)
+      || ts.segmentation.feature_active(sidx, SegLvl::SEG_LVL_GLOBALMV);
+    if !ts.segmentation.feature_active(sidx, SegLvl::SEG_LVL_REF_FRAME)
+      && !ts.segmentation.feature_active(sidx, SegLvl::SEG_LVL_GLOBALMV)
+    {
+      cw.write_is_inter(w, tile_bo, is_inter);
+    }
     if is_inter {
       cw.fill_neighbours_ref_counts(tile_bo);
-      cw.write_ref_frames(w, fi, tile_bo);
+      if implied_ref_frame.is_none() {
+        cw.write_ref_frames(w, fi, tile_bo);
+      }
 
-      if luma_mode.is_compound() {
+      if implied_global_mv {
+        assert!(luma_mode == PredictionMode::GLOBALMV);
+      } else if luma_mode.is_compound() {
         cw.write_compound_mode(w, luma_mode, mode_context);
       } else {
         cw.write_inter_mode(w, luma_mode, mode_context);
@@ -1679,10 +2477,14 @@ pub fn encode_block_post_cdef<T: Pixel>(
         }
       }
 
+      let global_mvs = [
+        cw.global_mv(fi, tile_bo, bsize, ref_frames[0]),
+        cw.global_mv(fi, tile_bo, bsize, ref_frames[1]),
+      ];
       let ref_mvs = if num_mv_found > 0 {
         [mv_stack[ref_mv_idx].this_mv, mv_stack[ref_mv_idx].comp_mv]
       } else {
-        [MotionVector::default(); 2]
+        global_mvs
       };
 
       let mv_precision = if fi.force_integer_mv != 0 {
@@ -1727,69 +2529,118 @@ pub fn encode_block_post_cdef<T: Pixel>(
           assert!(mv_stack[ref_mv_idx].this_mv.row == mvs[0].row);
           assert!(mv_stack[ref_mv_idx].this_mv.col == mvs[0].col);
         } else {
-          assert!(0 == mvs[0].row);
-          assert!(0 == mvs[0].col);
+          assert!(global_mvs[0].row == mvs[0].row);
+          assert!(global_mvs[0].col == mvs[0].col);
         }
       } else if luma_mode == PredictionMode::NEARESTMV {
         if mv_stack.is_empty() {
-          assert_eq!(mvs[0].row, 0);
-          assert_eq!(mvs[0].col, 0);
+          assert_eq!(mvs[0].row, global_mvs[0].row);
+          assert_eq!(mvs[0].col, global_mvs[0].col);
         } else {
           assert_eq!(mvs[0].row, mv_stack[0].this_mv.row);
           assert_eq!(mvs[0].col, mv_stack[0].this_mv.col);
         }
       }
+
+      if cw.motion_mode_coded(fi, tile_bo, bsize, luma_mode, ref_frames) {
+        let motion_mode = cw.bc.blocks[tile_bo].motion_mode;
+        cw.write_motion_mode(
+          w,
+          fi,
+          tile_bo,
+          bsize,
+          ref_frames[0],
+          mvs[0],
+          motion_mode,
+        );
+      }
+
+      let motion_mode = cw.bc.blocks[tile_bo].motion_mode;
+      let interp_filter = cw.bc.blocks[tile_bo].interp_filter;
+      if cw.interp_filter_coded(fi, bsize, luma_mode, ref_frames, motion_mode)
+      {
+        cw.write_interp_filter(w, fi, tile_bo, ref_frames, interp_filter);
+      } else {
+        debug_assert_eq!(interp_filter, [fi.default_filter; 2]);
+      }
     } else {
       cw.write_intra_mode(w, bsize, luma_mode);
     }
   } else {
-    cw.write_intra_mode_kf(w, tile_bo, luma_mode);
+    if fi.allow_intrabc {
+      cw.write_intrabc(w, is_inter);
+    }
+    if is_inter {
+      let ref_dv = intrabc_ref_dv(mv_stack, tile_bo, ts.sb_size_log2);
+      cw.write_dv(w, mvs[0], ref_dv);
+    } else {
+      cw.write_intra_mode_kf(w, tile_bo, luma_mode);
+    }
   }
 
   if !is_inter {
-    if luma_mode.is_directional() && bsize >= BlockSize::BLOCK_8X8 {
+    if luma_mode.is_directional() && bsize.at_least_8x8() {
       cw.write_angle_delta(w, angle_delta.y, luma_mode);
     }
     if has_chroma(tile_bo, bsize, xdec, ydec, fi.sequence.chroma_sampling) {
-      cw.write_intra_uv_mode(w, chroma_mode, luma_mode, bsize);
+      let cfl_allowed = bsize.cfl_allowed(fi.lossless, xdec, ydec);
+      cw.write_intra_uv_mode(w, chroma_mode, luma_mode, cfl_allowed);
       if chroma_mode.is_cfl() {
-        assert!(bsize.cfl_allowed());
+        assert!(cfl_allowed);
         cw.write_cfl_alphas(w, cfl);
       }
-      if chroma_mode.is_directional() && bsize >= BlockSize::BLOCK_8X8 {
+      if chroma_mode.is_directional() && bsize.at_least_8x8() {
         cw.write_angle_delta(w, angle_delta.uv, chroma_mode);
       }
     }
 
-    if fi.allow_screen_content_tools > 0
-      && bsize >= BlockSize::BLOCK_8X8
-      && bsize.width() <= 64
-      && bsize.height() <= 64
-    {
+    if fi.allow_screen_content_tools > 0 && palette_allowed(bsize) {
       cw.write_use_palette_mode(
         w,
-        false,
+        &palette,
         bsize,
         tile_bo,
         luma_mode,
         chroma_mode,
+        fi.sequence.bit_depth,
         xdec,
         ydec,
         fi.sequence.chroma_sampling,
       );
     }
 
+    let filter_intra_mode = cw.bc.blocks[tile_bo].filter_intra_mode;
     if fi.sequence.enable_filter_intra
       && luma_mode == PredictionMode::DC_PRED
-      && bsize.width() <= 32
-      && bsize.height() <= 32
+      && !palette.is_enabled()
+      && FilterIntraMode::allowed(bsize)
     {
-      cw.write_use_filter_intra(w, false, bsize); // turn off FILTER_INTRA
+      cw.write_use_filter_intra(w, filter_intra_mode.is_some(), bsize);
+      if let Some(mode) = filter_intra_mode {
+        cw.write_filter_intra_mode(w, mode);
+      }
+    } else {
+      debug_assert!(filter_intra_mode.is_none());
     }
   }
 
+  if palette.is_enabled() {
+    let onscreen_width =
+      ((ts.mi_width - tile_bo.0.x) << MI_SIZE_LOG2).min(bsize.width());
+    let onscreen_height =
+      ((ts.mi_height - tile_bo.0.y) << MI_SIZE_LOG2).min(bsize.height());
+    cw.write_palette_tokens(
+      w,
+      &palette,
+      &ts.input_tile.planes[0]
+        .subregion(Area::BlockStartingAt { bo: tile_bo.0 }),
+      onscreen_width,
+      onscreen_height,
+    );
+  }
+
   // write tx_size here
-  if fi.tx_mode_select {
+  if fi.tx_mode_select && !fi.lossless {
     if bsize > BlockSize::BLOCK_4X4 && (!is_inter || !skip) {
       if !is_inter {
         cw.write_tx_size_intra(w, tile_bo, bsize, tx_size);
@@ -1806,20 +2657,29 @@ pub fn encode_block_post_cdef<T: Pixel>(
 
         //TODO: "&& tx_size.block_size() < bsize" will be replaced with tx-split info for a partition
         //  once it is available.
-        let txfm_split =
-          fi.enable_inter_txfm_split && tx_size.block_size() < bsize;
+        let txfm_split = fi.enable_inter_txfm_split
+          && tx_size.block_size() < max_tx_size.block_size();
 
         // TODO: Revise write_tx_size_inter() for txfm_split = true
-        cw.write_tx_size_inter(
-          w,
-          tile_bo,
-          bsize,
-          max_tx_size,
-          txfm_split,
-          0,
-          0,
-          0,
-        );
+        // Blocks larger than 64x64 code the transform size of each 64x64 unit.
+        for by in 0..bsize.height_mi() / max_tx_size.height_mi() {
+          for bx in 0..bsize.width_mi() / max_tx_size.width_mi() {
+            let tx_bo = TileBlockOffset(BlockOffset {
+              x: tile_bo.0.x + bx * max_tx_size.width_mi(),
+              y: tile_bo.0.y + by * max_tx_size.height_mi(),
+            });
+            cw.write_tx_size_inter(
+              w,
+              tx_bo,
+              bsize,
+              max_tx_size,
+              txfm_split,
+              bx,
+              by,
+              0,
+            );
+          }
+        }
       }
     } else {
       debug_assert!(bsize == BlockSize::BLOCK_4X4 || (is_inter && skip));
@@ -1983,148 +2843,156 @@ pub fn write_tx_blocks<T: Pixel>(
   let bw = bsize.width_mi() / tx_size.width_mi();
   let bh = bsize.height_mi() / tx_size.height_mi();
   let qidx = get_qidx(fi, ts, cw, tile_bo);
-  assert_ne!(qidx, 0); // lossless is not yet supported
+  // Lossless blocks are only coded in lossless frames
+  assert!(fi.lossless || qidx != 0);
 
   let PlaneConfig { xdec, ydec, .. } = ts.input.planes[1].cfg;
   let mut ac: Aligned<[i16; 32 * 32]> = Aligned::uninitialized();
   let mut partition_has_coeff: bool = false;
   let mut tx_dist = ScaledDistortion::zero();
   let do_chroma =
-    has_chroma(tile_bo, bsize, xdec, ydec, fi.sequence.chroma_sampling);
-
-  ts.qc.update(
-    qidx,
-    tx_size,
-    luma_mode.is_intra(),
-    fi.sequence.bit_depth,
-    fi.dc_delta_q[0],
-    0,
-  );
-
-  for by in 0..bh {
-    for bx in 0..bw {
-      let tx_bo = TileBlockOffset(BlockOffset {
-        x: tile_bo.0.x + bx * tx_size.width_mi(),
-        y: tile_bo.0.y + by * tx_size.height_mi(),
-      });
-      if tx_bo.0.x >= ts.mi_width || tx_bo.0.y >= ts.mi_height {
-        continue;
-      }
-      let po = tx_bo.plane_offset(&ts.input.planes[0].cfg);
-      let (has_coeff, dist) = encode_tx_block(
-        fi,
-        ts,
-        cw,
-        w,
-        0,
-        tile_bo,
-        bx,
-        by,
-        tx_bo,
-        luma_mode,
-        tx_size,
-        tx_type,
-        bsize,
-        po,
-        skip,
-        qidx,
-        &ac.data,
-        IntraParam::AngleDelta(angle_delta.y),
-        rdo_type,
-        need_recon_pixel,
-      );
-      partition_has_coeff |= has_coeff;
-      tx_dist += dist;
-    }
-  }
+    has_chroma(tile_bo, bsize, xdec, ydec, fi.sequence.chroma_sampling)
+      && !luma_only
+      && fi.config.chroma_sampling != ChromaSampling::Cs400;
 
-  if !do_chroma
-    || luma_only
-    || fi.config.chroma_sampling == ChromaSampling::Cs400
-  {
-    return (partition_has_coeff, tx_dist);
+  let uv_tx_size = if fi.lossless {
+    TxSize::TX_4X4
+  } else {
+    bsize.largest_chroma_tx_size(xdec, ydec)
   };
-  debug_assert!(has_chroma(
-    tile_bo,
-    bsize,
-    xdec,
-    ydec,
-    fi.sequence.chroma_sampling
-  ));
-
-  let uv_tx_size = bsize.largest_chroma_tx_size(xdec, ydec);
 
-  let mut bw_uv = (bw * tx_size.width_mi()) >> xdec;
-  let mut bh_uv = (bh * tx_size.height_mi()) >> ydec;
-
-  if bw_uv == 0 || bh_uv == 0 {
-    bw_uv = 1;
-    bh_uv = 1;
-  }
+  // Chroma of sub-8x8 blocks covers at least one 4x4 unit in each direction
+  let mut bw_uv = ((bw * tx_size.width_mi()) >> xdec).max(1);
+  let mut bh_uv = ((bh * tx_size.height_mi()) >> ydec).max(1);
 
   bw_uv /= uv_tx_size.width_mi();
   bh_uv /= uv_tx_size.height_mi();
 
-  if chroma_mode.is_cfl() {
-    luma_ac(&mut ac.data, ts, tile_bo, bsize, tx_size, fi);
-  }
+  let uv_tx_type =
+    if fi.lossless || uv_tx_size.width() >= 32 || uv_tx_size.height() >= 32 {
+      TxType::DCT_DCT
+    } else {
+      uv_intra_mode_to_tx_type_context(chroma_mode)
+    };
 
-  let uv_tx_type = if uv_tx_size.width() >= 32 || uv_tx_size.height() >= 32 {
-    TxType::DCT_DCT
-  } else {
-    uv_intra_mode_to_tx_type_context(chroma_mode)
-  };
+  // Blocks larger than 64x64 are coded in 64x64 chunks, each with its luma
+  // transform blocks followed by its chroma ones.
+  let chunks_w = (bsize.width_mi() / BlockSize::BLOCK_64X64.width_mi()).max(1);
+  let chunks_h =
+    (bsize.height_mi() / BlockSize::BLOCK_64X64.height_mi()).max(1);
 
-  for p in 1..3 {
-    ts.qc.update(
-      qidx,
-      uv_tx_size,
-      true,
-      fi.sequence.bit_depth,
-      fi.dc_delta_q[p],
-      fi.ac_delta_q[p],
-    );
-    let alpha = cfl.alpha(p - 1);
-    for by in 0..bh_uv {
-      for bx in 0..bw_uv {
-        let tx_bo = TileBlockOffset(BlockOffset {
-          x: tile_bo.0.x + ((bx * uv_tx_size.width_mi()) << xdec)
-            - ((bw * tx_size.width_mi() == 1) as usize) * xdec,
-          y: tile_bo.0.y + ((by * uv_tx_size.height_mi()) << ydec)
-            - ((bh * tx_size.height_mi() == 1) as usize) * ydec,
-        });
+  for chunk_y in 0..chunks_h {
+    for chunk_x in 0..chunks_w {
+      ts.qc.update(
+        qidx,
+        tx_size,
+        luma_mode.is_intra(),
+        fi.sequence.bit_depth,
+        fi.dc_delta_q[0],
+        0,
+      );
 
-        let mut po = tile_bo.plane_offset(&ts.input.planes[p].cfg);
-        po.x += (bx * uv_tx_size.width()) as isize;
-        po.y += (by * uv_tx_size.height()) as isize;
-        let (has_coeff, dist) = encode_tx_block(
-          fi,
-          ts,
-          cw,
-          w,
-          p,
-          tile_bo,
-          bx,
-          by,
-          tx_bo,
-          chroma_mode,
-          uv_tx_size,
-          uv_tx_type,
-          bsize,
-          po,
-          skip,
+      for by in chunk_y * bh / chunks_h..(chunk_y + 1) * bh / chunks_h {
+        for bx in chunk_x * bw / chunks_w..(chunk_x + 1) * bw / chunks_w {
+          let tx_bo = TileBlockOffset(BlockOffset {
+            x: tile_bo.0.x + bx * tx_size.width_mi(),
+            y: tile_bo.0.y + by * tx_size.height_mi(),
+          });
+          if tx_bo.0.x >= ts.mi_width || tx_bo.0.y >= ts.mi_height {
+            continue;
+          }
+          let po = tx_bo.plane_offset(&ts.input.planes[0].cfg);
+          let (has_coeff, dist) = encode_tx_block(
+            fi,
+            ts,
+            cw,
+            w,
+            0,
+            tile_bo,
+            bx,
+            by,
+            tx_bo,
+            luma_mode,
+            tx_size,
+            tx_type,
+            bsize,
+            po,
+            skip,
+            qidx,
+            &ac.data,
+            IntraParam::AngleDelta(angle_delta.y),
+            rdo_type,
+            need_recon_pixel,
+          );
+          partition_has_coeff |= has_coeff;
+          tx_dist += dist;
+        }
+      }
+
+      if !do_chroma {
+        continue;
+      }
+
+      // CfL is only allowed for blocks of a single chunk
+      if chroma_mode.is_cfl() {
+        luma_ac(&mut ac.data, ts, tile_bo, bsize, tx_size, fi);
+      }
+
+      for p in 1..3 {
+        ts.qc.update(
           qidx,
-          &ac.data,
-          if chroma_mode.is_cfl() {
-            IntraParam::Alpha(alpha)
-          } else {
-            IntraParam::AngleDelta(angle_delta.uv)
-          },
-          rdo_type,
-          need_recon_pixel,
+          uv_tx_size,
+          true,
+          fi.sequence.bit_depth,
+          fi.dc_delta_q[p],
+          fi.ac_delta_q[p],
         );
-        partition_has_coeff |= has_coeff;
-        tx_dist += dist;
+        let alpha = cfl.alpha(p - 1);
+        for by in chunk_y * bh_uv / chunks_h..(chunk_y + 1) * bh_uv / chunks_h
+        {
+          for bx in
+            chunk_x * bw_uv / chunks_w..(chunk_x + 1) * bw_uv / chunks_w
+          {
+            let tx_bo = TileBlockOffset(BlockOffset {
+              x: tile_bo.0.x + ((bx * uv_tx_size.width_mi()) << xdec)
+                - ((bw * tx_size.width_mi() == 1) as usize) * xdec,
+              y: tile_bo.0.y + ((by * uv_tx_size.height_mi()) << ydec)
+                - ((bh * tx_size.height_mi() == 1) as usize) * ydec,
+            });
+
+            let mut po = tile_bo.plane_offset(&ts.input.planes[p].cfg);
+            po.x += (bx * uv_tx_size.width()) as isize;
+            po.y += (by * uv_tx_size.height()) as isize;
+            let (has_coeff, dist) = encode_tx_block(
+              fi,
+              ts,
+              cw,
+              w,
+              p,
+              tile_bo,
+              bx,
+              by,
+              tx_bo,
+              chroma_mode,
+              uv_tx_size,
+              uv_tx_type,
+              bsize,
+              po,
+              skip,
+              qidx,
+              &ac.data,
+              if chroma_mode.is_cfl() {
+                IntraParam::Alpha(alpha)
+              } else {
+                IntraParam::AngleDelta(angle_delta.uv)
+              },
+              rdo_type,
+              need_recon_pixel,
+            );
+            partition_has_coeff |= has_coeff;
+            tx_dist += dist;
+          }
+        }
       }
     }
   }
@@ -2150,134 +3018,138 @@ pub fn write_tx_tree<T: Pixel>(
   let ac = &[0i16; 0];
   let mut partition_has_coeff: bool = false;
   let mut tx_dist = ScaledDistortion::zero();
-
-  ts.qc.update(
-    qidx,
-    tx_size,
-    luma_mode.is_intra(),
-    fi.sequence.bit_depth,
-    fi.dc_delta_q[0],
-    0,
-  );
-
-  // TODO: If tx-parition more than only 1-level, this code does not work.
-  // It should recursively traverse the tx block that are split recursivelty by calling write_tx_tree(),
-  // as defined in https://aomediacodec.github.io/av1-spec/#transform-tree-syntax
-  for by in 0..bh {
-    for bx in 0..bw {
-      let tx_bo = TileBlockOffset(BlockOffset {
-        x: tile_bo.0.x + bx * tx_size.width_mi(),
-        y: tile_bo.0.y + by * tx_size.height_mi(),
-      });
-      if tx_bo.0.x >= ts.mi_width || tx_bo.0.y >= ts.mi_height {
-        continue;
-      }
-
-      let po = tx_bo.plane_offset(&ts.input.planes[0].cfg);
-      let (has_coeff, dist) = encode_tx_block(
-        fi,
-        ts,
-        cw,
-        w,
-        0,
-        tile_bo,
-        0,
-        0,
-        tx_bo,
-        luma_mode,
-        tx_size,
-        tx_type,
-        bsize,
-        po,
-        skip,
-        qidx,
-        ac,
-        IntraParam::AngleDelta(angle_delta_y),
-        rdo_type,
-        need_recon_pixel,
-      );
-      partition_has_coeff |= has_coeff;
-      tx_dist += dist;
-    }
-  }
-
-  if !has_chroma(tile_bo, bsize, xdec, ydec, fi.sequence.chroma_sampling)
-    || luma_only
-    || fi.config.chroma_sampling == ChromaSampling::Cs400
-  {
-    return (partition_has_coeff, tx_dist);
-  };
-  debug_assert!(has_chroma(
-    tile_bo,
-    bsize,
-    xdec,
-    ydec,
-    fi.sequence.chroma_sampling
-  ));
+  let do_chroma =
+    has_chroma(tile_bo, bsize, xdec, ydec, fi.sequence.chroma_sampling)
+      && !luma_only
+      && fi.config.chroma_sampling != ChromaSampling::Cs400;
 
   let max_tx_size = max_txsize_rect_lookup[bsize as usize];
   debug_assert!(max_tx_size.block_size() <= BlockSize::BLOCK_64X64);
-  let uv_tx_size = bsize.largest_chroma_tx_size(xdec, ydec);
+  let uv_tx_size = if fi.lossless {
+    TxSize::TX_4X4
+  } else {
+    bsize.largest_chroma_tx_size(xdec, ydec)
+  };
 
-  let mut bw_uv = max_tx_size.width_mi() >> xdec;
-  let mut bh_uv = max_tx_size.height_mi() >> ydec;
-
-  if bw_uv == 0 || bh_uv == 0 {
-    bw_uv = 1;
-    bh_uv = 1;
-  }
+  // Chroma transform blocks of each 64x64 chunk
+  let mut bw_uv = (max_tx_size.width_mi() >> xdec).max(1);
+  let mut bh_uv = (max_tx_size.height_mi() >> ydec).max(1);
 
   bw_uv /= uv_tx_size.width_mi();
   bh_uv /= uv_tx_size.height_mi();
 
-  let uv_tx_type = if partition_has_coeff { tx_type } else { TxType::DCT_DCT }; // if inter mode, uv_tx_type == tx_type
+  // Blocks larger than 64x64 are coded in 64x64 chunks, each with its luma
+  // transform blocks followed by its chroma ones.
+  let chunks_w = bsize.width_mi() / max_tx_size.width_mi();
+  let chunks_h = bsize.height_mi() / max_tx_size.height_mi();
+
+  for chunk_y in 0..chunks_h {
+    for chunk_x in 0..chunks_w {
+      ts.qc.update(
+        qidx,
+        tx_size,
+        luma_mode.is_intra(),
+        fi.sequence.bit_depth,
+        fi.dc_delta_q[0],
+        0,
+      );
+
+      // TODO: If tx-parition more than only 1-level, this code does not work.
+      // It should recursively traverse the tx block that are split recursivelty by calling write_tx_tree(),
+      // as defined in https://aomediacodec.github.io/av1-spec/#transform-tree-syntax
+      for by in chunk_y * bh / chunks_h..(chunk_y + 1) * bh / chunks_h {
+        for bx in chunk_x * bw / chunks_w..(chunk_x + 1) * bw / chunks_w {
+          let tx_bo = TileBlockOffset(BlockOffset {
+            x: tile_bo.0.x + bx * tx_size.width_mi(),
+            y: tile_bo.0.y + by * tx_size.height_mi(),
+          });
+          if tx_bo.0.x >= ts.mi_width || tx_bo.0.y >= ts.mi_height {
+            continue;
+          }
+
+          let po = tx_bo.plane_offset(&ts.input.planes[0].cfg);
+          let (has_coeff, dist) = encode_tx_block(
+            fi,
+            ts,
+            cw,
+            w,
+            0,
+            tile_bo,
+            0,
+            0,
+            tx_bo,
+            luma_mode,
+            tx_size,
+            tx_type,
+            bsize,
+            po,
+            skip,
+            qidx,
+            ac,
+            IntraParam::AngleDelta(angle_delta_y),
+            rdo_type,
+            need_recon_pixel,
+          );
+          partition_has_coeff |= has_coeff;
+          tx_dist += dist;
+        }
+      }
 
-  for p in 1..3 {
-    ts.qc.update(
-      qidx,
-      uv_tx_size,
-      false,
-      fi.sequence.bit_depth,
-      fi.dc_delta_q[p],
-      fi.ac_delta_q[p],
-    );
+      if !do_chroma {
+        continue;
+      }
 
-    for by in 0..bh_uv {
-      for bx in 0..bw_uv {
-        let tx_bo = TileBlockOffset(BlockOffset {
-          x: tile_bo.0.x + ((bx * uv_tx_size.width_mi()) << xdec)
-            - (max_tx_size.width_mi() == 1) as usize * xdec,
-          y: tile_bo.0.y + ((by * uv_tx_size.height_mi()) << ydec)
-            - (max_tx_size.height_mi() == 1) as usize * ydec,
-        });
+      let uv_tx_type =
+        if partition_has_coeff { tx_type } else { TxType::DCT_DCT }; // if inter mode, uv_tx_type == tx_type
 
-        let mut po = tile_bo.plane_offset(&ts.input.planes[p].cfg);
-        po.x += (bx * uv_tx_size.width()) as isize;
-        po.y += (by * uv_tx_size.height()) as isize;
-        let (has_coeff, dist) = encode_tx_block(
-          fi,
-          ts,
-          cw,
-          w,
-          p,
-          tile_bo,
-          bx,
-          by,
-          tx_bo,
-          luma_mode,
-          uv_tx_size,
-          uv_tx_type,
-          bsize,
-          po,
-          skip,
+      for p in 1..3 {
+        ts.qc.update(
           qidx,
-          ac,
-          IntraParam::AngleDelta(angle_delta_y),
-          rdo_type,
-          need_recon_pixel,
+          uv_tx_size,
+          false,
+          fi.sequence.bit_depth,
+          fi.dc_delta_q[p],
+          fi.ac_delta_q[p],
         );
-        partition_has_coeff |= has_coeff;
-        tx_dist += dist;
+
+        for by in chunk_y * bh_uv..(chunk_y + 1) * bh_uv {
+          for bx in chunk_x * bw_uv..(chunk_x + 1) * bw_uv {
+            let tx_bo = TileBlockOffset(BlockOffset {
+              x: tile_bo.0.x + ((bx * uv_tx_size.width_mi()) << xdec)
+                - (max_tx_size.width_mi() == 1) as usize * xdec,
+              y: tile_bo.0.y + ((by * uv_tx_size.height_mi()) << ydec)
+                - (max_tx_size.height_mi() == 1) as usize * ydec,
+            });
+
+            let mut po = tile_bo.plane_offset(&ts.input.planes[p].cfg);
+            po.x += (bx * uv_tx_size.width()) as isize;
+            po.y += (by * uv_tx_size.height()) as isize;
+            let (has_coeff, dist) = encode_tx_block(
+              fi,
+              ts,
+              cw,
+              w,
+              p,
+              tile_bo,
+              bx,
+              by,
+              tx_bo,
+              luma_mode,
+              uv_tx_size,
+              uv_tx_type,
+              bsize,
+              po,
+              skip,
+              qidx,
+              ac,
+              IntraParam::AngleDelta(angle_delta_y),
+              rdo_type,
+              need_recon_pixel,
+            );
+            partition_has_coeff |= has_coeff;
+            tx_dist += dist;
+          }
+        }
       }
     }
   }
@@ -2302,6 +3174,23 @@ pub fn encode_block_with_modes<T: Pixel>(
   // Set correct segmentation ID before encoding and before
   // rdo_tx_size_type().
   cw.bc.blocks.set_segmentation_idx(tile_bo, bsize, mode_decision.sidx);
+  cw.bc.blocks.set_palette(tile_bo, bsize, mode_decision.palette);
+  cw.bc.blocks.set_filter_intra_mode(
+    tile_bo,
+    bsize,
+    mode_decision.filter_intra_mode,
+  );
+  let motion_mode = if mode_decision.motion_mode == MotionMode::WARPED_CAUSAL
+    && cw
+      .local_warp_params(fi, tile_bo, bsize, mode_luma, ref_frames, mvs[0])
+      .is_some()
+  {
+    MotionMode::WARPED_CAUSAL
+  } else {
+    MotionMode::SIMPLE_TRANSLATION
+  };
+  cw.bc.blocks.set_motion_mode(tile_bo, bsize, motion_mode);
+  cw.bc.blocks.set_interp_filter(tile_bo, bsize, mode_decision.interp_filter);
 
   let mut mv_stack = ArrayVec::<[CandidateMV; 9]>::new();
   let is_compound = ref_frames[1] != NONE_FRAME;
@@ -2318,7 +3207,7 @@ pub fn encode_block_with_modes<T: Pixel>(
   };
 
   cdef_coded = encode_block_pre_cdef(
-    &fi.sequence,
+    fi,
     ts,
     cw,
     if cdef_coded { w_post_cdef } else { w_pre_cdef },
@@ -2350,6 +3239,83 @@ pub fn encode_block_with_modes<T: Pixel>(
   );
 }
 
+/// The extended partition types to search for a square block of size
+/// `bsize` that does not need to be split at the frame edges.
+fn ext_partition_types<T: Pixel>(
+  fi: &FrameInvariants<T>, bsize: BlockSize,
+) -> ArrayVec<[PartitionType; 6]> {
+  let mut partition_types = ArrayVec::new();
+  if !fi.config.speed_settings.ext_partition_types
+    || bsize < BlockSize::BLOCK_16X16
+    || bsize <= fi.partition_range.min
+  {
+    return partition_types;
+  }
+
+  // FIXME: the vertical ones are skipped for 4:2:2 like PARTITION_VERT
+  let is_422 = fi.sequence.chroma_sampling == ChromaSampling::Cs422;
+  partition_types.push(PartitionType::PARTITION_HORZ_A);
+  partition_types.push(PartitionType::PARTITION_HORZ_B);
+  if !is_422 {
+    partition_types.push(PartitionType::PARTITION_VERT_A);
+    partition_types.push(PartitionType::PARTITION_VERT_B);
+  }
+  // HORZ_4 and VERT_4 are not allowed for 128x128 blocks
+  if bsize != BlockSize::BLOCK_128X128
+    && bsize.width() >> 2 >= fi.partition_range.min.width()
+  {
+    partition_types.push(PartitionType::PARTITION_HORZ_4);
+    if !is_422 {
+      partition_types.push(PartitionType::PARTITION_VERT_4);
+    }
+  }
+  partition_types
+}
+
+/// Chooses the modes of a block of an extended partition, which is not
+/// partitioned any further, and encodes it.
+fn encode_leaf_bottomup<T: Pixel, W: Writer>(
+  fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
+  cw: &mut ContextWriter, w_pre_cdef: &mut W, w_post_cdef: &mut W,
+  bsize: BlockSize, tile_bo: TileBlockOffset, inter_cfg: &InterConfig,
+) -> PartitionGroupParameters {
+  let mode_decision = rdo_mode_decision(fi, ts, cw, bsize, tile_bo, inter_cfg);
+
+  if !mode_decision.pred_mode_luma.is_intra()
+    && mode_decision.ref_frames[0] != INTRA_FRAME
+  {
+    save_block_motion(
+      ts,
+      mode_decision.bsize,
+      mode_decision.bo,
+      mode_decision.ref_frames[0].to_index(),
+      mode_decision.mvs[0],
+    );
+  }
+
+  encode_block_with_modes(
+    fi,
+    ts,
+    cw,
+    w_pre_cdef,
+    w_post_cdef,
+    bsize,
+    tile_bo,
+    &mode_decision,
+    RDOType::PixelDistRealRate,
+    true,
+  );
+
+  let rd_cost = mode_decision.rd_cost;
+  let mut part_modes = ArrayVec::new();
+  part_modes.push(mode_decision);
+  PartitionGroupParameters {
+    rd_cost,
+    part_type: PartitionType::PARTITION_NONE,
+    part_modes,
+  }
+}
+
 fn encode_partition_bottomup<T: Pixel, W: Writer>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
   cw: &mut ContextWriter, w_pre_cdef: &mut W, w_post_cdef: &mut W,
@@ -2376,9 +3342,6 @@ fn encode_partition_bottomup<T: Pixel, W: Writer>(
   let is_straddle_x = tile_bo.0.x + bsize.width_mi() > ts.mi_width;
   let is_straddle_y = tile_bo.0.y + bsize.height_mi() > ts.mi_height;
 
-  // TODO: Update for 128x128 superblocks
-  assert!(fi.partition_range.max <= BlockSize::BLOCK_64X64);
-
   let must_split =
     is_square && (bsize > fi.partition_range.max || !has_cols || !has_rows);
 
@@ -2413,7 +3376,9 @@ fn encode_partition_bottomup<T: Pixel, W: Writer>(
     let mode_decision =
       rdo_mode_decision(fi, ts, cw, bsize, tile_bo, inter_cfg);
 
-    if !mode_decision.pred_mode_luma.is_intra() {
+    if !mode_decision.pred_mode_luma.is_intra()
+      && mode_decision.ref_frames[0] != INTRA_FRAME
+    {
       // Fill the saved motion structure
       save_block_motion(
         ts,
@@ -2452,7 +3417,7 @@ fn encode_partition_bottomup<T: Pixel, W: Writer>(
   if can_split {
     debug_assert!(is_square);
 
-    let mut partition_types = ArrayVec::<[PartitionType; 3]>::new();
+    let mut partition_types = ArrayVec::<[PartitionType; 9]>::new();
     if fi.config.speed_settings.non_square_partition
       || is_straddle_x
       || is_straddle_y
@@ -2464,6 +3429,9 @@ fn encode_partition_bottomup<T: Pixel, W: Writer>(
         partition_types.push(PartitionType::PARTITION_VERT);
       }
     }
+    if !must_split {
+      partition_types.extend(ext_partition_types(fi, bsize));
+    }
     partition_types.push(PartitionType::PARTITION_SPLIT);
 
     for partition in partition_types {
@@ -2486,9 +3454,6 @@ fn encode_partition_bottomup<T: Pixel, W: Writer>(
       w_pre_cdef.rollback(&w_pre_checkpoint);
       w_post_cdef.rollback(&w_post_checkpoint);
 
-      let subsize = bsize.subsize(partition);
-      let hbsw = subsize.width_mi(); // Half the block size width in blocks
-      let hbsh = subsize.height_mi(); // Half the block size height in blocks
       let mut child_modes = ArrayVec::<[PartitionParameters; 4]>::new();
       rd_cost = 0.0;
 
@@ -2501,42 +3466,41 @@ fn encode_partition_bottomup<T: Pixel, W: Writer>(
           compute_rd_cost(fi, w.tell_frac() - tell, ScaledDistortion::zero());
       }
 
-      let four_partitions = [
-        tile_bo,
-        TileBlockOffset(BlockOffset {
-          x: tile_bo.0.x + hbsw as usize,
-          y: tile_bo.0.y,
-        }),
-        TileBlockOffset(BlockOffset {
-          x: tile_bo.0.x,
-          y: tile_bo.0.y + hbsh as usize,
-        }),
-        TileBlockOffset(BlockOffset {
-          x: tile_bo.0.x + hbsw as usize,
-          y: tile_bo.0.y + hbsh as usize,
-        }),
-      ];
-      let partitions = get_sub_partitions(&four_partitions, partition);
+      let partitions = get_sub_partitions(tile_bo, bsize, partition);
 
       early_exit = false;
       // If either of horz or vert partition types is being tested,
       // two partitioned rectangles, defined in 'partitions', of the current block
       // is passed to encode_partition_bottomup()
-      for offset in partitions {
+      for (offset, subsize) in partitions {
         if offset.0.x >= ts.mi_width || offset.0.y >= ts.mi_height {
           continue;
         }
-        let child_rdo_output = encode_partition_bottomup(
-          fi,
-          ts,
-          cw,
-          w_pre_cdef,
-          w_post_cdef,
-          subsize,
-          offset,
-          best_rd,
-          inter_cfg,
-        );
+        // The blocks of the extended partitions are not partitioned further
+        let child_rdo_output = if partition > PartitionType::PARTITION_SPLIT {
+          encode_leaf_bottomup(
+            fi,
+            ts,
+            cw,
+            w_pre_cdef,
+            w_post_cdef,
+            subsize,
+            offset,
+            inter_cfg,
+          )
+        } else {
+          encode_partition_bottomup(
+            fi,
+            ts,
+            cw,
+            w_pre_cdef,
+            w_post_cdef,
+            subsize,
+            offset,
+            best_rd,
+            inter_cfg,
+          )
+        };
         let cost = child_rdo_output.rd_cost;
         assert!(cost >= 0.0);
 
@@ -2577,7 +3541,6 @@ fn encode_partition_bottomup<T: Pixel, W: Writer>(
       w_post_cdef.rollback(&w_post_checkpoint);
 
       assert!(best_partition != PartitionType::PARTITION_NONE || !must_split);
-      let subsize = bsize.subsize(best_partition);
 
       if bsize >= BlockSize::BLOCK_8X8 {
         let w: &mut W =
@@ -2585,9 +3548,8 @@ fn encode_partition_bottomup<T: Pixel, W: Writer>(
         cw.write_partition(w, tile_bo, best_partition, bsize);
       }
       for mode in rdo_output.part_modes.clone() {
-        assert!(subsize == mode.bsize);
-
-        if !mode.pred_mode_luma.is_intra() {
+        if !mode.pred_mode_luma.is_intra() && mode.ref_frames[0] != INTRA_FRAME
+        {
           save_block_motion(
             ts,
             mode.bsize,
@@ -2621,11 +3583,7 @@ fn encode_partition_bottomup<T: Pixel, W: Writer>(
     && (bsize == BlockSize::BLOCK_8X8
       || best_partition != PartitionType::PARTITION_SPLIT)
   {
-    cw.bc.update_partition_context(
-      tile_bo,
-      bsize.subsize(best_partition),
-      bsize,
-    );
+    cw.bc.update_ext_partition_context(tile_bo, best_partition, bsize);
   }
 
   rdo_output.rd_cost = best_rd;
@@ -2637,6 +3595,191 @@ fn encode_partition_bottomup<T: Pixel, W: Writer>(
   rdo_output
 }
 
+fn encode_block_topdown<T: Pixel, W: Writer>(
+  fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
+  cw: &mut ContextWriter, w_pre_cdef: &mut W, w_post_cdef: &mut W,
+  bsize: BlockSize, tile_bo: TileBlockOffset,
+  part_decision: &PartitionParameters,
+) {
+  let mut mode_luma = part_decision.pred_mode_luma;
+  let mut mode_chroma = part_decision.pred_mode_chroma;
+
+  let cfl = part_decision.pred_cfl_params;
+  let skip = part_decision.skip;
+  let ref_frames = part_decision.ref_frames;
+  let mvs = part_decision.mvs;
+  let mut cdef_coded = cw.bc.cdef_coded;
+
+  // Set correct segmentation ID before encoding and before
+  // rdo_tx_size_type().
+  cw.bc.blocks.set_segmentation_idx(tile_bo, bsize, part_decision.sidx);
+  cw.bc.blocks.set_palette(tile_bo, bsize, part_decision.palette);
+  cw.bc.blocks.set_filter_intra_mode(
+    tile_bo,
+    bsize,
+    part_decision.filter_intra_mode,
+  );
+
+  // NOTE: Cannot avoid calling rdo_tx_size_type() here again,
+  // because, with top-down partition RDO, the neighboring contexts
+  // of current partition can change, i.e. neighboring partitions can split down more.
+  let (tx_size, tx_type) = rdo_tx_size_type(
+    fi, ts, cw, bsize, tile_bo, mode_luma, ref_frames, mvs, skip,
+  );
+
+  let mut mv_stack = ArrayVec::<[CandidateMV; 9]>::new();
+  let is_compound = ref_frames[1] != NONE_FRAME;
+  let mode_context =
+    cw.find_mvrefs(tile_bo, ref_frames, &mut mv_stack, bsize, fi, is_compound);
+
+  // TODO: proper remap when is_compound is true
+  // Intra block copy always signals its DV explicitly as NEWMV.
+  if !mode_luma.is_intra() && ref_frames[0] != INTRA_FRAME {
+    if is_compound && mode_luma != PredictionMode::GLOBAL_GLOBALMV {
+      let match0 = mv_stack[0].this_mv.row == mvs[0].row
+        && mv_stack[0].this_mv.col == mvs[0].col;
+      let match1 = mv_stack[0].comp_mv.row == mvs[1].row
+        && mv_stack[0].comp_mv.col == mvs[1].col;
+
+      let match2 = mv_stack[1].this_mv.row == mvs[0].row
+        && mv_stack[1].this_mv.col == mvs[0].col;
+      let match3 = mv_stack[1].comp_mv.row == mvs[1].row
+        && mv_stack[1].comp_mv.col == mvs[1].col;
+
+      let match4 = mv_stack.len() > 2 && mv_stack[2].this_mv == mvs[0];
+      let match5 = mv_stack.len() > 2 && mv_stack[2].comp_mv == mvs[1];
+
+      let match6 = mv_stack.len() > 3 && mv_stack[3].this_mv == mvs[0];
+      let match7 = mv_stack.len() > 3 && mv_stack[3].comp_mv == mvs[1];
+
+      let global_mvs = [
+        cw.global_mv(fi, tile_bo, bsize, ref_frames[0]),
+        cw.global_mv(fi, tile_bo, bsize, ref_frames[1]),
+      ];
+      let global_warps = block_warps(
+        fi,
+        cw,
+        PredictionMode::GLOBAL_GLOBALMV,
+        ref_frames,
+        global_mvs,
+        bsize,
+        tile_bo,
+      );
+
+      mode_luma = if match0 && match1 {
+        PredictionMode::NEAREST_NEARESTMV
+      } else if match2 && match3 {
+        PredictionMode::NEAR_NEAR0MV
+      } else if match4 && match5 {
+        PredictionMode::NEAR_NEAR1MV
+      } else if match6 && match7 {
+        PredictionMode::NEAR_NEAR2MV
+      } else if match0 {
+        PredictionMode::NEAREST_NEWMV
+      } else if match1 {
+        PredictionMode::NEW_NEARESTMV
+      } else {
+        PredictionMode::NEW_NEWMV
+      };
+
+      if mode_luma != PredictionMode::NEAREST_NEARESTMV
+        && mvs == global_mvs
+        && global_warps == [None; 2]
+      {
+        mode_luma = PredictionMode::GLOBAL_GLOBALMV;
+      }
+      mode_chroma = mode_luma;
+    } else if !is_compound && mode_luma != PredictionMode::GLOBALMV {
+      mode_luma = PredictionMode::NEWMV;
+      for (c, m) in mv_stack.iter().take(4).zip(
+        [
+          PredictionMode::NEARESTMV,
+          PredictionMode::NEAR0MV,
+          PredictionMode::NEAR1MV,
+          PredictionMode::NEAR2MV,
+        ]
+        .iter(),
+      ) {
+        if c.this_mv.row == mvs[0].row && c.this_mv.col == mvs[0].col {
+          mode_luma = *m;
+        }
+      }
+      let global_mv = cw.global_mv(fi, tile_bo, bsize, ref_frames[0]);
+      if mode_luma == PredictionMode::NEWMV && mvs[0] == global_mv {
+        if mv_stack.is_empty() {
+          mode_luma = PredictionMode::NEARESTMV;
+        } else if mv_stack.len() == 1 {
+          mode_luma = PredictionMode::NEAR0MV;
+        } else if !fi.gm_params[ref_frames[0].to_index()].is_warp() {
+          // A warped global motion would change the prediction.
+          mode_luma = PredictionMode::GLOBALMV;
+        }
+      }
+      mode_chroma = mode_luma;
+    }
+
+    save_block_motion(
+      ts,
+      part_decision.bsize,
+      part_decision.bo,
+      part_decision.ref_frames[0].to_index(),
+      part_decision.mvs[0],
+    );
+  }
+
+  let motion_mode = if part_decision.motion_mode == MotionMode::WARPED_CAUSAL
+    && cw
+      .local_warp_params(fi, tile_bo, bsize, mode_luma, ref_frames, mvs[0])
+      .is_some()
+  {
+    MotionMode::WARPED_CAUSAL
+  } else {
+    MotionMode::SIMPLE_TRANSLATION
+  };
+  cw.bc.blocks.set_motion_mode(tile_bo, bsize, motion_mode);
+  // The mode may have changed to one implying the regular filter.
+  let interp_filter =
+    if cw.interp_filter_coded(fi, bsize, mode_luma, ref_frames, motion_mode) {
+      part_decision.interp_filter
+    } else {
+      [fi.default_filter; 2]
+    };
+  cw.bc.blocks.set_interp_filter(tile_bo, bsize, interp_filter);
+
+  // FIXME: every final block that has gone through the RDO decision process is encoded twice
+  cdef_coded = encode_block_pre_cdef(
+    fi,
+    ts,
+    cw,
+    if cdef_coded { w_post_cdef } else { w_pre_cdef },
+    bsize,
+    tile_bo,
+    skip,
+  );
+  encode_block_post_cdef(
+    fi,
+    ts,
+    cw,
+    if cdef_coded { w_post_cdef } else { w_pre_cdef },
+    mode_luma,
+    mode_chroma,
+    part_decision.angle_delta,
+    ref_frames,
+    mvs,
+    bsize,
+    tile_bo,
+    skip,
+    cfl,
+    tx_size,
+    tx_type,
+    mode_context,
+    &mv_stack,
+    RDOType::PixelDistRealRate,
+    true,
+    true,
+  );
+}
+
 fn encode_partition_topdown<T: Pixel, W: Writer>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
   cw: &mut ContextWriter, w_pre_cdef: &mut W, w_post_cdef: &mut W,
@@ -2652,9 +3795,6 @@ fn encode_partition_topdown<T: Pixel, W: Writer>(
   let has_cols = tile_bo.0.x + hbs < ts.mi_width;
   let has_rows = tile_bo.0.y + hbs < ts.mi_height;
 
-  // TODO: Update for 128x128 superblocks
-  assert!(fi.partition_range.max <= BlockSize::BLOCK_64X64);
-
   let must_split =
     is_square && (bsize > fi.partition_range.max || !has_cols || !has_rows);
 
@@ -2680,12 +3820,13 @@ fn encode_partition_topdown<T: Pixel, W: Writer>(
   } else if can_split {
     debug_assert!(bsize.is_sqr());
     // Blocks of sizes within the supported range are subjected to a partitioning decision
-    let mut partition_types = ArrayVec::<[PartitionType; 3]>::new();
+    let mut partition_types = ArrayVec::<[PartitionType; 8]>::new();
 
     partition_types.push(PartitionType::PARTITION_SPLIT);
     if !must_split {
       partition_types.push(PartitionType::PARTITION_NONE);
     }
+    partition_types.extend(ext_partition_types(fi, bsize));
     rdo_output = rdo_partition_decision(
       fi,
       ts,
@@ -2728,153 +3869,35 @@ fn encode_partition_topdown<T: Pixel, W: Writer>(
         rdo_mode_decision(fi, ts, cw, bsize, tile_bo, inter_cfg)
       };
 
-      let mut mode_luma = part_decision.pred_mode_luma;
-      let mut mode_chroma = part_decision.pred_mode_chroma;
-
-      let cfl = part_decision.pred_cfl_params;
-      let skip = part_decision.skip;
-      let ref_frames = part_decision.ref_frames;
-      let mvs = part_decision.mvs;
-      let mut cdef_coded = cw.bc.cdef_coded;
-
-      // Set correct segmentation ID before encoding and before
-      // rdo_tx_size_type().
-      cw.bc.blocks.set_segmentation_idx(tile_bo, bsize, part_decision.sidx);
-
-      // NOTE: Cannot avoid calling rdo_tx_size_type() here again,
-      // because, with top-down partition RDO, the neighboring contexts
-      // of current partition can change, i.e. neighboring partitions can split down more.
-      let (tx_size, tx_type) = rdo_tx_size_type(
-        fi, ts, cw, bsize, tile_bo, mode_luma, ref_frames, mvs, skip,
-      );
-
-      let mut mv_stack = ArrayVec::<[CandidateMV; 9]>::new();
-      let is_compound = ref_frames[1] != NONE_FRAME;
-      let mode_context = cw.find_mvrefs(
-        tile_bo,
-        ref_frames,
-        &mut mv_stack,
-        bsize,
-        fi,
-        is_compound,
-      );
-
-      // TODO: proper remap when is_compound is true
-      if !mode_luma.is_intra() {
-        if is_compound && mode_luma != PredictionMode::GLOBAL_GLOBALMV {
-          let match0 = mv_stack[0].this_mv.row == mvs[0].row
-            && mv_stack[0].this_mv.col == mvs[0].col;
-          let match1 = mv_stack[0].comp_mv.row == mvs[1].row
-            && mv_stack[0].comp_mv.col == mvs[1].col;
-
-          let match2 = mv_stack[1].this_mv.row == mvs[0].row
-            && mv_stack[1].this_mv.col == mvs[0].col;
-          let match3 = mv_stack[1].comp_mv.row == mvs[1].row
-            && mv_stack[1].comp_mv.col == mvs[1].col;
-
-          let match4 = mv_stack.len() > 2 && mv_stack[2].this_mv == mvs[0];
-          let match5 = mv_stack.len() > 2 && mv_stack[2].comp_mv == mvs[1];
-
-          let match6 = mv_stack.len() > 3 && mv_stack[3].this_mv == mvs[0];
-          let match7 = mv_stack.len() > 3 && mv_stack[3].comp_mv == mvs[1];
-
-          mode_luma = if match0 && match1 {
-            PredictionMode::NEAREST_NEARESTMV
-          } else if match2 && match3 {
-            PredictionMode::NEAR_NEAR0MV
-          } else if match4 && match5 {
-            PredictionMode::NEAR_NEAR1MV
-          } else if match6 && match7 {
-            PredictionMode::NEAR_NEAR2MV
-          } else if match0 {
-            PredictionMode::NEAREST_NEWMV
-          } else if match1 {
-            PredictionMode::NEW_NEARESTMV
-          } else {
-            PredictionMode::NEW_NEWMV
-          };
-
-          if mode_luma != PredictionMode::NEAREST_NEARESTMV
-            && mvs[0].row == 0
-            && mvs[0].col == 0
-            && mvs[1].row == 0
-            && mvs[1].col == 0
-          {
-            mode_luma = PredictionMode::GLOBAL_GLOBALMV;
-          }
-          mode_chroma = mode_luma;
-        } else if !is_compound && mode_luma != PredictionMode::GLOBALMV {
-          mode_luma = PredictionMode::NEWMV;
-          for (c, m) in mv_stack.iter().take(4).zip(
-            [
-              PredictionMode::NEARESTMV,
-              PredictionMode::NEAR0MV,
-              PredictionMode::NEAR1MV,
-              PredictionMode::NEAR2MV,
-            ]
-            .iter(),
-          ) {
-            if c.this_mv.row == mvs[0].row && c.this_mv.col == mvs[0].col {
-              mode_luma = *m;
-            }
-          }
-          if mode_luma == PredictionMode::NEWMV
-            && mvs[0].row == 0
-            && mvs[0].col == 0
-          {
-            mode_luma = if mv_stack.is_empty() {
-              PredictionMode::NEARESTMV
-            } else if mv_stack.len() == 1 {
-              PredictionMode::NEAR0MV
-            } else {
-              PredictionMode::GLOBALMV
-            };
-          }
-          mode_chroma = mode_luma;
-        }
-
-        save_block_motion(
-          ts,
-          part_decision.bsize,
-          part_decision.bo,
-          part_decision.ref_frames[0].to_index(),
-          part_decision.mvs[0],
-        );
-      }
-
-      // FIXME: every final block that has gone through the RDO decision process is encoded twice
-      cdef_coded = encode_block_pre_cdef(
-        &fi.sequence,
-        ts,
-        cw,
-        if cdef_coded { w_post_cdef } else { w_pre_cdef },
-        bsize,
-        tile_bo,
-        skip,
-      );
-      encode_block_post_cdef(
+      encode_block_topdown(
         fi,
         ts,
         cw,
-        if cdef_coded { w_post_cdef } else { w_pre_cdef },
-        mode_luma,
-        mode_chroma,
-        part_decision.angle_delta,
-        ref_frames,
-        mvs,
+        w_pre_cdef,
+        w_post_cdef,
         bsize,
         tile_bo,
-        skip,
-        cfl,
-        tx_size,
-        tx_type,
-        mode_context,
-        &mv_stack,
-        RDOType::PixelDistRealRate,
-        true,
-        true,
+        &part_decision,
       );
     }
+    PARTITION_HORZ_A | PARTITION_HORZ_B | PARTITION_VERT_A
+    | PARTITION_VERT_B | PARTITION_HORZ_4 | PARTITION_VERT_4 => {
+      // The blocks of the extended partitions are not partitioned further,
+      // and their modes are known from an rdo_partition_decision() call
+      debug_assert!(!rdo_output.part_modes.is_empty());
+      for mode in rdo_output.part_modes.iter() {
+        encode_block_topdown(
+          fi,
+          ts,
+          cw,
+          w_pre_cdef,
+          w_post_cdef,
+          mode.bsize,
+          mode.bo,
+          mode,
+        );
+      }
+    }
     PARTITION_SPLIT | PARTITION_HORZ | PARTITION_VERT => {
       if !rdo_output.part_modes.is_empty() {
         debug_assert!(can_split && !must_split);
@@ -2902,26 +3925,9 @@ fn encode_partition_topdown<T: Pixel, W: Writer>(
         }
       } else {
         debug_assert!(must_split);
-        let hbsw = subsize.width_mi(); // Half the block size width in blocks
-        let hbsh = subsize.height_mi(); // Half the block size height in blocks
-        let four_partitions = [
-          tile_bo,
-          TileBlockOffset(BlockOffset {
-            x: tile_bo.0.x + hbsw as usize,
-            y: tile_bo.0.y,
-          }),
-          TileBlockOffset(BlockOffset {
-            x: tile_bo.0.x,
-            y: tile_bo.0.y + hbsh as usize,
-          }),
-          TileBlockOffset(BlockOffset {
-            x: tile_bo.0.x + hbsw as usize,
-            y: tile_bo.0.y + hbsh as usize,
-          }),
-        ];
-        let partitions = get_sub_partitions(&four_partitions, partition);
-
-        partitions.iter().for_each(|&offset| {
+        let partitions = get_sub_partitions(tile_bo, bsize, partition);
+
+        partitions.iter().for_each(|&(offset, subsize)| {
           encode_partition_topdown(
             fi,
             ts,
@@ -2944,7 +3950,7 @@ fn encode_partition_topdown<T: Pixel, W: Writer>(
     && (bsize == BlockSize::BLOCK_8X8
       || partition != PartitionType::PARTITION_SPLIT)
   {
-    cw.bc.update_partition_context(tile_bo, subsize, bsize);
+    cw.bc.update_ext_partition_context(tile_bo, partition, bsize);
   }
 }
 
@@ -2952,7 +3958,6 @@ fn encode_partition_topdown<T: Pixel, W: Writer>(
 pub(crate) fn build_coarse_pmvs<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, inter_cfg: &InterConfig,
 ) -> Vec<[Option<MotionVector>; REF_FRAMES]> {
-  assert!(!fi.sequence.use_128x128_superblock);
   if ts.mi_width >= 16 && ts.mi_height >= 16 {
     let mut frame_pmvs = Vec::with_capacity(ts.sb_width * ts.sb_height);
     for sby in 0..ts.sb_height {
@@ -3001,6 +4006,11 @@ fn encode_tile_group<T: Pixel>(
   let mut blocks = FrameBlocks::new(fi.w_in_b, fi.h_in_b);
   let ti = &fi.tiling;
 
+  // The loop filter levels follow the quantizer of the superblocks, but
+  // cannot be adapted with intra block copy.
+  fs.deblock.block_deltas_enabled = fi.delta_q_present && !fi.allow_intrabc;
+  fs.deblock.segment_deltas = fs.segmentation.deblock_deltas();
+
   let initial_cdf = get_initial_cdfcontext(fi);
   // dynamic allocation: once per frame
   let mut cdfs = vec![initial_cdf; ti.tile_count()];
@@ -3022,12 +4032,19 @@ fn encode_tile_group<T: Pixel>(
     fs.enc_stats += &tile_stats;
   }
 
+  if fi.enable_segmentation {
+    fs.segment_map = Arc::new(SegmentMap::new(&blocks));
+  }
+
   /* Frame deblocking operates over a single large tile wrapping the
    * frame rather than the frame itself so that deblocking is
    * available inside RDO when needed */
-  /* TODO: Don't apply if lossless */
   let levels;
-  {
+  if fi.allow_intrabc || fi.lossless {
+    // All the in-loop filters are disabled with intra block copy and in
+    // lossless frames
+    levels = [0; MAX_PLANES + 1];
+  } else {
     let ts = &mut fs.as_tile_state_mut();
     let rec = &mut ts.rec;
     levels = deblock_filter_optimize(
@@ -3055,25 +4072,24 @@ fn encode_tile_group<T: Pixel>(
     );
   }
 
-  if fi.sequence.enable_restoration {
+  if fi.allow_intrabc || fi.lossless {
+    // Neither CDEF nor loop restoration are signaled
+  } else if fi.sequence.enable_restoration && !fi.use_superres() {
     // Until the loop filters are better pipelined, we'll need to keep
     // around a copy of both the deblocked and cdeffed frame.
     let deblocked_frame = fs.rec.clone();
 
-    /* TODO: Don't apply if lossless */
     if fi.sequence.enable_cdef {
       let ts = &mut fs.as_tile_state_mut();
       let rec = &mut ts.rec;
       cdef_filter_tile(fi, &deblocked_frame, &blocks.as_tile_blocks(), rec);
     }
-    /* TODO: Don't apply if lossless */
     fs.restoration.lrf_filter_frame(
       Arc::make_mut(&mut fs.rec),
       &deblocked_frame,
       fi,
     );
   } else {
-    /* TODO: Don't apply if lossless */
     if fi.sequence.enable_cdef {
       let deblocked_frame = fs.rec.clone();
       let ts = &mut fs.as_tile_state_mut();
@@ -3082,6 +4098,10 @@ fn encode_tile_group<T: Pixel>(
     }
   }
 
+  if fi.use_superres() {
+    fs.rec = Arc::new(upscale_frame(fi, &fs.rec));
+  }
+
   let (idx_max, max_len) = raw_tiles
     .iter()
     .map(Vec::len)
@@ -3197,7 +4217,6 @@ pub(crate) fn build_half_res_pmvs<T: Pixel>(
             None
           };
 
-          assert!(!fi.sequence.use_128x128_superblock);
           pmvs[1][r] = estimate_motion_ss2(
             fi,
             ts,
@@ -3290,8 +4309,6 @@ pub(crate) fn build_full_res_pmvs<T: Pixel>(
       if pmvs[r].is_none() {
         pmvs[r] = half_res_pmvs_this_block[0][r];
         if let Some(pmv) = pmvs[r] {
-          assert!(!fi.sequence.use_128x128_superblock);
-
           let pmvs_w = if sbx > 0 {
             half_res_pmvs[sby * ts.sb_width + sbx - 1]
           } else {
@@ -3414,8 +4431,14 @@ pub struct SBSQueueEntry {
   pub sbo: TileSuperBlockOffset,
   pub lru_index: [i32; MAX_PLANES],
   pub cdef_coded: bool,
+  /// 64x64 unit whose CDEF index is coded between w_pre_cdef and w_post_cdef
+  pub cdef_sbo: TileSuperBlockOffset,
   pub w_pre_cdef: WriterBase<WriterRecorder>,
   pub w_post_cdef: WriterBase<WriterRecorder>,
+  /// Further 64x64 units of a 128x128 superblock whose CDEF index is coded,
+  /// each with the symbols coded after it
+  pub w_post_cdef_units:
+    ArrayVec<[(TileSuperBlockOffset, WriterBase<WriterRecorder>); 3]>,
 }
 
 fn check_lf_queue<T: Pixel>(
@@ -3443,7 +4466,10 @@ fn check_lf_queue<T: Pixel>(
       }
       if check_queue {
         // yes, this entry is ready
-        if qe.cdef_coded || fi.sequence.enable_restoration {
+        if (qe.cdef_coded || fi.sequence.enable_restoration)
+          && !fi.allow_intrabc
+          && !fi.lossless
+        {
           // only RDO once for a given LRU.
 
           // One quirk worth noting: LRUs in different planes
@@ -3495,10 +4521,15 @@ fn check_lf_queue<T: Pixel>(
         qe.w_pre_cdef.replay(w);
         // Now code CDEF into the middle of the block
         if qe.cdef_coded {
-          let cdef_index = cw.bc.blocks.get_cdef(qe.sbo);
+          let cdef_index = cw.bc.blocks.get_cdef(qe.cdef_sbo);
           cw.write_cdef(w, cdef_index, fi.cdef_bits);
           // Code queued symbols that come after the CDEF index
           qe.w_post_cdef.replay(w);
+          for (cdef_sbo, w_post_cdef) in qe.w_post_cdef_units.iter_mut() {
+            let cdef_index = cw.bc.blocks.get_cdef(*cdef_sbo);
+            cw.write_cdef(w, cdef_index, fi.cdef_bits);
+            w_post_cdef.replay(w);
+          }
         }
         sbs_q.pop_front();
       }
@@ -3520,33 +4551,48 @@ fn encode_tile<'a, T: Pixel>(
 
   let bc = BlockContext::new(blocks);
   let mut cw = ContextWriter::new(fc, bc);
+  // The delta-q of the first superblock is coded from base_q_idx
+  cw.bc.current_qidx = fi.base_q_idx;
   let mut sbs_q: VecDeque<SBSQueueEntry> = VecDeque::new();
   let mut last_lru_ready = [-1; 3];
   let mut last_lru_rdoed = [-1; 3];
   let mut last_lru_coded = [-1; 3];
 
+  // Superblocks are coded in units of the sequence superblock size, while
+  // the tile state is addressed in 64x64 units
+  let sb_shift = ts.sb_size_log2 - SUPERBLOCK_TO_PLANE_SHIFT;
+  let sb_size = if sb_shift > 0 {
+    BlockSize::BLOCK_128X128
+  } else {
+    BlockSize::BLOCK_64X64
+  };
+
   // main loop
-  for sby in 0..ts.sb_height {
+  for sby in 0..ts.sb_height.align_power_of_two_and_shift(sb_shift) {
     cw.bc.reset_left_contexts(planes);
 
-    for sbx in 0..ts.sb_width {
-      let tile_sbo = TileSuperBlockOffset(SuperBlockOffset { x: sbx, y: sby });
+    for sbx in 0..ts.sb_width.align_power_of_two_and_shift(sb_shift) {
+      let tile_sbo = TileSuperBlockOffset(SuperBlockOffset {
+        x: sbx << sb_shift,
+        y: sby << sb_shift,
+      });
       let mut sbs_qe = SBSQueueEntry {
         sbo: tile_sbo,
         lru_index: [-1; MAX_PLANES],
         cdef_coded: false,
+        cdef_sbo: tile_sbo,
         w_pre_cdef: WriterRecorder::new(),
         w_post_cdef: WriterRecorder::new(),
+        w_post_cdef_units: ArrayVec::new(),
       };
 
       let tile_bo = tile_sbo.block_offset(0, 0);
       cw.bc.cdef_coded = false;
+      cw.bc.cdef_units.clear();
       cw.bc.code_deltas = fi.delta_q_present;
 
-      let is_straddle_sbx =
-        tile_bo.0.x + BlockSize::BLOCK_64X64.width_mi() > ts.mi_width;
-      let is_straddle_sby =
-        tile_bo.0.y + BlockSize::BLOCK_64X64.height_mi() > ts.mi_height;
+      let is_straddle_sbx = tile_bo.0.x + sb_size.width_mi() > ts.mi_width;
+      let is_straddle_sby = tile_bo.0.y + sb_size.height_mi() > ts.mi_height;
 
       // Encode SuperBlock
       if fi.config.speed_settings.encode_bottomup
@@ -3559,7 +4605,7 @@ fn encode_tile<'a, T: Pixel>(
           &mut cw,
           &mut sbs_qe.w_pre_cdef,
           &mut sbs_qe.w_post_cdef,
-          BlockSize::BLOCK_64X64,
+          sb_size,
           tile_bo,
           std::f64::MAX,
           inter_cfg,
@@ -3571,7 +4617,7 @@ fn encode_tile<'a, T: Pixel>(
           &mut cw,
           &mut sbs_qe.w_pre_cdef,
           &mut sbs_qe.w_post_cdef,
-          BlockSize::BLOCK_64X64,
+          sb_size,
           tile_bo,
           &None,
           inter_cfg,
@@ -3582,6 +4628,20 @@ fn encode_tile<'a, T: Pixel>(
         let mut check_queue = false;
         // queue our superblock for when the LRU is complete
         sbs_qe.cdef_coded = cw.bc.cdef_coded;
+        // Split off the symbols coded after each further CDEF index of a
+        // 128x128 superblock, last first
+        for (cdef_sbo, checkpoint) in cw.bc.cdef_units.iter().skip(1).rev() {
+          let w_post_cdef = sbs_qe.w_post_cdef.split_off(checkpoint);
+          sbs_qe.w_post_cdef_units.insert(0, (*cdef_sbo, w_post_cdef));
+        }
+        if let Some(&(cdef_sbo, _)) = cw.bc.cdef_units.first() {
+          sbs_qe.cdef_sbo = cdef_sbo;
+        }
+        // The last 64x64 unit of the superblock decides LRU readiness
+        let last_sbo = TileSuperBlockOffset(SuperBlockOffset {
+          x: (tile_sbo.0.x + (1 << sb_shift) - 1).min(ts.sb_width - 1),
+          y: (tile_sbo.0.y + (1 << sb_shift) - 1).min(ts.sb_height - 1),
+        });
         for pli in 0..planes {
           if let Some((lru_x, lru_y)) =
             ts.restoration.planes[pli].restoration_unit_index(tile_sbo, false)
@@ -3591,7 +4651,7 @@ fn encode_tile<'a, T: Pixel>(
               as i32;
             sbs_qe.lru_index[pli] = lru_index;
             if ts.restoration.planes[pli]
-              .restoration_unit_last_sb_for_rdo(fi, ts.sbo, tile_sbo)
+              .restoration_unit_last_sb_for_rdo(fi, ts.sbo, last_sbo)
             {
               last_lru_ready[pli] = lru_index;
               check_queue = true;
@@ -3624,7 +4684,6 @@ fn encode_tile<'a, T: Pixel>(
 
   if fi.sequence.enable_delayed_loopfilter_rdo {
     // Solve deblocking for just this tile
-    /* TODO: Don't apply if lossless */
     let deblock_levels = deblock_filter_optimize(
       fi,
       &ts.rec.as_const(),
@@ -3634,7 +4693,10 @@ fn encode_tile<'a, T: Pixel>(
       fi.height,
     );
 
-    if deblock_levels[0] != 0 || deblock_levels[1] != 0 {
+    if !fi.allow_intrabc
+      && !fi.lossless
+      && (deblock_levels[0] != 0 || deblock_levels[1] != 0)
+    {
       // copy reconstruction to a temp frame to restore it later
       let rec_copy = if planes == 3 {
         vec![
@@ -3733,12 +4795,8 @@ pub fn encode_show_existing_frame<T: Pixel>(
   write_obus(&mut packet, fi, fs, inter_cfg).unwrap();
   let map_idx = fi.frame_to_show_map_idx as usize;
   if let Some(ref rec) = fi.rec_buffer.frames[map_idx] {
-    let fs_rec = Arc::make_mut(&mut fs.rec);
-    let planes =
-      if fi.sequence.chroma_sampling == ChromaSampling::Cs400 { 1 } else { 3 };
-    for p in 0..planes {
-      fs_rec.planes[p].data.copy_from_slice(&rec.frame.planes[p].data);
-    }
+    // The shown frame may have been coded at another size.
+    fs.rec = rec.frame.clone();
   }
   packet
 }
@@ -3769,6 +4827,10 @@ pub fn encode_frame<T: Pixel>(
     fs.segmentation = get_initial_segmentation(fi);
     segmentation_optimize(fi, fs);
   }
+  if fi.allow_intrabc {
+    fs.block_hashes =
+      Some(BlockHashes::new(&fs.input.planes[0], fi.width, fi.height));
+  }
   let tile_group = encode_tile_group(fi, fs, inter_cfg);
 
   write_obus(&mut packet, fi, fs, inter_cfg).unwrap();
@@ -3796,13 +4858,28 @@ pub fn update_rec_buffer<T: Pixel>(
 ) {
   let rfs = Arc::new(ReferenceFrame {
     order_hint: fi.order_hint,
+    base_q_idx: fi.base_q_idx,
     frame: fs.rec.clone(),
+    width: fi.upscaled_width,
+    height: fi.height,
     input_hres: fs.input_hres.clone(),
     input_qres: fs.input_qres.clone(),
     cdfs: fs.cdfs,
-    frame_mvs: fs.frame_mvs.clone(),
+    frame_mvs: if fi.use_superres() {
+      // The motion field of a super-resolved frame is not projected.
+      let w_in_b = 2 * fi.upscaled_width.align_power_of_two_and_shift(3);
+      let mut vec = Vec::with_capacity(REF_FRAMES);
+      for _ in 0..REF_FRAMES {
+        vec.push(FrameMotionVectors::new(w_in_b, fi.h_in_b));
+      }
+      Arc::new(vec)
+    } else {
+      fs.frame_mvs.clone()
+    },
     output_frameno,
     segmentation: fs.segmentation,
+    segment_map: fs.segment_map.clone(),
+    gm_params: fi.gm_params,
   });
   for i in 0..(REF_FRAMES as usize) {
     if (fi.refresh_frame_flags & (1 << i)) != 0 {
@@ -17,7 +17,7 @@ use crate::transform::*;
 pub const PALETTE_BSIZE_CTXS: usize = 7;
 pub const PALETTE_Y_MODE_CONTEXTS: usize = 3;
 pub const PALETTE_UV_MODE_CONTEXTS: usize = 2;
-const PALETTE_COLOR_INDEX_CONTEXTS: usize = 5;
+pub const PALETTE_COLOR_INDEX_CONTEXTS: usize = 5;
 const CDFMAX: u16 = 32768;
 const BLOCK_SIZE_GROUPS: usize = 4;
 const RESTORE_SWITCHABLE_TYPES: usize = 3;
@@ -852,11 +852,9 @@ pub static default_cfl_alpha_cdf: [[u16; cdf_size!(CFL_ALPHABET_SIZE)];
   ),
 ];
 
-// This does not appear to be used in the rust project currently
-const SWITCHABLE_FILTERS: usize = 3;
-const SWITCHABLE_FILTER_CONTEXTS: usize = (SWITCHABLE_FILTERS + 1) * 4;
+pub const SWITCHABLE_FILTERS: usize = 3;
+pub const SWITCHABLE_FILTER_CONTEXTS: usize = (SWITCHABLE_FILTERS + 1) * 4;
 
-#[allow(unused)]
 pub static default_switchable_interp_cdf: [[u16;
   cdf_size!(SWITCHABLE_FILTERS)];
   SWITCHABLE_FILTER_CONTEXTS] = [
@@ -1078,7 +1076,6 @@ pub static default_wedge_idx_cdf: [[u16; cdf_size!(16)];
   ),
 ];
 
-#[allow(unused)]
 pub static default_motion_mode_cdf: [[u16;
   cdf_size!(MotionMode::MOTION_MODES as usize)];
   BlockSize::BLOCK_SIZES_ALL as usize] = [
@@ -1106,7 +1103,6 @@ pub static default_motion_mode_cdf: [[u16;
   cdf!(29742, 31203),
 ];
 
-#[allow(unused)]
 pub static default_obmc_cdf: [[u16; cdf_size!(2)];
   BlockSize::BLOCK_SIZES_ALL] = [
   cdf!(16384),
@@ -1187,7 +1183,6 @@ pub static default_comp_bwdref_cdf: [[[u16; cdf_size!(2)]; BWD_REFS - 1];
   [cdf!(30606), cdf!(30489)],
 ];
 
-#[allow(unused)]
 pub static default_palette_y_size_cdf: [[u16;
   cdf_size!(PaletteSize::PALETTE_SIZES as usize)];
   PALETTE_BSIZE_CTXS] = [
@@ -1228,7 +1223,6 @@ pub static default_palette_y_mode_cdfs: [[[u16; cdf_size!(2)];
 pub static default_palette_uv_mode_cdfs: [[u16; cdf_size!(2)];
   PALETTE_UV_MODE_CONTEXTS] = [cdf!(32461), cdf!(21488)];
 
-#[allow(unused)]
 pub static default_palette_y_color_index_cdf: [[[u16;
   cdf_size!(PaletteColor::PALETTE_COLORS as usize)];
   PALETTE_COLOR_INDEX_CONTEXTS];
@@ -1388,10 +1382,8 @@ pub static default_comp_group_idx_cdfs: [[u16; cdf_size!(2)];
   cdf!(22674),
 ];
 
-#[allow(unused)]
 pub static default_intrabc_cdf: [u16; cdf_size!(2)] = cdf!(30531);
 
-#[allow(unused)]
 pub static default_filter_intra_mode_cdf: [u16;
   cdf_size!(FilterIntraMode::FILTER_INTRA_MODES as usize)] =
   cdf!(8949, 12776, 17211, 29558);
@@ -1429,7 +1421,6 @@ pub static default_wiener_restore_cdf: [u16; cdf_size!(2)] = cdf!(11570);
 
 pub static default_sgrproj_restore_cdf: [u16; cdf_size!(2)] = cdf!(16855);
 
-#[allow(unused)]
 pub static default_delta_q_cdf: [u16; cdf_size!(DELTA_Q_PROBS + 1)] =
   cdf!(28160, 32120, 32677);
 
@@ -1449,7 +1440,6 @@ pub static default_delta_lf_cdf: [u16; cdf_size!(DELTA_LF_PROBS + 1)] =
 pub static default_seg_tree_cdf: [u16; cdf_size!(MAX_SEGMENTS)] =
   cdf!(4096, 8192, 12288, 16384, 20480, 24576, 28672);
 
-#[allow(unused)]
 pub static default_segment_pred_cdf: [[u16; cdf_size!(2)];
   SEG_TEMPORAL_PRED_CTXS] =
   [cdf!(128 * 128), cdf!(128 * 128), cdf!(128 * 128)];
@@ -0,0 +1,536 @@
+// Copyright (c) 2020, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::color::{ChromaSampling, PixelRange};
+use crate::frame::*;
+use crate::hawktracer::*;
+use crate::util::*;
+
+use arrayvec::ArrayVec;
+
+pub const NUM_Y_POINTS_MAX: usize = 14;
+pub const NUM_UV_POINTS_MAX: usize = 10;
+
+/// Lag of the auto-regressive grain model we estimate.
+pub const AR_COEFF_LAG: usize = 3;
+pub const NUM_AR_COEFFS_Y: usize = 2 * AR_COEFF_LAG * (AR_COEFF_LAG + 1);
+pub const NUM_AR_COEFFS_UV: usize = NUM_AR_COEFFS_Y + 1;
+
+/// Standard deviation of the Gaussian sequence used by the decoder to
+/// generate white grain, in 12-bit units.
+const GAUSSIAN_SEQUENCE_STD: f64 = 512.0;
+
+/// Number of intensity intervals used to estimate the scaling function.
+const NUM_INTENSITY_BINS: usize = 8;
+
+/// Minimum number of samples an intensity interval must contain to yield a
+/// scaling point.
+const MIN_BIN_SAMPLES: usize = 64;
+
+/// Side of the blocks used to select flat areas, in luma pixels.
+const FLAT_BLOCK_SIZE: usize = 16;
+
+/// Film grain synthesis parameters, as signaled in the frame header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilmGrainParams {
+  pub random_seed: u16,
+  /// Piecewise-linear scaling function for luma, as (value, scaling) pairs.
+  pub scaling_points_y: ArrayVec<[[u8; 2]; NUM_Y_POINTS_MAX]>,
+  pub chroma_scaling_from_luma: bool,
+  pub scaling_points_cb: ArrayVec<[[u8; 2]; NUM_UV_POINTS_MAX]>,
+  pub scaling_points_cr: ArrayVec<[[u8; 2]; NUM_UV_POINTS_MAX]>,
+  /// Shift applied to the scaling function, in the range 8..=11.
+  pub scaling_shift: u8,
+  pub ar_coeff_lag: u8,
+  pub ar_coeffs_y: ArrayVec<[i8; NUM_AR_COEFFS_Y]>,
+  pub ar_coeffs_cb: ArrayVec<[i8; NUM_AR_COEFFS_UV]>,
+  pub ar_coeffs_cr: ArrayVec<[i8; NUM_AR_COEFFS_UV]>,
+  /// Shift applied to the auto-regressive coefficients, in the range 6..=9.
+  pub ar_coeff_shift: u8,
+  pub grain_scale_shift: u8,
+  pub cb_mult: u8,
+  pub cb_luma_mult: u8,
+  pub cb_offset: u16,
+  pub cr_mult: u8,
+  pub cr_luma_mult: u8,
+  pub cr_offset: u16,
+  pub overlap_flag: bool,
+  pub clip_to_restricted_range: bool,
+}
+
+/// Derives a per-frame grain seed, so that consecutive frames do not repeat
+/// the same grain pattern.
+pub fn film_grain_seed(input_frameno: u64) -> u16 {
+  (7391u64.wrapping_add(input_frameno.wrapping_mul(3381)) & 0xFFFF) as u16
+}
+
+/// The noise removed from one plane, along with the denoised samples.
+struct PlaneNoise {
+  width: usize,
+  height: usize,
+  noise: Vec<f64>,
+  /// Denoised samples, scaled to 8 bits.
+  intensity: Vec<u8>,
+}
+
+impl PlaneNoise {
+  fn new<T: Pixel>(
+    orig: &Plane<T>, denoised: &Plane<T>, width: usize, height: usize,
+    bit_depth: usize,
+  ) -> Self {
+    let mut noise = Vec::with_capacity(width * height);
+    let mut intensity = Vec::with_capacity(width * height);
+    for y in 0..height {
+      for x in 0..width {
+        let o = i32::cast_from(orig.p(x, y));
+        let d = i32::cast_from(denoised.p(x, y));
+        noise.push(f64::from(o - d));
+        intensity.push((d >> (bit_depth - 8)) as u8);
+      }
+    }
+    PlaneNoise { width, height, noise, intensity }
+  }
+
+  #[inline(always)]
+  fn at(&self, x: usize, y: usize) -> f64 {
+    self.noise[y * self.width + x]
+  }
+}
+
+/// Accumulates the normal equations of a linear least squares problem.
+struct LeastSquares {
+  n: usize,
+  ata: Vec<f64>,
+  atb: Vec<f64>,
+  btb: f64,
+  count: usize,
+}
+
+impl LeastSquares {
+  fn new(n: usize) -> Self {
+    LeastSquares {
+      n,
+      ata: vec![0.; n * n],
+      atb: vec![0.; n],
+      btb: 0.,
+      count: 0,
+    }
+  }
+
+  fn add(&mut self, a: &[f64], b: f64) {
+    for i in 0..self.n {
+      for j in i..self.n {
+        self.ata[i * self.n + j] += a[i] * a[j];
+      }
+      self.atb[i] += a[i] * b;
+    }
+    self.btb += b * b;
+    self.count += 1;
+  }
+
+  /// Returns the solution along with the ratio between the variance of the
+  /// target and the variance of the residual, which is the power gain of
+  /// the fitted auto-regressive filter.
+  fn solve(&self) -> Option<(Vec<f64>, f64)> {
+    if self.count <= self.n {
+      return None;
+    }
+    let n = self.n;
+    let mut a = self.ata.clone();
+    for i in 0..n {
+      for j in 0..i {
+        a[i * n + j] = a[j * n + i];
+      }
+      // Light ridge regularization keeps the system well conditioned on
+      // nearly constant noise.
+      a[i * n + i] += 1e-6 * self.count as f64;
+    }
+    let x = cholesky_solve(&mut a, &self.atb, n)?;
+    let residual = self.btb
+      - x.iter().zip(self.atb.iter()).map(|(&x, &b)| x * b).sum::<f64>();
+    if residual <= 0. || self.btb <= 0. {
+      return None;
+    }
+    Some((x, self.btb / residual))
+  }
+}
+
+fn cholesky_solve(a: &mut [f64], b: &[f64], n: usize) -> Option<Vec<f64>> {
+  for j in 0..n {
+    let mut d = a[j * n + j];
+    for k in 0..j {
+      d -= a[j * n + k] * a[j * n + k];
+    }
+    if d <= 0. {
+      return None;
+    }
+    let d = d.sqrt();
+    a[j * n + j] = d;
+    for i in j + 1..n {
+      let mut s = a[i * n + j];
+      for k in 0..j {
+        s -= a[i * n + k] * a[j * n + k];
+      }
+      a[i * n + j] = s / d;
+    }
+  }
+  let mut y = vec![0.; n];
+  for i in 0..n {
+    let mut s = b[i];
+    for k in 0..i {
+      s -= a[i * n + k] * y[k];
+    }
+    y[i] = s / a[i * n + i];
+  }
+  let mut x = vec![0.; n];
+  for i in (0..n).rev() {
+    let mut s = y[i];
+    for k in i + 1..n {
+      s -= a[k * n + i] * x[k];
+    }
+    x[i] = s / a[i * n + i];
+  }
+  Some(x)
+}
+
+/// Returns a flag per `FLAT_BLOCK_SIZE` luma block, set for the blocks whose
+/// denoised variance is at or below the median, where the removed noise is
+/// the least contaminated by image structure.
+fn flat_blocks(luma: &PlaneNoise) -> (usize, Vec<bool>) {
+  let cols = luma.width / FLAT_BLOCK_SIZE;
+  let rows = luma.height / FLAT_BLOCK_SIZE;
+  let mut variances = Vec::with_capacity(cols * rows);
+  for by in 0..rows {
+    for bx in 0..cols {
+      let mut s = 0.;
+      let mut s2 = 0.;
+      for y in by * FLAT_BLOCK_SIZE..(by + 1) * FLAT_BLOCK_SIZE {
+        for x in bx * FLAT_BLOCK_SIZE..(bx + 1) * FLAT_BLOCK_SIZE {
+          let v = f64::from(luma.intensity[y * luma.width + x]);
+          s += v;
+          s2 += v * v;
+        }
+      }
+      let n = (FLAT_BLOCK_SIZE * FLAT_BLOCK_SIZE) as f64;
+      variances.push(s2 / n - (s / n) * (s / n));
+    }
+  }
+  let mut sorted = variances.clone();
+  sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+  let median = sorted.get(sorted.len() / 2).cloned().unwrap_or(0.);
+  (cols, variances.iter().map(|&v| v <= median).collect())
+}
+
+/// Fits the auto-regressive model of one plane over the flat blocks.
+/// For chroma planes, the co-located luma noise is used as an extra input.
+fn fit_ar_model(
+  plane: &PlaneNoise, luma: Option<&PlaneNoise>, xdec: usize, ydec: usize,
+  flat: &(usize, Vec<bool>),
+) -> Option<(Vec<f64>, f64)> {
+  let lag = AR_COEFF_LAG;
+  let num_coeffs = NUM_AR_COEFFS_Y + luma.is_some() as usize;
+  let mut ls = LeastSquares::new(num_coeffs);
+  let mut a = vec![0.; num_coeffs];
+
+  let (cols, ref is_flat) = *flat;
+  let block_w = FLAT_BLOCK_SIZE >> xdec;
+  let block_h = FLAT_BLOCK_SIZE >> ydec;
+
+  for (i, _) in is_flat.iter().enumerate().filter(|&(_, &f)| f) {
+    let (bx, by) = (i % cols, i / cols);
+    let y_start = (by * block_h).max(lag);
+    let y_end = ((by + 1) * block_h).min(plane.height);
+    let x_start = (bx * block_w).max(lag);
+    let x_end = ((bx + 1) * block_w).min(plane.width.saturating_sub(lag));
+    for y in y_start..y_end {
+      for x in x_start..x_end {
+        let mut k = 0;
+        for dy in 0..=lag {
+          for dx in 0..=2 * lag {
+            if dy == lag && dx == lag {
+              break;
+            }
+            a[k] = plane.at(x + dx - lag, y + dy - lag);
+            k += 1;
+          }
+        }
+        if let Some(luma) = luma {
+          let mut s = 0.;
+          for j in 0..1 << ydec {
+            for i in 0..1 << xdec {
+              s += luma.at((x << xdec) + i, (y << ydec) + j);
+            }
+          }
+          a[k] = s / (1 << (xdec + ydec)) as f64;
+        }
+        ls.add(&a, plane.at(x, y));
+      }
+    }
+  }
+
+  ls.solve()
+}
+
+/// Measures the standard deviation of the noise over intervals of the
+/// denoised intensity, over the flat blocks.
+fn noise_std_by_intensity(
+  plane: &PlaneNoise, xdec: usize, ydec: usize, flat: &(usize, Vec<bool>),
+) -> ArrayVec<[(f64, f64); NUM_INTENSITY_BINS]> {
+  let mut count = [0usize; NUM_INTENSITY_BINS];
+  let mut sum_intensity = [0f64; NUM_INTENSITY_BINS];
+  let mut sum = [0f64; NUM_INTENSITY_BINS];
+  let mut sum_sq = [0f64; NUM_INTENSITY_BINS];
+
+  let (cols, ref is_flat) = *flat;
+  let block_w = FLAT_BLOCK_SIZE >> xdec;
+  let block_h = FLAT_BLOCK_SIZE >> ydec;
+
+  for (i, _) in is_flat.iter().enumerate().filter(|&(_, &f)| f) {
+    let (bx, by) = (i % cols, i / cols);
+    for y in by * block_h..((by + 1) * block_h).min(plane.height) {
+      for x in bx * block_w..((bx + 1) * block_w).min(plane.width) {
+        let v = plane.intensity[y * plane.width + x];
+        let bin = v as usize * NUM_INTENSITY_BINS / 256;
+        let n = plane.at(x, y);
+        count[bin] += 1;
+        sum_intensity[bin] += f64::from(v);
+        sum[bin] += n;
+        sum_sq[bin] += n * n;
+      }
+    }
+  }
+
+  (0..NUM_INTENSITY_BINS)
+    .filter(|&bin| count[bin] >= MIN_BIN_SAMPLES)
+    .map(|bin| {
+      let n = count[bin] as f64;
+      let mean = sum[bin] / n;
+      let var = (sum_sq[bin] / n - mean * mean).max(0.);
+      (sum_intensity[bin] / n, var.sqrt())
+    })
+    .collect()
+}
+
+/// Quantizes auto-regressive coefficients with the given shift.
+fn quantize_ar_coeffs<A: arrayvec::Array<Item = i8>>(
+  coeffs: &[f64], shift: u8,
+) -> ArrayVec<A> {
+  coeffs
+    .iter()
+    .map(|&c| (c * f64::from(1 << shift)).round().max(-128.).min(127.) as i8)
+    .collect()
+}
+
+/// Estimates the film grain parameters from the difference between the
+/// source frame `orig` and its denoised version.
+///
+/// Returns `None` when no grain could be measured, in which case grain
+/// synthesis should be disabled for the frame.
+#[hawktracer(estimate_film_grain_params)]
+pub fn estimate_film_grain_params<T: Pixel>(
+  orig: &Frame<T>, denoised: &Frame<T>, width: usize, height: usize,
+  chroma_sampling: ChromaSampling, pixel_range: PixelRange, bit_depth: usize,
+) -> Option<FilmGrainParams> {
+  let luma = PlaneNoise::new(
+    &orig.planes[0],
+    &denoised.planes[0],
+    width,
+    height,
+    bit_depth,
+  );
+  let flat = flat_blocks(&luma);
+  if !flat.1.iter().any(|&f| f) {
+    return None;
+  }
+
+  let (coeffs_y, gain_y) = fit_ar_model(&luma, None, 0, 0, &flat)?;
+  let std_y = noise_std_by_intensity(&luma, 0, 0, &flat);
+  if std_y.is_empty() || std_y.iter().all(|&(_, s)| s < 0.5) {
+    return None;
+  }
+
+  // Standard deviation of the white grain generated by the decoder before
+  // the auto-regressive filter, in `bit_depth` units.
+  let white_std = GAUSSIAN_SEQUENCE_STD / (1 << (12 - bit_depth)) as f64;
+
+  let mut chroma = ArrayVec::<[_; 2]>::new();
+  if chroma_sampling != ChromaSampling::Cs400 {
+    let (xdec, ydec) = chroma_sampling.get_decimation().unwrap();
+    let chroma_width = (width + xdec) >> xdec;
+    let chroma_height = (height + ydec) >> ydec;
+    for p in 1..3 {
+      let plane = PlaneNoise::new(
+        &orig.planes[p],
+        &denoised.planes[p],
+        chroma_width,
+        chroma_height,
+        bit_depth,
+      );
+      let model = fit_ar_model(&plane, Some(&luma), xdec, ydec, &flat);
+      let std = noise_std_by_intensity(&plane, xdec, ydec, &flat);
+      chroma.push(match model {
+        Some((coeffs, gain)) if !std.is_empty() => Some((coeffs, gain, std)),
+        _ => None,
+      });
+    }
+  }
+
+  // Pick the largest shift such that every coefficient fits in 8 bits.
+  let max_coeff = coeffs_y
+    .iter()
+    .chain(chroma.iter().flatten().flat_map(|(c, _, _)| c.iter()))
+    .fold(0f64, |m, &c| m.max(c.abs()));
+  let ar_coeff_shift = (6..=9u8)
+    .rev()
+    .find(|&s| max_coeff * f64::from(1 << s) <= 127.)
+    .unwrap_or(6);
+
+  // Converts measured noise levels into scaling function values for a
+  // given shift, knowing the power gain of the auto-regressive filter.
+  let scaling = |std: &[(f64, f64)], gain: f64, shift: u8| {
+    let grain_std = white_std * gain.sqrt();
+    std
+      .iter()
+      .map(|&(intensity, s)| {
+        (intensity.round() as u8, s * f64::from(1 << shift) / grain_std)
+      })
+      .collect::<Vec<_>>()
+  };
+  let max_scaling = |shift: u8| {
+    let mut m =
+      scaling(&std_y, gain_y, shift).iter().fold(0f64, |m, &(_, s)| m.max(s));
+    for (_, gain, std) in chroma.iter().flatten() {
+      m = scaling(std, *gain, shift).iter().fold(m, |m, &(_, s)| m.max(s));
+    }
+    m
+  };
+  let scaling_shift =
+    (8..=11u8).rev().find(|&s| max_scaling(s) <= 255.).unwrap_or(8);
+
+  let to_points = |std: &[(f64, f64)], gain: f64| {
+    let mut points: Vec<[u8; 2]> = Vec::new();
+    for (value, s) in scaling(std, gain, scaling_shift) {
+      // Scaling points must have strictly increasing values.
+      if points.last().map(|p| p[0] < value).unwrap_or(true) {
+        points.push([value, s.round().min(255.) as u8]);
+      }
+    }
+    points
+  };
+
+  let scaling_points_y = to_points(&std_y, gain_y).into_iter().collect();
+  let mut scaling_points_cb = ArrayVec::new();
+  let mut scaling_points_cr = ArrayVec::new();
+  let mut ar_coeffs_cb = ArrayVec::new();
+  let mut ar_coeffs_cr = ArrayVec::new();
+  for (p, model) in chroma.iter().enumerate() {
+    if let Some((coeffs, gain, std)) = model {
+      let (points, ar_coeffs) = if p == 0 {
+        (&mut scaling_points_cb, &mut ar_coeffs_cb)
+      } else {
+        (&mut scaling_points_cr, &mut ar_coeffs_cr)
+      };
+      *points = to_points(std, *gain).into_iter().collect();
+      *ar_coeffs = quantize_ar_coeffs(coeffs, ar_coeff_shift);
+    }
+  }
+
+  Some(FilmGrainParams {
+    random_seed: 0,
+    scaling_points_y,
+    chroma_scaling_from_luma: false,
+    scaling_points_cb,
+    scaling_points_cr,
+    scaling_shift,
+    ar_coeff_lag: AR_COEFF_LAG as u8,
+    ar_coeffs_y: quantize_ar_coeffs(&coeffs_y, ar_coeff_shift),
+    ar_coeffs_cb,
+    ar_coeffs_cr,
+    ar_coeff_shift,
+    grain_scale_shift: 0,
+    // The chroma scaling functions are indexed by the chroma intensity
+    // alone, which is what they were measured against.
+    cb_mult: 128 + 64,
+    cb_luma_mult: 128,
+    cb_offset: 256,
+    cr_mult: 128 + 64,
+    cr_luma_mult: 128,
+    cr_offset: 256,
+    overlap_flag: true,
+    clip_to_restricted_range: pixel_range == PixelRange::Limited,
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::denoise::denoise_frame;
+  use rand::{Rng, SeedableRng};
+  use rand_chacha::ChaChaRng;
+
+  fn noisy_frame(w: usize, h: usize, amplitude: i32) -> Frame<u8> {
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+    let mut frame = Frame::<u8>::new(w, h, ChromaSampling::Cs420);
+    for plane in frame.planes.iter_mut() {
+      let PlaneConfig { stride, xorigin, yorigin, xdec, ydec, .. } = plane.cfg;
+      for y in 0..(h >> ydec) {
+        for x in 0..(w >> xdec) {
+          let n: i32 = ra.gen_range(-amplitude, amplitude + 1);
+          plane.data[(yorigin + y) * stride + xorigin + x] = (128 + n) as u8;
+        }
+      }
+    }
+    frame
+  }
+
+  #[test]
+  fn estimate_grain_on_noisy_frame() {
+    let (w, h) = (64, 64);
+    let frame = noisy_frame(w, h, 6);
+    let denoised = denoise_frame(&frame, w, h, ChromaSampling::Cs420, 24, 8);
+    let params = estimate_film_grain_params(
+      &frame,
+      &denoised,
+      w,
+      h,
+      ChromaSampling::Cs420,
+      PixelRange::Limited,
+      8,
+    )
+    .unwrap();
+
+    assert!(!params.scaling_points_y.is_empty());
+    assert!(params.scaling_points_y.iter().any(|p| p[1] > 0));
+    assert_eq!(params.ar_coeffs_y.len(), NUM_AR_COEFFS_Y);
+    assert!(params.scaling_shift >= 8 && params.scaling_shift <= 11);
+    assert!(params.ar_coeff_shift >= 6 && params.ar_coeff_shift <= 9);
+    for points in &[&params.scaling_points_cb, &params.scaling_points_cr] {
+      if !points.is_empty() {
+        assert!(points.windows(2).all(|p| p[0][0] < p[1][0]));
+      }
+    }
+  }
+
+  #[test]
+  fn no_grain_on_flat_frame() {
+    let (w, h) = (64, 64);
+    let frame = noisy_frame(w, h, 0);
+    let denoised = denoise_frame(&frame, w, h, ChromaSampling::Cs420, 24, 8);
+    assert!(estimate_film_grain_params(
+      &frame,
+      &denoised,
+      w,
+      h,
+      ChromaSampling::Cs420,
+      PixelRange::Limited,
+      8,
+    )
+    .is_none());
+  }
+}
@@ -9,7 +9,7 @@
 
 use num_derive::FromPrimitive;
 
-use crate::context::SB_SIZE;
+use crate::context::MAX_SB_SIZE_LOG2;
 use crate::mc::SUBPEL_FILTER_SIZE;
 use crate::util::*;
 
@@ -19,7 +19,7 @@ mod plane;
 pub use plane::*;
 
 const FRAME_MARGIN: usize = 16 + SUBPEL_FILTER_SIZE;
-const LUMA_PADDING: usize = SB_SIZE + FRAME_MARGIN;
+const LUMA_PADDING: usize = (1 << MAX_SB_SIZE_LOG2) + FRAME_MARGIN;
 
 /// Override the frame type decision
 ///
@@ -33,13 +33,30 @@ pub enum FrameTypeOverride {
   Key,
 }
 
+impl Default for FrameTypeOverride {
+  fn default() -> Self {
+    FrameTypeOverride::No
+  }
+}
+
 /// Optional per-frame encoder parameters
-#[derive(Debug)]
+///
+/// The parameters left out with `..Default::default()` keep the
+/// configured behavior.
+#[derive(Debug, Default)]
 pub struct FrameParameters {
   /// Force emitted frame to be of the type selected
   pub frame_type_override: FrameTypeOverride,
   /// Output the provided data in the matching encoded Packet
   pub opaque: Option<Box<dyn std::any::Any + Send>>,
+  /// Code the frame at this size (width, height) instead of the configured
+  /// one, from the top-left area of the frame, without a key frame
+  pub frame_size: Option<(usize, usize)>,
+  /// Offsets to the quantizer index of each 64x64 block of the frame, in
+  /// raster order over the configured size, coded with delta-q, or with
+  /// segments when `EncoderConfig::delta_q` is off and the speed settings
+  /// enable segmentation
+  pub qp_offsets: Option<Box<[i16]>>,
 }
 
 pub use v_frame::frame::Frame;
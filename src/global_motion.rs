@@ -0,0 +1,372 @@
+// Copyright (c) 2020, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::encoder::{FrameInvariants, FrameState};
+use crate::frame::*;
+use crate::me::FrameMotionVectors;
+use crate::partition::{GlobalMVMode, ALL_INTER_REFS, INTER_REFS_PER_FRAME};
+use crate::tiling::Area;
+use crate::util::*;
+use crate::warp::*;
+
+/// Side of the blocks the lookahead motion vectors are estimated for.
+const GM_BLOCK_SIZE: usize = 16;
+/// Blocks whose luma variance (at 8 bits) is below this are too flat to
+/// give a reliable motion vector.
+const GM_MIN_BLOCK_VARIANCE: u32 = 16;
+/// Fewer correspondences than this are not enough for a robust fit.
+const GM_MIN_POINTS: usize = 16;
+const GM_RANSAC_ITERATIONS: usize = 256;
+/// Maximum distance in pixels between the position predicted by a model and
+/// the one found by the motion search for a point to be an inlier.
+const GM_INLIER_THRESHOLD: f64 = 1.0;
+
+/// A correspondence between the center of a block in the current frame and
+/// the position it points to in the reference frame, as `[x, y, x', y']` in
+/// pixels.
+type Point = [f64; 4];
+
+/// A model in the layout of `WarpedMotionParams::wmmat`, unquantized.
+type Model = [f64; 6];
+
+/// Estimates the global motion of the frame relative to each of its
+/// references from the lookahead motion vectors in `fs.frame_mvs`.
+pub(crate) fn estimate_global_motion<T: Pixel>(
+  fi: &FrameInvariants<T>, fs: &FrameState<T>,
+) -> [WarpedMotionParams; INTER_REFS_PER_FRAME] {
+  let mut gm_params = [WarpedMotionParams::default(); INTER_REFS_PER_FRAME];
+
+  for (i, r) in ALL_INTER_REFS.iter().enumerate() {
+    let slot = fi.ref_frames[r.to_index()];
+    // The lookahead only searches the first reference pointing to a slot.
+    let first = fi.ref_frames.iter().position(|&s| s == slot).unwrap();
+    if first != i {
+      gm_params[i] = gm_params[first];
+      continue;
+    }
+    if fi.rec_buffer.frames[slot as usize].is_none() {
+      continue;
+    }
+
+    let points = collect_points(
+      &fs.frame_mvs[r.to_index()],
+      &fs.input.planes[0],
+      fi.sequence.bit_depth,
+    );
+    if let Some(params) = fit_global_motion(&points) {
+      gm_params[i] = params;
+    }
+  }
+
+  gm_params
+}
+
+/// Gathers the correspondences of the textured blocks away from the frame
+/// edges, where the motion search is clamped.
+fn collect_points<T: Pixel>(
+  mvs: &FrameMotionVectors, plane: &Plane<T>, bit_depth: usize,
+) -> Vec<Point> {
+  let mi_step = GM_BLOCK_SIZE >> 2;
+  let w_in_b = plane.cfg.width / GM_BLOCK_SIZE;
+  let h_in_b = plane.cfg.height / GM_BLOCK_SIZE;
+  let mut points = Vec::new();
+
+  for by in 1..h_in_b.saturating_sub(1) {
+    for bx in 1..w_in_b.saturating_sub(1) {
+      if by * mi_step >= mvs.rows || bx * mi_step >= mvs.cols {
+        continue;
+      }
+      let region = plane.region(Area::Rect {
+        x: (bx * GM_BLOCK_SIZE) as isize,
+        y: (by * GM_BLOCK_SIZE) as isize,
+        width: GM_BLOCK_SIZE,
+        height: GM_BLOCK_SIZE,
+      });
+      let (sum, sum_sq) =
+        region.rows_iter().flatten().fold((0u64, 0u64), |(s, ss), &p| {
+          let p = u32::cast_from(p) as u64;
+          (s + p, ss + p * p)
+        });
+      let n = (GM_BLOCK_SIZE * GM_BLOCK_SIZE) as u64;
+      let variance = ((sum_sq - sum * sum / n) / n) >> (2 * (bit_depth - 8));
+      if variance < GM_MIN_BLOCK_VARIANCE as u64 {
+        continue;
+      }
+
+      let mv = mvs[by * mi_step][bx * mi_step];
+      let x = (bx * GM_BLOCK_SIZE + GM_BLOCK_SIZE / 2) as f64;
+      let y = (by * GM_BLOCK_SIZE + GM_BLOCK_SIZE / 2) as f64;
+      points.push([x, y, x + mv.col as f64 / 8., y + mv.row as f64 / 8.]);
+    }
+  }
+
+  points
+}
+
+/// Robustly fits a rotation-zoom or affine model to the correspondences,
+/// returning `None` if no model explains most of them.
+fn fit_global_motion(points: &[Point]) -> Option<WarpedMotionParams> {
+  if points.len() < GM_MIN_POINTS {
+    return None;
+  }
+
+  let (rotzoom, rotzoom_inliers) = ransac(points, 2, fit_rotzoom)?;
+  let (affine, affine_inliers) =
+    ransac(points, 3, fit_affine).unwrap_or((rotzoom, 0));
+
+  // The affine model costs more to signal, so it has to be clearly better.
+  let (wmtype, model, inliers) = if affine_inliers * 20 >= rotzoom_inliers * 21
+  {
+    (GlobalMVMode::AFFINE, affine, affine_inliers)
+  } else {
+    (GlobalMVMode::ROTZOOM, rotzoom, rotzoom_inliers)
+  };
+  if inliers * 2 < points.len() {
+    return None;
+  }
+
+  let params = quantize_model(wmtype, &model)?;
+  if params.wmmat == WarpedMotionParams::default().wmmat {
+    Some(WarpedMotionParams::default())
+  } else {
+    Some(params)
+  }
+}
+
+/// Fits models to random minimal subsets of the points and refines the one
+/// with the most inliers by least squares over them.
+fn ransac(
+  points: &[Point], min_points: usize, fit: fn(&[Point]) -> Option<Model>,
+) -> Option<(Model, usize)> {
+  // A fixed seed keeps the encoder deterministic.
+  let mut state = 0x2545_f491_4f6c_dd1d_u64;
+  let mut random = |n: usize| {
+    state ^= state << 13;
+    state ^= state >> 7;
+    state ^= state << 17;
+    (state % n as u64) as usize
+  };
+
+  let mut best: Option<(Model, usize)> = None;
+  let mut subset = [[0.; 4]; 3];
+  for _ in 0..GM_RANSAC_ITERATIONS {
+    let mut idx = [usize::MAX; 3];
+    for k in 0..min_points {
+      let mut j = random(points.len());
+      while idx[..k].contains(&j) {
+        j = random(points.len());
+      }
+      idx[k] = j;
+      subset[k] = points[j];
+    }
+    if let Some(model) = fit(&subset[..min_points]) {
+      let inliers = count_inliers(points, &model);
+      if best.map_or(true, |(_, n)| inliers > n) {
+        best = Some((model, inliers));
+      }
+    }
+  }
+
+  let (model, _) = best?;
+  let inliers: Vec<Point> = points
+    .iter()
+    .copied()
+    .filter(|p| point_error(p, &model) < GM_INLIER_THRESHOLD)
+    .collect();
+  let model = fit(&inliers).unwrap_or(model);
+  Some((model, count_inliers(points, &model)))
+}
+
+fn point_error(p: &Point, m: &Model) -> f64 {
+  let x = m[2] * p[0] + m[3] * p[1] + m[0];
+  let y = m[4] * p[0] + m[5] * p[1] + m[1];
+  ((x - p[2]).powi(2) + (y - p[3]).powi(2)).sqrt()
+}
+
+fn count_inliers(points: &[Point], m: &Model) -> usize {
+  points.iter().filter(|p| point_error(p, m) < GM_INLIER_THRESHOLD).count()
+}
+
+/// Centers the source and destination positions around their means,
+/// returning the means and the centered points.
+fn center(points: &[Point]) -> (Point, Vec<Point>) {
+  let n = points.len() as f64;
+  let mut mean = [0.; 4];
+  for p in points {
+    for (m, &v) in mean.iter_mut().zip(p.iter()) {
+      *m += v / n;
+    }
+  }
+  let centered = points
+    .iter()
+    .map(|p| [p[0] - mean[0], p[1] - mean[1], p[2] - mean[2], p[3] - mean[3]])
+    .collect();
+  (mean, centered)
+}
+
+/// Least squares fit of x' = a * x - b * y + c, y' = b * x + a * y + d.
+fn fit_rotzoom(points: &[Point]) -> Option<Model> {
+  let (mean, centered) = center(points);
+  let (mut den, mut sa, mut sb) = (0., 0., 0.);
+  for p in &centered {
+    den += p[0] * p[0] + p[1] * p[1];
+    sa += p[0] * p[2] + p[1] * p[3];
+    sb += p[0] * p[3] - p[1] * p[2];
+  }
+  if den < 1. {
+    return None;
+  }
+  let (a, b) = (sa / den, sb / den);
+  Some([
+    mean[2] - a * mean[0] + b * mean[1],
+    mean[3] - b * mean[0] - a * mean[1],
+    a,
+    -b,
+    b,
+    a,
+  ])
+}
+
+/// Least squares fit of x' = a * x + b * y + c, y' = d * x + e * y + f.
+fn fit_affine(points: &[Point]) -> Option<Model> {
+  let (mean, centered) = center(points);
+  let (mut sxx, mut sxy, mut syy) = (0., 0., 0.);
+  let (mut sxu, mut syu, mut sxv, mut syv) = (0., 0., 0., 0.);
+  for p in &centered {
+    sxx += p[0] * p[0];
+    sxy += p[0] * p[1];
+    syy += p[1] * p[1];
+    sxu += p[0] * p[2];
+    syu += p[1] * p[2];
+    sxv += p[0] * p[3];
+    syv += p[1] * p[3];
+  }
+  let det = sxx * syy - sxy * sxy;
+  if det.abs() < 1. {
+    return None;
+  }
+  let m2 = (syy * sxu - sxy * syu) / det;
+  let m3 = (sxx * syu - sxy * sxu) / det;
+  let m4 = (syy * sxv - sxy * syv) / det;
+  let m5 = (sxx * syv - sxy * sxv) / det;
+  Some([
+    mean[2] - m2 * mean[0] - m3 * mean[1],
+    mean[3] - m4 * mean[0] - m5 * mean[1],
+    m2,
+    m3,
+    m4,
+    m5,
+  ])
+}
+
+/// Rounds the model to the precision the frame header can signal, returning
+/// `None` if it is out of range or unusable for prediction.
+fn quantize_model(
+  wmtype: GlobalMVMode, model: &Model,
+) -> Option<WarpedMotionParams> {
+  let alpha_step = (1 << (WARPEDMODEL_PREC_BITS - GM_ALPHA_PREC_BITS)) as f64;
+  let alpha_max =
+    1 << (GM_ABS_ALPHA_BITS + WARPEDMODEL_PREC_BITS - GM_ALPHA_PREC_BITS);
+  let trans_step = (1 << (WARPEDMODEL_PREC_BITS - GM_TRANS_PREC_BITS)) as f64;
+  let trans_max =
+    1 << (GM_ABS_TRANS_BITS + WARPEDMODEL_PREC_BITS - GM_TRANS_PREC_BITS);
+
+  let one = (1 << WARPEDMODEL_PREC_BITS) as f64;
+  let mut wmmat = [0i32; 6];
+  for (i, (w, &v)) in wmmat.iter_mut().zip(model.iter()).enumerate() {
+    *w = if i < 2 {
+      let t = (v * one / trans_step).round() as i32 * trans_step as i32;
+      if t.abs() > trans_max {
+        return None;
+      }
+      t
+    } else {
+      let identity =
+        if i == 2 || i == 5 { 1 << WARPEDMODEL_PREC_BITS } else { 0 };
+      let a = (v * one / alpha_step).round() as i32 * alpha_step as i32;
+      if (a - identity).abs() > alpha_max {
+        return None;
+      }
+      a
+    };
+  }
+  if wmtype == GlobalMVMode::ROTZOOM {
+    wmmat[4] = -wmmat[3];
+    wmmat[5] = wmmat[2];
+  }
+
+  let params = WarpedMotionParams { wmtype, wmmat };
+  params.shear().map(|_| params)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn grid_points(model: &Model) -> Vec<Point> {
+    let mut points = Vec::new();
+    for y in 0..8 {
+      for x in 0..8 {
+        let (x, y) = (x as f64 * 40. + 24., y as f64 * 24. + 24.);
+        points.push([
+          x,
+          y,
+          model[2] * x + model[3] * y + model[0],
+          model[4] * x + model[5] * y + model[1],
+        ]);
+      }
+    }
+    points
+  }
+
+  #[test]
+  fn fit_rotzoom_with_outliers() {
+    // Rotate by about 1 degree and zoom in by 2%.
+    let (a, b) = (1.02 * 0.01745f64.cos(), 1.02 * 0.01745f64.sin());
+    let model = [3.5, -2.25, a, -b, b, a];
+    let mut points = grid_points(&model);
+    for p in points.iter_mut().step_by(5) {
+      p[2] += 7.;
+      p[3] -= 11.;
+    }
+
+    let params = fit_global_motion(&points).unwrap();
+    assert_eq!(params.wmtype, GlobalMVMode::ROTZOOM);
+    assert!(params.shear().is_some());
+    for (i, &v) in params.wmmat.iter().enumerate() {
+      let expected = model[i] * 65536.;
+      let tolerance = if i < 2 { 1024. } else { 2. };
+      assert!((v as f64 - expected).abs() <= tolerance, "{} {}", i, v);
+    }
+  }
+
+  #[test]
+  fn fit_affine_shear() {
+    let model = [-1., 0.5, 1.01, 0.02, -0.005, 0.97];
+    let params = fit_global_motion(&grid_points(&model)).unwrap();
+    assert_eq!(params.wmtype, GlobalMVMode::AFFINE);
+  }
+
+  #[test]
+  fn fit_static_is_identity() {
+    let model = [0., 0., 1., 0., 0., 1.];
+    let params = fit_global_motion(&grid_points(&model)).unwrap();
+    assert_eq!(params, WarpedMotionParams::default());
+  }
+
+  #[test]
+  fn fit_rejects_incoherent_motion() {
+    let mut points = grid_points(&[0., 0., 1., 0., 0., 1.]);
+    for (i, p) in points.iter_mut().enumerate() {
+      p[2] += (i * 7 % 13) as f64 * 3.;
+      p[3] -= (i * 5 % 11) as f64 * 3.;
+    }
+    assert!(fit_global_motion(&points).is_none());
+  }
+}
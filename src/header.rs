@@ -12,9 +12,12 @@ use crate::context::*;
 use crate::ec::*;
 use crate::lrf::*;
 use crate::partition::*;
+use crate::rate::BUFFER_DELAY_LENGTH;
+use crate::scale::{SUPERRES_DENOM_BITS, SUPERRES_DENOM_MIN};
 use crate::tiling::MAX_TILE_WIDTH;
 use crate::util::Fixed;
 use crate::util::Pixel;
+use crate::warp::*;
 
 use crate::DeblockState;
 use crate::FrameInvariants;
@@ -173,6 +176,12 @@ pub trait UncompressedHeader {
   fn write_frame_size_override<T: Pixel>(
     &mut self, fi: &FrameInvariants<T>,
   ) -> io::Result<()>;
+  fn write_render_size<T: Pixel>(
+    &mut self, fi: &FrameInvariants<T>,
+  ) -> io::Result<()>;
+  fn write_superres_params<T: Pixel>(
+    &mut self, fi: &FrameInvariants<T>,
+  ) -> io::Result<()>;
   fn write_deblock_filter_a<T: Pixel>(
     &mut self, fi: &FrameInvariants<T>, deblock: &DeblockState,
   ) -> io::Result<()>;
@@ -189,6 +198,13 @@ pub trait UncompressedHeader {
     &mut self, fi: &FrameInvariants<T>, segmentation: &SegmentationState,
   ) -> io::Result<()>;
   fn write_delta_q(&mut self, delta_q: i8) -> io::Result<()>;
+  fn write_global_param<T: Pixel>(
+    &mut self, fi: &FrameInvariants<T>, params: &WarpedMotionParams,
+    prev_params: &WarpedMotionParams, idx: usize,
+  ) -> io::Result<()>;
+  fn write_film_grain_params<T: Pixel>(
+    &mut self, fi: &FrameInvariants<T>,
+  ) -> io::Result<()>;
 }
 
 impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
@@ -271,7 +287,7 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
       assert_eq!(fi.sequence.decoder_model_info_present_flag, false);
       assert_eq!(fi.sequence.operating_points_cnt_minus_1, 0);
       assert_eq!(fi.sequence.operating_point_idc[0], 0);
-      self.write(5, 31)?; // level
+      self.write(5, fi.sequence.seq_level_idx(0) as u32)?; // level
       assert_eq!(fi.sequence.tier[0], 0);
     } else {
       self.write_bit(fi.sequence.timing_info_present)?; // timing info present
@@ -282,14 +298,35 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
 
         self.write_bit(true)?; // equal picture interval
         self.write_bit(true)?; // zero interval
-        self.write_bit(false)?; // decoder model info present flag
+        self.write_bit(fi.sequence.decoder_model_info_present_flag)?;
+
+        if fi.sequence.decoder_model_info_present_flag {
+          self.write(5, BUFFER_DELAY_LENGTH - 1)?; // buffer delay length
+          self.write(32, fi.config.time_base.num)?; // units in decoding tick
+
+          // Buffer removal and frame presentation times are never signaled.
+          self.write(5, 0)?; // buffer removal time length
+          self.write(5, 0)?; // frame presentation time length
+        }
+      } else {
+        assert!(!fi.sequence.decoder_model_info_present_flag);
       }
 
       self.write_bit(false)?; // initial display delay present flag
       self.write(5, 0)?; // one operating point
       self.write(12, 0)?; // idc
-      self.write(5, 31)?; // level
-      self.write(1, 0)?; // tier
+      let seq_level_idx = fi.sequence.seq_level_idx(0);
+      self.write(5, seq_level_idx as u32)?; // level
+      if seq_level_idx > 7 {
+        self.write(1, fi.sequence.tier[0] as u32)?; // tier
+      }
+
+      if fi.sequence.decoder_model_info_present_flag {
+        self.write_bit(true)?; // decoder model present for this op
+        self.write(BUFFER_DELAY_LENGTH, fi.sequence.decoder_buffer_delay)?;
+        self.write(BUFFER_DELAY_LENGTH, fi.sequence.encoder_buffer_delay)?;
+        self.write_bit(false)?; // low delay mode flag
+      }
     }
 
     self.write_sequence_header(fi)?;
@@ -538,11 +575,13 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
       //self.write(frame_id_len, fi.current_frame_id);
     }
 
-    let mut frame_size_override_flag = false;
+    let mut frame_size_override_flag = fi.upscaled_width
+      != fi.sequence.max_frame_width as usize
+      || fi.height != fi.sequence.max_frame_height as usize;
     if fi.frame_type == FrameType::SWITCH {
       frame_size_override_flag = true;
     } else if fi.sequence.reduced_still_picture_hdr {
-      frame_size_override_flag = false;
+      assert!(!frame_size_override_flag);
     } else {
       self.write_bit(frame_size_override_flag)?; // frame size overhead flag
     }
@@ -558,7 +597,7 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     }
 
     if fi.sequence.decoder_model_info_present_flag {
-      unimplemented!();
+      self.write_bit(false)?; // buffer removal time present flag
     }
 
     if fi.frame_type == FrameType::KEY {
@@ -602,15 +641,11 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     // FIXME: Not sure whether putting frame/render size here is good idea
     if fi.intra_only {
       if frame_size_override_flag {
-        self.write_frame_size_override(fi);
-      }
-      if fi.sequence.enable_superres {
-        unimplemented!();
+        self.write_frame_size_override(fi)?;
       }
-      self.write_bit(false)?; // render_and_frame_size_different
-                              // if render_and_frame_size_different { }
-      if fi.allow_screen_content_tools != 0 {
-        // TODO: && UpscaledWidth == FrameWidth.
+      self.write_superres_params(fi)?;
+      self.write_render_size(fi)?;
+      if fi.allow_screen_content_tools != 0 && fi.upscaled_width == fi.width {
         self.write_bit(fi.allow_intrabc)?;
       }
     }
@@ -635,16 +670,30 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
         }
       }
 
-      if !fi.error_resilient && frame_size_override_flag {
-        unimplemented!();
-      } else {
-        if frame_size_override_flag {
-          self.write_frame_size_override(fi);
-        }
-        if fi.sequence.enable_superres {
-          unimplemented!();
+      // All frames are rendered at the same size, so any reference of the
+      // same size can provide the frame size.
+      let found_ref = if !fi.error_resilient && frame_size_override_flag {
+        let found_ref = fi.ref_frames.iter().position(|&slot| {
+          fi.rec_buffer.frames[slot as usize].as_ref().map_or(false, |rec| {
+            rec.width == fi.upscaled_width && rec.height == fi.height
+          })
+        });
+        for i in 0..INTER_REFS_PER_FRAME {
+          self.write_bit(found_ref == Some(i))?;
+          if found_ref == Some(i) {
+            break;
+          }
         }
-        self.write_bit(false)?; // render_and_frame_size_different
+        found_ref
+      } else {
+        None
+      };
+      if found_ref.is_none() && frame_size_override_flag {
+        self.write_frame_size_override(fi)?;
+      }
+      self.write_superres_params(fi)?;
+      if found_ref.is_none() {
+        self.write_render_size(fi)?;
       }
 
       if fi.force_integer_mv == 0 {
@@ -675,10 +724,14 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     // is a slight misnomer; it's more constrained than just a uniform
     // spacing.
     let ti = &fi.tiling;
+    // The tile sizes are coded in superblocks of the coded size.
+    let sb_cols = ti.frame_width.align_power_of_two_and_shift(ti.sb_size_log2);
+    let sb_rows =
+      ti.frame_height.align_power_of_two_and_shift(ti.sb_size_log2);
 
-    if fi.sb_width.align_power_of_two_and_shift(ti.tile_cols_log2)
+    if sb_cols.align_power_of_two_and_shift(ti.tile_cols_log2)
       == ti.tile_width_sb
-      && fi.sb_height.align_power_of_two_and_shift(ti.tile_rows_log2)
+      && sb_rows.align_power_of_two_and_shift(ti.tile_rows_log2)
         == ti.tile_height_sb
     {
       // yes; our actual tile width/height setting (which is always
@@ -707,27 +760,26 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
       let mut sofar = 0;
       let mut widest_tile_sb = 0;
       for _ in 0..ti.cols {
-        let max = (MAX_TILE_WIDTH
-          >> if fi.sequence.use_128x128_superblock { 7 } else { 6 })
-        .min(fi.sb_width - sofar) as u16;
-        let this_sb_width = ti.tile_width_sb.min(fi.sb_width - sofar);
+        let max =
+          (MAX_TILE_WIDTH >> ti.sb_size_log2).min(sb_cols - sofar) as u16;
+        let this_sb_width = ti.tile_width_sb.min(sb_cols - sofar);
         self.write_quniform(max, (this_sb_width - 1) as u16);
         sofar += this_sb_width;
         widest_tile_sb = widest_tile_sb.max(this_sb_width);
       }
 
       let max_tile_area_sb = if ti.min_tiles_log2 > 0 {
-        (fi.sb_height * fi.sb_width) >> (ti.min_tiles_log2 + 1)
+        (sb_rows * sb_cols) >> (ti.min_tiles_log2 + 1)
       } else {
-        fi.sb_height * fi.sb_width
+        sb_rows * sb_cols
       };
 
       let max_tile_height_sb = (max_tile_area_sb / widest_tile_sb).max(1);
 
       sofar = 0;
       for i in 0..ti.rows {
-        let max = max_tile_height_sb.min(fi.sb_height - sofar) as u16;
-        let this_sb_height = ti.tile_height_sb.min(fi.sb_height - sofar);
+        let max = max_tile_height_sb.min(sb_rows - sofar) as u16;
+        let this_sb_height = ti.tile_height_sb.min(sb_rows - sofar);
 
         self.write_quniform(max, (this_sb_height - 1) as u16);
         sofar += this_sb_height;
@@ -745,7 +797,6 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     }
 
     // quantization
-    assert!(fi.base_q_idx > 0);
     self.write(8, fi.base_q_idx)?; // base_q_idx
     self.write_delta_q(fi.dc_delta_q[0])?;
     if fi.sequence.chroma_sampling != ChromaSampling::Cs400 {
@@ -760,13 +811,26 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
         self.write_delta_q(fi.ac_delta_q[2])?;
       }
     }
-    self.write_bit(false)?; // no qm
+    let using_qmatrix = fi.using_qmatrix();
+    self.write_bit(using_qmatrix)?;
+    if using_qmatrix {
+      self.write(4, fi.qm_levels[0])?; // qm_y
+      self.write(4, fi.qm_levels[1])?; // qm_u
+      if fi.sequence.chroma_sampling != ChromaSampling::Cs400 {
+        self.write(4, fi.qm_levels[2])?; // qm_v, as separate_uv_delta_q is set
+      }
+    }
 
     // segmentation
     self.write_segment_data(fi, &fs.segmentation)?;
 
     // delta_q
-    self.write_bit(false)?; // delta_q_present_flag: no delta q
+    if fi.base_q_idx > 0 {
+      self.write_bit(fi.delta_q_present)?;
+    }
+    if fi.delta_q_present {
+      self.write(2, fi.delta_q_res)?;
+    }
 
     // delta_lf_params in the spec
     self.write_deblock_filter_a(fi, &fs.deblock)?;
@@ -774,15 +838,22 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     // code for features not yet implemented....
 
     // loop_filter_params in the spec
-    self.write_deblock_filter_b(fi, &fs.deblock)?;
+    if !fi.allow_intrabc && !fi.lossless {
+      self.write_deblock_filter_b(fi, &fs.deblock)?;
+    }
 
     // cdef
-    self.write_frame_cdef(fi)?;
+    if !fi.allow_intrabc && !fi.lossless {
+      self.write_frame_cdef(fi)?;
+    }
 
     // loop restoration
     self.write_frame_lrf(fi, &fs.restoration)?;
 
-    self.write_bit(fi.tx_mode_select)?; // tx mode
+    // Lossless frames only use 4x4 transforms
+    if !fi.lossless {
+      self.write_bit(fi.tx_mode_select)?; // tx mode
+    }
 
     let mut reference_select = false;
     if !fi.intra_only {
@@ -807,7 +878,8 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     // global motion
     if !fi.intra_only {
       for i in 0..7 {
-        let mode = fi.globalmv_transformation_type[i];
+        let params = &fi.gm_params[i];
+        let mode = params.wmtype;
         self.write_bit(mode != GlobalMVMode::IDENTITY)?;
         if mode != GlobalMVMode::IDENTITY {
           self.write_bit(mode == GlobalMVMode::ROTZOOM)?;
@@ -815,38 +887,37 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
             self.write_bit(mode == GlobalMVMode::TRANSLATION)?;
           }
         }
-        match mode {
-          GlobalMVMode::IDENTITY => { /* Nothing to do */ }
-          GlobalMVMode::TRANSLATION => {
-            let mv_x = 0;
-            let mv_x_ref = 0;
-            let mv_y = 0;
-            let mv_y_ref = 0;
-            let bits = 12 - 6 + 3 - !fi.allow_high_precision_mv as u8;
-            let bits_diff = 12 - 3 + fi.allow_high_precision_mv as u8;
-            BCodeWriter::write_s_refsubexpfin(
-              self,
-              (1 << bits) + 1,
-              3,
-              mv_x_ref >> bits_diff,
-              mv_x >> bits_diff,
-            )?;
-            BCodeWriter::write_s_refsubexpfin(
-              self,
-              (1 << bits) + 1,
-              3,
-              mv_y_ref >> bits_diff,
-              mv_y >> bits_diff,
-            )?;
-          }
-          GlobalMVMode::ROTZOOM => unimplemented!(),
-          GlobalMVMode::AFFINE => unimplemented!(),
+        let prev_params = if fi.primary_ref_frame == PRIMARY_REF_NONE {
+          WarpedMotionParams::default()
+        } else {
+          fi.rec_buffer.frames
+            [fi.ref_frames[fi.primary_ref_frame as usize] as usize]
+            .as_ref()
+            .map(|rec| rec.gm_params[i])
+            .unwrap_or_default()
         };
+        if mode >= GlobalMVMode::ROTZOOM {
+          self.write_global_param(fi, params, &prev_params, 2)?;
+          self.write_global_param(fi, params, &prev_params, 3)?;
+          if mode == GlobalMVMode::AFFINE {
+            self.write_global_param(fi, params, &prev_params, 4)?;
+            self.write_global_param(fi, params, &prev_params, 5)?;
+          } else {
+            debug_assert_eq!(params.wmmat[4], -params.wmmat[3]);
+            debug_assert_eq!(params.wmmat[5], params.wmmat[2]);
+          }
+        }
+        if mode >= GlobalMVMode::TRANSLATION {
+          self.write_global_param(fi, params, &prev_params, 0)?;
+          self.write_global_param(fi, params, &prev_params, 1)?;
+        }
       }
     }
 
-    if fi.sequence.film_grain_params_present && fi.show_frame {
-      unimplemented!();
+    if fi.sequence.film_grain_params_present
+      && (fi.show_frame || fi.showable_frame)
+    {
+      self.write_film_grain_params(fi)?;
     }
 
     if fi.large_scale_tile {
@@ -862,30 +933,51 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
   fn write_frame_size<T: Pixel>(
     &mut self, fi: &FrameInvariants<T>,
   ) -> io::Result<()> {
-    // width_bits and height_bits will have to be moved to the sequence header OBU
-    // when we add support for it.
-    let width_bits = 32 - (fi.width as u32).leading_zeros();
-    let height_bits = 32 - (fi.height as u32).leading_zeros();
-    assert!(width_bits <= 16);
-    assert!(height_bits <= 16);
-    self.write(4, width_bits - 1)?;
-    self.write(4, height_bits - 1)?;
-    self.write(width_bits, (fi.width - 1) as u16)?;
-    self.write(height_bits, (fi.height - 1) as u16)?;
+    let seq = &fi.sequence;
+    self.write(4, seq.num_bits_width - 1)?;
+    self.write(4, seq.num_bits_height - 1)?;
+    self.write(seq.num_bits_width, seq.max_frame_width - 1)?;
+    self.write(seq.num_bits_height, seq.max_frame_height - 1)?;
     Ok(())
   }
 
   fn write_frame_size_override<T: Pixel>(
     &mut self, fi: &FrameInvariants<T>,
   ) -> io::Result<()> {
-    // width_bits and height_bits will have to be moved to the sequence header OBU
-    // when we add support for it.
-    let width_bits = 32 - (fi.width as u32).leading_zeros();
-    let height_bits = 32 - (fi.height as u32).leading_zeros();
-    assert!(width_bits <= 16);
-    assert!(height_bits <= 16);
-    self.write(width_bits, (fi.width - 1) as u16)?;
-    self.write(height_bits, (fi.height - 1) as u16)?;
+    let seq = &fi.sequence;
+    self.write(seq.num_bits_width, (fi.upscaled_width - 1) as u32)?;
+    self.write(seq.num_bits_height, (fi.height - 1) as u32)?;
+    Ok(())
+  }
+
+  fn write_render_size<T: Pixel>(
+    &mut self, fi: &FrameInvariants<T>,
+  ) -> io::Result<()> {
+    // Frames coded at a reduced size are rendered at the configured size.
+    let seq = &fi.sequence;
+    let render_and_frame_size_different = fi.upscaled_width
+      != seq.max_frame_width as usize
+      || fi.height != seq.max_frame_height as usize;
+    self.write_bit(render_and_frame_size_different)?;
+    if render_and_frame_size_different {
+      self.write(16, seq.max_frame_width - 1)?;
+      self.write(16, seq.max_frame_height - 1)?;
+    }
+    Ok(())
+  }
+
+  fn write_superres_params<T: Pixel>(
+    &mut self, fi: &FrameInvariants<T>,
+  ) -> io::Result<()> {
+    if fi.sequence.enable_superres {
+      self.write_bit(fi.use_superres())?;
+      if fi.use_superres() {
+        self.write(
+          SUPERRES_DENOM_BITS,
+          fi.superres_denom - SUPERRES_DENOM_MIN,
+        )?;
+      }
+    }
     Ok(())
   }
 
@@ -986,8 +1078,7 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
   fn write_frame_lrf<T: Pixel>(
     &mut self, fi: &FrameInvariants<T>, rs: &RestorationState,
   ) -> io::Result<()> {
-    if fi.sequence.enable_restoration && !fi.allow_intrabc {
-      // && !self.lossless
+    if fi.sequence.enable_restoration && !fi.allow_intrabc && !fi.lossless {
       let planes = if fi.sequence.chroma_sampling == ChromaSampling::Cs400 {
         1
       } else {
@@ -1043,10 +1134,11 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
       if fi.primary_ref_frame == PRIMARY_REF_NONE {
         assert_eq!(segmentation.update_map, true);
         assert_eq!(segmentation.update_data, true);
+        assert_eq!(segmentation.temporal_update, false);
       } else {
         self.write_bit(segmentation.update_map)?;
         if segmentation.update_map {
-          self.write_bit(false)?; /* Without using temporal prediction */
+          self.write_bit(segmentation.temporal_update)?;
         }
         self.write_bit(segmentation.update_data)?;
       }
@@ -1078,4 +1170,130 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     }
     Ok(())
   }
+
+  fn write_global_param<T: Pixel>(
+    &mut self, fi: &FrameInvariants<T>, params: &WarpedMotionParams,
+    prev_params: &WarpedMotionParams, idx: usize,
+  ) -> io::Result<()> {
+    let (abs_bits, prec_bits) = if idx >= 2 {
+      (GM_ABS_ALPHA_BITS, GM_ALPHA_PREC_BITS)
+    } else if params.wmtype == GlobalMVMode::TRANSLATION {
+      let hp = fi.allow_high_precision_mv as u32;
+      (GM_ABS_TRANS_ONLY_BITS - 1 + hp, GM_TRANS_ONLY_PREC_BITS - 1 + hp)
+    } else {
+      (GM_ABS_TRANS_BITS, GM_TRANS_PREC_BITS)
+    };
+    let prec_diff = WARPEDMODEL_PREC_BITS - prec_bits;
+    let sub = if idx % 3 == 2 { 1 << prec_bits } else { 0 };
+    let value = params.wmmat[idx];
+    debug_assert_eq!(value & ((1 << prec_diff) - 1), 0);
+    BCodeWriter::write_s_refsubexpfin(
+      self,
+      (1 << abs_bits) + 1,
+      3,
+      ((prev_params.wmmat[idx] >> prec_diff) - sub) as i16,
+      ((value >> prec_diff) - sub) as i16,
+    )
+  }
+
+  fn write_film_grain_params<T: Pixel>(
+    &mut self, fi: &FrameInvariants<T>,
+  ) -> io::Result<()> {
+    let params = match fi.film_grain_params {
+      Some(ref params) => params,
+      None => {
+        self.write_bit(false)?; // apply_grain
+        return Ok(());
+      }
+    };
+
+    self.write_bit(true)?; // apply_grain
+    self.write(16, params.random_seed)?;
+    if fi.frame_type == FrameType::INTER {
+      // Parameters are never copied from a reference frame
+      self.write_bit(true)?; // update_grain
+    }
+
+    assert!(params.scaling_points_y.len() <= 14);
+    self.write(4, params.scaling_points_y.len() as u8)?;
+    for point in params.scaling_points_y.iter() {
+      self.write(8, point[0])?;
+      self.write(8, point[1])?;
+    }
+
+    let monochrome = fi.sequence.chroma_sampling == ChromaSampling::Cs400;
+    if monochrome {
+      assert!(!params.chroma_scaling_from_luma);
+    } else {
+      self.write_bit(params.chroma_scaling_from_luma)?;
+    }
+
+    let (num_cb_points, num_cr_points) = if monochrome
+      || params.chroma_scaling_from_luma
+      || (fi.sequence.chroma_sampling == ChromaSampling::Cs420
+        && params.scaling_points_y.is_empty())
+    {
+      (0, 0)
+    } else {
+      assert!(params.scaling_points_cb.len() <= 10);
+      self.write(4, params.scaling_points_cb.len() as u8)?;
+      for point in params.scaling_points_cb.iter() {
+        self.write(8, point[0])?;
+        self.write(8, point[1])?;
+      }
+      assert!(params.scaling_points_cr.len() <= 10);
+      self.write(4, params.scaling_points_cr.len() as u8)?;
+      for point in params.scaling_points_cr.iter() {
+        self.write(8, point[0])?;
+        self.write(8, point[1])?;
+      }
+      (params.scaling_points_cb.len(), params.scaling_points_cr.len())
+    };
+
+    assert!(params.scaling_shift >= 8 && params.scaling_shift <= 11);
+    self.write(2, params.scaling_shift - 8)?; // grain_scaling_minus_8
+    assert!(params.ar_coeff_lag <= 3);
+    self.write(2, params.ar_coeff_lag)?;
+
+    let num_pos_luma = 2 * params.ar_coeff_lag * (params.ar_coeff_lag + 1);
+    let num_pos_chroma = if !params.scaling_points_y.is_empty() {
+      assert_eq!(params.ar_coeffs_y.len(), num_pos_luma as usize);
+      for &coeff in params.ar_coeffs_y.iter() {
+        self.write(8, (i16::from(coeff) + 128) as u8)?; // ar_coeffs_y_plus_128
+      }
+      num_pos_luma + 1
+    } else {
+      num_pos_luma
+    };
+    if params.chroma_scaling_from_luma || num_cb_points > 0 {
+      assert_eq!(params.ar_coeffs_cb.len(), num_pos_chroma as usize);
+      for &coeff in params.ar_coeffs_cb.iter() {
+        self.write(8, (i16::from(coeff) + 128) as u8)?; // ar_coeffs_cb_plus_128
+      }
+    }
+    if params.chroma_scaling_from_luma || num_cr_points > 0 {
+      assert_eq!(params.ar_coeffs_cr.len(), num_pos_chroma as usize);
+      for &coeff in params.ar_coeffs_cr.iter() {
+        self.write(8, (i16::from(coeff) + 128) as u8)?; // ar_coeffs_cr_plus_128
+      }
+    }
+
+    assert!(params.ar_coeff_shift >= 6 && params.ar_coeff_shift <= 9);
+    self.write(2, params.ar_coeff_shift - 6)?; // ar_coeff_shift_minus_6
+    self.write(2, params.grain_scale_shift)?;
+    if num_cb_points > 0 {
+      self.write(8, params.cb_mult)?;
+      self.write(8, params.cb_luma_mult)?;
+      self.write(9, params.cb_offset)?;
+    }
+    if num_cr_points > 0 {
+      self.write(8, params.cr_mult)?;
+      self.write(8, params.cr_luma_mult)?;
+      self.write(9, params.cr_offset)?;
+    }
+    self.write_bit(params.overlap_flag)?;
+    self.write_bit(params.clip_to_restricted_range)?;
+
+    Ok(())
+  }
 }
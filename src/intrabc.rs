@@ -0,0 +1,330 @@
+// Copyright (c) 2020, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::context::*;
+use crate::dist::get_sad;
+use crate::encoder::FrameInvariants;
+use crate::frame::*;
+use crate::mc::MotionVector;
+use crate::partition::BlockSize;
+use crate::tiling::*;
+use crate::util::*;
+
+/// Number of 64x64 superblocks the source of a block copy must lag behind
+/// the block being coded, so that hardware decoders can pipeline the
+/// reconstruction.
+pub const INTRABC_DELAYED_SB64: usize = 4;
+/// Horizontal lag of the default reference DV on the first superblock row
+/// of a tile.
+pub const INTRABC_DELAYED_PIXELS: usize = 256;
+
+/// Square block sizes for which the source frame is hashed.
+const HASH_BLOCK_SIZES: [usize; 4] = [8, 16, 32, 64];
+/// Number of matching positions checked for each block.
+const MAX_HASH_CANDIDATES: usize = 64;
+
+fn hash_combine(values: [u32; 4]) -> u32 {
+  values.iter().fold(0x811c_9dc5, |hash, &v| {
+    (hash ^ v).wrapping_mul(0x9e37_79b1).rotate_left(15)
+  })
+}
+
+fn pixel_hash<T: Pixel>(src: &PlaneRegion<'_, T>, x: usize, y: usize) -> u32 {
+  let px = |x: usize, y: usize| u32::from(u16::cast_from(src[y][x]));
+  hash_combine([px(x, y), px(x + 1, y), px(x, y + 1), px(x + 1, y + 1)])
+}
+
+/// Hash of the `size`x`size` block at (`x`, `y`), built bottom-up from the
+/// hashes of its four quadrants.
+fn block_hash<T: Pixel>(
+  src: &PlaneRegion<'_, T>, x: usize, y: usize, size: usize,
+) -> u32 {
+  if size == 2 {
+    return pixel_hash(src, x, y);
+  }
+  let half = size / 2;
+  hash_combine([
+    block_hash(src, x, y, half),
+    block_hash(src, x + half, y, half),
+    block_hash(src, x, y + half, half),
+    block_hash(src, x + half, y + half, half),
+  ])
+}
+
+/// Hashes of all the square blocks of the source luma plane, used to find
+/// exact copies of a block elsewhere in the frame.
+#[derive(Clone, Debug, Default)]
+pub struct BlockHashes {
+  /// Sorted `(hash, y << 16 | x)` pairs for each of `HASH_BLOCK_SIZES`.
+  /// Only even positions are kept, so that the DVs found are also integer
+  /// in subsampled chroma planes.
+  tables: [Vec<(u32, u32)>; 4],
+}
+
+impl BlockHashes {
+  pub fn new<T: Pixel>(plane: &Plane<T>, width: usize, height: usize) -> Self {
+    let src = plane.as_region();
+    let mut hashes = vec![0u32; width * height];
+    for y in 0..height.saturating_sub(1) {
+      for x in 0..width.saturating_sub(1) {
+        hashes[y * width + x] = pixel_hash(&src, x, y);
+      }
+    }
+
+    let mut tables: [Vec<(u32, u32)>; 4] = Default::default();
+    let mut size = 2;
+    for (table, &block_size) in tables.iter_mut().zip(&HASH_BLOCK_SIZES) {
+      // Each pass only reads positions at or after the one it writes, so
+      // the hashes can be combined in place.
+      while size < block_size {
+        for y in 0..(height + 1).saturating_sub(2 * size) {
+          for x in 0..(width + 1).saturating_sub(2 * size) {
+            let i = y * width + x;
+            hashes[i] = hash_combine([
+              hashes[i],
+              hashes[i + size],
+              hashes[i + size * width],
+              hashes[i + size * width + size],
+            ]);
+          }
+        }
+        size *= 2;
+      }
+      for y in (0..(height + 1).saturating_sub(size)).step_by(2) {
+        for x in (0..(width + 1).saturating_sub(size)).step_by(2) {
+          table.push((hashes[y * width + x], (y << 16 | x) as u32));
+        }
+      }
+      table.sort_unstable();
+    }
+
+    BlockHashes { tables }
+  }
+
+  fn table(&self, size: usize) -> Option<&[(u32, u32)]> {
+    HASH_BLOCK_SIZES
+      .iter()
+      .position(|&s| s == size)
+      .map(|i| self.tables[i].as_slice())
+  }
+}
+
+/// Checks the restrictions of the spec on the area a block can be copied
+/// from: it must be inside the current tile, already reconstructed, and far
+/// enough from the block being coded (see `is_dv_valid` in the spec).
+pub fn is_dv_valid<T: Pixel>(
+  fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, tile_bo: TileBlockOffset,
+  bsize: BlockSize, dv: MotionVector,
+) -> bool {
+  if dv.row & 7 != 0 || dv.col & 7 != 0 {
+    return false;
+  }
+
+  let frame_bo = ts.to_frame_block_offset(tile_bo);
+  let mi_row = (frame_bo.0.y << MI_SIZE_LOG2) as isize;
+  let mi_col = (frame_bo.0.x << MI_SIZE_LOG2) as isize;
+  let mut src_top = mi_row + (dv.row >> 3) as isize;
+  let mut src_left = mi_col + (dv.col >> 3) as isize;
+  let src_bottom = src_top + bsize.height() as isize;
+  let src_right = src_left + bsize.width() as isize;
+
+  let PlaneConfig { xdec, ydec, .. } = ts.input.planes[1].cfg;
+  if has_chroma(tile_bo, bsize, xdec, ydec, fi.sequence.chroma_sampling) {
+    if bsize.width() < 8 && xdec != 0 {
+      src_left -= 4;
+    }
+    if bsize.height() < 8 && ydec != 0 {
+      src_top -= 4;
+    }
+  }
+
+  let tile_rect = ts.tile_rect();
+  if src_top < tile_rect.y as isize
+    || src_left < tile_rect.x as isize
+    || src_bottom > (tile_rect.y + tile_rect.height) as isize
+    || src_right > (tile_rect.x + tile_rect.width) as isize
+  {
+    return false;
+  }
+
+  let sb_height = 1 << ts.sb_size_log2;
+  let active_sb_row = mi_row / sb_height;
+  let active_sb64_col = mi_col >> 6;
+  let src_sb_row = (src_bottom - 1) / sb_height;
+  let src_sb64_col = (src_right - 1) >> 6;
+  let total_sb64_per_row = ((ts.mi_width as isize - 1) >> 4) + 1;
+  let active_sb64 = active_sb_row * total_sb64_per_row + active_sb64_col;
+  let src_sb64 = src_sb_row * total_sb64_per_row + src_sb64_col;
+  if src_sb64 >= active_sb64 - INTRABC_DELAYED_SB64 as isize {
+    return false;
+  }
+
+  let gradient = 1 + INTRABC_DELAYED_SB64 as isize + (sb_height > 64) as isize;
+  let wf_offset = gradient * (active_sb_row - src_sb_row);
+  src_sb_row <= active_sb_row
+    && src_sb64_col
+      < active_sb64_col - INTRABC_DELAYED_SB64 as isize + wf_offset
+}
+
+/// Returns the DV the block DV is coded against: the first non-zero
+/// candidate of the stack, or a default pointing one superblock away.
+pub fn intrabc_ref_dv(
+  mv_stack: &[CandidateMV], tile_bo: TileBlockOffset, sb_size_log2: usize,
+) -> MotionVector {
+  mv_stack
+    .iter()
+    .take(2)
+    .map(|c| c.this_mv)
+    .find(|mv| mv.row != 0 || mv.col != 0)
+    .unwrap_or_else(|| {
+      let sb_size = 1 << sb_size_log2;
+      if tile_bo.0.y << MI_SIZE_LOG2 < sb_size {
+        let col = -(((sb_size + INTRABC_DELAYED_PIXELS) * 8) as i16);
+        MotionVector { row: 0, col }
+      } else {
+        MotionVector { row: -((sb_size * 8) as i16), col: 0 }
+      }
+    })
+}
+
+fn dv_in_range(dv: MotionVector, ref_dv: MotionVector) -> bool {
+  let in_range = |v: i32| MV_LOW < v && v < MV_UPP;
+  in_range(dv.row as i32)
+    && in_range(dv.col as i32)
+    && in_range(dv.row as i32 - ref_dv.row as i32)
+    && in_range(dv.col as i32 - ref_dv.col as i32)
+}
+
+/// Looks up the positions of the frame with the same source content as the
+/// block, and returns the valid DV whose reconstruction is the closest.
+/// `ref_dv` is the DV it will be coded against.
+pub fn intrabc_search<T: Pixel>(
+  fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, tile_bo: TileBlockOffset,
+  bsize: BlockSize, ref_dv: MotionVector,
+) -> Option<MotionVector> {
+  let size = bsize.width();
+  if !bsize.is_sqr() {
+    return None;
+  }
+  let table = ts.block_hashes?.table(size)?;
+
+  let frame_bo = ts.to_frame_block_offset(tile_bo);
+  let x = frame_bo.0.x << MI_SIZE_LOG2;
+  let y = frame_bo.0.y << MI_SIZE_LOG2;
+  if x + size > fi.width || y + size > fi.height {
+    return None;
+  }
+
+  let hash = block_hash(&ts.input.planes[0].as_region(), x, y, size);
+  let lower_bound = |key| match table.binary_search(&key) {
+    Ok(i) | Err(i) => i,
+  };
+  // Walk back from the current position, so that the closest candidates,
+  // which are the cheapest to code, are checked first.
+  let candidates =
+    &table[lower_bound((hash, 0))..lower_bound((hash, (y << 16 | x) as u32))];
+
+  let tile_rect = ts.tile_rect();
+  let org =
+    ts.input_tile.planes[0].subregion(Area::BlockStartingAt { bo: tile_bo.0 });
+  let mut best: Option<(MotionVector, u32)> = None;
+  for &(_, pos) in candidates.iter().rev().take(MAX_HASH_CANDIDATES) {
+    let (src_x, src_y) = ((pos & 0xffff) as usize, (pos >> 16) as usize);
+    let (dy, dx) = (src_y as isize - y as isize, src_x as isize - x as isize);
+    if dy.abs() >= 1 << 11 || dx.abs() >= 1 << 11 {
+      continue;
+    }
+    let dv = MotionVector { row: (dy * 8) as i16, col: (dx * 8) as i16 };
+    if !dv_in_range(dv, ref_dv) || !is_dv_valid(fi, ts, tile_bo, bsize, dv) {
+      continue;
+    }
+
+    let src = ts.rec.planes[0].subregion(Area::StartingAt {
+      x: (src_x - tile_rect.x) as isize,
+      y: (src_y - tile_rect.y) as isize,
+    });
+    let sad =
+      get_sad(&org, &src, bsize, fi.sequence.bit_depth, fi.cpu_feature_level);
+    if best.map_or(true, |(_, best_sad)| sad < best_sad) {
+      best = Some((dv, sad));
+      if sad == 0 {
+        break;
+      }
+    }
+  }
+
+  best.map(|(dv, _)| dv)
+}
+
+/// Copies a block of the reconstruction of the current frame, at the
+/// offset given by the luma DV.
+pub fn predict_intrabc<T: Pixel>(
+  rec: &mut PlaneRegionMut<'_, T>, po: PlaneOffset, dv: MotionVector,
+  width: usize, height: usize,
+) {
+  let &PlaneConfig { xdec, ydec, .. } = rec.plane_cfg;
+  let src_x = po.x + (dv.col as isize >> (3 + xdec));
+  let src_y = po.y + (dv.row as isize >> (3 + ydec));
+  for y in 0..height {
+    for x in 0..width {
+      let v =
+        rec[(src_y + y as isize) as usize][(src_x + x as isize) as usize];
+      rec[(po.y + y as isize) as usize][(po.x + x as isize) as usize] = v;
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn block_hash_matches_table() {
+    let (w, h) = (80, 72);
+    let mut plane = Plane::<u8>::new(w, h, 0, 0, 0, 0);
+    let stride = plane.cfg.stride;
+    for (y, row) in plane.data.chunks_mut(stride).take(h).enumerate() {
+      for (x, v) in row[..w].iter_mut().enumerate() {
+        *v = ((x * 7 + y * 13) % 251) as u8;
+      }
+    }
+
+    let hashes = BlockHashes::new(&plane, w, h);
+    let src = plane.as_region();
+    for &size in HASH_BLOCK_SIZES.iter() {
+      let table = hashes.table(size).unwrap();
+      assert_eq!(
+        table.len(),
+        ((w - size) / 2 + 1) * ((h - size) / 2 + 1),
+        "size {}",
+        size
+      );
+      for &(hash, pos) in table.iter() {
+        let (x, y) = ((pos & 0xffff) as usize, (pos >> 16) as usize);
+        assert_eq!(hash, block_hash(&src, x, y, size));
+      }
+    }
+  }
+
+  #[test]
+  fn repeated_blocks_share_hash() {
+    let (w, h) = (64, 64);
+    let mut plane = Plane::<u16>::new(w, h, 0, 0, 0, 0);
+    let stride = plane.cfg.stride;
+    for (y, row) in plane.data.chunks_mut(stride).take(h).enumerate() {
+      for (x, v) in row[..w].iter_mut().enumerate() {
+        *v = (((x % 16) * 31 + (y % 16) * 17) % 1023) as u16;
+      }
+    }
+
+    let src = plane.as_region();
+    assert_eq!(block_hash(&src, 0, 0, 16), block_hash(&src, 32, 16, 16));
+    assert_ne!(block_hash(&src, 0, 0, 16), block_hash(&src, 2, 0, 16));
+  }
+}
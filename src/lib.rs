@@ -19,6 +19,7 @@
 //! * DC, H, V, Paeth, smooth, and a subset of directional prediction modes
 //! * DCT, (FLIP-)ADST and identity transforms (up to 64x64, 16x16 and 32x32
 //!   respectively)
+//! * Lossless coding with the Walsh-Hadamard transform
 //! * 8-, 10- and 12-bit depth color
 //! * 4:2:0 (full support), 4:2:2 and 4:4:4 (limited) chroma sampling
 //! * Variable speed settings
@@ -176,8 +177,10 @@ mod activity;
 pub(crate) mod asm;
 mod dist;
 mod ec;
+mod palette;
 mod partition;
 mod predict;
+mod qm_tables;
 mod quantize;
 mod rdo;
 mod rdo_tables;
@@ -186,19 +189,25 @@ mod util;
 mod cdef;
 mod context;
 mod deblock;
+mod denoise;
 mod encoder;
 mod entropymode;
+mod film_grain;
+mod global_motion;
+mod intrabc;
 mod lrf;
 mod mc;
 mod me;
 mod rate;
 mod recon_intra;
+mod scale;
 mod scan_order;
 mod scenechange;
 mod segmentation;
 mod stats;
 mod tiling;
 mod token_cdfs;
+mod warp;
 
 mod api;
 mod frame;
@@ -215,7 +224,7 @@ pub use crate::util::{CastFromPrimitive, Pixel, PixelType};
 /// Commonly used types and traits.
 pub mod prelude {
   pub use crate::api::*;
-  pub use crate::encoder::Tune;
+  pub use crate::encoder::{SuperresMode, Tune};
   pub use crate::frame::{
     Frame, FrameParameters, FrameTypeOverride, Plane, PlaneConfig,
   };
@@ -236,6 +245,8 @@ pub mod data {
 }
 
 pub use crate::api::color;
+#[doc(hidden)]
+pub use crate::api::isobmff;
 
 /// Encoder configuration and settings
 pub mod config {
@@ -16,7 +16,7 @@ cfg_if::cfg_if! {
 }
 
 use crate::color::ChromaSampling::Cs400;
-use crate::context::{MAX_PLANES, SB_SIZE};
+use crate::context::{MAX_PLANES, SB_SIZE, SB_SIZE_LOG2};
 use crate::encoder::FrameInvariants;
 use crate::frame::{
   AsRegion, Frame, Plane, PlaneConfig, PlaneOffset, PlaneSlice,
@@ -1307,6 +1307,12 @@ impl RestorationState {
       } else {
         2 // small
       };
+      // The luma LRU can't be smaller than a 128x128 superblock.
+      let lrf_base_shift = if fi.sequence.use_128x128_superblock {
+        lrf_base_shift.min(1)
+      } else {
+        lrf_base_shift
+      };
       let lrf_chroma_shift = if stripe_uv_decimate > 0 {
         // 4:2:0 only
         if lrf_base_shift == 2 {
@@ -1405,13 +1411,18 @@ impl RestorationState {
       / uv_unit_size)
       .max(1);
 
+    // Restoration of a super-resolved frame applies to the upscaled frame,
+    // which is not searched, so it is left off.
+    let lrf_type =
+      if fi.use_superres() { RESTORE_NONE } else { RESTORE_SWITCHABLE };
+
     RestorationState {
       planes: [
         RestorationPlane::new(
-          RESTORE_SWITCHABLE,
+          lrf_type,
           y_unit_size,
-          y_unit_log2 - y_sb_log2,
-          y_unit_log2 - y_sb_log2,
+          y_unit_log2 - SB_SIZE_LOG2,
+          y_unit_log2 - SB_SIZE_LOG2,
           fi.sb_width,
           fi.sb_height,
           0,
@@ -1419,10 +1430,10 @@ impl RestorationState {
           y_rows,
         ),
         RestorationPlane::new(
-          RESTORE_SWITCHABLE,
+          lrf_type,
           uv_unit_size,
-          uv_unit_log2 - uv_sb_h_log2,
-          uv_unit_log2 - uv_sb_v_log2,
+          uv_unit_log2 - (SB_SIZE_LOG2 - xdec),
+          uv_unit_log2 - (SB_SIZE_LOG2 - ydec),
           fi.sb_width,
           fi.sb_height,
           stripe_uv_decimate,
@@ -1430,10 +1441,10 @@ impl RestorationState {
           uv_rows,
         ),
         RestorationPlane::new(
-          RESTORE_SWITCHABLE,
+          lrf_type,
           uv_unit_size,
-          uv_unit_log2 - uv_sb_h_log2,
-          uv_unit_log2 - uv_sb_v_log2,
+          uv_unit_log2 - (SB_SIZE_LOG2 - xdec),
+          uv_unit_log2 - (SB_SIZE_LOG2 - ydec),
           fi.sb_width,
           fi.sb_height,
           stripe_uv_decimate,
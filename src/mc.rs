@@ -21,6 +21,7 @@ use crate::cpu_features::CpuFeatureLevel;
 use crate::frame::*;
 use crate::tiling::*;
 use crate::util::*;
+use crate::warp::*;
 
 use simd_helpers::cold_for_target_arch;
 
@@ -165,6 +166,500 @@ const SUBPEL_FILTERS: [[[i32; SUBPEL_FILTER_SIZE]; 16]; 6] = [
   ],
 ];
 
+/// Filters used by the warp filter (spec `Warped_Filters`), indexed by the
+/// position in 1/64 pel offset by 64.
+const WARPED_FILTERS: [[i8; 8]; 193] = [
+  // [-1, 0)
+  [0, 0, 127, 1, 0, 0, 0, 0],
+  [0, -1, 127, 2, 0, 0, 0, 0],
+  [1, -3, 127, 4, -1, 0, 0, 0],
+  [1, -4, 126, 6, -2, 1, 0, 0],
+  [1, -5, 126, 8, -3, 1, 0, 0],
+  [1, -6, 125, 11, -4, 1, 0, 0],
+  [1, -7, 124, 13, -4, 1, 0, 0],
+  [2, -8, 123, 15, -5, 1, 0, 0],
+  [2, -9, 122, 18, -6, 1, 0, 0],
+  [2, -10, 121, 20, -6, 1, 0, 0],
+  [2, -11, 120, 22, -7, 2, 0, 0],
+  [2, -12, 119, 25, -8, 2, 0, 0],
+  [3, -13, 117, 27, -8, 2, 0, 0],
+  [3, -13, 116, 29, -9, 2, 0, 0],
+  [3, -14, 114, 32, -10, 3, 0, 0],
+  [3, -15, 113, 35, -10, 2, 0, 0],
+  [3, -15, 111, 37, -11, 3, 0, 0],
+  [3, -16, 109, 40, -11, 3, 0, 0],
+  [3, -16, 108, 42, -12, 3, 0, 0],
+  [4, -17, 106, 45, -13, 3, 0, 0],
+  [4, -17, 104, 47, -13, 3, 0, 0],
+  [4, -17, 102, 50, -14, 3, 0, 0],
+  [4, -17, 100, 52, -14, 3, 0, 0],
+  [4, -18, 98, 55, -15, 4, 0, 0],
+  [4, -18, 96, 58, -15, 3, 0, 0],
+  [4, -18, 94, 60, -16, 4, 0, 0],
+  [4, -18, 91, 63, -16, 4, 0, 0],
+  [4, -18, 89, 65, -16, 4, 0, 0],
+  [4, -18, 87, 68, -17, 4, 0, 0],
+  [4, -18, 85, 70, -17, 4, 0, 0],
+  [4, -18, 82, 73, -17, 4, 0, 0],
+  [4, -18, 80, 75, -17, 4, 0, 0],
+  [4, -18, 78, 78, -18, 4, 0, 0],
+  [4, -17, 75, 80, -18, 4, 0, 0],
+  [4, -17, 73, 82, -18, 4, 0, 0],
+  [4, -17, 70, 85, -18, 4, 0, 0],
+  [4, -17, 68, 87, -18, 4, 0, 0],
+  [4, -16, 65, 89, -18, 4, 0, 0],
+  [4, -16, 63, 91, -18, 4, 0, 0],
+  [4, -16, 60, 94, -18, 4, 0, 0],
+  [3, -15, 58, 96, -18, 4, 0, 0],
+  [4, -15, 55, 98, -18, 4, 0, 0],
+  [3, -14, 52, 100, -17, 4, 0, 0],
+  [3, -14, 50, 102, -17, 4, 0, 0],
+  [3, -13, 47, 104, -17, 4, 0, 0],
+  [3, -13, 45, 106, -17, 4, 0, 0],
+  [3, -12, 42, 108, -16, 3, 0, 0],
+  [3, -11, 40, 109, -16, 3, 0, 0],
+  [3, -11, 37, 111, -15, 3, 0, 0],
+  [2, -10, 35, 113, -15, 3, 0, 0],
+  [3, -10, 32, 114, -14, 3, 0, 0],
+  [2, -9, 29, 116, -13, 3, 0, 0],
+  [2, -8, 27, 117, -13, 3, 0, 0],
+  [2, -8, 25, 119, -12, 2, 0, 0],
+  [2, -7, 22, 120, -11, 2, 0, 0],
+  [1, -6, 20, 121, -10, 2, 0, 0],
+  [1, -6, 18, 122, -9, 2, 0, 0],
+  [1, -5, 15, 123, -8, 2, 0, 0],
+  [1, -4, 13, 124, -7, 1, 0, 0],
+  [1, -4, 11, 125, -6, 1, 0, 0],
+  [1, -3, 8, 126, -5, 1, 0, 0],
+  [1, -2, 6, 126, -4, 1, 0, 0],
+  [0, -1, 4, 127, -3, 1, 0, 0],
+  [0, 0, 2, 127, -1, 0, 0, 0],
+  // [0, 1)
+  [0, 0, 0, 127, 1, 0, 0, 0],
+  [0, 0, -1, 127, 2, 0, 0, 0],
+  [0, 1, -3, 127, 4, -2, 1, 0],
+  [0, 1, -5, 127, 6, -2, 1, 0],
+  [0, 2, -6, 126, 8, -3, 1, 0],
+  [-1, 2, -7, 126, 11, -4, 2, -1],
+  [-1, 3, -8, 125, 13, -5, 2, -1],
+  [-1, 3, -10, 124, 16, -6, 3, -1],
+  [-1, 4, -11, 123, 18, -7, 3, -1],
+  [-1, 4, -12, 122, 20, -7, 3, -1],
+  [-1, 4, -13, 121, 23, -8, 3, -1],
+  [-2, 5, -14, 120, 25, -9, 4, -1],
+  [-1, 5, -15, 119, 27, -10, 4, -1],
+  [-1, 5, -16, 118, 30, -11, 4, -1],
+  [-2, 6, -17, 116, 33, -12, 5, -1],
+  [-2, 6, -17, 114, 35, -12, 5, -1],
+  [-2, 6, -18, 113, 38, -13, 5, -1],
+  [-2, 7, -19, 111, 41, -14, 6, -2],
+  [-2, 7, -19, 110, 43, -15, 6, -2],
+  [-2, 7, -20, 108, 46, -15, 6, -2],
+  [-2, 7, -20, 106, 49, -16, 6, -2],
+  [-2, 7, -21, 104, 51, -16, 7, -2],
+  [-2, 7, -21, 102, 54, -17, 7, -2],
+  [-2, 8, -21, 100, 56, -18, 7, -2],
+  [-2, 8, -22, 98, 59, -18, 7, -2],
+  [-2, 8, -22, 96, 62, -19, 7, -2],
+  [-2, 8, -22, 94, 64, -19, 7, -2],
+  [-2, 8, -22, 91, 67, -20, 8, -2],
+  [-2, 8, -22, 89, 69, -20, 8, -2],
+  [-2, 8, -22, 87, 72, -21, 8, -2],
+  [-2, 8, -21, 84, 74, -21, 8, -2],
+  [-2, 8, -22, 82, 77, -21, 8, -2],
+  [-2, 8, -21, 79, 79, -21, 8, -2],
+  [-2, 8, -21, 77, 82, -22, 8, -2],
+  [-2, 8, -21, 74, 84, -21, 8, -2],
+  [-2, 8, -21, 72, 87, -22, 8, -2],
+  [-2, 8, -20, 69, 89, -22, 8, -2],
+  [-2, 8, -20, 67, 91, -22, 8, -2],
+  [-2, 7, -19, 64, 94, -22, 8, -2],
+  [-2, 7, -19, 62, 96, -22, 8, -2],
+  [-2, 7, -18, 59, 98, -22, 8, -2],
+  [-2, 7, -18, 56, 100, -21, 8, -2],
+  [-2, 7, -17, 54, 102, -21, 7, -2],
+  [-2, 7, -16, 51, 104, -21, 7, -2],
+  [-2, 6, -16, 49, 106, -20, 7, -2],
+  [-2, 6, -15, 46, 108, -20, 7, -2],
+  [-2, 6, -15, 43, 110, -19, 7, -2],
+  [-2, 6, -14, 41, 111, -19, 7, -2],
+  [-1, 5, -13, 38, 113, -18, 6, -2],
+  [-1, 5, -12, 35, 114, -17, 6, -2],
+  [-1, 5, -12, 33, 116, -17, 6, -2],
+  [-1, 4, -11, 30, 118, -16, 5, -1],
+  [-1, 4, -10, 27, 119, -15, 5, -1],
+  [-1, 4, -9, 25, 120, -14, 5, -2],
+  [-1, 3, -8, 23, 121, -13, 4, -1],
+  [-1, 3, -7, 20, 122, -12, 4, -1],
+  [-1, 3, -7, 18, 123, -11, 4, -1],
+  [-1, 3, -6, 16, 124, -10, 3, -1],
+  [-1, 2, -5, 13, 125, -8, 3, -1],
+  [-1, 2, -4, 11, 126, -7, 2, -1],
+  [0, 1, -3, 8, 126, -6, 2, 0],
+  [0, 1, -2, 6, 127, -5, 1, 0],
+  [0, 1, -2, 4, 127, -3, 1, 0],
+  [0, 0, 0, 2, 127, -1, 0, 0],
+  // [1, 2)
+  [0, 0, 0, 1, 127, 0, 0, 0],
+  [0, 0, 0, -1, 127, 2, 0, 0],
+  [0, 0, 1, -3, 127, 4, -1, 0],
+  [0, 0, 1, -4, 126, 6, -2, 1],
+  [0, 0, 1, -5, 126, 8, -3, 1],
+  [0, 0, 1, -6, 125, 11, -4, 1],
+  [0, 0, 1, -7, 124, 13, -4, 1],
+  [0, 0, 2, -8, 123, 15, -5, 1],
+  [0, 0, 2, -9, 122, 18, -6, 1],
+  [0, 0, 2, -10, 121, 20, -6, 1],
+  [0, 0, 2, -11, 120, 22, -7, 2],
+  [0, 0, 2, -12, 119, 25, -8, 2],
+  [0, 0, 3, -13, 117, 27, -8, 2],
+  [0, 0, 3, -13, 116, 29, -9, 2],
+  [0, 0, 3, -14, 114, 32, -10, 3],
+  [0, 0, 3, -15, 113, 35, -10, 2],
+  [0, 0, 3, -15, 111, 37, -11, 3],
+  [0, 0, 3, -16, 109, 40, -11, 3],
+  [0, 0, 3, -16, 108, 42, -12, 3],
+  [0, 0, 4, -17, 106, 45, -13, 3],
+  [0, 0, 4, -17, 104, 47, -13, 3],
+  [0, 0, 4, -17, 102, 50, -14, 3],
+  [0, 0, 4, -17, 100, 52, -14, 3],
+  [0, 0, 4, -18, 98, 55, -15, 4],
+  [0, 0, 4, -18, 96, 58, -15, 3],
+  [0, 0, 4, -18, 94, 60, -16, 4],
+  [0, 0, 4, -18, 91, 63, -16, 4],
+  [0, 0, 4, -18, 89, 65, -16, 4],
+  [0, 0, 4, -18, 87, 68, -17, 4],
+  [0, 0, 4, -18, 85, 70, -17, 4],
+  [0, 0, 4, -18, 82, 73, -17, 4],
+  [0, 0, 4, -18, 80, 75, -17, 4],
+  [0, 0, 4, -18, 78, 78, -18, 4],
+  [0, 0, 4, -17, 75, 80, -18, 4],
+  [0, 0, 4, -17, 73, 82, -18, 4],
+  [0, 0, 4, -17, 70, 85, -18, 4],
+  [0, 0, 4, -17, 68, 87, -18, 4],
+  [0, 0, 4, -16, 65, 89, -18, 4],
+  [0, 0, 4, -16, 63, 91, -18, 4],
+  [0, 0, 4, -16, 60, 94, -18, 4],
+  [0, 0, 3, -15, 58, 96, -18, 4],
+  [0, 0, 4, -15, 55, 98, -18, 4],
+  [0, 0, 3, -14, 52, 100, -17, 4],
+  [0, 0, 3, -14, 50, 102, -17, 4],
+  [0, 0, 3, -13, 47, 104, -17, 4],
+  [0, 0, 3, -13, 45, 106, -17, 4],
+  [0, 0, 3, -12, 42, 108, -16, 3],
+  [0, 0, 3, -11, 40, 109, -16, 3],
+  [0, 0, 3, -11, 37, 111, -15, 3],
+  [0, 0, 2, -10, 35, 113, -15, 3],
+  [0, 0, 3, -10, 32, 114, -14, 3],
+  [0, 0, 2, -9, 29, 116, -13, 3],
+  [0, 0, 2, -8, 27, 117, -13, 3],
+  [0, 0, 2, -8, 25, 119, -12, 2],
+  [0, 0, 2, -7, 22, 120, -11, 2],
+  [0, 0, 1, -6, 20, 121, -10, 2],
+  [0, 0, 1, -6, 18, 122, -9, 2],
+  [0, 0, 1, -5, 15, 123, -8, 2],
+  [0, 0, 1, -4, 13, 124, -7, 1],
+  [0, 0, 1, -4, 11, 125, -6, 1],
+  [0, 0, 1, -3, 8, 126, -5, 1],
+  [0, 0, 1, -2, 6, 126, -4, 1],
+  [0, 0, 0, -1, 4, 127, -3, 1],
+  [0, 0, 0, 0, 2, 127, -1, 0],
+  // dummy (replicate row index 191)
+  [0, 0, 0, 0, 2, 127, -1, 0],
+];
+
+const WARPEDDIFF_PREC_BITS: u32 = 10;
+const WARPEDPIXEL_PREC_SHIFTS: i32 = 1 << 6;
+
+/// Runs the warp filter on the 8x8 block centered at `(src_x, src_y)` in
+/// the (luma) coordinates of the current frame (spec 7.11.3.5).
+fn warp_block<T: Pixel>(
+  src: &Plane<T>, params: &WarpedMotionParams, shear: &ShearParams,
+  src_x: i64, src_y: i64, round0: i32, round1: i32,
+) -> [[i32; 8]; 8] {
+  let &PlaneConfig { xdec, ydec, width, height, .. } = &src.cfg;
+  let mat = &params.wmmat;
+  let dst_x = mat[2] as i64 * src_x + mat[3] as i64 * src_y + mat[0] as i64;
+  let dst_y = mat[4] as i64 * src_x + mat[5] as i64 * src_y + mat[1] as i64;
+  let x4 = dst_x >> xdec;
+  let y4 = dst_y >> ydec;
+  // The filter positions only keep the precision of the shear parameters.
+  let phase_mask =
+    ((1 << WARPEDMODEL_PREC_BITS) - 1) & !((1 << WARP_PARAM_REDUCE_BITS) - 1);
+  let ix4 = (x4 >> WARPEDMODEL_PREC_BITS) as isize;
+  let sx4 = (x4 & phase_mask) as i32;
+  let iy4 = (y4 >> WARPEDMODEL_PREC_BITS) as isize;
+  let sy4 = (y4 & phase_mask) as i32;
+  let last_x = width as isize - 1;
+  let last_y = height as isize - 1;
+
+  let filter = |pos: i32| {
+    let offs = round_shift(pos, WARPEDDIFF_PREC_BITS as usize)
+      + WARPEDPIXEL_PREC_SHIFTS;
+    &WARPED_FILTERS[offs as usize]
+  };
+
+  let mut intermediate = [[0i32; 8]; 15];
+  for (i1, row) in (-7..8).zip(intermediate.iter_mut()) {
+    let y = (iy4 + i1 as isize).max(0).min(last_y) as usize;
+    for (i2, out) in (-4..4).zip(row.iter_mut()) {
+      let f = filter(sx4 + shear.alpha * i2 + shear.beta * i1);
+      let sum: i32 = f
+        .iter()
+        .enumerate()
+        .map(|(i3, &c)| {
+          let x = (ix4 + (i2 - 3) as isize + i3 as isize).max(0).min(last_x);
+          c as i32 * i32::cast_from(src.p(x as usize, y))
+        })
+        .sum();
+      *out = round_shift(sum, round0 as usize);
+    }
+  }
+
+  let mut block = [[0i32; 8]; 8];
+  for (i1, row) in (-4..4).zip(block.iter_mut()) {
+    for (i2, out) in (-4..4).zip(row.iter_mut()) {
+      let f = filter(sy4 + shear.gamma * i2 + shear.delta * i1);
+      let sum: i32 = f
+        .iter()
+        .enumerate()
+        .map(|(i3, &c)| {
+          c as i32 * intermediate[(i1 + 4) as usize + i3][(i2 + 4) as usize]
+        })
+        .sum();
+      *out = round_shift(sum, round1 as usize);
+    }
+  }
+  block
+}
+
+/// Calls `f` with every 8x8 warped block covering a `width`x`height`
+/// prediction at `po`, along with the position of the block in it.
+fn for_each_warp_block<T: Pixel, F: FnMut(usize, usize, &[[i32; 8]; 8])>(
+  src: &Plane<T>, po: PlaneOffset, width: usize, height: usize,
+  params: &WarpedMotionParams, round0: i32, round1: i32, mut f: F,
+) {
+  let shear = params.shear().expect("invalid warp parameters");
+  let &PlaneConfig { xdec, ydec, .. } = &src.cfg;
+  for y8 in (0..height).step_by(8) {
+    for x8 in (0..width).step_by(8) {
+      let src_x = ((po.x + x8 as isize + 4) << xdec) as i64;
+      let src_y = ((po.y + y8 as isize + 4) << ydec) as i64;
+      let block =
+        warp_block(src, params, &shear, src_x, src_y, round0, round1);
+      f(x8, y8, &block);
+    }
+  }
+}
+
+/// Predicts a block at `po` (in frame coordinates) by warping `src` with
+/// `params`.
+pub fn put_warp<T: Pixel>(
+  dst: &mut PlaneRegionMut<'_, T>, src: &Plane<T>, po: PlaneOffset,
+  width: usize, height: usize, params: &WarpedMotionParams, bit_depth: usize,
+) {
+  let max_sample_val = ((1 << bit_depth) - 1) as i32;
+  let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+  let round0 = 7 - intermediate_bits;
+  let round1 = 7 + intermediate_bits;
+  for_each_warp_block(
+    src,
+    po,
+    width,
+    height,
+    params,
+    round0,
+    round1,
+    |x8, y8, block| {
+      for (r, row) in block.iter().enumerate().take(height - y8) {
+        let dst_slice = &mut dst[y8 + r][x8..];
+        for (d, &v) in dst_slice.iter_mut().zip(row.iter()).take(width - x8) {
+          *d = T::cast_from(v.max(0).min(max_sample_val));
+        }
+      }
+    },
+  );
+}
+
+/// Same as `put_warp`, but writes the intermediate values used for compound
+/// prediction, at the scale of `prep_8tap`.
+pub fn prep_warp<T: Pixel>(
+  tmp: &mut [i16], src: &Plane<T>, po: PlaneOffset, width: usize,
+  height: usize, params: &WarpedMotionParams, bit_depth: usize,
+) {
+  let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+  let round0 = 7 - intermediate_bits;
+  for_each_warp_block(
+    src,
+    po,
+    width,
+    height,
+    params,
+    round0,
+    7,
+    |x8, y8, block| {
+      for (r, row) in block.iter().enumerate().take(height - y8) {
+        let tmp_slice = &mut tmp[(y8 + r) * width + x8..];
+        for (d, &v) in tmp_slice.iter_mut().zip(row.iter()).take(width - x8) {
+          *d = v as i16;
+        }
+      }
+    },
+  );
+}
+
+fn get_filter(
+  mode: FilterMode, frac: i32, length: usize,
+) -> [i32; SUBPEL_FILTER_SIZE] {
+  // Blocks of 4 samples or less use the 4-tap regular filter in place of the
+  // sharp one.
+  let filter_idx = if mode == FilterMode::BILINEAR || length > 4 {
+    mode as usize
+  } else if mode == FilterMode::SMOOTH {
+    5
+  } else {
+    4
+  };
+  SUBPEL_FILTERS[filter_idx][frac as usize]
+}
+
+const REF_SCALE_SHIFT: u32 = 14;
+const SCALE_SUBPEL_BITS: u32 = 10;
+
+/// Rounds `value` to `bit` fewer bits of precision, symmetrically around 0.
+const fn round_shift_signed(value: i64, bit: u32) -> i64 {
+  let round = 1 << bit >> 1;
+  if value >= 0 {
+    (value + round) >> bit
+  } else {
+    -((-value + round) >> bit)
+  }
+}
+
+/// Calls `f` with every sample of a `width`x`height` prediction at `po`
+/// (in frame coordinates), moved by `mv`, from a reference frame of
+/// `ref_size` luma samples for a frame of `frame_size` (spec 7.11.3.3 and
+/// 7.11.3.4).
+///
+/// `src` must be padded past the reference size, so that clamping to the
+/// plane reads the same samples as clamping to the reference.
+fn for_each_scaled_sample<T: Pixel, F: FnMut(usize, usize, i32)>(
+  src: &Plane<T>, po: PlaneOffset, mv: MotionVector, width: usize,
+  height: usize, frame_size: (usize, usize), ref_size: (usize, usize),
+  mode_x: FilterMode, mode_y: FilterMode, round0: i32, round1: i32, mut f: F,
+) {
+  let &PlaneConfig { xdec, ydec, .. } = &src.cfg;
+  let last_x = src.cfg.width as i64 - 1;
+  let last_y = src.cfg.height as i64 - 1;
+  let scale = |ref_len: usize, len: usize| {
+    (((ref_len << REF_SCALE_SHIFT) + len / 2) / len) as i64
+  };
+  let x_scale = scale(ref_size.0, frame_size.0);
+  let y_scale = scale(ref_size.1, frame_size.1);
+  let start = |pos: isize, mv: i16, dec: usize, scale: i64| {
+    let orig = ((pos as i64) << 4) + ((2 * mv as i64) >> dec) + 8;
+    let base = orig * scale - (8 << REF_SCALE_SHIFT);
+    round_shift_signed(base, REF_SCALE_SHIFT + 4 - SCALE_SUBPEL_BITS) + 32
+  };
+  let start_x = start(po.x, mv.col, xdec, x_scale);
+  let start_y = start(po.y, mv.row, ydec, y_scale);
+  let x_step =
+    round_shift_signed(x_scale, REF_SCALE_SHIFT - SCALE_SUBPEL_BITS);
+  let y_step =
+    round_shift_signed(y_scale, REF_SCALE_SHIFT - SCALE_SUBPEL_BITS);
+  let filter = |mode: FilterMode, pos: i64, length: usize| {
+    get_filter(mode, ((pos >> 6) & 15) as i32, length)
+  };
+
+  let intermediate_height =
+    ((((height as i64 - 1) * y_step + (1 << SCALE_SUBPEL_BITS) - 1)
+      >> SCALE_SUBPEL_BITS)
+      + 8) as usize;
+  // dynamic allocation: once per block
+  let mut intermediate = vec![0i32; intermediate_height * width];
+  for (r, row) in intermediate.chunks_exact_mut(width).enumerate() {
+    let y = ((start_y >> SCALE_SUBPEL_BITS) + r as i64 - 3).max(0).min(last_y);
+    for (c, out) in row.iter_mut().enumerate() {
+      let pos = start_x + x_step * c as i64;
+      let sum: i32 = filter(mode_x, pos, width)
+        .iter()
+        .enumerate()
+        .map(|(t, &coeff)| {
+          let x =
+            ((pos >> SCALE_SUBPEL_BITS) + t as i64 - 3).max(0).min(last_x);
+          coeff * i32::cast_from(src.p(x as usize, y as usize))
+        })
+        .sum();
+      *out = round_shift(sum, round0 as usize);
+    }
+  }
+
+  for r in 0..height {
+    let pos = (start_y & ((1 << SCALE_SUBPEL_BITS) - 1)) + y_step * r as i64;
+    let first = (pos >> SCALE_SUBPEL_BITS) as usize;
+    let y_filter = filter(mode_y, pos, height);
+    for c in 0..width {
+      let sum: i32 = y_filter
+        .iter()
+        .enumerate()
+        .map(|(t, &coeff)| coeff * intermediate[(first + t) * width + c])
+        .sum();
+      f(r, c, round_shift(sum, round1 as usize));
+    }
+  }
+}
+
+/// Predicts a block at `po` (in frame coordinates) by translating `src` by
+/// `mv`, when the reference is scaled relative to the frame.
+pub fn put_8tap_scaled<T: Pixel>(
+  dst: &mut PlaneRegionMut<'_, T>, src: &Plane<T>, po: PlaneOffset,
+  mv: MotionVector, width: usize, height: usize, frame_size: (usize, usize),
+  ref_size: (usize, usize), mode_x: FilterMode, mode_y: FilterMode,
+  bit_depth: usize,
+) {
+  let max_sample_val = ((1 << bit_depth) - 1) as i32;
+  let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+  for_each_scaled_sample(
+    src,
+    po,
+    mv,
+    width,
+    height,
+    frame_size,
+    ref_size,
+    mode_x,
+    mode_y,
+    7 - intermediate_bits,
+    7 + intermediate_bits,
+    |r, c, v| dst[r][c] = T::cast_from(v.max(0).min(max_sample_val)),
+  );
+}
+
+/// Same as `put_8tap_scaled`, but writes the intermediate values used for
+/// compound prediction, at the scale of `prep_8tap`.
+pub fn prep_8tap_scaled<T: Pixel>(
+  tmp: &mut [i16], src: &Plane<T>, po: PlaneOffset, mv: MotionVector,
+  width: usize, height: usize, frame_size: (usize, usize),
+  ref_size: (usize, usize), mode_x: FilterMode, mode_y: FilterMode,
+  bit_depth: usize,
+) {
+  let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+  for_each_scaled_sample(
+    src,
+    po,
+    mv,
+    width,
+    height,
+    frame_size,
+    ref_size,
+    mode_x,
+    mode_y,
+    7 - intermediate_bits,
+    7,
+    |r, c, v| tmp[r * width + c] = v as i16,
+  );
+}
+
 pub(crate) mod rust {
   use super::*;
   use num_traits::*;
@@ -182,17 +677,6 @@ pub(crate) mod rust {
       .sum::<i32>()
   }
 
-  fn get_filter(
-    mode: FilterMode, frac: i32, length: usize,
-  ) -> [i32; SUBPEL_FILTER_SIZE] {
-    let filter_idx = if mode == FilterMode::BILINEAR || length > 4 {
-      mode as usize
-    } else {
-      (mode as usize).min(1) + 4
-    };
-    SUBPEL_FILTERS[filter_idx][frac as usize]
-  }
-
   #[cold_for_target_arch("x86_64")]
   pub fn put_8tap<T: Pixel>(
     dst: &mut PlaneRegionMut<'_, T>, src: PlaneSlice<'_, T>, width: usize,
@@ -206,7 +206,8 @@ pub fn motion_estimation<T: Pixel>(
   tile_bo: TileBlockOffset, ref_frame: RefType, cmv: MotionVector,
   pmv: [MotionVector; 2],
 ) -> MotionVector {
-  match fi.rec_buffer.frames[fi.ref_frames[ref_frame.to_index()] as usize] {
+  match fi.me_rec_buffer().frames[fi.ref_frames[ref_frame.to_index()] as usize]
+  {
     Some(ref rec) => {
       let blk_w = bsize.width();
       let blk_h = bsize.height();
@@ -280,7 +281,7 @@ pub fn estimate_motion_ss2<T: Pixel>(
   ref_frame: RefType,
 ) -> Option<MotionVector> {
   if let Some(ref rec) =
-    fi.rec_buffer.frames[fi.ref_frames[ref_frame.to_index()] as usize]
+    fi.me_rec_buffer().frames[fi.ref_frames[ref_frame.to_index()] as usize]
   {
     let blk_w = bsize.width();
     let blk_h = bsize.height();
@@ -324,7 +325,7 @@ pub fn estimate_motion<T: Pixel>(
   debug_assert!(pmvs.len() <= 7);
 
   if let Some(ref rec) =
-    fi.rec_buffer.frames[fi.ref_frames[ref_frame.to_index()] as usize]
+    fi.me_rec_buffer().frames[fi.ref_frames[ref_frame.to_index()] as usize]
   {
     let blk_w = bsize.width();
     let blk_h = bsize.height();
@@ -373,8 +374,9 @@ fn full_pixel_me<T: Pixel>(
   bsize: BlockSize, ref_frame: RefType,
 ) -> MVSearchResult {
   let tile_mvs = &ts.mvs[ref_frame.to_index()].as_const();
-  let frame_ref =
-    fi.rec_buffer.frames[fi.ref_frames[0] as usize].as_ref().map(Arc::as_ref);
+  let frame_ref = fi.me_rec_buffer().frames[fi.ref_frames[0] as usize]
+    .as_ref()
+    .map(Arc::as_ref);
   let predictors = get_subset_predictors(
     tile_bo,
     cmvs,
@@ -444,8 +446,9 @@ fn me_ss2<T: Pixel>(
     &ts.input_hres.region(Area::StartingAt { x: po.x, y: po.y });
 
   let tile_mvs = &ts.mvs[ref_frame.to_index()].as_const();
-  let frame_ref =
-    fi.rec_buffer.frames[fi.ref_frames[0] as usize].as_ref().map(Arc::as_ref);
+  let frame_ref = fi.me_rec_buffer().frames[fi.ref_frames[0] as usize]
+    .as_ref()
+    .map(Arc::as_ref);
 
   let mut predictors = get_subset_predictors::<T>(
     tile_bo_adj,
@@ -691,6 +694,8 @@ fn get_subpel_mv_rd_cost<T: Pixel>(
     bsize.height(),
     ref_frame,
     cand_mv,
+    None,
+    [fi.default_filter; 2],
   );
   let plane_ref = tmp_region.as_const();
   compute_mv_rd_cost(
@@ -797,7 +802,7 @@ pub fn estimate_motion_ss4<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, bsize: BlockSize,
   ref_idx: usize, tile_bo: TileBlockOffset,
 ) -> Option<MotionVector> {
-  if let Some(ref rec) = fi.rec_buffer.frames[ref_idx] {
+  if let Some(ref rec) = fi.me_rec_buffer().frames[ref_idx] {
     let blk_w = bsize.width();
     let blk_h = bsize.height();
     let tile_bo_adj =
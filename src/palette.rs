@@ -0,0 +1,392 @@
+// Copyright (c) 2020, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::frame::*;
+use crate::partition::BlockSize;
+use crate::tiling::*;
+use crate::util::*;
+
+use arrayvec::ArrayVec;
+
+pub const PALETTE_MIN_SIZE: usize = 2;
+pub const PALETTE_MAX_SIZE: usize = 8;
+pub const PALETTE_CACHE_SIZE: usize = 2 * PALETTE_MAX_SIZE;
+const PALETTE_NUM_NEIGHBORS: usize = 3;
+const PALETTE_COLOR_HASH_MULTIPLIERS: [usize; PALETTE_NUM_NEIGHBORS] =
+  [1, 2, 2];
+const PALETTE_COLOR_CONTEXT: [usize; 9] = [0, 0, 0, 0, 0, 4, 3, 2, 1];
+
+/// Blocks with more distinct colors than this are not searched for a
+/// palette, since they are unlikely to be screen content.
+const PALETTE_MAX_SOURCE_COLORS: usize = 64;
+const PALETTE_KMEANS_ITERATIONS: usize = 8;
+
+/// Side of the blocks used by the screen content detection.
+const SCREEN_CONTENT_BLOCK_SIZE: usize = 16;
+/// Maximum number of colors of a block that looks like screen content.
+const SCREEN_CONTENT_MAX_COLORS: usize = 4;
+
+/// Luma palette of a block coded in palette mode.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PaletteParams {
+  /// Number of colors, 0 when palette mode is not used.
+  pub size: u8,
+  /// Colors in ascending order, only the first `size` are meaningful.
+  pub colors: [u16; PALETTE_MAX_SIZE],
+}
+
+impl PaletteParams {
+  fn from_colors(colors: &[u16]) -> Self {
+    debug_assert!(colors.len() >= PALETTE_MIN_SIZE);
+    debug_assert!(colors.len() <= PALETTE_MAX_SIZE);
+    debug_assert!(colors.windows(2).all(|c| c[0] < c[1]));
+    let mut palette = PaletteParams::default();
+    palette.size = colors.len() as u8;
+    palette.colors[..colors.len()].copy_from_slice(colors);
+    palette
+  }
+
+  #[inline]
+  pub fn is_enabled(&self) -> bool {
+    self.size > 0
+  }
+
+  #[inline]
+  pub fn colors(&self) -> &[u16] {
+    &self.colors[..self.size as usize]
+  }
+
+  /// Index of the palette color closest to `value`.
+  #[inline]
+  pub fn color_index(&self, value: u16) -> u8 {
+    let mut best = 0;
+    let mut best_dist = u16::max_value();
+    for (i, &c) in self.colors().iter().enumerate() {
+      let dist = if c > value { c - value } else { value - c };
+      if dist < best_dist {
+        best = i;
+        best_dist = dist;
+      }
+    }
+    best as u8
+  }
+}
+
+/// Whether a block of size `bsize` may be coded in palette mode.
+pub fn palette_allowed(bsize: BlockSize) -> bool {
+  bsize.at_least_8x8() && bsize.width() <= 64 && bsize.height() <= 64
+}
+
+/// Merges the sorted colors of the above and left neighbors into the
+/// palette cache, as described in `get_palette_cache()` of the spec.
+pub fn palette_cache(
+  above: &[u16], left: &[u16],
+) -> ArrayVec<[u16; PALETTE_CACHE_SIZE]> {
+  let mut cache = ArrayVec::new();
+  let push = |cache: &mut ArrayVec<[u16; PALETTE_CACHE_SIZE]>, c| {
+    if cache.last() != Some(&c) {
+      cache.push(c);
+    }
+  };
+
+  let (mut a, mut l) = (0, 0);
+  while a < above.len() && l < left.len() {
+    if left[l] < above[a] {
+      push(&mut cache, left[l]);
+      l += 1;
+    } else {
+      push(&mut cache, above[a]);
+      if left[l] == above[a] {
+        l += 1;
+      }
+      a += 1;
+    }
+  }
+  for &c in above[a..].iter().chain(left[l..].iter()) {
+    push(&mut cache, c);
+  }
+
+  cache
+}
+
+/// Returns the context used to code the color index at row `r` and column
+/// `c` of `color_map`, along with the order in which the `n` colors are
+/// ranked for that position.
+pub fn palette_color_context(
+  color_map: &[u8], stride: usize, r: usize, c: usize, n: usize,
+) -> (usize, [u8; PALETTE_MAX_SIZE]) {
+  let mut scores = [0; PALETTE_MAX_SIZE];
+  let mut order = [0, 1, 2, 3, 4, 5, 6, 7];
+
+  if c > 0 {
+    scores[color_map[r * stride + c - 1] as usize] += 2;
+  }
+  if r > 0 && c > 0 {
+    scores[color_map[(r - 1) * stride + c - 1] as usize] += 1;
+  }
+  if r > 0 {
+    scores[color_map[(r - 1) * stride + c] as usize] += 2;
+  }
+
+  for i in 0..PALETTE_NUM_NEIGHBORS {
+    let mut max_score = scores[i];
+    let mut max_idx = i;
+    for j in i + 1..n {
+      if scores[j] > max_score {
+        max_score = scores[j];
+        max_idx = j;
+      }
+    }
+    if max_idx != i {
+      let max_order = order[max_idx];
+      for k in (i + 1..=max_idx).rev() {
+        scores[k] = scores[k - 1];
+        order[k] = order[k - 1];
+      }
+      scores[i] = max_score;
+      order[i] = max_order;
+    }
+  }
+
+  let hash = scores
+    .iter()
+    .zip(PALETTE_COLOR_HASH_MULTIPLIERS.iter())
+    .map(|(&s, &m)| s * m)
+    .sum::<usize>();
+
+  (PALETTE_COLOR_CONTEXT[hash], order)
+}
+
+/// Returns the distinct values of the visible area of `src` along with
+/// their number of occurrences, or `None` if there are more than `max`.
+fn color_histogram<T: Pixel>(
+  src: &PlaneRegion<'_, T>, width: usize, height: usize, max: usize,
+) -> Option<Vec<(u16, u32)>> {
+  let mut values: Vec<u16> = src
+    .rows_iter()
+    .take(height)
+    .flat_map(|row| row[..width].iter().map(|&v| u16::cast_from(v)))
+    .collect();
+  values.sort_unstable();
+
+  let mut histogram: Vec<(u16, u32)> = Vec::new();
+  for v in values {
+    match histogram.last_mut() {
+      Some((last, count)) if *last == v => *count += 1,
+      _ => {
+        if histogram.len() == max {
+          return None;
+        }
+        histogram.push((v, 1));
+      }
+    }
+  }
+
+  Some(histogram)
+}
+
+/// Clusters the weighted `histogram` into at most `n` colors with k-means,
+/// starting from the most frequent values.
+fn kmeans_palette(
+  histogram: &[(u16, u32)], n: usize,
+) -> Option<PaletteParams> {
+  let mut by_count = histogram.to_vec();
+  by_count.sort_by_key(|&(v, count)| (std::cmp::Reverse(count), v));
+  let mut centroids: ArrayVec<[u16; PALETTE_MAX_SIZE]> =
+    by_count.iter().take(n).map(|&(v, _)| v).collect();
+  centroids.sort_unstable();
+
+  for _ in 0..PALETTE_KMEANS_ITERATIONS {
+    let palette = PaletteParams::from_colors(&centroids);
+    let mut sums = [(0u64, 0u64); PALETTE_MAX_SIZE];
+    for &(v, count) in histogram {
+      let (sum, total) = &mut sums[palette.color_index(v) as usize];
+      *sum += u64::from(v) * u64::from(count);
+      *total += u64::from(count);
+    }
+
+    // The clusters are ordered, so merged centroids are adjacent.
+    let mut updated = ArrayVec::<[u16; PALETTE_MAX_SIZE]>::new();
+    for &(sum, total) in sums[..centroids.len()].iter() {
+      if total > 0 {
+        let c = ((sum + total / 2) / total) as u16;
+        if updated.last() != Some(&c) {
+          updated.push(c);
+        }
+      }
+    }
+    if updated.len() < PALETTE_MIN_SIZE {
+      return None;
+    }
+    if updated == centroids {
+      break;
+    }
+    centroids = updated;
+  }
+
+  Some(PaletteParams::from_colors(&centroids))
+}
+
+/// Derives the candidate luma palettes for the visible `width`x`height`
+/// area of `src`, from the largest to the smallest.
+///
+/// A block with few distinct values gets a single exact palette; otherwise
+/// its values are clustered into smaller palettes.
+pub fn palette_candidates<T: Pixel>(
+  src: &PlaneRegion<'_, T>, width: usize, height: usize,
+) -> ArrayVec<[PaletteParams; 3]> {
+  let mut candidates = ArrayVec::new();
+  let histogram =
+    match color_histogram(src, width, height, PALETTE_MAX_SOURCE_COLORS) {
+      Some(histogram) if histogram.len() >= PALETTE_MIN_SIZE => histogram,
+      _ => return candidates,
+    };
+
+  if histogram.len() <= PALETTE_MAX_SIZE {
+    let colors: ArrayVec<[u16; PALETTE_MAX_SIZE]> =
+      histogram.iter().map(|&(v, _)| v).collect();
+    candidates.push(PaletteParams::from_colors(&colors));
+    return candidates;
+  }
+
+  for &n in [PALETTE_MAX_SIZE, 4, PALETTE_MIN_SIZE].iter() {
+    if let Some(palette) = kmeans_palette(&histogram, n) {
+      if !candidates.contains(&palette) {
+        candidates.push(palette);
+      }
+    }
+  }
+
+  candidates
+}
+
+/// Fills `dst` with the palette prediction of the `width`x`height` area
+/// starting at (`x`, `y`) inside the block whose source is `src`.
+///
+/// Positions outside of the visible `onscreen_width`x`onscreen_height`
+/// part of the block replicate the color index of the closest visible
+/// position, as the decoder does.
+pub fn predict_palette<T: Pixel>(
+  palette: &PaletteParams, src: &PlaneRegion<'_, T>,
+  dst: &mut PlaneRegionMut<'_, T>, x: usize, y: usize, width: usize,
+  height: usize, onscreen_width: usize, onscreen_height: usize,
+) {
+  for (r, row) in dst.rows_iter_mut().take(height).enumerate() {
+    let src_row = &src[(y + r).min(onscreen_height - 1)];
+    for (c, v) in row[..width].iter_mut().enumerate() {
+      let idx = palette
+        .color_index(u16::cast_from(src_row[(x + c).min(onscreen_width - 1)]));
+      *v = T::cast_from(palette.colors[idx as usize]);
+    }
+  }
+}
+
+/// Heuristically detects whether `plane` looks like screen content, i.e.
+/// a good share of its blocks are made of a handful of colors while not
+/// being flat.
+pub fn is_screen_content<T: Pixel>(
+  plane: &Plane<T>, width: usize, height: usize, bit_depth: usize,
+) -> bool {
+  let bs = SCREEN_CONTENT_BLOCK_SIZE;
+  let var_threshold = 5u64 << (2 * (bit_depth - 8));
+  let mut blocks = 0;
+  let mut few_colors = 0;
+  let mut textured = 0;
+
+  let region = plane.as_region();
+  for by in (0..height / bs).map(|y| y * bs) {
+    for bx in (0..width / bs).map(|x| x * bs) {
+      blocks += 1;
+      let block = region.subregion(Area::Rect {
+        x: bx as isize,
+        y: by as isize,
+        width: bs,
+        height: bs,
+      });
+      let histogram =
+        match color_histogram(&block, bs, bs, SCREEN_CONTENT_MAX_COLORS) {
+          Some(histogram) => histogram,
+          None => continue,
+        };
+      few_colors += 1;
+
+      let n = (bs * bs) as u64;
+      let (sum, sum_sq) =
+        histogram.iter().fold((0, 0), |(sum, sum_sq), &(v, count)| {
+          let (v, count) = (u64::from(v), u64::from(count));
+          (sum + v * count, sum_sq + v * v * count)
+        });
+      if (sum_sq * n - sum * sum) / (n * n) > var_threshold {
+        textured += 1;
+      }
+    }
+  }
+
+  few_colors * 10 > blocks && textured * 12 > blocks
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn palette_cache_merges_neighbors() {
+    assert_eq!(
+      &palette_cache(&[10, 20, 30], &[5, 20, 40])[..],
+      &[5, 10, 20, 30, 40]
+    );
+    assert_eq!(&palette_cache(&[], &[1, 2])[..], &[1, 2]);
+    assert!(palette_cache(&[], &[]).is_empty());
+  }
+
+  #[test]
+  fn palette_color_context_orders_neighbors() {
+    // 0 1
+    // 1 ?
+    let color_map = [0, 1, 1, 0];
+    let (ctx, order) = palette_color_context(&color_map, 2, 1, 1, 3);
+    // Color 1 scores 4, color 0 scores 1.
+    assert_eq!(&order[..3], &[1, 0, 2]);
+    assert_eq!(ctx, PALETTE_COLOR_CONTEXT[4 + 2]);
+
+    let (ctx, order) = palette_color_context(&color_map, 2, 0, 0, 2);
+    assert_eq!(&order[..2], &[0, 1]);
+    assert_eq!(ctx, 0);
+  }
+
+  #[test]
+  fn exact_palette_for_few_colors() {
+    let mut plane = Plane::<u8>::new(16, 16, 0, 0, 0, 0);
+    let stride = plane.cfg.stride;
+    for (y, row) in plane.data.chunks_mut(stride).take(16).enumerate() {
+      for (x, v) in row[..16].iter_mut().enumerate() {
+        *v = [16, 128, 235][(x + y) % 3];
+      }
+    }
+    let region = plane.as_region();
+    let candidates = palette_candidates(&region, 16, 16);
+    assert_eq!(candidates.len(), 1);
+    assert_eq!(candidates[0].colors(), &[16, 128, 235]);
+    assert_eq!(candidates[0].color_index(130), 1);
+  }
+
+  #[test]
+  fn no_palette_for_natural_content() {
+    let mut plane = Plane::<u8>::new(16, 16, 0, 0, 0, 0);
+    let stride = plane.cfg.stride;
+    for (y, row) in plane.data.chunks_mut(stride).take(16).enumerate() {
+      for (x, v) in row[..16].iter_mut().enumerate() {
+        *v = (y * 16 + x) as u8;
+      }
+    }
+    let region = plane.as_region();
+    assert!(palette_candidates(&region, 16, 16).is_empty());
+    assert!(!is_screen_content(&plane, 16, 16, 8));
+  }
+}
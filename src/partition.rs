@@ -193,10 +193,22 @@ impl BlockSize {
     }
   }
 
+  /// Whether chroma from luma prediction is allowed for the block. Lossless
+  /// blocks only allow it when their chroma fits a single 4x4 transform.
   #[inline]
-  pub fn cfl_allowed(self) -> bool {
-    // TODO: fix me when enabling EXT_PARTITION_TYPES
-    self <= BlockSize::BLOCK_32X32
+  pub fn cfl_allowed(self, lossless: bool, xdec: usize, ydec: usize) -> bool {
+    if lossless {
+      self.subsampled_size(xdec, ydec) == BLOCK_4X4
+    } else {
+      self <= BlockSize::BLOCK_32X32
+    }
+  }
+
+  /// Whether the block is at least `BLOCK_8X8` in the block size order of
+  /// the spec, which places the 4:1 sizes such as 4x16 after 128x128.
+  #[inline]
+  pub fn at_least_8x8(self) -> bool {
+    self as usize >= BLOCK_8X8 as usize
   }
 
   #[inline]
@@ -476,7 +488,7 @@ pub static RAV1E_PARTITION_TYPES: &[PartitionType] = &[
   PartitionType::PARTITION_SPLIT,
 ];
 
-#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd)]
 pub enum GlobalMVMode {
   IDENTITY = 0,    // identity transformation, 0-parameter
   TRANSLATION = 1, // translational motion 2-parameter
@@ -549,6 +561,8 @@ pub fn get_intra_edges<T: Pixel>(
   bx: usize,
   by: usize,
   partition_size: BlockSize, // partition size, BlockSize
+  partition: PartitionType,  // partition type the block was coded with
+  sb_size_log2: usize,       // coded superblock size
   po: PlaneOffset,
   tx_size: TxSize,
   bit_depth: usize,
@@ -713,11 +727,13 @@ pub fn get_intra_edges<T: Pixel>(
           partition_bo,
           have_top,
           right_available,
+          partition,
           tx_size,
           by4,
           bx4,
           plane_cfg.xdec,
           plane_cfg.ydec,
+          sb_size_log2,
         ) {
         tx_size.width().min(rect_w - x - tx_size.width())
       } else {
@@ -749,11 +765,13 @@ pub fn get_intra_edges<T: Pixel>(
           partition_bo,
           bottom_available,
           have_left,
+          partition,
           tx_size,
           by4,
           bx4,
           plane_cfg.xdec,
           plane_cfg.ydec,
+          sb_size_log2,
         ) {
         tx_size.height().min(rect_h - y - tx_size.height())
       } else {
@@ -779,10 +797,13 @@ pub fn get_intra_edges<T: Pixel>(
   edge_buf
 }
 
-pub fn has_tr(bo: TileBlockOffset, bsize: BlockSize) -> bool {
-  let sb_mi_size = BLOCK_64X64.width_mi(); /* Assume 64x64 for now */
-  let mask_row = bo.0.y & LOCAL_BLOCK_MASK;
-  let mask_col = bo.0.x & LOCAL_BLOCK_MASK;
+pub fn has_tr(
+  bo: TileBlockOffset, bsize: BlockSize, partition: PartitionType,
+  sb_size_log2: usize,
+) -> bool {
+  let sb_mi_size = 1 << (sb_size_log2 - MI_SIZE_LOG2);
+  let mask_row = bo.0.y & (sb_mi_size - 1);
+  let mask_col = bo.0.x & (sb_mi_size - 1);
   let target_n4_w = bsize.width_mi();
   let target_n4_h = bsize.height_mi();
 
@@ -808,38 +829,39 @@ pub fn has_tr(bo: TileBlockOffset, bsize: BlockSize) -> bool {
     bs <<= 1;
   }
 
-  /* The left hand of two vertical rectangles always has a top right (as the
-   * block above will have been decoded) */
-  if (target_n4_w < target_n4_h) && (bo.0.x & target_n4_w) == 0 {
+  /* In a VERT or VERT_4 partition, all the rectangles before the last one
+   * always have a top right (as the block above will have been decoded) */
+  if (target_n4_w < target_n4_h)
+    && ((bo.0.x + target_n4_w) & (target_n4_h - 1)) != 0
+  {
     has_tr = true;
   }
 
-  /* The bottom of two horizontal rectangles never has a top right (as the block
-   * to the right won't have been decoded) */
-  if (target_n4_w > target_n4_h) && (bo.0.y & target_n4_h) != 0 {
+  /* In a HORZ or HORZ_4 partition, the rectangles after the first one never
+   * have a top right (as the block to the right won't have been decoded) */
+  if (target_n4_w > target_n4_h) && (bo.0.y & (target_n4_w - 1)) != 0 {
     has_tr = false;
   }
 
   /* The bottom left square of a Vertical A (in the old format) does
    * not have a top right as it is decoded before the right hand
    * rectangle of the partition */
-  /*
-    if blk.partition == PartitionType::PARTITION_VERT_A {
-      if blk.n4_w == blk.n4_h {
-        if (mask_row & bs) != 0 {
-          has_tr = false;
-        }
-      }
-    }
-  */
+  if partition == PartitionType::PARTITION_VERT_A
+    && target_n4_w == target_n4_h
+    && (mask_row & bs) != 0
+  {
+    has_tr = false;
+  }
 
   has_tr
 }
 
-pub fn has_bl(bo: TileBlockOffset, bsize: BlockSize) -> bool {
-  let sb_mi_size = BLOCK_64X64.width_mi(); /* Assume 64x64 for now */
-  let mask_row = bo.0.y & LOCAL_BLOCK_MASK;
-  let mask_col = bo.0.x & LOCAL_BLOCK_MASK;
+pub fn has_bl(
+  bo: TileBlockOffset, bsize: BlockSize, sb_size_log2: usize,
+) -> bool {
+  let sb_mi_size = 1 << (sb_size_log2 - MI_SIZE_LOG2);
+  let mask_row = bo.0.y & (sb_mi_size - 1);
+  let mask_col = bo.0.x & (sb_mi_size - 1);
   let target_n4_w = bsize.width_mi();
   let target_n4_h = bsize.height_mi();
 
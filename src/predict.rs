@@ -30,6 +30,7 @@ use crate::partition::*;
 use crate::tiling::*;
 use crate::transform::*;
 use crate::util::*;
+use crate::warp::WarpedMotionParams;
 use std::convert::TryInto;
 
 pub const ANGLE_STEP: i8 = 3;
@@ -300,15 +301,44 @@ impl PredictionMode {
     self, fi: &FrameInvariants<T>, tile_rect: TileRect, p: usize,
     po: PlaneOffset, dst: &mut PlaneRegionMut<'_, T>, width: usize,
     height: usize, ref_frame: RefType, mv: MotionVector,
+    warp: Option<WarpedMotionParams>, interp_filter: [FilterMode; 2],
   ) {
     assert!(!self.is_intra());
     let frame_po = tile_rect.to_frame_plane_offset(po);
 
-    let mode = fi.default_filter;
+    let [mode_y, mode_x] = interp_filter;
 
     if let Some(ref rec) =
       fi.rec_buffer.frames[fi.ref_frames[ref_frame.to_index()] as usize]
     {
+      if let Some(params) = warp {
+        put_warp(
+          dst,
+          &rec.frame.planes[p],
+          frame_po,
+          width,
+          height,
+          &params,
+          fi.sequence.bit_depth,
+        );
+        return;
+      }
+      if rec.width != fi.width || rec.height != fi.height {
+        put_8tap_scaled(
+          dst,
+          &rec.frame.planes[p],
+          frame_po,
+          mv,
+          width,
+          height,
+          (fi.width, fi.height),
+          (rec.width, rec.height),
+          mode_x,
+          mode_y,
+          fi.sequence.bit_depth,
+        );
+        return;
+      }
       let (row_frac, col_frac, src) =
         PredictionMode::get_mv_params(&rec.frame.planes[p], frame_po, mv);
       put_8tap(
@@ -318,8 +348,8 @@ impl PredictionMode {
         height,
         col_frac,
         row_frac,
-        mode,
-        mode,
+        mode_x,
+        mode_y,
         fi.sequence.bit_depth,
         fi.cpu_feature_level,
       );
@@ -331,17 +361,46 @@ impl PredictionMode {
     self, fi: &FrameInvariants<T>, tile_rect: TileRect, p: usize,
     po: PlaneOffset, dst: &mut PlaneRegionMut<'_, T>, width: usize,
     height: usize, ref_frames: [RefType; 2], mvs: [MotionVector; 2],
+    warps: [Option<WarpedMotionParams>; 2], interp_filter: [FilterMode; 2],
     buffer: &mut InterCompoundBuffers,
   ) {
     assert!(!self.is_intra());
     let frame_po = tile_rect.to_frame_plane_offset(po);
 
-    let mode = fi.default_filter;
+    let [mode_y, mode_x] = interp_filter;
 
     for i in 0..2 {
       if let Some(ref rec) =
         fi.rec_buffer.frames[fi.ref_frames[ref_frames[i].to_index()] as usize]
       {
+        if let Some(params) = warps[i] {
+          prep_warp(
+            buffer.get_buffer_mut(i),
+            &rec.frame.planes[p],
+            frame_po,
+            width,
+            height,
+            &params,
+            fi.sequence.bit_depth,
+          );
+          continue;
+        }
+        if rec.width != fi.width || rec.height != fi.height {
+          prep_8tap_scaled(
+            buffer.get_buffer_mut(i),
+            &rec.frame.planes[p],
+            frame_po,
+            mvs[i],
+            width,
+            height,
+            (fi.width, fi.height),
+            (rec.width, rec.height),
+            mode_x,
+            mode_y,
+            fi.sequence.bit_depth,
+          );
+          continue;
+        }
         let (row_frac, col_frac, src) = PredictionMode::get_mv_params(
           &rec.frame.planes[p],
           frame_po,
@@ -354,8 +413,8 @@ impl PredictionMode {
           height,
           col_frac,
           row_frac,
-          mode,
-          mode,
+          mode_x,
+          mode_y,
           fi.sequence.bit_depth,
           fi.cpu_feature_level,
         );
@@ -374,10 +433,15 @@ impl PredictionMode {
 
   /// Inter prediction that determines whether compound mode is being used based
   /// on the second ['RefType'] in ['ref_frames'].
+  ///
+  /// References with a warp in ['warps'] are predicted with the warp filter
+  /// instead of translation by their motion vector, which otherwise uses the
+  /// vertical and horizontal filters of ['interp_filter'].
   pub fn predict_inter<T: Pixel>(
     self, fi: &FrameInvariants<T>, tile_rect: TileRect, p: usize,
     po: PlaneOffset, dst: &mut PlaneRegionMut<'_, T>, width: usize,
     height: usize, ref_frames: [RefType; 2], mvs: [MotionVector; 2],
+    warps: [Option<WarpedMotionParams>; 2], interp_filter: [FilterMode; 2],
     compound_buffer: &mut InterCompoundBuffers,
   ) {
     let is_compound = ref_frames[1] != RefType::INTRA_FRAME
@@ -394,6 +458,8 @@ impl PredictionMode {
         height,
         ref_frames[0],
         mvs[0],
+        warps[0],
+        interp_filter,
       )
     } else {
       self.predict_inter_compound(
@@ -406,6 +472,8 @@ impl PredictionMode {
         height,
         ref_frames,
         mvs,
+        warps,
+        interp_filter,
         compound_buffer,
       );
     }
@@ -509,6 +577,114 @@ pub enum FilterIntraMode {
   FILTER_INTRA_MODES,
 }
 
+pub static RAV1E_FILTER_INTRA_MODES: &[FilterIntraMode] = &[
+  FilterIntraMode::FILTER_DC_PRED,
+  FilterIntraMode::FILTER_V_PRED,
+  FilterIntraMode::FILTER_H_PRED,
+  FilterIntraMode::FILTER_D157_PRED,
+  FilterIntraMode::FILTER_PAETH_PRED,
+];
+
+// Taps of the recursive filter, for each of the 8 pixels of a 4x2 block, in
+// the order: top-left, 4 above and 2 left neighbors.
+#[rustfmt::skip]
+static filter_intra_taps: [[[i8; 7]; 8]; FilterIntraMode::FILTER_INTRA_MODES as usize] = [
+  [
+    [-6, 10, 0, 0, 0, 12, 0],
+    [-5, 2, 10, 0, 0, 9, 0],
+    [-3, 1, 1, 10, 0, 7, 0],
+    [-3, 1, 1, 2, 10, 5, 0],
+    [-4, 6, 0, 0, 0, 2, 12],
+    [-3, 2, 6, 0, 0, 2, 9],
+    [-3, 2, 2, 6, 0, 2, 7],
+    [-3, 1, 2, 2, 6, 3, 5],
+  ],
+  [
+    [-10, 16, 0, 0, 0, 10, 0],
+    [-6, 0, 16, 0, 0, 6, 0],
+    [-4, 0, 0, 16, 0, 4, 0],
+    [-2, 0, 0, 0, 16, 2, 0],
+    [-10, 16, 0, 0, 0, 0, 10],
+    [-6, 0, 16, 0, 0, 0, 6],
+    [-4, 0, 0, 16, 0, 0, 4],
+    [-2, 0, 0, 0, 16, 0, 2],
+  ],
+  [
+    [-8, 8, 0, 0, 0, 16, 0],
+    [-8, 0, 8, 0, 0, 16, 0],
+    [-8, 0, 0, 8, 0, 16, 0],
+    [-8, 0, 0, 0, 8, 16, 0],
+    [-4, 4, 0, 0, 0, 0, 16],
+    [-4, 0, 4, 0, 0, 0, 16],
+    [-4, 0, 0, 4, 0, 0, 16],
+    [-4, 0, 0, 0, 4, 0, 16],
+  ],
+  [
+    [-2, 8, 0, 0, 0, 10, 0],
+    [-1, 3, 8, 0, 0, 6, 0],
+    [-1, 2, 3, 8, 0, 4, 0],
+    [0, 1, 2, 3, 8, 2, 0],
+    [-1, 4, 0, 0, 0, 3, 10],
+    [-1, 3, 4, 0, 0, 4, 6],
+    [-1, 2, 3, 4, 0, 4, 4],
+    [-1, 2, 2, 3, 4, 3, 3],
+  ],
+  [
+    [-12, 14, 0, 0, 0, 14, 0],
+    [-10, 0, 14, 0, 0, 12, 0],
+    [-9, 0, 0, 14, 0, 11, 0],
+    [-8, 0, 0, 0, 14, 10, 0],
+    [-10, 12, 0, 0, 0, 0, 14],
+    [-9, 1, 12, 0, 0, 0, 12],
+    [-8, 0, 0, 12, 0, 1, 11],
+    [-7, 0, 0, 1, 12, 1, 9],
+  ],
+];
+
+impl FilterIntraMode {
+  /// Filter intra is only allowed on blocks up to 32x32 predicted with
+  /// DC_PRED.
+  #[inline]
+  pub fn allowed(bsize: BlockSize) -> bool {
+    bsize.width() <= 32 && bsize.height() <= 32
+  }
+
+  /// The intra direction used for the transform type contexts.
+  #[inline]
+  pub fn intra_dir(self) -> PredictionMode {
+    match self {
+      FilterIntraMode::FILTER_V_PRED => PredictionMode::V_PRED,
+      FilterIntraMode::FILTER_H_PRED => PredictionMode::H_PRED,
+      FilterIntraMode::FILTER_D157_PRED => PredictionMode::D157_PRED,
+      _ => PredictionMode::DC_PRED,
+    }
+  }
+
+  /// Luma prediction of a transform block, there is no chroma variant.
+  pub fn predict_intra<T: Pixel>(
+    self, dst: &mut PlaneRegionMut<'_, T>, tx_size: TxSize, bit_depth: usize,
+    edge_buf: &Aligned<[T; 4 * MAX_TX_SIZE + 1]>,
+  ) {
+    let width = tx_size.width();
+    let height = tx_size.height();
+
+    // left pixels are ordered from bottom to top and right-aligned
+    let (left, not_left) = edge_buf.data.split_at(2 * MAX_TX_SIZE);
+    let (top_left, above) = not_left.split_at(1);
+
+    rust::pred_filter_intra(
+      dst,
+      &above[..width],
+      &left[2 * MAX_TX_SIZE - height..],
+      top_left[0],
+      self,
+      width,
+      height,
+      bit_depth,
+    );
+  }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum IntraParam {
   AngleDelta(i8),
@@ -835,6 +1011,51 @@ pub(crate) mod rust {
     }
   }
 
+  pub(crate) fn pred_filter_intra<T: Pixel>(
+    output: &mut PlaneRegionMut<'_, T>, above: &[T], left: &[T],
+    above_left: T, mode: FilterIntraMode, width: usize, height: usize,
+    bit_depth: usize,
+  ) {
+    let taps = &filter_intra_taps[mode as usize];
+    let sample_max = (1 << bit_depth) - 1;
+
+    // Each 4x2 block is predicted from the 7 pixels above and to its left,
+    // which may belong to the previously predicted blocks.
+    for y in (0..height).step_by(2) {
+      for x in (0..width).step_by(4) {
+        let mut p = [0i32; 7];
+        p[0] = match (x, y) {
+          (0, 0) => above_left.into(),
+          (_, 0) => above[x - 1].into(),
+          (0, _) => left[height - y].into(),
+          _ => output[y - 1][x - 1].into(),
+        };
+        for i in 0..4 {
+          p[1 + i] = if y == 0 {
+            above[x + i].into()
+          } else {
+            output[y - 1][x + i].into()
+          };
+        }
+        for i in 0..2 {
+          p[5 + i] = if x == 0 {
+            left[height - 1 - (y + i)].into()
+          } else {
+            output[y + i][x - 1].into()
+          };
+        }
+
+        for (i, t) in taps.iter().enumerate() {
+          let pr: i32 =
+            t.iter().zip(p.iter()).map(|(&t, &p)| t as i32 * p).sum();
+          let v = if pr < 0 { -((-pr + 8) >> 4) } else { (pr + 8) >> 4 };
+          output[y + (i >> 2)][x + (i & 3)] =
+            T::cast_from(v.max(0).min(sample_max));
+        }
+      }
+    }
+  }
+
   pub(crate) fn pred_smooth<T: Pixel>(
     output: &mut PlaneRegionMut<'_, T>, above: &[T], left: &[T], width: usize,
     height: usize,
@@ -1522,6 +1743,26 @@ mod test {
     }
   }
 
+  #[test]
+  fn pred_filter_intra_matches_u8() {
+    let above = [100u8; 4];
+    let left = [132u8, 116];
+
+    let mut output = Plane::from_slice(&vec![0u8; 4 * 2], 4);
+
+    pred_filter_intra(
+      &mut output.as_region_mut(),
+      &above,
+      &left,
+      100,
+      FilterIntraMode::FILTER_V_PRED,
+      4,
+      2,
+      8,
+    );
+    assert_eq!(&output.data[..], [110, 106, 104, 102, 120, 112, 108, 104]);
+  }
+
   #[test]
   fn pred_max() {
     let max12bit = 4096 - 1;
@@ -1594,5 +1835,24 @@ mod test {
         assert_eq!(*v, max12bit);
       }
     }
+
+    for &mode in RAV1E_FILTER_INTRA_MODES {
+      pred_filter_intra(
+        &mut o.as_region_mut(),
+        &above[..8],
+        &left[..4],
+        max12bit,
+        mode,
+        8,
+        4,
+        12,
+      );
+
+      for l in o.data.chunks(32).take(4) {
+        for v in l[..8].iter() {
+          assert_eq!(*v, max12bit);
+        }
+      }
+    }
   }
 }
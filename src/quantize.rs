@@ -17,6 +17,8 @@ cfg_if::cfg_if! {
   }
 }
 
+use crate::context::av1_get_coded_tx_size;
+use crate::qm_tables::*;
 use crate::transform::{TxSize, TxType};
 use crate::util::*;
 
@@ -31,6 +33,41 @@ pub fn get_log_tx_scale(tx_size: TxSize) -> usize {
     + Into::<usize>::into(num_pixels > 1024)
 }
 
+/// The quantization matrix weighting the quantizer of each coefficient of a
+/// transform block, unless the level is flat. Only the 2-D transforms are
+/// weighted.
+pub fn qm_matrix(
+  qm_level: u8, plane: usize, tx_size: TxSize, tx_type: TxType,
+) -> Option<&'static [u8]> {
+  if qm_level as usize >= NUM_QM_LEVELS - 1 || tx_type >= TxType::IDTX {
+    return None;
+  }
+  // The coefficients are transposed in relation to the spec, so are the
+  // matrices, which makes the matrix of a rectangular size the one of the
+  // transposed size.
+  let offset =
+    QM_OFFSETS[TxSize::by_dims(tx_size.height(), tx_size.width()) as usize];
+  let area = av1_get_coded_tx_size(tx_size).area();
+  let qm = &QUANTIZER_MATRIX[qm_level as usize][(plane > 0) as usize];
+  Some(&qm[offset..][..area])
+}
+
+/// Selects the quantization matrix level of a quantizer index. The steeper
+/// matrices of the lower levels are used with the finer quantizers, where
+/// the high frequencies are coded at a precision that is hardly perceived.
+pub fn select_qm_level(qindex: u8) -> u8 {
+  const QM_FIRST: usize = 5;
+  const QM_LAST: usize = 9;
+  (QM_FIRST + qindex as usize * (QM_LAST + 1 - QM_FIRST) / QINDEX_RANGE) as u8
+}
+
+/// Weighs a quantizer, or a rounding offset of it, with the weight of a
+/// quantization matrix.
+#[inline(always)]
+fn qm_weigh(value: u32, weight: u8) -> u32 {
+  (value * weight as u32 + (1 << (QM_BITS - 1))) >> QM_BITS
+}
+
 pub fn dc_q(qindex: u8, delta_q: i8, bit_depth: usize) -> i16 {
   let &table = match bit_depth {
     8 => &dc_qlookup_Q3,
@@ -197,6 +234,33 @@ mod test {
       assert!(tx_size.1 == get_log_tx_scale(tx_size.0));
     }
   }
+
+  #[test]
+  fn qm_matrix_transposes_rect_sizes() {
+    let tx_sizes = [
+      (TX_4X8, TX_8X4),
+      (TX_8X16, TX_16X8),
+      (TX_16X32, TX_32X16),
+      (TX_4X16, TX_16X4),
+      (TX_8X32, TX_32X8),
+    ];
+    for qm_level in 0..NUM_QM_LEVELS as u8 - 1 {
+      for plane in 0..2 {
+        for &(a, b) in tx_sizes.iter() {
+          let qm_a = qm_matrix(qm_level, plane, a, TxType::DCT_DCT).unwrap();
+          let qm_b = qm_matrix(qm_level, plane, b, TxType::DCT_DCT).unwrap();
+          for row in 0..a.height() {
+            for col in 0..a.width() {
+              assert_eq!(
+                qm_a[col * a.height() + row],
+                qm_b[row * b.height() + col]
+              );
+            }
+          }
+        }
+      }
+    }
+  }
 }
 
 impl QuantizationContext {
@@ -249,9 +313,14 @@ impl QuantizationContext {
   #[inline]
   pub fn quantize<T: Coefficient>(
     &self, coeffs: &[T], qcoeffs: &mut [T], tx_size: TxSize, tx_type: TxType,
+    qm: Option<&[u8]>,
   ) -> usize {
     let scan = av1_scan_orders[tx_size as usize][tx_type as usize].scan;
 
+    if let Some(qm) = qm {
+      return self.quantize_qm(coeffs, qcoeffs, scan, qm);
+    }
+
     qcoeffs[0] = {
       let coeff: i32 =
         i32::cast_from(coeffs[0]) << (self.log_tx_scale as usize);
@@ -330,6 +399,65 @@ impl QuantizationContext {
 
     eob
   }
+
+  /// Quantizes like `quantize()`, with the quantizers and their rounding
+  /// offsets weighted for each coefficient position by a quantization
+  /// matrix.
+  fn quantize_qm<T: Coefficient>(
+    &self, coeffs: &[T], qcoeffs: &mut [T], scan: &[u16], qm: &[u8],
+  ) -> usize {
+    qcoeffs[0] = {
+      let coeff = i32::cast_from(coeffs[0]) << self.log_tx_scale;
+      let abs_coeff = coeff.unsigned_abs();
+      let dc_quant = qm_weigh(self.dc_quant, qm[0]);
+      let dc_offset = qm_weigh(self.dc_offset, qm[0]);
+      T::cast_from(copysign((abs_coeff + dc_offset) / dc_quant, coeff))
+    };
+
+    // The last coefficient which is non-zero with the EOB rounding bias.
+    let eob = {
+      let eob_minus_two = scan[1..].iter().rposition(|&pos| {
+        let pos = pos as usize;
+        let abs_coeff =
+          i32::cast_from(coeffs[pos]).unsigned_abs() << self.log_tx_scale;
+        abs_coeff + qm_weigh(self.ac_offset_eob, qm[pos])
+          >= qm_weigh(self.ac_quant, qm[pos])
+      });
+      eob_minus_two.map(|n| n + 2).unwrap_or_else(|| {
+        if qcoeffs[0] == T::cast_from(0) {
+          0
+        } else {
+          1
+        }
+      })
+    };
+
+    let mut level_mode = 1;
+    for &pos in scan.iter().take(eob).skip(1) {
+      let pos = pos as usize;
+      let coeff = i32::cast_from(coeffs[pos]) << self.log_tx_scale;
+      let abs_coeff = coeff.unsigned_abs();
+      let ac_quant = qm_weigh(self.ac_quant, qm[pos]);
+
+      let level0 = abs_coeff / ac_quant;
+      let offset = if level0 > 1 - level_mode {
+        self.ac_offset1
+      } else {
+        self.ac_offset0
+      };
+
+      let abs_qcoeff = (abs_coeff + qm_weigh(offset, qm[pos])) / ac_quant;
+      if level_mode != 0 && abs_qcoeff == 0 {
+        level_mode = 0;
+      } else if abs_qcoeff > 1 {
+        level_mode = 1;
+      }
+
+      qcoeffs[pos] = T::cast_from(copysign(abs_qcoeff, coeff));
+    }
+
+    eob
+  }
 }
 
 pub mod rust {
@@ -338,7 +466,8 @@ pub mod rust {
 
   pub fn dequantize<T: Coefficient>(
     qindex: u8, coeffs: &[T], _eob: usize, rcoeffs: &mut [T], tx_size: TxSize,
-    bit_depth: usize, dc_delta_q: i8, ac_delta_q: i8, _cpu: CpuFeatureLevel,
+    bit_depth: usize, dc_delta_q: i8, ac_delta_q: i8, qm: Option<&[u8]>,
+    _cpu: CpuFeatureLevel,
   ) {
     let log_tx_scale = get_log_tx_scale(tx_size) as i32;
     let offset = (1 << log_tx_scale) - 1;
@@ -352,6 +481,10 @@ pub mod rust {
       .enumerate()
     {
       let quant = if i == 0 { dc_quant } else { ac_quant };
+      let quant = match qm {
+        Some(qm) => qm_weigh(quant as u32, qm[i]) as i32,
+        None => quant,
+      };
       *r = T::cast_from((c * quant + ((c >> 31) & offset)) >> log_tx_scale);
     }
   }
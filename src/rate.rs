@@ -8,7 +8,7 @@
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
 use crate::api::color::ChromaSampling;
-use crate::api::ContextInner;
+use crate::api::{ContextInner, EncoderConfig};
 use crate::encoder::TEMPORAL_DELIMITER;
 use crate::quantize::{ac_q, dc_q, select_ac_qi, select_dc_qi};
 use crate::util::{clamp, ILog, Pixel};
@@ -22,7 +22,6 @@ pub const FRAME_SUBTYPE_I: usize = 0;
 pub const FRAME_SUBTYPE_P: usize = 1;
 #[allow(unused)]
 pub const FRAME_SUBTYPE_B0: usize = 2;
-#[allow(unused)]
 pub const FRAME_SUBTYPE_B1: usize = 3;
 pub const FRAME_SUBTYPE_SEF: usize = 4;
 
@@ -77,6 +76,110 @@ const DQP_Q57: &[i64; FRAME_NSUBTYPES] = &[
   (2.0 * (33_810_170.0 / 86_043_287.0) * (1i64 << 57) as f64) as i64,
 ];
 
+// In constant quality mode, the quantizer of an inter frame is scaled by its
+//  complexity (as estimated by the lookahead) raised to this power, so that
+//  detail which is masked by motion costs fewer bits.
+// This plays the role of x264's qcomp, with 1 - 0.6 = 0.4.
+const CRF_COMPLEXITY_EXP_Q12: i64 = (0.4 * (1 << 12) as f64) as i64;
+
+// The lookahead cost per pixel (for 8-bit pixels) of an inter frame coded with
+//  the constant quality factor as its quantizer index, unmodified.
+const CRF_REF_COMPLEXITY: i64 = 4;
+
+// The largest adjustment of the log quantizer made in constant quality mode,
+//  in Q57, i.e., a frame's quantizer is at most halved or doubled.
+const CRF_MAX_LOG_Q_OFFSET: i64 = q57(1);
+
+// The length in bits of the decoder and encoder buffer delays signaled for the
+//  decoder model.
+pub(crate) const BUFFER_DELAY_LENGTH: u32 = 24;
+
+// Computes the delay (in units of 1/90000 s) between the arrival of the first
+//  bit of the first TU in the decoder buffer and its removal.
+// The decoder buffer starts half full.
+pub(crate) fn decoder_buffer_delay(max_bitrate: i32, buffer_size: i32) -> u64 {
+  90_000 * ((buffer_size as u64) >> 1) / (max_bitrate as u64)
+}
+
+// The limits of a level, as defined in Annex A of the AV1 specification.
+struct Level {
+  seq_level_idx: usize,
+  max_pic_size: u64,
+  max_h_size: usize,
+  max_v_size: usize,
+  max_display_rate: u64,
+  max_header_rate: u64,
+  // The maximum bitrate of the main tier, in units of 0.1 Mbps.
+  main_mbps_x10: i64,
+}
+
+const fn level(
+  seq_level_idx: usize, max_pic_size: u64, max_h_size: usize,
+  max_v_size: usize, max_display_rate: u64, max_header_rate: u64,
+  main_mbps_x10: i64,
+) -> Level {
+  Level {
+    seq_level_idx,
+    max_pic_size,
+    max_h_size,
+    max_v_size,
+    max_display_rate,
+    max_header_rate,
+    main_mbps_x10,
+  }
+}
+
+// The defined levels, from the lowest.
+const LEVELS: &[Level] = &[
+  level(0, 147_456, 2048, 1152, 4_423_680, 150, 15),
+  level(1, 278_784, 2816, 1584, 8_363_520, 150, 30),
+  level(4, 665_856, 4352, 2448, 19_975_680, 150, 60),
+  level(5, 1_065_024, 5504, 3096, 31_950_720, 150, 100),
+  level(8, 2_359_296, 6144, 3456, 70_778_880, 300, 120),
+  level(9, 2_359_296, 6144, 3456, 141_557_760, 300, 200),
+  level(12, 8_912_896, 8192, 4352, 267_386_880, 300, 300),
+  level(13, 8_912_896, 8192, 4352, 534_773_760, 300, 400),
+  level(14, 8_912_896, 8192, 4352, 1_069_547_520, 300, 600),
+  level(15, 8_912_896, 8192, 4352, 1_069_547_520, 300, 600),
+  level(16, 35_651_584, 16384, 8704, 1_069_547_520, 300, 600),
+  level(17, 35_651_584, 16384, 8704, 2_139_095_040, 300, 1000),
+  level(18, 35_651_584, 16384, 8704, 4_278_190_080, 300, 1600),
+  level(19, 35_651_584, 16384, 8704, 4_278_190_080, 300, 1600),
+];
+
+// Selects the lowest level of the main tier which supports the decoder buffer
+//  model, returning its seq_level_idx.
+// A decoder applies the model with the bitrate of the level (scaled by the
+//  BitrateProfileFactor) and a buffer of one second at that bitrate, so the
+//  level must cover the maximum bitrate, as well as the frame size and rate.
+pub(crate) fn decoder_model_level(config: &EncoderConfig) -> Option<usize> {
+  // The BitrateProfileFactor of the profile chosen by Sequence::new().
+  let profile_factor = if config.bit_depth == 12
+    || config.chroma_sampling == ChromaSampling::Cs422
+  {
+    3
+  } else if config.chroma_sampling == ChromaSampling::Cs444 {
+    2
+  } else {
+    1
+  };
+  let pic_size = (config.width * config.height) as u64;
+  let frame_rate =
+    (config.time_base.den + config.time_base.num - 1) / config.time_base.num;
+  LEVELS
+    .iter()
+    .find(|level| {
+      config.width <= level.max_h_size
+        && config.height <= level.max_v_size
+        && pic_size <= level.max_pic_size
+        && pic_size * frame_rate <= level.max_display_rate
+        && frame_rate <= level.max_header_rate
+        && config.max_bitrate as i64
+          <= level.main_mbps_x10 * 100_000 * profile_factor
+    })
+    .map(|level| level.seq_level_idx)
+}
+
 // Convert an integer into a Q57 fixed-point fraction.
 // The integer must be in the range -64 to 63, inclusive.
 pub(crate) const fn q57(v: i32) -> i64 {
@@ -554,6 +657,19 @@ pub struct RCState {
   maybe_ac_qi_max: Option<u8>,
   // The minimum quantizer index to allow (for the luma AC coefficients).
   ac_qi_min: u8,
+  // The constant quality factor to use instead of the fixed quantizer when
+  //  rate control is not active, if any.
+  maybe_crf: Option<u8>,
+  // The number of bits added to the decoder buffer model per TU, or 0 if there
+  //  is no decoder buffer model.
+  buffer_bits_per_tu: i64,
+  // The size of the decoder buffer model in bits.
+  buffer_max: i64,
+  // The fullness of the decoder buffer model when the current TU is removed
+  //  from it.
+  buffer_fullness: i64,
+  // The bits used so far by the frames of the current TU.
+  buffer_tu_bits: i64,
   // Will we drop frames to meet bitrate requirements?
   drop_frames: bool,
   // Do we respect the maximum reservoir fullness?
@@ -730,7 +846,7 @@ impl QuantizerParameters {
     QuantizerParameters {
       log_base_q,
       log_target_q,
-      // TODO: Allow lossless mode; i.e. qi == 0.
+      // Lossless mode, i.e. qi == 0, is only selected explicitly.
       dc_qi: [
         clamp_qi(select_dc_qi(quantizer, bit_depth)),
         if mono { 0 } else { clamp_qi(select_dc_qi(quantizer_u, bit_depth)) },
@@ -745,6 +861,22 @@ impl QuantizerParameters {
       dist_scale: [1.0, lambda / lambda_u, lambda / lambda_v],
     }
   }
+
+  fn new_lossless(bit_depth: usize) -> QuantizerParameters {
+    let log_q = blog64(ac_q(0, 0, bit_depth) as i64)
+      - q57(QSCALE + bit_depth as i32 - 8);
+    let lambda = (::std::f64::consts::LN_2 / 6.0)
+      * ((log_q as f64) * Q57_SQUARE_EXP_SCALE).exp();
+
+    QuantizerParameters {
+      log_base_q: log_q,
+      log_target_q: log_q,
+      dc_qi: [0; 3],
+      ac_qi: [0; 3],
+      lambda,
+      dist_scale: [1.0; 3],
+    }
+  }
 }
 
 // The parameters that are required by twopass_out().
@@ -766,7 +898,8 @@ impl RCState {
     frame_width: i32, frame_height: i32, framerate_num: i64,
     framerate_den: i64, target_bitrate: i32, maybe_ac_qi_max: Option<u8>,
     ac_qi_min: u8, max_key_frame_interval: i32,
-    maybe_reservoir_frame_delay: Option<i32>,
+    maybe_reservoir_frame_delay: Option<i32>, max_bitrate: i32,
+    buffer_size: i32,
   ) -> RCState {
     // The default buffer size is set equal to 1.5x the keyframe interval, or 240
     //  frames; whichever is smaller, with a minimum of 12.
@@ -793,6 +926,18 @@ impl RCState {
       0x4000_0000_0000,
     ) - (TEMPORAL_DELIMITER.len() * 8) as i64;
     let reservoir_max = bits_per_tu * (reservoir_frame_delay as i64);
+    // The decoder buffer model is filled at the maximum bit-rate, starting
+    //  from the fullness implied by the signaled decoder buffer delay.
+    let (buffer_bits_per_tu, buffer_fullness) = if max_bitrate > 0 {
+      (
+        (max_bitrate as i64) * framerate_den / framerate_num,
+        (decoder_buffer_delay(max_bitrate, buffer_size) as i64)
+          * (max_bitrate as i64)
+          / 90_000,
+      )
+    } else {
+      (0, 0)
+    };
     // Start with a buffer fullness and fullness target of 50%.
     let reservoir_target = (reservoir_max + 1) >> 1;
     // Pick exponents and initial scales for quantizer selection.
@@ -835,6 +980,11 @@ impl RCState {
       reservoir_frame_delay_is_set: maybe_reservoir_frame_delay.is_some(),
       maybe_ac_qi_max,
       ac_qi_min,
+      maybe_crf: None,
+      buffer_bits_per_tu,
+      buffer_max: buffer_size as i64,
+      buffer_fullness,
+      buffer_tu_bits: 0,
       drop_frames: false,
       cap_overflow: true,
       cap_underflow: false,
@@ -910,9 +1060,15 @@ impl RCState {
       // TODO: Rename "quantizer" something that indicates it is a quantizer
       //  index, and move it somewhere more sensible (or choose a better way to
       //  parameterize a "quality" configuration parameter).
-      let base_qi = ctx.config.quantizer;
+      let base_qi =
+        self.maybe_crf.map_or(ctx.config.quantizer, |crf| crf as usize);
       let bit_depth = ctx.config.bit_depth;
       let chroma_sampling = ctx.config.chroma_sampling;
+      // A quantizer index of 0 selects lossless coding, for every frame type
+      //  and regardless of the frame complexity.
+      if base_qi == 0 {
+        return QuantizerParameters::new_lossless(bit_depth);
+      }
       // We use the AC quantizer as the source quantizer since its quantizer
       //  tables have unique entries, while the DC tables do not.
       let ac_quantizer = ac_q(base_qi as u8, 0, bit_depth) as i64;
@@ -925,8 +1081,23 @@ impl RCState {
       // Target the midpoint of the chosen entries.
       let log_base_q = (log_ac_q + log_dc_q + 1) >> 1;
       // Adjust the quantizer for the frame type, result is Q57:
-      let log_q = ((log_base_q + (1i64 << 11)) >> 12) * (MQP_Q12[fti] as i64)
+      let mut log_q = ((log_base_q + (1i64 << 11)) >> 12)
+        * (MQP_Q12[fti] as i64)
         + DQP_Q57[fti];
+      // In constant quality mode, also adjust it for the frame complexity.
+      // Keyframes keep the quantizer of their type, since their complexity is
+      //  not comparable to that of inter frames.
+      if self.maybe_crf.is_some() && fti != FRAME_SUBTYPE_I {
+        if let Some(costs) = ctx.get_lookahead_costs(output_frameno) {
+          log_q += self.complexity_log_q_offset(costs.best, bit_depth);
+        }
+      }
+      let log_q = self.buffer_limited_log_q(
+        log_q,
+        fti,
+        (self.scalefilter[fti].y[0] as i64) << 33,
+        ctx.tu_frames_left(output_frameno),
+      );
       QuantizerParameters::new_from_log_q(
         log_base_q,
         log_q,
@@ -1211,6 +1382,13 @@ impl RCState {
           // If that target is unreasonable, oh well; we'll have to drop.
         }
       }
+      // Whatever the reservoir allows, never underflow the decoder buffer.
+      log_q = self.buffer_limited_log_q(
+        log_q,
+        fti,
+        log_cur_scale,
+        ctx.tu_frames_left(output_frameno),
+      );
       QuantizerParameters::new_from_log_q(
         log_base_q,
         log_q,
@@ -1229,8 +1407,12 @@ impl RCState {
       assert!(bits > 0);
     }
     let mut dropped = false;
+    if !trial && self.has_buffer_model() {
+      self.update_buffer_model(bits, show_frame);
+    }
     // Update rate control only if rate control is active.
-    if self.target_bitrate > 0 {
+    // The rate model is also needed to respect the decoder buffer model.
+    if self.target_bitrate > 0 || self.has_buffer_model() {
       let mut estimated_bits = 0;
       let mut bits = bits;
       let mut droppable = droppable;
@@ -1382,6 +1564,125 @@ impl RCState {
     dropped
   }
 
+  pub(crate) fn has_buffer_model(&self) -> bool {
+    self.buffer_bits_per_tu > 0
+  }
+
+  // The number of bits the next frame can use without underflowing the
+  //  decoder buffer model, given the number of frames left to code in its TU
+  //  (including itself).
+  // Each of the following frames of the TU is left a small share, enough for
+  //  a coarsely coded frame or one showing an existing frame.
+  fn buffer_available_bits(&self, tu_frames: usize) -> i64 {
+    self.buffer_fullness
+      - self.buffer_tu_bits
+      - (TEMPORAL_DELIMITER.len() * 8) as i64
+      - (tu_frames as i64 - 1) * (self.buffer_bits_per_tu >> 3)
+  }
+
+  // The share of the available bits of each frame left to code in the TU, one
+  //  more share being kept for the following TUs so that the buffer is not
+  //  drained by a single TU.
+  fn buffer_share(&self, tu_frames: usize) -> i64 {
+    self.buffer_available_bits(tu_frames) / (tu_frames as i64 + 1)
+  }
+
+  // The number of bits the next frame should use, keeping a share of the
+  //  available bits for the following TUs.
+  fn buffer_budget(&self, tu_frames: usize) -> i64 {
+    self.buffer_available_bits(tu_frames) - self.buffer_share(tu_frames)
+  }
+
+  fn update_buffer_model(&mut self, bits: i64, show_frame: bool) {
+    self.buffer_tu_bits += bits;
+    if show_frame {
+      self.buffer_fullness = (self.buffer_fullness
+        - self.buffer_tu_bits
+        - (TEMPORAL_DELIMITER.len() * 8) as i64
+        + self.buffer_bits_per_tu)
+        .min(self.buffer_max);
+      self.buffer_tu_bits = 0;
+    }
+  }
+
+  // Raises the log quantizer (Q57) of a frame so that its size, as predicted
+  //  by the rate model with the given scale, fits in its share of the decoder
+  //  buffer model.
+  fn buffer_limited_log_q(
+    &self, log_q: i64, fti: usize, log_cur_scale: i64, tu_frames: usize,
+  ) -> i64 {
+    if !self.has_buffer_model() {
+      return log_q;
+    }
+    // Keep 1/8 of the share as a margin for prediction error.
+    let share = self.buffer_share(tu_frames);
+    let log_hard_limit = blog64((share - (share >> 3)).max(1));
+    let log_scale_pixels = log_cur_scale + self.log_npixels;
+    let exp = self.exp[fti] as i64;
+    let log_q_exp = ((log_q + 32) >> 6) * exp;
+    if log_scale_pixels - log_q_exp > log_hard_limit {
+      ((log_scale_pixels - log_hard_limit + (exp >> 1)) / exp) << 6
+    } else {
+      log_q
+    }
+  }
+
+  // Checks whether a frame of the given size fits in the decoder buffer
+  //  model.
+  pub(crate) fn fits_buffer(&self, bits: i64, tu_frames: usize) -> bool {
+    !self.has_buffer_model() || bits <= self.buffer_available_bits(tu_frames)
+  }
+
+  // Checks whether a frame of the given size is within its budget in the
+  //  decoder buffer model.
+  pub(crate) fn within_buffer_budget(
+    &self, bits: i64, tu_frames: usize,
+  ) -> bool {
+    !self.has_buffer_model() || bits <= self.buffer_budget(tu_frames)
+  }
+
+  // Selects coarser quantizers for a frame which used `bits` with `qps` and
+  //  exceeded its budget in the decoder buffer model.
+  pub(crate) fn select_buffer_limited_qi(
+    &self, qps: &QuantizerParameters, bits: i64, fti: usize, tu_frames: usize,
+    bit_depth: usize, chroma_sampling: ChromaSampling,
+  ) -> QuantizerParameters {
+    // Step by at least an eighth of an octave.
+    let min_step = q57(1) >> 3;
+    // Solve rate = scale*(quantizer**-exp) for the budget.
+    let log_excess =
+      blog64(bits.max(1)) - blog64(self.buffer_budget(tu_frames).max(1));
+    let mut log_q = qps.log_target_q
+      + ((log_excess / (self.exp[fti] as i64)) << 6).max(min_step);
+    loop {
+      let limited_qps = QuantizerParameters::new_from_log_q(
+        qps.log_base_q,
+        log_q,
+        bit_depth,
+        chroma_sampling,
+      );
+      if limited_qps.ac_qi[0] > qps.ac_qi[0] || limited_qps.ac_qi[0] == 255 {
+        return limited_qps;
+      }
+      log_q += min_step;
+    }
+  }
+
+  // Computes the adjustment of the log quantizer (Q57) of a frame in constant
+  //  quality mode from its lookahead cost.
+  fn complexity_log_q_offset(&self, cost: u64, bit_depth: usize) -> i64 {
+    // The log of the cost per pixel, normalized to 8-bit pixels, in Q57.
+    let log_complexity = blog64(cost.max(1) as i64)
+      - self.log_npixels
+      - q57(bit_depth as i32 - 8);
+    let log_ratio = log_complexity - blog64(CRF_REF_COMPLEXITY);
+    clamp(
+      ((log_ratio + (1i64 << 11)) >> 12) * CRF_COMPLEXITY_EXP_Q12,
+      -CRF_MAX_LOG_Q_OFFSET,
+      CRF_MAX_LOG_Q_OFFSET,
+    )
+  }
+
   pub fn needs_trial_encode(&self, fti: usize) -> bool {
     self.target_bitrate > 0 && self.nframes[fti] == 0
   }
@@ -1425,6 +1726,16 @@ impl RCState {
     TwoPassOutParams { pass1_log_base_q, done_processing }
   }
 
+  // Select a constant quality, modulated per frame by lookahead complexity
+  pub(crate) fn init_constant_quality(&mut self, crf: u8) {
+    debug_assert!(self.target_bitrate <= 0);
+    self.maybe_crf = Some(crf);
+  }
+
+  pub(crate) fn is_constant_quality(&self) -> bool {
+    self.maybe_crf.is_some()
+  }
+
   // Initialize the first pass and emit a placeholder summary
   pub(crate) fn init_first_pass(&mut self, pass1_log_base_q: i64) {
     if self.twopass_state == PASS_SINGLE {
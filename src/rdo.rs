@@ -17,25 +17,31 @@ use crate::deblock::*;
 use crate::dist::*;
 use crate::ec::{Writer, WriterCounter, OD_BITRES};
 use crate::encode_block_with_modes;
-use crate::encoder::{FrameInvariants, IMPORTANCE_BLOCK_SIZE};
+use crate::encoder::{block_warps, FrameInvariants, IMPORTANCE_BLOCK_SIZE};
 use crate::frame::Frame;
 use crate::frame::*;
 use crate::header::ReferenceMode;
+use crate::intrabc::{intrabc_ref_dv, intrabc_search};
 use crate::lrf::*;
 use crate::luma_ac;
-use crate::mc::MotionVector;
+use crate::mc::{FilterMode, MotionVector};
 use crate::me::*;
 use crate::motion_compensate;
+use crate::palette::*;
 use crate::partition::RefType::*;
 use crate::partition::*;
 use crate::predict::{
-  AngleDelta, IntraEdgeFilterParameters, IntraParam, PredictionMode,
+  AngleDelta, FilterIntraMode, IntraEdgeFilterParameters, IntraParam,
+  MotionMode, PredictionMode, RAV1E_FILTER_INTRA_MODES,
   RAV1E_INTER_COMPOUND_MODES, RAV1E_INTER_MODES_MINIMAL, RAV1E_INTRA_MODES,
 };
+use crate::qm_tables::QM_BITS;
+use crate::quantize::{ac_q, dc_q, get_log_tx_scale};
 use crate::rdo_tables::*;
+use crate::scan_order::av1_scan_orders;
 use crate::tiling::*;
 use crate::transform::{TxSet, TxSize, TxType, RAV1E_TX_TYPES};
-use crate::util::{Aligned, CastFromPrimitive, Pixel};
+use crate::util::{clamp, Aligned, CastFromPrimitive, Pixel};
 use crate::write_tx_blocks;
 use crate::write_tx_tree;
 use crate::Tune;
@@ -99,6 +105,10 @@ pub struct PartitionParameters {
   pub tx_size: TxSize,
   pub tx_type: TxType,
   pub sidx: u8,
+  pub palette: PaletteParams,
+  pub filter_intra_mode: Option<FilterIntraMode>,
+  pub motion_mode: MotionMode,
+  pub interp_filter: [FilterMode; 2],
 }
 
 impl Default for PartitionParameters {
@@ -118,6 +128,10 @@ impl Default for PartitionParameters {
       tx_size: TxSize::TX_4X4,
       tx_type: TxType::DCT_DCT,
       sidx: 0,
+      palette: PaletteParams::default(),
+      filter_intra_mode: None,
+      motion_mode: MotionMode::SIMPLE_TRANSLATION,
+      interp_filter: [FilterMode::REGULAR; 2],
     }
   }
 }
@@ -369,7 +383,7 @@ fn compute_distortion<T: Pixel>(
         },
       )
     }
-    Tune::Psnr | Tune::Psychovisual => sse_wxh(
+    Tune::Psnr | Tune::Psychovisual | Tune::ScreenContent => sse_wxh(
       &input_region,
       &rec_region,
       visible_w,
@@ -427,7 +441,7 @@ fn compute_tx_distortion<T: Pixel>(
   is_chroma_block: bool, tile_bo: TileBlockOffset, tx_dist: ScaledDistortion,
   skip: bool, luma_only: bool,
 ) -> ScaledDistortion {
-  assert!(fi.config.tune == Tune::Psnr);
+  assert!(fi.config.tune != Tune::Psychovisual);
   let area = Area::BlockStartingAt { bo: tile_bo.0 };
   let input_region = ts.input_tile.planes[0].subregion(area);
   let rec_region = ts.rec.planes[0].subregion(area);
@@ -506,20 +520,54 @@ fn compute_tx_distortion<T: Pixel>(
 }
 
 /// Compute a scaling factor to multiply the distortion of a block by,
-/// this factor is determined using temporal RDO.
+/// this factor is determined using temporal RDO and the quantizer of the
+/// superblock with delta-q, or of the area with segmentation.
 pub fn distortion_scale<T: Pixel>(
   fi: &FrameInvariants<T>, frame_bo: PlaneBlockOffset, bsize: BlockSize,
 ) -> DistortionScale {
-  if !fi.config.temporal_rdo() {
-    return DistortionScale::default();
+  let scale = if fi.config.temporal_rdo() {
+    // EncoderConfig::temporal_rdo() should always return false in situations
+    // where distortion is computed on > 8x8 blocks, so we should never hit
+    // this assert.
+    assert!(bsize <= BlockSize::BLOCK_8X8);
+    importance_scale(fi, frame_bo)
+  } else {
+    DistortionScale::default()
+  };
+
+  let qidx = if fi.delta_q_present {
+    fi.sb_qidx(frame_bo)
+  } else if fi.enable_segmentation {
+    // The quantizer level of the area, around which the segments are chosen.
+    let level = fi.segment_q_levels[fi.segment_q_level(frame_bo)];
+    clamp(fi.base_q_idx as i16 + level, 1, 255) as u8
+  } else {
+    fi.base_q_idx
+  };
+
+  if qidx != fi.base_q_idx {
+    // Equivalent to the lambda of the quantizer of the area, which is
+    // proportional to the square of the quantizer.
+    let bit_depth = fi.sequence.bit_depth;
+    let base_q = ac_q(fi.base_q_idx, 0, bit_depth) as f64;
+    let q = ac_q(qidx, 0, bit_depth) as f64;
+    DistortionScale::new(f64::from(scale) * (base_q / q).powi(2))
+  } else {
+    scale
   }
-  // EncoderConfig::temporal_rdo() should always return false in situations
-  // where distortion is computed on > 8x8 blocks, so we should never hit this
-  // assert.
-  assert!(bsize <= BlockSize::BLOCK_8X8);
+}
 
-  let x = frame_bo.0.x >> IMPORTANCE_BLOCK_TO_BLOCK_SHIFT;
-  let y = frame_bo.0.y >> IMPORTANCE_BLOCK_TO_BLOCK_SHIFT;
+/// The distortion scale given by the temporal importance of the block.
+pub fn importance_scale<T: Pixel>(
+  fi: &FrameInvariants<T>, frame_bo: PlaneBlockOffset,
+) -> DistortionScale {
+  // The importance blocks are laid out at the configured frame size.
+  let x = (frame_bo.0.x * fi.config.width / fi.width)
+    >> IMPORTANCE_BLOCK_TO_BLOCK_SHIFT;
+  let x = x.min(fi.w_in_imp_b - 1);
+  let y = (frame_bo.0.y * fi.config.height / fi.height)
+    >> IMPORTANCE_BLOCK_TO_BLOCK_SHIFT;
+  let y = y.min(fi.h_in_imp_b - 1);
 
   fi.distortion_scales[y * fi.w_in_imp_b + x]
 }
@@ -689,12 +737,232 @@ pub fn compute_rd_cost<T: Pixel>(
   distortion.0 as f64 + fi.lambda * rate_in_bits
 }
 
+/// Rate-distortion optimized quantization of a transform block.
+///
+/// Each coefficient before the end of block is visited in reverse scan order
+/// and set to whichever of its nearest level and the level below it has the
+/// lower D + λR. Coefficients later in scan order are decided first, so the
+/// contexts of each symbol are known, and its rate is estimated from the
+/// current CDFs without coding the block. The end of block is then moved to
+/// the position that minimizes the cost of the whole block, which may leave
+/// it without coefficients. Returns the new end of block.
+pub fn rdo_quantize<T: Pixel>(
+  fi: &FrameInvariants<T>, cw: &ContextWriter, p: usize,
+  tx_bo: TileBlockOffset, coeffs: &[T::Coeff], qcoeffs: &mut [T::Coeff],
+  eob: usize, tx_size: TxSize, tx_type: TxType, qm: Option<&[u8]>,
+  plane_bsize: BlockSize, xdec: usize, ydec: usize, qidx: u8,
+  frame_clipped_txw: usize, frame_clipped_txh: usize, bias: DistortionScale,
+) -> usize {
+  let scan = &av1_scan_orders[tx_size as usize][tx_type as usize].scan[..eob];
+  let log_tx_scale = get_log_tx_scale(tx_size);
+  let bit_depth = fi.sequence.bit_depth;
+  let dc_quant = dc_q(qidx, fi.dc_delta_q[p], bit_depth) as i64;
+  let ac_quant = ac_q(qidx, fi.ac_delta_q[p], bit_depth) as i64;
+
+  // Squared errors are measured on coefficients scaled by the quantizer's
+  // 1 << log_tx_scale, which are 8 times the pixel-domain values.
+  let dist_weight = f64::from(bias) * fi.dist_scale[p] / 64.0;
+  let lambda = fi.lambda / f64::from(1 << OD_BITRES);
+
+  let coded_tx_size = av1_get_coded_tx_size(tx_size);
+  let height = coded_tx_size.height();
+  let area = coded_tx_size.area();
+  let bhl = ContextWriter::get_txb_bhl(tx_size);
+  let txs_ctx = ContextWriter::get_txsize_entropy_ctx(tx_size);
+  let br_txs_ctx = txs_ctx.min(TxSize::TX_32X32 as usize);
+  let tx_class = tx_type_to_class[tx_type as usize];
+  let plane_type = if p == 0 { 0 } else { 1 };
+  let txb_ctx = cw.bc.get_txb_ctx(
+    plane_bsize,
+    tx_size,
+    p,
+    tx_bo,
+    xdec,
+    ydec,
+    frame_clipped_txw,
+    frame_clipped_txh,
+  );
+
+  let fc = &cw.fc;
+  let wc = WriterCounter::new();
+  let bits = |s: u32, cdf: &[u16]| wc.symbol_bits(s, &cdf[..cdf.len() - 1]);
+
+  let mut levels_buf = [0u8; TX_PAD_2D];
+  let levels: &mut [u8] =
+    &mut levels_buf[TX_PAD_TOP * (height + TX_PAD_HOR)..];
+  cw.txb_init_levels(qcoeffs, height, levels, height + TX_PAD_HOR);
+
+  // Rate of the symbols coded after the base level of a coefficient: the
+  // base range, the sign and the golomb coded remainder.
+  let tail_bits = |levels: &[u8], c: usize, pos: usize, level: u32, sign| {
+    let mut rate = 0;
+    if level > NUM_BASE_LEVELS as u32 {
+      let br_ctx = ContextWriter::get_br_ctx(levels, pos, bhl, tx_class);
+      let cdf = &fc.coeff_br_cdf[br_txs_ctx][plane_type][br_ctx];
+      let base_range = level - 1 - NUM_BASE_LEVELS as u32;
+      let mut idx = 0;
+      while idx < COEFF_BASE_RANGE as u32 {
+        let k = (base_range - idx).min(BR_CDF_SIZE as u32 - 1);
+        rate += bits(k, cdf);
+        if k < BR_CDF_SIZE as u32 - 1 {
+          break;
+        }
+        idx += BR_CDF_SIZE as u32 - 1;
+      }
+    }
+    rate += if c == 0 {
+      bits(sign, &fc.dc_sign_cdf[plane_type][txb_ctx.dc_sign_ctx])
+    } else {
+      1 << OD_BITRES
+    };
+    if level > (COEFF_BASE_RANGE + NUM_BASE_LEVELS) as u32 {
+      let x = level - (COEFF_BASE_RANGE + NUM_BASE_LEVELS) as u32;
+      rate += (2 * (32 - x.leading_zeros()) - 1) << OD_BITRES;
+    }
+    rate
+  };
+
+  // Cost of each coefficient when coded before the end of block, when coded
+  // as its last coefficient, and the distortion of zeroing it.
+  let mut coded_cost = [0f64; 32 * 32];
+  let mut last_cost = [f64::INFINITY; 32 * 32];
+  let mut zero_dist = [0f64; 32 * 32];
+
+  for (c, &pos) in scan.iter().enumerate().rev() {
+    let pos = pos as usize;
+    let is_last = c + 1 == eob;
+    let quant = if c == 0 { dc_quant } else { ac_quant };
+    // A quantization matrix weighs the quantizer of the coefficient, and
+    // the error by the inverse of that weight, like the distortion in the
+    // transform domain.
+    let (quant, qm_weight) = match qm {
+      Some(qm) => {
+        let weight = qm[pos] as i64;
+        let quant = (quant * weight + (1 << (QM_BITS - 1))) >> QM_BITS;
+        (quant, ((1 << QM_BITS) as f64 / weight as f64).powi(2))
+      }
+      None => (quant, 1.),
+    };
+    let coeff = i32::cast_from(coeffs[pos]);
+    let sign = if coeff < 0 { 1 } else { 0 };
+    let abs_coeff = (coeff.abs() as i64) << log_tx_scale;
+    let dist = |level: u32| {
+      let err = abs_coeff - level as i64 * quant;
+      (err * err) as f64 * dist_weight * qm_weight
+    };
+    zero_dist[c] = dist(0);
+
+    let nz_map_ctx = |is_eob| {
+      ContextWriter::get_nz_map_ctx(
+        levels, pos, bhl, area, c, is_eob, tx_size, tx_class,
+      )
+    };
+    let base_cdf = &fc.coeff_base_cdf[txs_ctx][plane_type][nz_map_ctx(false)];
+    let eob_cdf =
+      &fc.coeff_base_eob_cdf[txs_ctx][plane_type][nz_map_ctx(true)];
+    let level_cost = |level: u32, is_last: bool| {
+      let base_bits = if is_last {
+        bits(level.min(3) - 1, eob_cdf)
+      } else {
+        bits(level.min(3), base_cdf)
+      };
+      let rate = if level == 0 {
+        base_bits
+      } else {
+        base_bits + tail_bits(levels, c, pos, level, sign)
+      };
+      dist(level) + lambda * rate as f64
+    };
+
+    // The deadzone quantizer rounds down from the nearest level at most
+    // once, so both of its choices are candidates.
+    let mut level = ((abs_coeff + (quant >> 1)) / quant) as u32;
+    let lowest = if is_last { 1 } else { 0 };
+    if level > lowest
+      && level_cost(level - 1, is_last) < level_cost(level, is_last)
+    {
+      level -= 1;
+    }
+
+    if !is_last {
+      coded_cost[c] = level_cost(level, false);
+    }
+    if level > 0 {
+      last_cost[c] = level_cost(level, true);
+    }
+
+    qcoeffs[pos] = T::Coeff::cast_from(if coeff < 0 {
+      -(level as i32)
+    } else {
+      level as i32
+    });
+    let padded_idx = pos + ((pos >> bhl) << TX_PAD_HOR_LOG2);
+    levels[padded_idx] = level.min(127) as u8;
+  }
+
+  let eob_multi_ctx = if tx_class == TX_CLASS_2D { 0 } else { 1 };
+  let eob_flag_cdf: &[u16] = match coded_tx_size.area_log2() - 4 {
+    0 => &fc.eob_flag_cdf16[plane_type][eob_multi_ctx],
+    1 => &fc.eob_flag_cdf32[plane_type][eob_multi_ctx],
+    2 => &fc.eob_flag_cdf64[plane_type][eob_multi_ctx],
+    3 => &fc.eob_flag_cdf128[plane_type][eob_multi_ctx],
+    4 => &fc.eob_flag_cdf256[plane_type][eob_multi_ctx],
+    5 => &fc.eob_flag_cdf512[plane_type][eob_multi_ctx],
+    _ => &fc.eob_flag_cdf1024[plane_type][eob_multi_ctx],
+  };
+  let eob_bits = |eob: usize| {
+    let mut eob_extra = 0;
+    let eob_pt = ContextWriter::get_eob_pos_token(eob, &mut eob_extra);
+    let mut rate = bits(eob_pt - 1, eob_flag_cdf);
+    let eob_offset_bits = k_eob_offset_bits[eob_pt as usize] as u32;
+    if eob_offset_bits > 0 {
+      let cdf = &fc.eob_extra_cdf[txs_ctx][plane_type][eob_pt as usize - 3];
+      rate += bits((eob_extra >> (eob_offset_bits - 1)) & 1, cdf);
+      rate += (eob_offset_bits - 1) << OD_BITRES;
+    }
+    rate
+  };
+
+  // The tx type is only coded for blocks with coefficients, and is left out
+  // of their rate, which slightly favours keeping them.
+  let skip_cdf = &fc.txb_skip_cdf[txs_ctx][txb_ctx.txb_skip_ctx];
+  let mut dropped_dist: f64 = zero_dist[..eob].iter().sum();
+  let mut best_cost = dropped_dist + lambda * bits(1, skip_cdf) as f64;
+  let mut best_eob = 0;
+  let mut kept_cost = lambda * bits(0, skip_cdf) as f64;
+  for c in 0..eob {
+    dropped_dist -= zero_dist[c];
+    if last_cost[c].is_finite() {
+      let cost = kept_cost
+        + last_cost[c]
+        + dropped_dist
+        + lambda * eob_bits(c + 1) as f64;
+      if cost < best_cost {
+        best_cost = cost;
+        best_eob = c + 1;
+      }
+    }
+    kept_cost += coded_cost[c];
+  }
+
+  for &pos in &scan[best_eob..] {
+    qcoeffs[pos as usize] = T::Coeff::cast_from(0);
+  }
+
+  best_eob
+}
+
 pub fn rdo_tx_size_type<T: Pixel>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
   cw: &mut ContextWriter, bsize: BlockSize, tile_bo: TileBlockOffset,
   luma_mode: PredictionMode, ref_frames: [RefType; 2], mvs: [MotionVector; 2],
   skip: bool,
 ) -> (TxSize, TxType) {
+  // Lossless blocks only use the 4x4 Walsh-Hadamard transform
+  if fi.lossless {
+    return (TxSize::TX_4X4, TxType::DCT_DCT);
+  }
+
   let is_inter = !luma_mode.is_intra();
   let mut tx_size = max_txsize_rect_lookup[bsize as usize];
 
@@ -775,10 +1043,17 @@ fn luma_chroma_mode_rdo<T: Pixel>(
   mvs: [MotionVector; 2], ref_frames: [RefType; 2],
   mode_set_chroma: &[PredictionMode], luma_mode_is_intra: bool,
   mode_context: usize, mv_stack: &ArrayVec<[CandidateMV; 9]>,
-  angle_delta: AngleDelta,
+  angle_delta: AngleDelta, palette: PaletteParams,
+  filter_intra_mode: Option<FilterIntraMode>, motion_mode: MotionMode,
+  interp_filter: [FilterMode; 2],
 ) {
   let PlaneConfig { xdec, ydec, .. } = ts.input.planes[1].cfg;
 
+  cw.bc.blocks.set_palette(tile_bo, bsize, palette);
+  cw.bc.blocks.set_filter_intra_mode(tile_bo, bsize, filter_intra_mode);
+  cw.bc.blocks.set_motion_mode(tile_bo, bsize, motion_mode);
+  cw.bc.blocks.set_interp_filter(tile_bo, bsize, interp_filter);
+
   let is_chroma_block =
     has_chroma(tile_bo, bsize, xdec, ydec, fi.sequence.chroma_sampling);
 
@@ -786,19 +1061,21 @@ fn luma_chroma_mode_rdo<T: Pixel>(
   let mut chroma_rdo = |skip: bool| -> bool {
     let mut zero_distortion = false;
 
-    // If skip is true or segmentation is turned off, sidx is not coded.
-    let sidx_range = if skip || !fi.enable_segmentation {
-      0..=0
-    } else if fi.base_q_idx as i16
-      + ts.segmentation.data[2][SegLvl::SEG_LVL_ALT_Q as usize]
-      < 1
+    // If segmentation is turned off, or skip is true and the segment is
+    // coded after it, sidx is not coded.
+    let sidx_candidates = if !fi.enable_segmentation
+      || (skip && !ts.segmentation.preskip)
     {
-      0..=1
+      Some(0).into_iter().collect()
     } else {
-      0..=2
+      let frame_bo = ts.to_frame_block_offset(tile_bo);
+      ts.segment_classes.candidates(fi, frame_bo, bsize)
     };
 
-    for sidx in sidx_range {
+    for sidx in sidx_candidates {
+      if !ts.segmentation.allows(sidx, bsize, luma_mode, ref_frames, skip) {
+        continue;
+      }
       cw.bc.blocks.set_segmentation_idx(tile_bo, bsize, sidx);
 
       let (tx_size, tx_type) = rdo_tx_size_type(
@@ -808,7 +1085,12 @@ fn luma_chroma_mode_rdo<T: Pixel>(
         let wr = &mut WriterCounter::new();
         let tell = wr.tell_frac();
 
-        if bsize >= BlockSize::BLOCK_8X8 && bsize.is_sqr() {
+        // The square blocks of the extended partitions are leaves, with no
+        // partition coded.
+        if bsize >= BlockSize::BLOCK_8X8
+          && bsize.is_sqr()
+          && cw.bc.blocks[tile_bo].partition <= PartitionType::PARTITION_SPLIT
+        {
           cw.write_partition(
             wr,
             tile_bo,
@@ -821,7 +1103,7 @@ fn luma_chroma_mode_rdo<T: Pixel>(
         let need_recon_pixel =
           luma_mode_is_intra && tx_size.block_size() != bsize;
 
-        encode_block_pre_cdef(&fi.sequence, ts, cw, wr, bsize, tile_bo, skip);
+        encode_block_pre_cdef(fi, ts, cw, wr, bsize, tile_bo, skip);
         let (has_coeff, tx_dist) = encode_block_post_cdef(
           fi,
           ts,
@@ -875,6 +1157,10 @@ fn luma_chroma_mode_rdo<T: Pixel>(
           best.tx_size = tx_size;
           best.tx_type = tx_type;
           best.sidx = sidx;
+          best.palette = palette;
+          best.motion_mode = motion_mode;
+          best.interp_filter = interp_filter;
+          best.filter_intra_mode = filter_intra_mode;
           zero_distortion = is_zero_dist;
         }
 
@@ -885,9 +1171,10 @@ fn luma_chroma_mode_rdo<T: Pixel>(
     zero_distortion
   };
 
-  // Don't skip when using intra modes
+  // Don't skip when using intra modes, nor in lossless frames, where blocks
+  // without residual are still coded as skipped
   let zero_distortion =
-    if !luma_mode_is_intra { chroma_rdo(true) } else { false };
+    if !luma_mode_is_intra && !fi.lossless { chroma_rdo(true) } else { false };
   // early skip
   if !zero_distortion {
     chroma_rdo(false);
@@ -924,6 +1211,16 @@ pub fn rdo_mode_decision<T: Pixel>(
       &cw_checkpoint,
       rdo_type,
     )
+  } else if fi.allow_intrabc {
+    intrabc_rdo_mode_decision(
+      fi,
+      ts,
+      cw,
+      bsize,
+      tile_bo,
+      &cw_checkpoint,
+      rdo_type,
+    )
   } else {
     PartitionParameters::default()
   };
@@ -945,8 +1242,13 @@ pub fn rdo_mode_decision<T: Pixel>(
     );
   }
 
-  if best.pred_mode_luma.is_intra() && is_chroma_block && bsize.cfl_allowed() {
+  if best.pred_mode_luma.is_intra()
+    && is_chroma_block
+    && bsize.cfl_allowed(fi.lossless, xdec, ydec)
+  {
     cw.bc.blocks.set_segmentation_idx(tile_bo, bsize, best.sidx);
+    cw.bc.blocks.set_palette(tile_bo, bsize, best.palette);
+    cw.bc.blocks.set_filter_intra_mode(tile_bo, bsize, best.filter_intra_mode);
 
     let chroma_mode = PredictionMode::UV_CFL_PRED;
     let cw_checkpoint = cw.checkpoint();
@@ -977,15 +1279,7 @@ pub fn rdo_mode_decision<T: Pixel>(
         let wr: &mut dyn Writer = &mut WriterCounter::new();
         let tell = wr.tell_frac();
 
-        encode_block_pre_cdef(
-          &fi.sequence,
-          ts,
-          cw,
-          wr,
-          bsize,
-          tile_bo,
-          best.skip,
-        );
+        encode_block_pre_cdef(fi, ts, cw, wr, bsize, tile_bo, best.skip);
         let (has_coeff, _) = encode_block_post_cdef(
           fi,
           ts,
@@ -1031,6 +1325,10 @@ pub fn rdo_mode_decision<T: Pixel>(
   cw.bc.blocks.set_mode(tile_bo, bsize, best.pred_mode_luma);
   cw.bc.blocks.set_ref_frames(tile_bo, bsize, best.ref_frames);
   cw.bc.blocks.set_motion_vectors(tile_bo, bsize, best.mvs);
+  cw.bc.blocks.set_palette(tile_bo, bsize, best.palette);
+  cw.bc.blocks.set_filter_intra_mode(tile_bo, bsize, best.filter_intra_mode);
+  cw.bc.blocks.set_motion_mode(tile_bo, bsize, best.motion_mode);
+  cw.bc.blocks.set_interp_filter(tile_bo, bsize, best.interp_filter);
 
   assert!(best.rd_cost >= 0_f64);
 
@@ -1049,6 +1347,10 @@ pub fn rdo_mode_decision<T: Pixel>(
     tx_size: best.tx_size,
     tx_type: best.tx_type,
     sidx: best.sidx,
+    palette: best.palette,
+    filter_intra_mode: best.filter_intra_mode,
+    motion_mode: best.motion_mode,
+    interp_filter: best.interp_filter,
   }
 }
 
@@ -1094,6 +1396,7 @@ fn inter_frame_rdo_mode_decision<T: Pixel>(
   let mut satds = ArrayVec::<[u32; 20]>::new();
   let mut mv_stacks = ArrayVec::<[_; 20]>::new();
   let mut mode_contexts = ArrayVec::<[_; 7]>::new();
+  let mut global_mvs = ArrayVec::<[[MotionVector; 2]; 7]>::new();
 
   let pmv_idxs = {
     let SuperBlockOffset { x: sbx, y: sby } = tile_bo.sb_offset().0;
@@ -1116,6 +1419,10 @@ fn inter_frame_rdo_mode_decision<T: Pixel>(
       fi,
       false,
     ));
+    global_mvs.push([
+      cw.global_mv(fi, tile_bo, bsize, ref_frames[0]),
+      MotionVector::default(),
+    ]);
 
     let mut pmv = [MotionVector::default(); 2];
     if !mv_stack.is_empty() {
@@ -1144,7 +1451,19 @@ fn inter_frame_rdo_mode_decision<T: Pixel>(
     if !mv_stack.is_empty() {
       inter_mode_set.push((PredictionMode::NEAR0MV, i));
     }
-    if mv_stack.len() >= 2 {
+    // Blocks without motion from LAST_FRAME may be coded in the static
+    // segments, which imply GLOBALMV.
+    let static_block = ref_frames[0] == LAST_FRAME
+      && ts.segment_classes.is_static(
+        fi,
+        ts.to_frame_block_offset(tile_bo),
+        bsize,
+      );
+    if mv_stack.len() >= 2
+      || fi.gm_params[ref_frames[0].to_index()].wmtype
+        != GlobalMVMode::IDENTITY
+      || static_block
+    {
       inter_mode_set.push((PredictionMode::GLOBALMV, i));
     }
     let include_near_mvs = fi.config.speed_settings.include_near_mvs;
@@ -1193,6 +1512,10 @@ fn inter_frame_rdo_mode_decision<T: Pixel>(
           fi,
           true,
         ));
+        global_mvs.push([
+          cw.global_mv(fi, tile_bo, bsize, ref_frames[0]),
+          cw.global_mv(fi, tile_bo, bsize, ref_frames[1]),
+        ]);
         for &x in RAV1E_INTER_COMPOUND_MODES {
           // exclude any NEAR mode based on speed setting
           if fi.config.speed_settings.include_near_mvs || !x.has_nearmv() {
@@ -1208,6 +1531,8 @@ fn inter_frame_rdo_mode_decision<T: Pixel>(
     }
   }
 
+  cw.bc.blocks.set_motion_mode(tile_bo, bsize, MotionMode::SIMPLE_TRANSLATION);
+
   let num_modes_rdo = if fi.config.speed_settings.prediction_modes
     >= PredictionModesSetting::ComplexAll
   {
@@ -1223,14 +1548,14 @@ fn inter_frame_rdo_mode_decision<T: Pixel>(
         if !mv_stacks[i].is_empty() {
           [mv_stacks[i][0].this_mv, mv_stacks[i][0].comp_mv]
         } else {
-          [MotionVector::default(); 2]
+          global_mvs[i]
         }
       }
       PredictionMode::NEAR0MV | PredictionMode::NEAR_NEAR0MV => {
         if mv_stacks[i].len() > 1 {
           [mv_stacks[i][1].this_mv, mv_stacks[i][1].comp_mv]
         } else {
-          [MotionVector::default(); 2]
+          global_mvs[i]
         }
       }
       PredictionMode::NEAR1MV
@@ -1247,7 +1572,7 @@ fn inter_frame_rdo_mode_decision<T: Pixel>(
         [mvs_from_me[i][0], mv_stacks[i][0].comp_mv]
       }
       PredictionMode::GLOBALMV | PredictionMode::GLOBAL_GLOBALMV => {
-        [MotionVector::default(); 2]
+        global_mvs[i]
       }
       _ => {
         unimplemented!();
@@ -1273,6 +1598,8 @@ fn inter_frame_rdo_mode_decision<T: Pixel>(
         bsize.height(),
         ref_frames_set[i],
         mvs,
+        block_warps(fi, cw, luma_mode, ref_frames_set[i], mvs, bsize, tile_bo),
+        [fi.default_filter; 2],
         &mut ts.inter_compound_buffers,
       );
 
@@ -1320,10 +1647,137 @@ fn inter_frame_rdo_mode_decision<T: Pixel>(
         mode_contexts[i],
         &mv_stacks[i],
         AngleDelta::default(),
+        PaletteParams::default(),
+        None,
+        MotionMode::SIMPLE_TRANSLATION,
+        [fi.default_filter; 2],
       );
     },
   );
 
+  // Try a local warp fitted to the neighbouring motion for the best single
+  // reference mode.
+  if let Some(i) = ref_frames_set.iter().position(|&r| r == best.ref_frames) {
+    let (luma_mode, ref_frames, mvs) =
+      (best.pred_mode_luma, best.ref_frames, best.mvs);
+    if ref_frames[1] == NONE_FRAME
+      && cw
+        .local_warp_params(fi, tile_bo, bsize, luma_mode, ref_frames, mvs[0])
+        .is_some()
+    {
+      luma_chroma_mode_rdo(
+        luma_mode,
+        fi,
+        bsize,
+        tile_bo,
+        ts,
+        cw,
+        rdo_type,
+        cw_checkpoint,
+        &mut best,
+        mvs,
+        ref_frames,
+        &[luma_mode],
+        false,
+        mode_contexts[i],
+        &mv_stacks[i],
+        AngleDelta::default(),
+        PaletteParams::default(),
+        None,
+        MotionMode::WARPED_CAUSAL,
+        [fi.default_filter; 2],
+      );
+    }
+  }
+
+  // Try the other interpolation filters for the best mode, unless its motion
+  // vectors point to whole samples in all planes (multiples of 16 in 1/8
+  // luma sample units, for the subsampled chroma planes).
+  if let Some(i) = ref_frames_set.iter().position(|&r| r == best.ref_frames) {
+    let (luma_mode, ref_frames, mvs, motion_mode) =
+      (best.pred_mode_luma, best.ref_frames, best.mvs, best.motion_mode);
+    let num_refs = if ref_frames[1] == NONE_FRAME { 1 } else { 2 };
+    let subpel = fi.has_scaled_refs()
+      || mvs[..num_refs].iter().any(|mv| (mv.row | mv.col) & 15 != 0);
+    if subpel
+      && cw.interp_filter_coded(fi, bsize, luma_mode, ref_frames, motion_mode)
+    {
+      let filters =
+        [FilterMode::REGULAR, FilterMode::SMOOTH, FilterMode::SHARP];
+      for &filter_y in filters.iter() {
+        for &filter_x in filters.iter() {
+          if filter_x == FilterMode::REGULAR && filter_y == FilterMode::REGULAR
+            || filter_x != filter_y && !fi.sequence.enable_dual_filter
+          {
+            continue;
+          }
+          luma_chroma_mode_rdo(
+            luma_mode,
+            fi,
+            bsize,
+            tile_bo,
+            ts,
+            cw,
+            rdo_type,
+            cw_checkpoint,
+            &mut best,
+            mvs,
+            ref_frames,
+            &[luma_mode],
+            false,
+            mode_contexts[i],
+            &mv_stacks[i],
+            AngleDelta::default(),
+            PaletteParams::default(),
+            None,
+            motion_mode,
+            [filter_y, filter_x],
+          );
+        }
+      }
+    }
+  }
+
+  best
+}
+
+fn intrabc_rdo_mode_decision<T: Pixel>(
+  fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
+  cw: &mut ContextWriter, bsize: BlockSize, tile_bo: TileBlockOffset,
+  cw_checkpoint: &ContextWriterCheckpoint, rdo_type: RDOType,
+) -> PartitionParameters {
+  let mut best = PartitionParameters::default();
+
+  let ref_frames = [INTRA_FRAME, NONE_FRAME];
+  let mut mv_stack = ArrayVec::<[CandidateMV; 9]>::new();
+  cw.find_mvrefs(tile_bo, ref_frames, &mut mv_stack, bsize, fi, false);
+  let ref_dv = intrabc_ref_dv(&mv_stack, tile_bo, ts.sb_size_log2);
+
+  if let Some(dv) = intrabc_search(fi, ts, tile_bo, bsize, ref_dv) {
+    luma_chroma_mode_rdo(
+      PredictionMode::NEWMV,
+      fi,
+      bsize,
+      tile_bo,
+      ts,
+      cw,
+      rdo_type,
+      cw_checkpoint,
+      &mut best,
+      [dv, MotionVector::default()],
+      ref_frames,
+      &[PredictionMode::NEWMV],
+      false,
+      0,
+      &mv_stack,
+      AngleDelta::default(),
+      PaletteParams::default(),
+      None,
+      MotionMode::SIMPLE_TRANSLATION,
+      [fi.default_filter; 2],
+    );
+  }
+
   best
 }
 
@@ -1387,6 +1841,8 @@ fn intra_frame_rdo_mode_decision<T: Pixel>(
           0,
           0,
           bsize,
+          cw.bc.blocks[tile_bo].partition,
+          fi.sb_size_log2(),
           po,
           tx_size,
           fi.sequence.bit_depth,
@@ -1472,12 +1928,14 @@ fn intra_frame_rdo_mode_decision<T: Pixel>(
       0,
       &ArrayVec::<[CandidateMV; 9]>::new(),
       AngleDelta::default(),
+      PaletteParams::default(),
+      None,
+      MotionMode::SIMPLE_TRANSLATION,
+      [fi.default_filter; 2],
     );
   });
 
-  if fi.config.speed_settings.fine_directional_intra
-    && bsize >= BlockSize::BLOCK_8X8
-  {
+  if fi.config.speed_settings.fine_directional_intra && bsize.at_least_8x8() {
     // Find the best angle delta for the current best prediction mode
     let luma_deltas = best.pred_mode_luma.angle_delta_count();
     let chroma_deltas = best.pred_mode_chroma.angle_delta_count();
@@ -1506,6 +1964,10 @@ fn intra_frame_rdo_mode_decision<T: Pixel>(
           0,
           &mv_stack,
           AngleDelta { y, uv },
+          PaletteParams::default(),
+          None,
+          MotionMode::SIMPLE_TRANSLATION,
+          [fi.default_filter; 2],
         );
       }
       best.angle_delta
@@ -1523,6 +1985,96 @@ fn intra_frame_rdo_mode_decision<T: Pixel>(
     }
   }
 
+  if fi.sequence.enable_filter_intra && FilterIntraMode::allowed(bsize) {
+    // Filter intra only replaces the DC_PRED luma prediction, so keep the
+    // best chroma mode found so far.
+    let (chroma_mode, angle_delta) = if best.pred_mode_luma.is_intra() {
+      (best.pred_mode_chroma, AngleDelta { y: 0, uv: best.angle_delta.uv })
+    } else {
+      (PredictionMode::DC_PRED, AngleDelta::default())
+    };
+    let mvs = [MotionVector::default(); 2];
+    let ref_frames = [INTRA_FRAME, NONE_FRAME];
+    let mv_stack = ArrayVec::<[_; 9]>::new();
+
+    for &filter_intra_mode in RAV1E_FILTER_INTRA_MODES {
+      luma_chroma_mode_rdo(
+        PredictionMode::DC_PRED,
+        fi,
+        bsize,
+        tile_bo,
+        ts,
+        cw,
+        rdo_type,
+        cw_checkpoint,
+        &mut best,
+        mvs,
+        ref_frames,
+        &[chroma_mode],
+        true,
+        0,
+        &mv_stack,
+        angle_delta,
+        PaletteParams::default(),
+        Some(filter_intra_mode),
+        MotionMode::SIMPLE_TRANSLATION,
+        [fi.default_filter; 2],
+      );
+    }
+  }
+
+  if fi.allow_screen_content_tools > 0 && palette_allowed(bsize) {
+    let (visible_w, visible_h) = clip_visible_bsize(
+      fi.width,
+      fi.height,
+      bsize,
+      ts.to_frame_block_offset(tile_bo).0.x << MI_SIZE_LOG2,
+      ts.to_frame_block_offset(tile_bo).0.y << MI_SIZE_LOG2,
+    );
+    let candidates = palette_candidates(
+      &ts.input_tile.planes[0]
+        .subregion(Area::BlockStartingAt { bo: tile_bo.0 }),
+      visible_w,
+      visible_h,
+    );
+
+    // Palettes only replace the luma prediction, so keep the best chroma
+    // mode found so far.
+    let (chroma_mode, angle_delta) = if best.pred_mode_luma.is_intra() {
+      (best.pred_mode_chroma, AngleDelta { y: 0, uv: best.angle_delta.uv })
+    } else {
+      (PredictionMode::DC_PRED, AngleDelta::default())
+    };
+    let mvs = [MotionVector::default(); 2];
+    let ref_frames = [INTRA_FRAME, NONE_FRAME];
+    let mv_stack = ArrayVec::<[_; 9]>::new();
+
+    for &palette in candidates.iter() {
+      luma_chroma_mode_rdo(
+        PredictionMode::DC_PRED,
+        fi,
+        bsize,
+        tile_bo,
+        ts,
+        cw,
+        rdo_type,
+        cw_checkpoint,
+        &mut best,
+        mvs,
+        ref_frames,
+        &[chroma_mode],
+        true,
+        0,
+        &mv_stack,
+        angle_delta,
+        palette,
+        None,
+        MotionMode::SIMPLE_TRANSLATION,
+        [fi.default_filter; 2],
+      );
+    }
+  }
+
   best
 }
 
@@ -1561,6 +2113,9 @@ pub fn rdo_cfl_alpha<T: Pixel>(
         0,
         0,
         bsize,
+        // CfL prediction does not use the top-right or bottom-left edges.
+        PartitionType::PARTITION_NONE,
+        fi.sb_size_log2(),
         po,
         uv_tx_size,
         fi.sequence.bit_depth,
@@ -1731,27 +2286,73 @@ pub fn rdo_tx_type_decision<T: Pixel>(
   (best_type, best_rd)
 }
 
+/// Returns the offsets and sizes of the blocks that the square block at
+/// `tile_bo` is divided into by `partition`, in coding order.
 pub fn get_sub_partitions(
-  four_partitions: &[TileBlockOffset; 4], partition: PartitionType,
-) -> ArrayVec<[TileBlockOffset; 4]> {
-  let mut partition_offsets = ArrayVec::<[TileBlockOffset; 4]>::new();
+  tile_bo: TileBlockOffset, bsize: BlockSize, partition: PartitionType,
+) -> ArrayVec<[(TileBlockOffset, BlockSize); 4]> {
+  let hbs = bsize.width_mi() >> 1; // Half the block size in blocks
+  let qbs = hbs >> 1; // Quarter of the block size in blocks
+  let subsize = bsize.subsize(partition);
+  let split_size = bsize.subsize(PARTITION_SPLIT);
+  let offset = |x: usize, y: usize| {
+    TileBlockOffset(BlockOffset { x: tile_bo.0.x + x, y: tile_bo.0.y + y })
+  };
 
-  partition_offsets.push(four_partitions[0]);
+  let mut partitions = ArrayVec::<[(TileBlockOffset, BlockSize); 4]>::new();
 
-  if partition == PARTITION_NONE {
-    return partition_offsets;
+  match partition {
+    PARTITION_NONE => {
+      partitions.push((tile_bo, bsize));
+    }
+    PARTITION_HORZ => {
+      partitions.push((offset(0, 0), subsize));
+      partitions.push((offset(0, hbs), subsize));
+    }
+    PARTITION_VERT => {
+      partitions.push((offset(0, 0), subsize));
+      partitions.push((offset(hbs, 0), subsize));
+    }
+    PARTITION_SPLIT => {
+      partitions.push((offset(0, 0), subsize));
+      partitions.push((offset(hbs, 0), subsize));
+      partitions.push((offset(0, hbs), subsize));
+      partitions.push((offset(hbs, hbs), subsize));
+    }
+    PARTITION_HORZ_A => {
+      partitions.push((offset(0, 0), split_size));
+      partitions.push((offset(hbs, 0), split_size));
+      partitions.push((offset(0, hbs), subsize));
+    }
+    PARTITION_HORZ_B => {
+      partitions.push((offset(0, 0), subsize));
+      partitions.push((offset(0, hbs), split_size));
+      partitions.push((offset(hbs, hbs), split_size));
+    }
+    PARTITION_VERT_A => {
+      partitions.push((offset(0, 0), split_size));
+      partitions.push((offset(0, hbs), split_size));
+      partitions.push((offset(hbs, 0), subsize));
+    }
+    PARTITION_VERT_B => {
+      partitions.push((offset(0, 0), subsize));
+      partitions.push((offset(hbs, 0), split_size));
+      partitions.push((offset(hbs, hbs), split_size));
+    }
+    PARTITION_HORZ_4 => {
+      for i in 0..4 {
+        partitions.push((offset(0, i * qbs), subsize));
+      }
+    }
+    PARTITION_VERT_4 => {
+      for i in 0..4 {
+        partitions.push((offset(i * qbs, 0), subsize));
+      }
+    }
+    PARTITION_INVALID => unreachable!(),
   }
-  if partition == PARTITION_VERT || partition == PARTITION_SPLIT {
-    partition_offsets.push(four_partitions[1]);
-  };
-  if partition == PARTITION_HORZ || partition == PARTITION_SPLIT {
-    partition_offsets.push(four_partitions[2]);
-  };
-  if partition == PARTITION_SPLIT {
-    partition_offsets.push(four_partitions[3]);
-  };
 
-  partition_offsets
+  partitions
 }
 
 #[inline(always)]
@@ -1763,6 +2364,7 @@ fn rdo_partition_none<T: Pixel>(
 ) -> Option<f64> {
   debug_assert!(tile_bo.0.x < ts.mi_width && tile_bo.0.y < ts.mi_height);
 
+  cw.bc.blocks.set_partition(tile_bo, bsize, PARTITION_NONE);
   let mode = rdo_mode_decision(fi, ts, cw, bsize, tile_bo, inter_cfg);
   let cost = mode.rd_cost;
 
@@ -1771,7 +2373,7 @@ fn rdo_partition_none<T: Pixel>(
   Some(cost)
 }
 
-// VERTICAL, HORIZONTAL or simple SPLIT
+// VERTICAL, HORIZONTAL, extended or simple SPLIT
 #[inline(always)]
 fn rdo_partition_simple<T: Pixel, W: Writer>(
   fi: &FrameInvariants<T>, ts: &mut TileStateMut<'_, T>,
@@ -1794,46 +2396,38 @@ fn rdo_partition_simple<T: Pixel, W: Writer>(
     0.0
   };
 
-  let hbsw = subsize.width_mi(); // Half the block size width in blocks
-  let hbsh = subsize.height_mi(); // Half the block size height in blocks
-  let four_partitions = [
-    tile_bo,
-    TileBlockOffset(BlockOffset {
-      x: tile_bo.0.x + hbsw as usize,
-      y: tile_bo.0.y,
-    }),
-    TileBlockOffset(BlockOffset {
-      x: tile_bo.0.x,
-      y: tile_bo.0.y + hbsh as usize,
-    }),
-    TileBlockOffset(BlockOffset {
-      x: tile_bo.0.x + hbsw as usize,
-      y: tile_bo.0.y + hbsh as usize,
-    }),
-  ];
-
-  let partitions = get_sub_partitions(&four_partitions, partition);
+  let partitions = get_sub_partitions(tile_bo, bsize, partition);
 
   let mut rd_cost_sum = 0.0;
 
-  for offset in partitions {
-    let hbs = subsize.width_mi() >> 1;
+  for (offset, sub_bsize) in partitions {
+    // The last of four rectangles may be outside the frame, and is not coded
+    if offset.0.x >= ts.mi_width || offset.0.y >= ts.mi_height {
+      continue;
+    }
+    let hbs = sub_bsize.width_mi() >> 1;
     let has_cols = offset.0.x + hbs < ts.mi_width;
     let has_rows = offset.0.y + hbs < ts.mi_height;
 
     if has_cols && has_rows {
       let mode_decision =
-        rdo_mode_decision(fi, ts, cw, subsize, offset, inter_cfg);
+        rdo_mode_decision(fi, ts, cw, sub_bsize, offset, inter_cfg);
 
       rd_cost_sum += mode_decision.rd_cost;
 
       if fi.enable_early_exit && rd_cost_sum > best_rd {
         return None;
       }
-      if subsize >= BlockSize::BLOCK_8X8 && subsize.is_sqr() {
+      // Only the blocks of a split are partitioned further
+      if partition == PARTITION_SPLIT && sub_bsize >= BlockSize::BLOCK_8X8 {
         let w: &mut W =
           if cw.bc.cdef_coded { w_post_cdef } else { w_pre_cdef };
-        cw.write_partition(w, offset, PartitionType::PARTITION_NONE, subsize);
+        cw.write_partition(
+          w,
+          offset,
+          PartitionType::PARTITION_NONE,
+          sub_bsize,
+        );
       }
       encode_block_with_modes(
         fi,
@@ -1841,7 +2435,7 @@ fn rdo_partition_simple<T: Pixel, W: Writer>(
         cw,
         w_pre_cdef,
         w_post_cdef,
-        subsize,
+        sub_bsize,
         offset,
         &mode_decision,
         rdo_type,
@@ -1882,7 +2476,7 @@ pub fn rdo_partition_decision<T: Pixel, W: Writer>(
     let mut child_modes = ArrayVec::<[_; 4]>::new();
 
     let cost = match partition {
-      PARTITION_NONE if bsize <= BlockSize::BLOCK_64X64 => rdo_partition_none(
+      PARTITION_NONE => rdo_partition_none(
         fi,
         ts,
         cw,
@@ -1891,25 +2485,21 @@ pub fn rdo_partition_decision<T: Pixel, W: Writer>(
         inter_cfg,
         &mut child_modes,
       ),
-      PARTITION_SPLIT | PARTITION_HORZ | PARTITION_VERT => {
-        rdo_partition_simple(
-          fi,
-          ts,
-          cw,
-          w_pre_cdef,
-          w_post_cdef,
-          bsize,
-          tile_bo,
-          inter_cfg,
-          partition,
-          rdo_type,
-          best_rd,
-          &mut child_modes,
-        )
-      }
-      _ => {
-        unreachable!();
-      }
+      PARTITION_INVALID => unreachable!(),
+      _ => rdo_partition_simple(
+        fi,
+        ts,
+        cw,
+        w_pre_cdef,
+        w_post_cdef,
+        bsize,
+        tile_bo,
+        inter_cfg,
+        partition,
+        rdo_type,
+        best_rd,
+        &mut child_modes,
+      ),
     };
 
     if let Some(rd) = cost {
@@ -1938,10 +2528,9 @@ fn rdo_loop_plane_error<T: Pixel>(
   sb_w: usize, sb_h: usize, fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>,
   blocks: &TileBlocks<'_>, test: &Frame<T>, src: &Tile<'_, T>, pli: usize,
 ) -> ScaledDistortion {
-  let sb_w_blocks =
-    if fi.sequence.use_128x128_superblock { 16 } else { 8 } * sb_w;
-  let sb_h_blocks =
-    if fi.sequence.use_128x128_superblock { 16 } else { 8 } * sb_h;
+  // The loop filter decisions are made on 64x64 superblocks.
+  let sb_w_blocks = 8 * sb_w;
+  let sb_h_blocks = 8 * sb_h;
   // Each direction block is 8x8 in y, potentially smaller if subsampled in chroma
   // accumulating in-frame and unpadded
   let mut err = Distortion::zero();
@@ -1986,6 +2575,37 @@ fn rdo_loop_plane_error<T: Pixel>(
   err * fi.dist_scale[pli]
 }
 
+/// Returns the 64x64 superblocks of the analysis area that share the CDEF
+/// index of the superblock at (`sbx`, `sby`), including itself, or nothing if
+/// it takes its index from a previous superblock.
+///
+/// The CDEF index is coded in the first 64x64 superblock covered by a block
+/// and applies to all of the superblocks the block covers.
+fn cdef_linked_superblocks(
+  blocks: &TileBlocks<'_>, sbx: usize, sby: usize, sb_w: usize, sb_h: usize,
+) -> ArrayVec<[(usize, usize); 4]> {
+  let owner = |x: usize, y: usize| {
+    let bsize = blocks[y << SUPERBLOCK_TO_BLOCK_SHIFT]
+      [x << SUPERBLOCK_TO_BLOCK_SHIFT]
+      .bsize;
+    (
+      if bsize.width() > SB_SIZE { x & !1 } else { x },
+      if bsize.height() > SB_SIZE { y & !1 } else { y },
+    )
+  };
+  let mut sbs = ArrayVec::new();
+  if owner(sbx, sby) == (sbx, sby) {
+    for y in sby..(sby + 2).min(sb_h) {
+      for x in sbx..(sbx + 2).min(sb_w) {
+        if owner(x, y) == (sbx, sby) {
+          sbs.push((x, y));
+        }
+      }
+    }
+  }
+  sbs
+}
+
 // Passed in a superblock offset representing the upper left corner of
 // the LRU area we're optimizing.  This area covers the largest LRU in
 // any of the present planes, but may consist of a number of
@@ -2131,7 +2751,7 @@ pub fn rdo_loop_decision<T: Pixel>(
 
   let mut cdef_work =
     if fi.sequence.enable_cdef { Some(rec_subset.clone()) } else { None };
-  let mut lrf_work = if fi.sequence.enable_restoration {
+  let mut lrf_work = if fi.sequence.enable_restoration && !fi.use_superres() {
     Some(Frame {
       planes: {
         let new_plane = |pli: usize| {
@@ -2179,149 +2799,162 @@ pub fn rdo_loop_decision<T: Pixel>(
     {
       for sby in 0..sb_h {
         for sbx in 0..sb_w {
+          // Superblocks covered by a block larger than 64x64 share the CDEF
+          // index coded in the first one, so they are decided together.
+          let cdef_sbs = cdef_linked_superblocks(
+            &tileblocks_subset.as_const(),
+            sbx,
+            sby,
+            sb_w,
+            sb_h,
+          );
+          if cdef_sbs.is_empty() {
+            continue;
+          }
           let prev_best_index = best_index[sby * sb_w + sbx];
           let mut best_cost = -1.;
           let mut best_new_index = -1i8;
 
-          /* offset of the superblock we're currently testing within the larger
-          analysis area */
-          let loop_sbo =
-            TileSuperBlockOffset(SuperBlockOffset { x: sbx, y: sby });
-
           /* cdef index testing loop */
           for cdef_index in 0..(1 << fi.cdef_bits) {
             let mut err = ScaledDistortion::zero();
             let mut rate = 0;
 
-            cdef_filter_superblock(
-              fi,
-              &rec_subset,
-              &mut cdef_ref.as_tile_mut(),
-              &tileblocks_subset.as_const(),
-              loop_sbo,
-              cdef_index,
-              &cdef_dirs[sby * sb_w + sbx],
-            );
-            // apply LRF if any
-            for pli in 0..planes {
-              // We need the cropped-to-visible-frame area of this SB
-              let wh =
-                if fi.sequence.use_128x128_superblock { 128 } else { 64 };
-              let PlaneConfig { xdec, ydec, .. } = cdef_ref.planes[pli].cfg;
-              let vis_width = (wh >> xdec).min(
-                (crop_w >> xdec)
-                  - loop_sbo.plane_offset(&cdef_ref.planes[pli].cfg).x
-                    as usize,
+            for &(sbx, sby) in cdef_sbs.iter() {
+              /* offset of the superblock we're currently testing within the
+              larger analysis area */
+              let loop_sbo =
+                TileSuperBlockOffset(SuperBlockOffset { x: sbx, y: sby });
+
+              cdef_filter_superblock(
+                fi,
+                &rec_subset,
+                &mut cdef_ref.as_tile_mut(),
+                &tileblocks_subset.as_const(),
+                loop_sbo,
+                cdef_index,
+                &cdef_dirs[sby * sb_w + sbx],
               );
-              let vis_height = (wh >> ydec).min(
-                (crop_h >> ydec)
-                  - loop_sbo.plane_offset(&cdef_ref.planes[pli].cfg).y
-                    as usize,
-              );
-              // which LRU are we currently testing against?
-              if let (Some((lru_x, lru_y)), Some(lrf_ref)) = {
-                let rp = &ts.restoration.planes[pli];
-                (
-                  rp.restoration_unit_offset(base_sbo, loop_sbo, false),
-                  &mut lrf_work,
-                )
-              } {
-                // We have a valid LRU, apply LRF, compute error
-                match best_lrf[lru_y * lru_w[pli] + lru_x][pli] {
-                  RestorationFilter::None {} => {
-                    err += rdo_loop_plane_error(
-                      base_sbo,
-                      loop_sbo,
-                      1,
-                      1,
-                      fi,
-                      ts,
-                      &tileblocks_subset.as_const(),
-                      cdef_ref,
-                      &src_subset,
-                      pli,
-                    );
-                    rate += if fi.sequence.enable_restoration {
-                      cw.fc.count_lrf_switchable(
+              // apply LRF if any
+              for pli in 0..planes {
+                // We need the cropped-to-visible-frame area of this SB
+                let wh = 1 << SUPERBLOCK_TO_PLANE_SHIFT;
+                let PlaneConfig { xdec, ydec, .. } = cdef_ref.planes[pli].cfg;
+                let vis_width = (wh >> xdec).min(
+                  (crop_w >> xdec)
+                    - loop_sbo.plane_offset(&cdef_ref.planes[pli].cfg).x
+                      as usize,
+                );
+                let vis_height = (wh >> ydec).min(
+                  (crop_h >> ydec)
+                    - loop_sbo.plane_offset(&cdef_ref.planes[pli].cfg).y
+                      as usize,
+                );
+                // which LRU are we currently testing against?
+                if let (Some((lru_x, lru_y)), Some(lrf_ref)) = {
+                  let rp = &ts.restoration.planes[pli];
+                  (
+                    rp.restoration_unit_offset(base_sbo, loop_sbo, false),
+                    &mut lrf_work,
+                  )
+                } {
+                  // We have a valid LRU, apply LRF, compute error
+                  match best_lrf[lru_y * lru_w[pli] + lru_x][pli] {
+                    RestorationFilter::None {} => {
+                      err += rdo_loop_plane_error(
+                        base_sbo,
+                        loop_sbo,
+                        1,
+                        1,
+                        fi,
+                        ts,
+                        &tileblocks_subset.as_const(),
+                        cdef_ref,
+                        &src_subset,
+                        pli,
+                      );
+                      rate += if fi.sequence.enable_restoration {
+                        cw.fc.count_lrf_switchable(
+                          w,
+                          &ts.restoration.as_const(),
+                          best_lrf[lru_y * lru_w[pli] + lru_x][pli],
+                          pli,
+                        )
+                      } else {
+                        0 // no relative cost differeneces to different
+                          // CDEF params.  If cdef is on, it's a wash.
+                      };
+                    }
+                    RestorationFilter::Sgrproj { set, xqd } => {
+                      // only run on this single superblock
+                      let loop_po =
+                        loop_sbo.plane_offset(&cdef_ref.planes[pli].cfg);
+                      // todo: experiment with borrowing border pixels
+                      // rather than edge-extending. Right now this is
+                      // hard-clipping to the superblock boundary.
+                      setup_integral_image(
+                        &mut ts.integral_buffer,
+                        SOLVE_IMAGE_STRIDE,
+                        vis_width,
+                        vis_height,
+                        vis_width,
+                        vis_height,
+                        &cdef_ref.planes[pli].slice(loop_po),
+                        &cdef_ref.planes[pli].slice(loop_po),
+                      );
+                      sgrproj_stripe_filter(
+                        set,
+                        xqd,
+                        fi,
+                        &ts.integral_buffer,
+                        SOLVE_IMAGE_STRIDE,
+                        &cdef_ref.planes[pli].slice(loop_po),
+                        &mut lrf_ref.planes[pli].region_mut(Area::Rect {
+                          x: loop_po.x,
+                          y: loop_po.y,
+                          width: vis_width,
+                          height: vis_height,
+                        }),
+                      );
+                      err += rdo_loop_plane_error(
+                        base_sbo,
+                        loop_sbo,
+                        1,
+                        1,
+                        fi,
+                        ts,
+                        &tileblocks_subset.as_const(),
+                        lrf_ref,
+                        &src_subset,
+                        pli,
+                      );
+                      rate += cw.fc.count_lrf_switchable(
                         w,
                         &ts.restoration.as_const(),
                         best_lrf[lru_y * lru_w[pli] + lru_x][pli],
                         pli,
-                      )
-                    } else {
-                      0 // no relative cost differeneces to different
-                        // CDEF params.  If cdef is on, it's a wash.
-                    };
+                      );
+                    }
+                    RestorationFilter::Wiener { .. } => unreachable!(), // coming soon
                   }
-                  RestorationFilter::Sgrproj { set, xqd } => {
-                    // only run on this single superblock
-                    let loop_po =
-                      loop_sbo.plane_offset(&cdef_ref.planes[pli].cfg);
-                    // todo: experiment with borrowing border pixels
-                    // rather than edge-extending. Right now this is
-                    // hard-clipping to the superblock boundary.
-                    setup_integral_image(
-                      &mut ts.integral_buffer,
-                      SOLVE_IMAGE_STRIDE,
-                      vis_width,
-                      vis_height,
-                      vis_width,
-                      vis_height,
-                      &cdef_ref.planes[pli].slice(loop_po),
-                      &cdef_ref.planes[pli].slice(loop_po),
-                    );
-                    sgrproj_stripe_filter(
-                      set,
-                      xqd,
-                      fi,
-                      &ts.integral_buffer,
-                      SOLVE_IMAGE_STRIDE,
-                      &cdef_ref.planes[pli].slice(loop_po),
-                      &mut lrf_ref.planes[pli].region_mut(Area::Rect {
-                        x: loop_po.x,
-                        y: loop_po.y,
-                        width: vis_width,
-                        height: vis_height,
-                      }),
-                    );
-                    err += rdo_loop_plane_error(
-                      base_sbo,
-                      loop_sbo,
-                      1,
-                      1,
-                      fi,
-                      ts,
-                      &tileblocks_subset.as_const(),
-                      lrf_ref,
-                      &src_subset,
-                      pli,
-                    );
-                    rate += cw.fc.count_lrf_switchable(
-                      w,
-                      &ts.restoration.as_const(),
-                      best_lrf[lru_y * lru_w[pli] + lru_x][pli],
-                      pli,
-                    );
-                  }
-                  RestorationFilter::Wiener { .. } => unreachable!(), // coming soon
+                } else {
+                  // No actual LRU here, compute error directly from CDEF output.
+                  err += rdo_loop_plane_error(
+                    base_sbo,
+                    loop_sbo,
+                    1,
+                    1,
+                    fi,
+                    ts,
+                    &tileblocks_subset.as_const(),
+                    cdef_ref,
+                    &src_subset,
+                    pli,
+                  );
+                  // no relative cost differeneces to different
+                  // CDEF params.  If cdef is on, it's a wash.
+                  // rate += 0;
                 }
-              } else {
-                // No actual LRU here, compute error directly from CDEF output.
-                err += rdo_loop_plane_error(
-                  base_sbo,
-                  loop_sbo,
-                  1,
-                  1,
-                  fi,
-                  ts,
-                  &tileblocks_subset.as_const(),
-                  cdef_ref,
-                  &src_subset,
-                  pli,
-                );
-                // no relative cost differeneces to different
-                // CDEF params.  If cdef is on, it's a wash.
-                // rate += 0;
               }
             }
 
@@ -2335,8 +2968,12 @@ pub fn rdo_loop_decision<T: Pixel>(
           // Did we change any preexisting choices?
           if best_new_index != prev_best_index {
             cdef_change = true;
-            best_index[sby * sb_w + sbx] = best_new_index;
-            tileblocks_subset.set_cdef(loop_sbo, best_new_index as u8);
+            for &(sbx, sby) in cdef_sbs.iter() {
+              let loop_sbo =
+                TileSuperBlockOffset(SuperBlockOffset { x: sbx, y: sby });
+              best_index[sby * sb_w + sbx] = best_new_index;
+              tileblocks_subset.set_cdef(loop_sbo, best_new_index as u8);
+            }
           }
 
           let mut cdef_ref_tm = TileMut::new(
@@ -2351,15 +2988,19 @@ pub fn rdo_loop_decision<T: Pixel>(
 
           // Keep cdef output up to date; we need it for restoration
           // both below and above (padding)
-          cdef_filter_superblock(
-            fi,
-            rec_copy,
-            &mut cdef_ref_tm,
-            &tileblocks_subset.as_const(),
-            loop_sbo,
-            best_index[sby * sb_w + sbx] as u8,
-            &cdef_dirs[sby * sb_w + sbx],
-          );
+          for &(sbx, sby) in cdef_sbs.iter() {
+            let loop_sbo =
+              TileSuperBlockOffset(SuperBlockOffset { x: sbx, y: sby });
+            cdef_filter_superblock(
+              fi,
+              rec_copy,
+              &mut cdef_ref_tm,
+              &tileblocks_subset.as_const(),
+              loop_sbo,
+              best_index[sby * sb_w + sbx] as u8,
+              &cdef_dirs[sby * sb_w + sbx],
+            );
+          }
         }
       }
     }
@@ -2542,3 +3183,134 @@ pub fn rdo_loop_decision<T: Pixel>(
 fn estimate_rate_test() {
   assert_eq!(estimate_rate(0, TxSize::TX_4X4, 0), RDO_RATE_TABLE[0][0][0]);
 }
+
+#[test]
+fn rdo_quantize_lowers_rd_cost() {
+  use crate::encoder::Sequence;
+  use crate::quantize::{dequantize, QuantizationContext};
+  use rand::{Rng, SeedableRng};
+  use rand_chacha::ChaChaRng;
+
+  let config = EncoderConfig {
+    width: 64,
+    height: 64,
+    quantizer: 120,
+    ..Default::default()
+  };
+  let sequence = Sequence::new(&config);
+  let fi = FrameInvariants::<u8>::new(config, sequence);
+  let mut fc = CDFContext::new(fi.base_q_idx);
+  let mut fb = FrameBlocks::new(fi.w_in_b, fi.h_in_b);
+  let mut tb = fb.as_tile_blocks_mut();
+  let mut cw = ContextWriter::new(&mut fc, BlockContext::new(&mut tb));
+  let mut qc = QuantizationContext::default();
+  let mut ra = ChaChaRng::from_seed([0; 32]);
+  let tx_bo = TileBlockOffset(BlockOffset { x: 0, y: 0 });
+  let tx_type = TxType::DCT_DCT;
+
+  // D + λR of a block, with the rate measured by coding it.
+  let rd_cost = |cw: &mut ContextWriter,
+                 tx_size: TxSize,
+                 coeffs: &[i16],
+                 qcoeffs: &[i16],
+                 eob: usize| {
+    let checkpoint = cw.checkpoint();
+    let mut w = WriterCounter::new();
+    cw.write_coeffs_lv_map(
+      &mut w,
+      0,
+      tx_bo,
+      qcoeffs,
+      eob,
+      PredictionMode::DC_PRED,
+      tx_size,
+      tx_type,
+      fi.base_q_idx,
+      tx_size.block_size(),
+      0,
+      0,
+      fi.use_reduced_tx_set,
+      tx_size.width(),
+      tx_size.height(),
+    );
+    cw.rollback(&checkpoint);
+
+    let mut rcoeffs = [0i16; 32 * 32];
+    let rcoeffs = &mut rcoeffs[..tx_size.area()];
+    dequantize(
+      fi.base_q_idx,
+      qcoeffs,
+      eob,
+      rcoeffs,
+      tx_size,
+      8,
+      0,
+      0,
+      None,
+      fi.cpu_feature_level,
+    );
+    let raw_dist = coeffs
+      .iter()
+      .zip(rcoeffs.iter())
+      .map(|(&a, &b)| {
+        let c = i32::from(a) - i32::from(b);
+        (c * c) as u64
+      })
+      .sum::<u64>()
+      >> (2 * (3 - get_log_tx_scale(tx_size)));
+    let dist = RawDistortion::new(raw_dist)
+      * DistortionScale::default()
+      * fi.dist_scale[0];
+    compute_rd_cost(&fi, w.tell_frac(), dist)
+  };
+
+  for &tx_size in
+    &[TxSize::TX_4X4, TxSize::TX_8X8, TxSize::TX_16X16, TxSize::TX_8X32]
+  {
+    qc.update(fi.base_q_idx, tx_size, true, 8, 0, 0);
+    let mut deadzone_cost = 0.;
+    let mut rdoq_cost = 0.;
+    for _ in 0..100 {
+      // Coefficients decay away from DC, like those of a residual.
+      let height = tx_size.height();
+      let coeffs: Vec<i16> = (0..tx_size.area())
+        .map(|i| {
+          let range = 2048 / (1 + i % height + i / height) as i16;
+          ra.gen_range(-range, range + 1)
+        })
+        .collect();
+      let mut qcoeffs = [0i16; 32 * 32];
+      let qcoeffs = &mut qcoeffs[..tx_size.area()];
+      let eob = qc.quantize(&coeffs, qcoeffs, tx_size, tx_type, None);
+      deadzone_cost += rd_cost(&mut cw, tx_size, &coeffs, qcoeffs, eob);
+
+      let eob = rdo_quantize(
+        &fi,
+        &cw,
+        0,
+        tx_bo,
+        &coeffs,
+        qcoeffs,
+        eob,
+        tx_size,
+        tx_type,
+        None,
+        tx_size.block_size(),
+        0,
+        0,
+        fi.base_q_idx,
+        tx_size.width(),
+        tx_size.height(),
+        DistortionScale::default(),
+      );
+      rdoq_cost += rd_cost(&mut cw, tx_size, &coeffs, qcoeffs, eob);
+    }
+    assert!(
+      rdoq_cost < deadzone_cost,
+      "{:?}: {} >= {}",
+      tx_size,
+      rdoq_cost,
+      deadzone_cost
+    );
+  }
+}
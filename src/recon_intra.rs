@@ -152,29 +152,25 @@ static has_tr_vert_tables: &[&[u8]] = &[
   has_tr_128x128,    // 128x128
 ];
 
-// TODO: Enable the case for PARTITION_VERT_A/B once they can be encoded by rav1e.
 pub fn get_has_tr_table(
-  /*partition: PartitionType, */ bsize: BlockSize,
+  partition: PartitionType, bsize: BlockSize,
 ) -> &'static [u8] {
-  let ret: &[u8];
   // If this is a mixed vertical partition, look up bsize in orders_vert.
-  /*if partition == PartitionType::PARTITION_VERT_A || partition == PartitionType::PARTITION_VERT_B {
-    debug_assert!(bsize < BlockSize::BLOCK_SIZES);
-    ret = has_tr_vert_tables[bsize as usize];
-  } else */
+  if partition == PartitionType::PARTITION_VERT_A
+    || partition == PartitionType::PARTITION_VERT_B
   {
-    ret = has_tr_tables[bsize as usize];
+    debug_assert!((bsize as usize) < BlockSize::BLOCK_SIZES);
+    has_tr_vert_tables[bsize as usize]
+  } else {
+    has_tr_tables[bsize as usize]
   }
-
-  //debug_assert!(ret != ptr::has_null());
-
-  ret
 }
 
 pub fn has_top_right(
   bsize: BlockSize, partition_bo: TileBlockOffset, top_available: bool,
-  right_available: bool, tx_size: TxSize, row_off: usize, col_off: usize,
-  ss_x: usize, _ss_y: usize,
+  right_available: bool, partition: PartitionType, tx_size: TxSize,
+  row_off: usize, col_off: usize, ss_x: usize, _ss_y: usize,
+  sb_size_log2: usize,
 ) -> bool {
   if !top_available || !right_available {
     return false;
@@ -189,7 +185,6 @@ pub fn has_top_right(
 
   if row_off > 0 {
     // Just need to check if enough pixels on the right.
-    // 128x128 SB is not supported yet by rav1e
     if bsize.width() > BLOCK_64X64.width() {
       // Special case: For 128x128 blocks, the transform unit whose
       // top-right corner is at the center of the block does in fact have
@@ -197,7 +192,7 @@ pub fn has_top_right(
       if row_off == BLOCK_64X64.height_mi() >> _ss_y
         && col_off + top_right_count_unit == BLOCK_64X64.width_mi() >> ss_x
       {
-        return false;
+        return true;
       }
       let plane_bw_unit_64 = BLOCK_64X64.width_mi() >> ss_x;
       let col_off_64 = col_off % plane_bw_unit_64;
@@ -212,7 +207,7 @@ pub fn has_top_right(
 
     let bw_in_mi_log2 = bsize.width_log2() - MI_SIZE_LOG2;
     let bh_in_mi_log2 = bsize.height_log2() - MI_SIZE_LOG2;
-    let sb_mi_size: usize = 16; // 64x64
+    let sb_mi_size: usize = 1 << (sb_size_log2 - MI_SIZE_LOG2);
     let blk_row_in_sb = (mi_row & (sb_mi_size - 1)) >> bh_in_mi_log2;
     let blk_col_in_sb = (mi_col & (sb_mi_size - 1)) >> bw_in_mi_log2;
 
@@ -234,7 +229,7 @@ pub fn has_top_right(
       (blk_row_in_sb << (MAX_MIB_SIZE_LOG2 - bw_in_mi_log2)) + blk_col_in_sb;
     let idx1 = this_blk_index / 8;
     let idx2 = this_blk_index % 8;
-    let has_tr_table: &[u8] = get_has_tr_table(/*partition,*/ bsize);
+    let has_tr_table: &[u8] = get_has_tr_table(partition, bsize);
 
     ((has_tr_table[idx1] >> idx2) & 1) != 0
   }
@@ -356,25 +351,24 @@ static has_bl_vert_tables: &[&[u8]] = &[
 ];
 
 pub fn get_has_bl_table(
-  /*partition: PartitionType, */ bsize: BlockSize,
+  partition: PartitionType, bsize: BlockSize,
 ) -> &'static [u8] {
-  let ret: &[u8];
   // If this is a mixed vertical partition, look up bsize in orders_vert.
-  /*if (partition == PARTITION_VERT_A || partition == PARTITION_VERT_B) {
-    //assert(bsize < BLOCK_SIZES);
-    ret = has_bl_vert_tables[bsize as usize];
-  } else*/
+  if partition == PartitionType::PARTITION_VERT_A
+    || partition == PartitionType::PARTITION_VERT_B
   {
-    ret = has_bl_tables[bsize as usize];
+    debug_assert!((bsize as usize) < BlockSize::BLOCK_SIZES);
+    has_bl_vert_tables[bsize as usize]
+  } else {
+    has_bl_tables[bsize as usize]
   }
-  //debug_assert!(ret != ptr::has_null());
-  ret
 }
 
 pub fn has_bottom_left(
   bsize: BlockSize, partition_bo: TileBlockOffset, bottom_available: bool,
-  left_available: bool, tx_size: TxSize, row_off: usize, col_off: usize,
-  _ss_x: usize, ss_y: usize,
+  left_available: bool, partition: PartitionType, tx_size: TxSize,
+  row_off: usize, col_off: usize, _ss_x: usize, ss_y: usize,
+  sb_size_log2: usize,
 ) -> bool {
   if !bottom_available || !left_available {
     return false;
@@ -383,7 +377,6 @@ pub fn has_bottom_left(
   // Special case for 128x* blocks, when col_off is half the block width.
   // This is needed because 128x* superblocks are divided into 64x* blocks in
   // raster order
-  // 128x128 SB is not supported yet by rav1e
   if bsize.width() > BLOCK_64X64.width() && col_off > 0 {
     let plane_bw_unit_64 = BLOCK_64X64.width_mi() >> _ss_x;
     let col_off_64 = col_off % plane_bw_unit_64;
@@ -416,7 +409,7 @@ pub fn has_bottom_left(
 
     let bw_in_mi_log2 = bsize.width_log2() - MI_SIZE_LOG2;
     let bh_in_mi_log2 = bsize.height_log2() - MI_SIZE_LOG2;
-    let sb_mi_size: usize = 16; // 64x64
+    let sb_mi_size: usize = 1 << (sb_size_log2 - MI_SIZE_LOG2);
     let blk_row_in_sb = (mi_row & (sb_mi_size - 1)) >> bh_in_mi_log2;
     let blk_col_in_sb = (mi_col & (sb_mi_size - 1)) >> bw_in_mi_log2;
 
@@ -443,7 +436,7 @@ pub fn has_bottom_left(
       (blk_row_in_sb << (MAX_MIB_SIZE_LOG2 - bw_in_mi_log2)) + blk_col_in_sb;
     let idx1 = this_blk_index / 8;
     let idx2 = this_blk_index % 8;
-    let has_bl_table: &[u8] = get_has_bl_table(/*partition,*/ bsize);
+    let has_bl_table: &[u8] = get_has_bl_table(partition, bsize);
 
     ((has_bl_table[idx1] >> idx2) & 1) != 0
   }
@@ -0,0 +1,309 @@
+// Copyright (c) 2020, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::color::ChromaSampling;
+use crate::context::MI_SIZE_LOG2;
+use crate::encoder::FrameInvariants;
+use crate::frame::*;
+use crate::util::*;
+
+pub const SUPERRES_NUM: u8 = 8;
+pub const SUPERRES_DENOM_MIN: u8 = 9;
+pub const SUPERRES_DENOM_BITS: u32 = 3;
+pub const SUPERRES_DENOM_MAX: u8 =
+  SUPERRES_DENOM_MIN + (1 << SUPERRES_DENOM_BITS) - 1;
+
+const SUPERRES_SCALE_BITS: u32 = 14;
+const SUPERRES_SCALE_MASK: i32 = (1 << SUPERRES_SCALE_BITS) - 1;
+const SUPERRES_EXTRA_BITS: u32 = 8;
+const SUPERRES_FILTER_OFFSET: isize = 3;
+
+/// Precision of the weights of the (non-normative) resampling filter.
+const RESAMPLE_BITS: u32 = 14;
+
+/// Filters of the super-resolution upscaler, by 1/64 pel position.
+const UPSCALE_FILTER: [[i32; 8]; 64] = [
+  [0, 0, 0, 128, 0, 0, 0, 0],
+  [0, 0, -1, 128, 2, -1, 0, 0],
+  [0, 1, -3, 127, 4, -2, 1, 0],
+  [0, 1, -4, 127, 6, -3, 1, 0],
+  [0, 2, -6, 126, 8, -3, 1, 0],
+  [0, 2, -7, 125, 11, -4, 1, 0],
+  [-1, 2, -8, 125, 13, -5, 2, 0],
+  [-1, 3, -9, 124, 15, -6, 2, 0],
+  [-1, 3, -10, 123, 18, -6, 2, -1],
+  [-1, 3, -11, 122, 20, -7, 3, -1],
+  [-1, 4, -12, 121, 22, -8, 3, -1],
+  [-1, 4, -13, 120, 25, -9, 3, -1],
+  [-1, 4, -14, 118, 28, -9, 3, -1],
+  [-1, 4, -15, 117, 30, -10, 4, -1],
+  [-1, 5, -16, 116, 32, -11, 4, -1],
+  [-1, 5, -16, 114, 35, -12, 4, -1],
+  [-1, 5, -17, 112, 38, -12, 4, -1],
+  [-1, 5, -18, 111, 40, -13, 5, -1],
+  [-1, 5, -18, 109, 43, -14, 5, -1],
+  [-1, 6, -19, 107, 45, -14, 5, -1],
+  [-1, 6, -19, 105, 48, -15, 5, -1],
+  [-1, 6, -19, 103, 51, -16, 5, -1],
+  [-1, 6, -20, 101, 53, -16, 6, -1],
+  [-1, 6, -20, 99, 56, -17, 6, -1],
+  [-1, 6, -20, 97, 58, -17, 6, -1],
+  [-1, 6, -20, 95, 61, -18, 6, -1],
+  [-2, 7, -20, 93, 64, -18, 6, -2],
+  [-2, 7, -20, 91, 66, -19, 6, -1],
+  [-2, 7, -20, 88, 69, -19, 6, -1],
+  [-2, 7, -20, 86, 71, -19, 6, -1],
+  [-2, 7, -20, 84, 74, -20, 7, -2],
+  [-2, 7, -20, 81, 76, -20, 7, -1],
+  [-2, 7, -20, 79, 79, -20, 7, -2],
+  [-1, 7, -20, 76, 81, -20, 7, -2],
+  [-2, 7, -20, 74, 84, -20, 7, -2],
+  [-1, 6, -19, 71, 86, -20, 7, -2],
+  [-1, 6, -19, 69, 88, -20, 7, -2],
+  [-1, 6, -19, 66, 91, -20, 7, -2],
+  [-2, 6, -18, 64, 93, -20, 7, -2],
+  [-1, 6, -18, 61, 95, -20, 6, -1],
+  [-1, 6, -17, 58, 97, -20, 6, -1],
+  [-1, 6, -17, 56, 99, -20, 6, -1],
+  [-1, 6, -16, 53, 101, -20, 6, -1],
+  [-1, 5, -16, 51, 103, -19, 6, -1],
+  [-1, 5, -15, 48, 105, -19, 6, -1],
+  [-1, 5, -14, 45, 107, -19, 6, -1],
+  [-1, 5, -14, 43, 109, -18, 5, -1],
+  [-1, 5, -13, 40, 111, -18, 5, -1],
+  [-1, 4, -12, 38, 112, -17, 5, -1],
+  [-1, 4, -12, 35, 114, -16, 5, -1],
+  [-1, 4, -11, 32, 116, -16, 5, -1],
+  [-1, 4, -10, 30, 117, -15, 4, -1],
+  [-1, 3, -9, 28, 118, -14, 4, -1],
+  [-1, 3, -9, 25, 120, -13, 4, -1],
+  [-1, 3, -8, 22, 121, -12, 4, -1],
+  [-1, 3, -7, 20, 122, -11, 3, -1],
+  [-1, 2, -6, 18, 123, -10, 3, -1],
+  [0, 2, -6, 15, 124, -9, 3, -1],
+  [0, 2, -5, 13, 125, -8, 2, -1],
+  [0, 1, -4, 11, 125, -7, 2, 0],
+  [0, 1, -3, 8, 126, -6, 2, 0],
+  [0, 1, -3, 6, 127, -4, 1, 0],
+  [0, 1, -2, 4, 127, -3, 1, 0],
+  [0, 0, -1, 2, 128, -1, 0, 0],
+];
+
+/// Returns the width at which a frame of `upscaled_width` is coded with the
+/// super-resolution denominator `denom`.
+pub const fn superres_coded_width(upscaled_width: usize, denom: u8) -> usize {
+  (upscaled_width * SUPERRES_NUM as usize + denom as usize / 2)
+    / denom as usize
+}
+
+/// Upscales the reconstruction of a frame coded at `fi.width` back to
+/// `fi.upscaled_width` (spec 7.16).
+///
+/// The filter reads the reconstruction up to the last 8x8 block column,
+/// past the coded width.
+pub fn upscale_frame<T: Pixel>(
+  fi: &FrameInvariants<T>, src: &Frame<T>,
+) -> Frame<T> {
+  let cs = fi.sequence.chroma_sampling;
+  let planes = if cs == ChromaSampling::Cs400 { 1 } else { 3 };
+  let max_val = (1 << fi.sequence.bit_depth) - 1;
+  let mut dst = Frame::new(fi.upscaled_width, fi.height, cs);
+
+  for p in 0..planes {
+    let src = &src.planes[p];
+    let dst = &mut dst.planes[p];
+    let PlaneConfig { xdec, ydec, .. } = src.cfg;
+    let down_w = ((fi.width + xdec) >> xdec) as i32;
+    let up_w = ((fi.upscaled_width + xdec) >> xdec) as i32;
+    let height = (fi.height + ydec) >> ydec;
+    let last_x = ((fi.w_in_b << MI_SIZE_LOG2) >> xdec) as isize - 1;
+
+    let step = ((down_w << SUPERRES_SCALE_BITS) + up_w / 2) / up_w;
+    let err = up_w * step - (down_w << SUPERRES_SCALE_BITS);
+    let initial_subpel =
+      ((-((up_w - down_w) << (SUPERRES_SCALE_BITS - 1)) + up_w / 2) / up_w
+        + (1 << (SUPERRES_EXTRA_BITS - 1))
+        - err / 2)
+        & SUPERRES_SCALE_MASK;
+
+    let dst_stride = dst.cfg.stride;
+    for y in 0..height {
+      let src_row = &src.data_origin()[y * src.cfg.stride..];
+      let dst_row = &mut dst.data_origin_mut()[y * dst_stride..];
+      for (x, d) in dst_row[..up_w as usize].iter_mut().enumerate() {
+        let src_x =
+          -(1 << SUPERRES_SCALE_BITS) + initial_subpel + x as i32 * step;
+        let px = (src_x >> SUPERRES_SCALE_BITS) as isize;
+        let filter = &UPSCALE_FILTER
+          [((src_x & SUPERRES_SCALE_MASK) >> SUPERRES_EXTRA_BITS) as usize];
+        let sum: i32 = filter
+          .iter()
+          .enumerate()
+          .map(|(k, &c)| {
+            let sx =
+              (px + k as isize - SUPERRES_FILTER_OFFSET).max(0).min(last_x);
+            c * i32::cast_from(src_row[sx as usize])
+          })
+          .sum();
+        *d = T::cast_from(round_shift(sum, 7).max(0).min(max_val));
+      }
+    }
+  }
+  dst
+}
+
+/// Returns the first source sample and the weights of a linear filter
+/// resampling `src_len` samples to each of `dst_len` samples. The filter is
+/// widened when downscaling so that it does not alias.
+fn resample_taps(src_len: usize, dst_len: usize) -> Vec<(isize, Vec<i32>)> {
+  let ratio = src_len as f64 / dst_len as f64;
+  let support = ratio.max(1.0);
+  (0..dst_len)
+    .map(|i| {
+      let center = (i as f64 + 0.5) * ratio - 0.5;
+      let start = (center - support).floor() as isize + 1;
+      let end = (center + support).ceil() as isize;
+      let weights: Vec<f64> = (start..end)
+        .map(|x| (1.0 - (x as f64 - center).abs() / support).max(0.0))
+        .collect();
+      let total: f64 = weights.iter().sum();
+      let mut taps: Vec<i32> = weights
+        .iter()
+        .map(|w| (w / total * (1 << RESAMPLE_BITS) as f64).round() as i32)
+        .collect();
+      // Keep the gain exactly 1 by adjusting the biggest tap.
+      let residual = (1 << RESAMPLE_BITS) - taps.iter().sum::<i32>();
+      let biggest = (0..taps.len()).max_by_key(|&k| taps[k]).unwrap();
+      taps[biggest] += residual;
+      (start, taps)
+    })
+    .collect()
+}
+
+/// Resamples the `src_w`x`src_h` visible area of `src` to the
+/// `dst_w`x`dst_h` visible area of `dst`.
+fn resample_plane<T: Pixel>(
+  src: &Plane<T>, src_w: usize, src_h: usize, dst: &mut Plane<T>,
+  dst_w: usize, dst_h: usize,
+) {
+  let h_taps = resample_taps(src_w, dst_w);
+  let v_taps = resample_taps(src_h, dst_h);
+  let round = 1 << (RESAMPLE_BITS - 1);
+
+  // dynamic allocation: once per plane
+  let mut tmp = vec![0i32; dst_w * src_h];
+  for (y, tmp_row) in tmp.chunks_exact_mut(dst_w).enumerate() {
+    let src_row = &src.data_origin()[y * src.cfg.stride..];
+    for (t, (start, taps)) in tmp_row.iter_mut().zip(h_taps.iter()) {
+      let sum: i32 = taps
+        .iter()
+        .enumerate()
+        .map(|(k, &c)| {
+          let x = (start + k as isize).max(0).min(src_w as isize - 1);
+          c * i32::cast_from(src_row[x as usize])
+        })
+        .sum();
+      *t = (sum + round) >> RESAMPLE_BITS;
+    }
+  }
+
+  let stride = dst.cfg.stride;
+  for (y, (start, taps)) in v_taps.iter().enumerate() {
+    let dst_row = &mut dst.data_origin_mut()[y * stride..y * stride + dst_w];
+    for (x, d) in dst_row.iter_mut().enumerate() {
+      let sum: i32 = taps
+        .iter()
+        .enumerate()
+        .map(|(k, &c)| {
+          let y = (start + k as isize).max(0).min(src_h as isize - 1);
+          c * tmp[y as usize * dst_w + x]
+        })
+        .sum();
+      *d = T::cast_from((sum + round) >> RESAMPLE_BITS);
+    }
+  }
+}
+
+/// Resamples a frame of `src_w`x`src_h` luma samples to a new, padded frame
+/// of `width`x`height`.
+///
+/// This is not normative: it prepares the input of a frame coded at a
+/// reduced size, and the references searched by its motion estimation.
+pub fn resample_frame<T: Pixel>(
+  src: &Frame<T>, src_w: usize, src_h: usize, width: usize, height: usize,
+  cs: ChromaSampling,
+) -> Frame<T> {
+  let planes = if cs == ChromaSampling::Cs400 { 1 } else { 3 };
+  let mut dst = Frame::new(width, height, cs);
+  for p in 0..planes {
+    let PlaneConfig { xdec, ydec, .. } = src.planes[p].cfg;
+    resample_plane(
+      &src.planes[p],
+      (src_w + xdec) >> xdec,
+      (src_h + ydec) >> ydec,
+      &mut dst.planes[p],
+      (width + xdec) >> xdec,
+      (height + ydec) >> ydec,
+    );
+  }
+  dst.pad(width, height, planes);
+  dst
+}
+
+/// Copies the `width`x`height` area at the top-left of `src` to a new,
+/// padded frame of that size.
+pub fn crop_frame<T: Pixel>(
+  src: &Frame<T>, width: usize, height: usize, cs: ChromaSampling,
+) -> Frame<T> {
+  let planes = if cs == ChromaSampling::Cs400 { 1 } else { 3 };
+  let mut dst = Frame::new(width, height, cs);
+  for p in 0..planes {
+    let PlaneConfig { xdec, ydec, .. } = src.planes[p].cfg;
+    let w = (width + xdec) >> xdec;
+    let h = (height + ydec) >> ydec;
+    let dst = &mut dst.planes[p];
+    let dst_stride = dst.cfg.stride;
+    for (y, src_row) in src.planes[p].rows_iter().take(h).enumerate() {
+      dst.data_origin_mut()[y * dst_stride..][..w]
+        .copy_from_slice(&src_row[..w]);
+    }
+  }
+  dst.pad(width, height, planes);
+  dst
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn superres_coded_widths() {
+    assert_eq!(superres_coded_width(200, SUPERRES_NUM), 200);
+    assert_eq!(superres_coded_width(200, 9), 178);
+    assert_eq!(superres_coded_width(200, 12), 133);
+    assert_eq!(superres_coded_width(200, SUPERRES_DENOM_MAX), 100);
+  }
+
+  #[test]
+  fn resample_flat_frame_is_flat() {
+    let mut frame = Frame::<u8>::new(64, 32, ChromaSampling::Cs420);
+    for plane in frame.planes.iter_mut() {
+      for p in plane.data.iter_mut() {
+        *p = 77;
+      }
+    }
+    let out = resample_frame(&frame, 64, 32, 37, 32, ChromaSampling::Cs420);
+    for plane in out.planes.iter() {
+      let PlaneConfig { xdec, ydec, .. } = plane.cfg;
+      for row in plane.rows_iter().take((32 + ydec) >> ydec) {
+        assert!(row[..(37 + xdec) >> xdec].iter().all(|&p| p == 77));
+      }
+    }
+  }
+}
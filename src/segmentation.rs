@@ -8,64 +8,405 @@
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
 use crate::context::*;
+use crate::frame::*;
 use crate::header::PRIMARY_REF_NONE;
-use crate::util::Pixel;
+use crate::partition::RefType::*;
+use crate::partition::*;
+use crate::predict::PredictionMode;
+use crate::quantize::ac_q;
+use crate::tiling::*;
+use crate::util::{clamp, CastFromPrimitive, Pixel};
 use crate::FrameInvariants;
 use crate::FrameState;
+use crate::SegmentationState;
+
+use arrayvec::ArrayVec;
+use std::cmp;
+
+// A series of AWCY runs with deltas 13, 15, 17, 18, 19, 20, 21, 22, 23
+// showed this to be the optimal one.
+const TEMPORAL_RDO_QI_DELTA: i16 = 21;
+
+/// Maximum number of segments adapting the quantizer, which leaves room
+/// for the segments of the static blocks.
+const MAX_Q_SEGMENTS: usize = 6;
+
+/// Minimum quantizer index difference between the levels of the segments.
+const MIN_Q_LEVEL_STEP: i16 = 8;
+
+/// Minimum proportion of static blocks, in 1/16ths of the frame, for which
+/// the static segments are worth coding the segment ids before skip.
+const MIN_STATIC_SIXTEENTHS: usize = 2;
+
+/// Groups the quantizer index offsets of the 64x64 blocks of a frame into
+/// the quantizer levels of its segments. Returns the levels, in ascending
+/// order, and the index of the level of each block.
+pub fn segment_q_levels(
+  offsets: &[i16], temporal_rdo: bool,
+) -> (Box<[i16]>, Box<[u8]>) {
+  let min = offsets.iter().copied().min().unwrap_or(0);
+  let max = offsets.iter().copied().max().unwrap_or(0);
+
+  if max - min < MIN_Q_LEVEL_STEP {
+    // With temporal RDO, the segment of each block is chosen between the
+    // quantizer of the frame and the deltas around it.
+    let mid = (min + max) / 2;
+    let (levels, class) = if temporal_rdo {
+      (
+        vec![mid - TEMPORAL_RDO_QI_DELTA, mid, mid + TEMPORAL_RDO_QI_DELTA],
+        1,
+      )
+    } else {
+      (vec![mid], 0)
+    };
+    return (levels.into_boxed_slice(), vec![class; offsets.len()].into());
+  }
+
+  let range = max - min;
+  let step = cmp::max(
+    MIN_Q_LEVEL_STEP,
+    (range + MAX_Q_SEGMENTS as i16 - 2) / (MAX_Q_SEGMENTS as i16 - 1),
+  );
+  let count = (range + step - 1) / step + 1;
+  let levels = (0..count).map(|i| min + i * step).collect();
+  let classes = offsets
+    .iter()
+    .map(|&offset| ((offset - min + step / 2) / step) as u8)
+    .collect();
+  (levels, classes)
+}
+
+/// Segment ids of the blocks of a coded frame, in 4x4 units, from which the
+/// segment ids of the frames using it as primary reference are predicted.
+#[derive(Debug, Clone, Default)]
+pub struct SegmentMap {
+  pub cols: usize,
+  pub rows: usize,
+  ids: Box<[u8]>,
+}
+
+impl SegmentMap {
+  pub fn new(blocks: &FrameBlocks) -> Self {
+    let ids = (0..blocks.rows)
+      .flat_map(|y| blocks[y].iter().map(|block| block.segmentation_idx))
+      .collect();
+    Self { cols: blocks.cols, rows: blocks.rows, ids }
+  }
+
+  /// The lowest segment id of the area of a block, as predicted for the
+  /// block with temporal updates.
+  pub fn predicted_id(&self, bo: PlaneBlockOffset, bsize: BlockSize) -> u8 {
+    let x_end = cmp::min(bo.0.x + bsize.width_mi(), self.cols);
+    let y_end = cmp::min(bo.0.y + bsize.height_mi(), self.rows);
+    (bo.0.y..y_end)
+      .flat_map(|y| self.ids[y * self.cols..][bo.0.x..x_end].iter())
+      .copied()
+      .min()
+      .unwrap_or(0)
+  }
+}
+
+/// The segment map of the primary reference frame, if it has the coded size
+/// of the frame, as the segment ids are otherwise predicted to be 0.
+fn prev_segment_map<T: Pixel>(fi: &FrameInvariants<T>) -> Option<&SegmentMap> {
+  if fi.primary_ref_frame == PRIMARY_REF_NONE {
+    return None;
+  }
+  let ref_frame_idx = fi.ref_frames[fi.primary_ref_frame as usize] as usize;
+  fi.rec_buffer.frames[ref_frame_idx]
+    .as_ref()
+    .map(|rec| &*rec.segment_map)
+    .filter(|map| map.cols == fi.w_in_b && map.rows == fi.h_in_b)
+}
+
+/// The segment id of a block predicted from the primary reference frame,
+/// when the segment ids are coded with temporal updates.
+pub fn temporal_segment_pred<T: Pixel>(
+  fi: &FrameInvariants<T>, ts: &TileStateMut<'_, T>, tile_bo: TileBlockOffset,
+  bsize: BlockSize,
+) -> Option<u8> {
+  if !ts.segmentation.temporal_update {
+    return None;
+  }
+  prev_segment_map(fi)
+    .map(|map| map.predicted_id(ts.to_frame_block_offset(tile_bo), bsize))
+}
+
+/// Classification of the blocks of a frame, from which the candidate
+/// segments of each block are chosen.
+#[derive(Debug, Clone, Default)]
+pub struct SegmentClasses {
+  /// Number of segments adapting the quantizer, from segment 0.
+  pub q_segments: usize,
+  /// Segment of the blocks predicted from LAST_FRAME without motion.
+  pub static_segid: Option<u8>,
+  /// Segment of the skipped blocks predicted from LAST_FRAME without
+  /// motion.
+  pub static_skip_segid: Option<u8>,
+  cols: usize,
+  rows: usize,
+  /// Whether each 8x8 block is static (1), or close enough to LAST_FRAME
+  /// to be skipped (2).
+  motion: Box<[u8]>,
+}
+
+impl SegmentClasses {
+  fn new<T: Pixel>(
+    fi: &FrameInvariants<T>, fs: &FrameState<T>, q_segments: usize,
+  ) -> Self {
+    let mut classes = SegmentClasses { q_segments, ..Default::default() };
+
+    if !fi.frame_type.has_inter()
+      || fi.use_superres()
+      || fi.gm_params[LAST_FRAME.to_index()].wmtype != GlobalMVMode::IDENTITY
+    {
+      return classes;
+    }
+    let last = match &fi.rec_buffer.frames[fi.ref_frames[0] as usize] {
+      Some(rec) if rec.width == fi.width && rec.height == fi.height => rec,
+      _ => return classes,
+    };
+
+    // Compare the blocks with the quantization noise of the frame, which
+    // has the variance of a uniform distribution over the quantizer step,
+    // as the transforms scale the quantizer by 8.
+    let bit_depth = fi.sequence.bit_depth;
+    let q = ac_q(fi.base_q_idx, 0, bit_depth) as u64;
+    let static_sse = q * q / (8 * 8 * 12);
+    let skip_sse = static_sse / 4;
+
+    let (cols, rows) = ((fi.width + 7) >> 3, (fi.height + 7) >> 3);
+    let input = &fs.input.planes[0];
+    let rec = &last.frame.planes[0];
+    let motion: Box<[u8]> = (0..rows)
+      .flat_map(|y| (0..cols).map(move |x| (x << 3, y << 3)))
+      .map(|(x, y)| {
+        let w = cmp::min(8, fi.width - x);
+        let h = cmp::min(8, fi.height - y);
+        let po = PlaneOffset { x: x as isize, y: y as isize };
+        let sse: u64 = input
+          .slice(po)
+          .rows_iter()
+          .zip(rec.slice(po).rows_iter())
+          .take(h)
+          .flat_map(|(a, b)| a[..w].iter().zip(b[..w].iter()))
+          .map(|(&a, &b)| {
+            let d = i32::cast_from(a) - i32::cast_from(b);
+            (d * d) as u64
+          })
+          .sum();
+        // Normalize the edge blocks to 8x8.
+        let sse = sse * 64 / (w * h) as u64;
+        if sse <= skip_sse * 64 {
+          2
+        } else if sse <= static_sse * 64 {
+          1
+        } else {
+          0
+        }
+      })
+      .collect();
+
+    let static_count = motion.iter().filter(|&&m| m > 0).count();
+    if static_count * 16 < motion.len() * MIN_STATIC_SIXTEENTHS {
+      return classes;
+    }
+
+    classes.static_segid = Some(q_segments as u8);
+    if motion.iter().any(|&m| m == 2) {
+      classes.static_skip_segid = Some(q_segments as u8 + 1);
+    }
+    classes.cols = cols;
+    classes.rows = rows;
+    classes.motion = motion;
+    classes
+  }
+
+  /// Lowest motion class of the 8x8 blocks covered by a block.
+  fn motion_of(&self, frame_bo: PlaneBlockOffset, bsize: BlockSize) -> u8 {
+    if self.motion.is_empty() {
+      return 0;
+    }
+    let x = frame_bo.0.x >> 1;
+    let y = frame_bo.0.y >> 1;
+    let x_end = cmp::min((frame_bo.0.x + bsize.width_mi() + 1) >> 1, self.cols);
+    let y_end = cmp::min((frame_bo.0.y + bsize.height_mi() + 1) >> 1, self.rows);
+    (y..y_end)
+      .flat_map(|y| self.motion[y * self.cols..][x..x_end].iter())
+      .copied()
+      .min()
+      .unwrap_or(0)
+  }
+
+  /// Whether a block may be coded in the static segments, to be searched
+  /// with GLOBALMV from LAST_FRAME.
+  pub fn is_static<T: Pixel>(
+    &self, fi: &FrameInvariants<T>, frame_bo: PlaneBlockOffset,
+    bsize: BlockSize,
+  ) -> bool {
+    self.static_segid.is_some()
+      && fi.frame_type.has_inter()
+      && bsize.width() >= 8
+      && bsize.height() >= 8
+      && self.motion_of(frame_bo, bsize) > 0
+  }
+
+  /// The segments to try for a block in RDO: the quantizer level of its
+  /// area and the ones next to it, then the static segments, if the block
+  /// qualifies for them.
+  pub fn candidates<T: Pixel>(
+    &self, fi: &FrameInvariants<T>, frame_bo: PlaneBlockOffset,
+    bsize: BlockSize,
+  ) -> ArrayVec<[u8; 5]> {
+    let mut candidates = ArrayVec::new();
+    let level = cmp::min(fi.segment_q_level(frame_bo), self.q_segments - 1);
+    for sidx in level.saturating_sub(1)..cmp::min(level + 2, self.q_segments)
+    {
+      candidates.push(sidx as u8);
+    }
+    if self.is_static(fi, frame_bo, bsize) {
+      candidates.extend(self.static_segid);
+      if self.motion_of(frame_bo, bsize) > 1 {
+        candidates.extend(self.static_skip_segid);
+      }
+    }
+    candidates
+  }
+}
+
+impl SegmentationState {
+  #[inline]
+  pub fn feature_active(&self, sidx: u8, feature: SegLvl) -> bool {
+    self.enabled && self.features[sidx as usize][feature as usize]
+  }
+
+  /// The reference frame implied by the features of a segment, if any.
+  pub fn implied_ref_frame(&self, sidx: u8) -> Option<RefType> {
+    if self.feature_active(sidx, SegLvl::SEG_LVL_REF_FRAME) {
+      let data = self.data[sidx as usize][SegLvl::SEG_LVL_REF_FRAME as usize];
+      let ref_frame = ALL_INTER_REFS.iter().find(|&&r| r as i16 == data);
+      Some(ref_frame.copied().unwrap_or(INTRA_FRAME))
+    } else if self.feature_active(sidx, SegLvl::SEG_LVL_SKIP)
+      || self.feature_active(sidx, SegLvl::SEG_LVL_GLOBALMV)
+    {
+      Some(LAST_FRAME)
+    } else {
+      None
+    }
+  }
+
+  /// Whether the features of a segment allow coding a block with the given
+  /// modes.
+  pub fn allows(
+    &self, sidx: u8, bsize: BlockSize, luma_mode: PredictionMode,
+    ref_frames: [RefType; 2], skip: bool,
+  ) -> bool {
+    let global_mv = self.feature_active(sidx, SegLvl::SEG_LVL_SKIP)
+      || self.feature_active(sidx, SegLvl::SEG_LVL_GLOBALMV);
+    if global_mv
+      && (luma_mode != PredictionMode::GLOBALMV
+        || bsize.width() < 8
+        || bsize.height() < 8)
+    {
+      return false;
+    }
+    if self.feature_active(sidx, SegLvl::SEG_LVL_SKIP)
+      && !(skip && self.preskip)
+    {
+      return false;
+    }
+    match self.implied_ref_frame(sidx) {
+      Some(INTRA_FRAME) => ref_frames[0] == INTRA_FRAME,
+      Some(r) => ref_frames == [r, NONE_FRAME],
+      None => true,
+    }
+  }
+
+  /// Loop filter level deltas of the segments, from their ALT_LF features.
+  pub fn deblock_deltas(&self) -> [[i8; FRAME_LF_COUNT]; 8] {
+    let mut deltas = [[0; FRAME_LF_COUNT]; 8];
+    if self.enabled {
+      for (i, deltas) in deltas.iter_mut().enumerate() {
+        for (j, delta) in deltas.iter_mut().enumerate() {
+          let feature = SegLvl::SEG_LVL_ALT_LF_Y_V as usize + j;
+          if self.features[i][feature] {
+            *delta = self.data[i][feature] as i8;
+          }
+        }
+      }
+    }
+    deltas
+  }
+}
 
 pub fn segmentation_optimize<T: Pixel>(
   fi: &FrameInvariants<T>, fs: &mut FrameState<T>,
 ) {
   assert!(fi.enable_segmentation);
-  fs.segmentation.enabled = true;
+  let inherited = fs.segmentation;
+  let mut segmentation = SegmentationState {
+    enabled: true,
+    update_map: true,
+    ..Default::default()
+  };
 
-  if fs.segmentation.enabled {
-    fs.segmentation.update_map = true;
+  // Avoid going into lossless mode by never bringing qidx below 1.
+  let offset_lower_limit = 1 - fi.base_q_idx as i16;
+  let offset_upper_limit = 255 - fi.base_q_idx as i16;
+  let q_levels = &fi.segment_q_levels;
 
-    // We don't change the values between frames.
-    fs.segmentation.update_data = fi.primary_ref_frame == PRIMARY_REF_NONE;
-
-    if !fs.segmentation.update_data {
-      return;
+  // Each level of quantizer has its matching loop filter level, unless the
+  // loop filter cannot be adapted with intra block copy.
+  for (i, &level) in q_levels.iter().enumerate() {
+    let delta = clamp(level, offset_lower_limit, offset_upper_limit);
+    segmentation.features[i][SegLvl::SEG_LVL_ALT_Q as usize] = true;
+    segmentation.data[i][SegLvl::SEG_LVL_ALT_Q as usize] = delta;
+    let lf_delta =
+      clamp(delta / 4, -(MAX_LOOP_FILTER as i16), MAX_LOOP_FILTER as i16);
+    if lf_delta != 0 && !fi.allow_intrabc {
+      for j in SegLvl::SEG_LVL_ALT_LF_Y_V as usize
+        ..=SegLvl::SEG_LVL_ALT_LF_V as usize
+      {
+        segmentation.features[i][j] = true;
+        segmentation.data[i][j] = lf_delta;
+      }
     }
+  }
 
-    // A series of AWCY runs with deltas 13, 15, 17, 18, 19, 20, 21, 22, 23
-    // showed this to be the optimal one.
-    const TEMPORAL_RDO_QI_DELTA: i16 = 21;
-
-    // Avoid going into lossless mode by never bringing qidx below 1.
-    // Because base_q_idx changes more frequently than the segmentation
-    // data, it is still possible for a segment to enter lossless, so
-    // enforcement elsewhere is needed.
-    let offset_lower_limit = 1 - fi.base_q_idx as i16;
-
-    // Fill in 3 slots with 0, delta, -delta. The slot IDs are also used in
-    // luma_chroma_mode_rdo() so if you change things here make sure to check
-    // that place too.
-    for i in 0..3 {
-      fs.segmentation.features[i][SegLvl::SEG_LVL_ALT_Q as usize] = true;
-      fs.segmentation.data[i][SegLvl::SEG_LVL_ALT_Q as usize] = match i {
-        0 => 0,
-        1 => TEMPORAL_RDO_QI_DELTA,
-        2 => (-TEMPORAL_RDO_QI_DELTA).max(offset_lower_limit),
-        _ => unreachable!(),
-      };
-    }
+  let classes = SegmentClasses::new(fi, fs, q_levels.len());
+  if let Some(sidx) = classes.static_segid {
+    let features = &mut segmentation.features[sidx as usize];
+    features[SegLvl::SEG_LVL_REF_FRAME as usize] = true;
+    features[SegLvl::SEG_LVL_GLOBALMV as usize] = true;
+    segmentation.data[sidx as usize][SegLvl::SEG_LVL_REF_FRAME as usize] =
+      LAST_FRAME as i16;
+  }
+  if let Some(sidx) = classes.static_skip_segid {
+    let features = &mut segmentation.features[sidx as usize];
+    features[SegLvl::SEG_LVL_REF_FRAME as usize] = true;
+    features[SegLvl::SEG_LVL_SKIP as usize] = true;
+    segmentation.data[sidx as usize][SegLvl::SEG_LVL_REF_FRAME as usize] =
+      LAST_FRAME as i16;
+  }
 
-    /* Figure out parameters */
-    fs.segmentation.preskip = false;
-    fs.segmentation.last_active_segid = 0;
-    if fs.segmentation.enabled {
-      for i in 0..8 {
-        for j in 0..SegLvl::SEG_LVL_MAX as usize {
-          if fs.segmentation.features[i][j] {
-            fs.segmentation.last_active_segid = i as u8;
-            if j >= SegLvl::SEG_LVL_REF_FRAME as usize {
-              fs.segmentation.preskip = true;
-            }
-          }
+  /* Figure out parameters */
+  for i in 0..8 {
+    for j in 0..SegLvl::SEG_LVL_MAX as usize {
+      if segmentation.features[i][j] {
+        segmentation.last_active_segid = i as u8;
+        if j >= SegLvl::SEG_LVL_REF_FRAME as usize {
+          segmentation.preskip = true;
         }
       }
     }
   }
+
+  // The data of the primary reference frame is kept when it is unchanged.
+  segmentation.update_data = fi.primary_ref_frame == PRIMARY_REF_NONE
+    || inherited.features != segmentation.features
+    || inherited.data != segmentation.data;
+  segmentation.temporal_update = prev_segment_map(fi).is_some();
+
+  fs.segmentation = segmentation;
+  fs.segment_classes = classes;
 }
@@ -52,8 +52,8 @@ impl<T: Pixel> TestDecoder<T> for AomDecoder<T> {
   }
 
   fn decode_packet(
-    &mut self, packet: &[u8], rec_fifo: &mut VecDeque<Frame<T>>, w: usize,
-    h: usize, chroma_sampling: ChromaSampling, bit_depth: usize,
+    &mut self, packet: &[u8], rec_fifo: &mut VecDeque<Frame<T>>,
+    chroma_sampling: ChromaSampling, bit_depth: usize,
   ) -> DecodeResult {
     let mut corrupted_count = 0;
     unsafe {
@@ -105,6 +105,8 @@ impl<T: Pixel> TestDecoder<T> for AomDecoder<T> {
           corrupted_count += corrupted;
 
           let rec = rec_fifo.pop_front().unwrap();
+          // Each frame is compared at the size it was coded at.
+          let (w, h) = ((*img).d_w as usize, (*img).d_h as usize);
           compare_img(img, &rec, bit_depth, w, h, chroma_sampling);
         }
       }
@@ -32,7 +32,9 @@ impl<T: Pixel> TestDecoder<T> for Dav1dDecoder<T> {
       dav1d_default_settings(settings.as_mut_ptr());
 
       // Was initialized by dav1d_default_settings().
-      let settings = settings.assume_init();
+      let mut settings = settings.assume_init();
+      // Compare the reconstruction before film grain synthesis.
+      settings.apply_grain = 0;
 
       let mut dec: Dav1dDecoder<T> =
         Dav1dDecoder { dec: ptr::null_mut(), pixel: PhantomData };
@@ -47,8 +49,8 @@ impl<T: Pixel> TestDecoder<T> for Dav1dDecoder<T> {
   }
 
   fn decode_packet(
-    &mut self, packet: &[u8], rec_fifo: &mut VecDeque<Frame<T>>, w: usize,
-    h: usize, chroma_sampling: ChromaSampling, bit_depth: usize,
+    &mut self, packet: &[u8], rec_fifo: &mut VecDeque<Frame<T>>,
+    chroma_sampling: ChromaSampling, bit_depth: usize,
   ) -> DecodeResult {
     let mut corrupted_count = 0;
     let mut data = SafeDav1dData::new(packet);
@@ -72,6 +74,8 @@ impl<T: Pixel> TestDecoder<T> for Dav1dDecoder<T> {
         }
 
         let rec = rec_fifo.pop_front().unwrap();
+        // Each frame is compared at the size it was coded at.
+        let (w, h) = (pic.0.p.w as usize, pic.0.p.h as usize);
         compare_pic(&pic.0, &rec, bit_depth, w, h, chroma_sampling);
       }
     }
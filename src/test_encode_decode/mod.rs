@@ -10,7 +10,10 @@
 // Fuzzing only uses a subset of these.
 #![cfg_attr(fuzzing, allow(unused))]
 
+use crate::api::InterpFilterSearch;
 use crate::color::ChromaSampling;
+use crate::encoder::{SuperresMode, Tune};
+use crate::frame::FrameParameters;
 use crate::util::Pixel;
 use crate::*;
 use interpolate_name::interpolate_test;
@@ -41,13 +44,15 @@ fn fill_frame<T: Pixel>(ra: &mut ChaChaRng, frame: &mut Frame<T>) {
 }
 
 fn read_frame_batch<T: Pixel>(
-  ctx: &mut Context<T>, ra: &mut ChaChaRng, limit: usize,
+  ctx: &mut Context<T>, ra: &mut ChaChaRng, frame_sizes: &[(usize, usize)],
 ) {
-  for _ in 0..limit {
+  for &frame_size in frame_sizes {
     let mut input = ctx.new_frame();
     fill_frame(ra, &mut input);
 
-    let _ = ctx.send_frame(input);
+    let params =
+      FrameParameters { frame_size: Some(frame_size), ..Default::default() };
+    let _ = ctx.send_frame((input, params));
   }
 
   ctx.flush();
@@ -70,9 +75,7 @@ pub(crate) trait TestDecoder<T: Pixel> {
     low_latency: bool, error_resilient: bool, bitrate: i32,
     tile_cols_log2: usize, tile_rows_log2: usize, still_picture: bool,
   ) {
-    let mut ra = ChaChaRng::from_seed([0; 32]);
-
-    let mut ctx: Context<T> = setup_encoder(
+    let enc = encoder_config(
       w,
       h,
       speed,
@@ -90,21 +93,39 @@ pub(crate) trait TestDecoder<T: Pixel> {
       still_picture,
     );
 
+    self.encode_decode_config(enc, limit);
+  }
+  fn encode_decode_config(&mut self, enc: EncoderConfig, limit: usize) {
+    let frame_sizes = vec![(enc.width, enc.height); limit];
+    self.encode_decode_frame_sizes(enc, &frame_sizes);
+  }
+  fn encode_decode_frame_sizes(
+    &mut self, enc: EncoderConfig, frame_sizes: &[(usize, usize)],
+  ) {
+    let limit = frame_sizes.len();
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+
+    let (w, h) = (enc.width, enc.height);
+    let (bit_depth, chroma_sampling) = (enc.bit_depth, enc.chroma_sampling);
     debug!(
-      "Encoding {}x{} speed {} quantizer {} bit-depth {} bitrate {}",
-      w, h, speed, quantizer, bit_depth, bitrate
+      "Encoding {}x{} quantizer {} bit-depth {} bitrate {}",
+      w, h, enc.quantizer, bit_depth, enc.bitrate
     );
     #[cfg(feature = "dump_ivf")]
     let mut out = std::fs::File::create(&format!(
-      "out-{}x{}-s{}-q{}-r{}-{:?}.ivf",
-      w, h, speed, quantizer, bitrate, chroma_sampling
+      "out-{}x{}-q{}-r{}-{:?}.ivf",
+      w, h, enc.quantizer, enc.bitrate, chroma_sampling
     ))
     .unwrap();
     #[cfg(feature = "dump_ivf")]
     ivf::write_ivf_header(&mut out, w, h, 30, 1);
 
+    assert!(bit_depth == 8 || std::mem::size_of::<T>() > 1);
+    let cfg = Config::new().with_encoder_config(enc);
+    let mut ctx: Context<T> = cfg.new_context().unwrap();
+
     let mut rec_fifo = VecDeque::new();
-    read_frame_batch(&mut ctx, &mut ra, limit);
+    read_frame_batch(&mut ctx, &mut ra, frame_sizes);
 
     for _ in 0..limit {
       let mut corrupted_count = 0;
@@ -124,8 +145,6 @@ pub(crate) trait TestDecoder<T: Pixel> {
           match self.decode_packet(
             &packet,
             &mut rec_fifo,
-            w,
-            h,
             chroma_sampling,
             bit_depth,
           ) {
@@ -145,8 +164,8 @@ pub(crate) trait TestDecoder<T: Pixel> {
     }
   }
   fn decode_packet(
-    &mut self, packet: &[u8], rec_fifo: &mut VecDeque<Frame<T>>, w: usize,
-    h: usize, chroma_sampling: ChromaSampling, bit_depth: usize,
+    &mut self, packet: &[u8], rec_fifo: &mut VecDeque<Frame<T>>,
+    chroma_sampling: ChromaSampling, bit_depth: usize,
   ) -> DecodeResult;
 }
 
@@ -167,14 +186,13 @@ pub fn compare_plane<T: Ord + std::fmt::Debug>(
   }
 }
 
-fn setup_encoder<T: Pixel>(
+fn encoder_config(
   w: usize, h: usize, speed: usize, quantizer: usize, bit_depth: usize,
   chroma_sampling: ChromaSampling, min_keyint: u64, max_keyint: u64,
   switch_frame_interval: u64, low_latency: bool, error_resilient: bool,
   bitrate: i32, tile_cols_log2: usize, tile_rows_log2: usize,
   still_picture: bool,
-) -> Context<T> {
-  assert!(bit_depth == 8 || std::mem::size_of::<T>() > 1);
+) -> EncoderConfig {
   let mut enc = EncoderConfig::with_speed_preset(speed);
   enc.quantizer = quantizer;
   enc.min_key_frame_interval = min_keyint;
@@ -191,9 +209,25 @@ fn setup_encoder<T: Pixel>(
   enc.tile_rows = 1 << tile_rows_log2;
   enc.still_picture = still_picture;
 
-  let cfg = Config::new().with_encoder_config(enc);
+  enc
+}
 
-  cfg.new_context().unwrap()
+/// Encodes and decodes frames of the given sizes, the first of which is the
+/// configured size, at a speed preset with a key frame every 15 frames and
+/// the feature under test set up by `setup`.
+fn encode_decode_feature<F: FnOnce(&mut EncoderConfig)>(
+  decoder: &str, speed: usize, frame_sizes: &[(usize, usize)], setup: F,
+) {
+  let (w, h) = frame_sizes[0];
+  let mut enc = EncoderConfig::with_speed_preset(speed);
+  enc.width = w;
+  enc.height = h;
+  enc.min_key_frame_interval = 15;
+  enc.max_key_frame_interval = 15;
+  setup(&mut enc);
+
+  let mut dec = get_decoder::<u8>(decoder, w, h);
+  dec.encode_decode_frame_sizes(enc, frame_sizes);
 }
 
 // TODO: support non-multiple-of-16 dimensions
@@ -724,6 +758,38 @@ macro_rules! test_chroma_sampling {
 test_chroma_sampling! {(400, ChromaSampling::Cs400), (420, ChromaSampling::Cs420),
 (422, ChromaSampling::Cs422), (444, ChromaSampling::Cs444)}
 
+fn lossless<T: Pixel>(decoder: &str, depth: usize, cs: ChromaSampling) {
+  let quantizer = 0;
+  let limit = 3; // Include inter frames
+  let speed = 10;
+  let w = 64;
+  let h = 80;
+
+  let mut dec = get_decoder::<T>(decoder, w as usize, h as usize);
+  dec.encode_decode(
+    w, h, speed, quantizer, limit, depth, cs, 15, 15, 0, true, false, 0, 0, 0,
+    false,
+  );
+}
+
+macro_rules! test_lossless {
+  ($(($T:ty, $B:expr, $S:expr, $I:expr)),+) => {
+    $(
+      paste::item!{
+        #[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+        #[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+        fn [<lossless_ $B bit_ $S>](decoder: &str) {
+          lossless::<$T>(decoder, $B, $I);
+        }
+      }
+    )*
+  }
+}
+
+test_lossless! {(u8, 8, 400, ChromaSampling::Cs400),
+(u8, 8, 420, ChromaSampling::Cs420), (u16, 10, 422, ChromaSampling::Cs422),
+(u16, 12, 444, ChromaSampling::Cs444)}
+
 #[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
 #[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
 fn tile_encoding_with_stretched_restoration_units(decoder: &str) {
@@ -859,3 +925,222 @@ fn rdo_loop_decision_cdef_sanity(decoder: &str) {
     false,
   );
 }
+
+// The aom decoder always applies the synthesized grain, so the output can
+// only be compared against the reconstruction with dav1d.
+#[cfg(feature = "decode_test_dav1d")]
+#[test]
+fn film_grain() {
+  encode_decode_feature("dav1d", 10, &[(64, 80); 5], |enc| {
+    enc.low_latency = true;
+    enc.film_grain = 20;
+  });
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn screen_content(decoder: &str) {
+  encode_decode_feature(decoder, 10, &[(64, 80); 5], |enc| {
+    enc.low_latency = true;
+    enc.tune = Tune::ScreenContent;
+  });
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn intrabc(decoder: &str) {
+  encode_decode_feature(decoder, 10, &[(64, 80); 5], |enc| {
+    enc.min_key_frame_interval = 2;
+    enc.max_key_frame_interval = 2;
+    enc.low_latency = true;
+    enc.tune = Tune::ScreenContent;
+    enc.intrabc = true;
+  });
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn filter_intra(decoder: &str) {
+  encode_decode_feature(decoder, 4, &[(64, 80); 5], |enc| {
+    assert!(enc.speed_settings.filter_intra);
+    enc.min_key_frame_interval = 2;
+    enc.max_key_frame_interval = 2;
+    enc.low_latency = true;
+  });
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn warped_motion(decoder: &str) {
+  encode_decode_feature(decoder, 3, &[(64, 80); 5], |enc| {
+    assert!(enc.speed_settings.global_motion);
+    assert!(enc.speed_settings.warped_motion);
+    enc.low_latency = true;
+  });
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn interp_filter_search(decoder: &str) {
+  for &(speed, search) in
+    [(3, InterpFilterSearch::Switchable), (1, InterpFilterSearch::Dual)].iter()
+  {
+    for &low_latency in [false, true].iter() {
+      encode_decode_feature(decoder, speed, &[(64, 80); 5], |enc| {
+        assert_eq!(enc.speed_settings.interp_filter_search, search);
+        enc.low_latency = low_latency;
+      });
+    }
+  }
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn reorder_pyramid_depth(decoder: &str) {
+  for pyramid_depth in 0..=4 {
+    encode_decode_feature(decoder, 10, &[(64, 80); 20], |enc| {
+      enc.pyramid_depth = pyramid_depth;
+    });
+  }
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn adaptive_pyramid_depth(decoder: &str) {
+  encode_decode_feature(decoder, 10, &[(64, 80); 20], |enc| {
+    enc.pyramid_depth = 4;
+    enc.adaptive_pyramid_depth = true;
+  });
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn buffer_model(decoder: &str) {
+  for &bitrate in [0, 50_000].iter() {
+    encode_decode_feature(decoder, 10, &[(64, 80); 20], |enc| {
+      enc.quantizer = if bitrate > 0 { 255 } else { 20 };
+      enc.bitrate = bitrate;
+      enc.max_bitrate = 100_000;
+      enc.buffer_size = 100_000;
+    });
+  }
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn superblock_128(decoder: &str) {
+  for &speed in [1, 9].iter() {
+    encode_decode_feature(decoder, speed, &[(200, 136); 5], |enc| {
+      enc.tile_cols = 2;
+      enc.superblock_128 = true;
+    });
+  }
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn ext_partition_types(decoder: &str) {
+  encode_decode_feature(decoder, 1, &[(64, 80); 5], |enc| {
+    assert!(enc.speed_settings.ext_partition_types);
+  });
+  // No preset searches them top-down
+  encode_decode_feature(decoder, 6, &[(64, 80); 5], |enc| {
+    enc.speed_settings.ext_partition_types = true;
+  });
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn superres(decoder: &str) {
+  for &(mode, denom) in [
+    (SuperresMode::Fixed, 9),
+    (SuperresMode::Fixed, 16),
+    (SuperresMode::Random, 16),
+    (SuperresMode::Qthresh, 16),
+  ]
+  .iter()
+  {
+    encode_decode_feature(decoder, 6, &[(200, 136); 5], |enc| {
+      enc.superres_mode = mode;
+      enc.superres_denom = denom;
+      enc.superres_qthresh = 0;
+    });
+  }
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn frame_size_switch(decoder: &str) {
+  let frame_sizes =
+    [(200, 136), (152, 104), (100, 68), (100, 68), (200, 136), (128, 120)];
+
+  for &(low_latency, superres_mode) in [
+    (true, SuperresMode::None),
+    (false, SuperresMode::None),
+    (true, SuperresMode::Random),
+  ]
+  .iter()
+  {
+    encode_decode_feature(decoder, 10, &frame_sizes, |enc| {
+      enc.low_latency = low_latency;
+      enc.superres_mode = superres_mode;
+    });
+  }
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn delta_q(decoder: &str) {
+  for &(speed, superblock_128) in [(1, false), (10, false), (10, true)].iter()
+  {
+    encode_decode_feature(decoder, speed, &[(200, 136); 5], |enc| {
+      enc.superblock_128 = superblock_128;
+      enc.delta_q = true;
+    });
+  }
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn segmentation(decoder: &str) {
+  for &low_latency in [false, true].iter() {
+    encode_decode_feature(decoder, 0, &[(64, 80); 5], |enc| {
+      assert!(enc.speed_settings.enable_segmentation);
+      enc.low_latency = low_latency;
+    });
+  }
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn rdoq(decoder: &str) {
+  // Weighed with temporal RDO as set up by the CLI
+  for &quantizer in [20, 100, 200].iter() {
+    encode_decode_feature(decoder, 2, &[(64, 80); 5], |enc| {
+      assert!(enc.speed_settings.rdoq);
+      enc.quantizer = quantizer;
+      enc.tune = Tune::Psychovisual;
+      enc.speed_settings.tx_domain_distortion = false;
+    });
+  }
+}
+
+#[cfg_attr(feature = "decode_test", interpolate_test(aom, "aom"))]
+#[cfg_attr(feature = "decode_test_dav1d", interpolate_test(dav1d, "dav1d"))]
+fn quant_matrices(decoder: &str) {
+  // Speed 1 codes rectangular transforms of both orientations, and 4:2:2
+  // rectangular chroma transforms
+  for &(speed, quantizer, chroma_sampling) in [
+    (1, 100, ChromaSampling::Cs420),
+    (10, 20, ChromaSampling::Cs422),
+    (10, 200, ChromaSampling::Cs400),
+  ]
+  .iter()
+  {
+    encode_decode_feature(decoder, speed, &[(64, 80); 5], |enc| {
+      enc.quantizer = quantizer;
+      enc.chroma_sampling = chroma_sampling;
+      enc.quant_matrices = true;
+    });
+  }
+}
@@ -8,9 +8,10 @@
 // PATENTS file, you can obtain it at www.aomedia.org/license/patent.
 
 use crate::context::*;
-use crate::mc::MotionVector;
+use crate::mc::{FilterMode, MotionVector};
+use crate::palette::PaletteParams;
 use crate::partition::*;
-use crate::predict::PredictionMode;
+use crate::predict::{FilterIntraMode, MotionMode, PredictionMode};
 use crate::transform::*;
 
 use std::cmp;
@@ -229,6 +230,13 @@ impl TileBlocksMut<'_> {
     self.for_each(bo, bsize, |block| block.mode = mode);
   }
 
+  #[inline(always)]
+  pub fn set_partition(
+    &mut self, bo: TileBlockOffset, bsize: BlockSize, partition: PartitionType,
+  ) {
+    self.for_each(bo, bsize, |block| block.partition = partition);
+  }
+
   #[inline(always)]
   pub fn set_block_size(&mut self, bo: TileBlockOffset, bsize: BlockSize) {
     let n4_w = bsize.width_mi() as u8;
@@ -261,6 +269,44 @@ impl TileBlocksMut<'_> {
     self.for_each(bo, bsize, |block| block.segmentation_idx = idx);
   }
 
+  #[inline(always)]
+  pub fn set_deblock_deltas(
+    &mut self, bo: TileBlockOffset, bsize: BlockSize,
+    deltas: [i8; FRAME_LF_COUNT],
+  ) {
+    self.for_each(bo, bsize, |block| block.deblock_deltas = deltas);
+  }
+
+  #[inline(always)]
+  pub fn set_palette(
+    &mut self, bo: TileBlockOffset, bsize: BlockSize, palette: PaletteParams,
+  ) {
+    self.for_each(bo, bsize, |block| block.palette = palette);
+  }
+
+  #[inline(always)]
+  pub fn set_filter_intra_mode(
+    &mut self, bo: TileBlockOffset, bsize: BlockSize,
+    mode: Option<FilterIntraMode>,
+  ) {
+    self.for_each(bo, bsize, |block| block.filter_intra_mode = mode);
+  }
+
+  #[inline(always)]
+  pub fn set_motion_mode(
+    &mut self, bo: TileBlockOffset, bsize: BlockSize, mode: MotionMode,
+  ) {
+    self.for_each(bo, bsize, |block| block.motion_mode = mode);
+  }
+
+  #[inline(always)]
+  pub fn set_interp_filter(
+    &mut self, bo: TileBlockOffset, bsize: BlockSize,
+    interp_filter: [FilterMode; 2],
+  ) {
+    self.for_each(bo, bsize, |block| block.interp_filter = interp_filter);
+  }
+
   #[inline(always)]
   pub fn set_ref_frames(
     &mut self, bo: TileBlockOffset, bsize: BlockSize, r: [RefType; 2],
@@ -278,7 +324,6 @@ impl TileBlocksMut<'_> {
   #[inline(always)]
   pub fn set_cdef(&mut self, sbo: TileSuperBlockOffset, cdef_index: u8) {
     let bo = sbo.block_offset(0, 0).0;
-    // Checkme: Is 16 still the right block unit for 128x128 superblocks?
     let bw = cmp::min(bo.x + MIB_SIZE, self.cols);
     let bh = cmp::min(bo.y + MIB_SIZE, self.rows);
     for y in bo.y..bh {
@@ -12,12 +12,14 @@ use super::*;
 use crate::context::*;
 use crate::encoder::*;
 use crate::frame::*;
+use crate::intrabc::BlockHashes;
 use crate::lrf::{IntegralImageBuffer, SOLVE_IMAGE_SIZE};
 use crate::mc::MotionVector;
 use crate::partition::{RefType, REF_FRAMES};
 use crate::predict::{InterCompoundBuffers, PredictionMode};
 use crate::quantize::*;
 use crate::rdo::*;
+use crate::segmentation::SegmentClasses;
 use crate::stats::EncoderStats;
 use crate::util::*;
 use std::ops::{Index, IndexMut};
@@ -45,8 +47,11 @@ use std::sync::Arc;
 /// frame-wise once the tile views vanish (e.g. for deblocking).
 #[derive(Debug)]
 pub struct TileStateMut<'a, T: Pixel> {
+  /// Offset of the tile, in 64x64 superblocks.
   pub sbo: PlaneSuperBlockOffset,
+  /// Log2 of the coded superblock size.
   pub sb_size_log2: usize,
+  /// Size of the tile, in 64x64 superblocks.
   pub sb_width: usize,
   pub sb_height: usize,
   pub mi_width: usize,
@@ -61,9 +66,11 @@ pub struct TileStateMut<'a, T: Pixel> {
   pub rec: TileMut<'a, T>,
   pub qc: QuantizationContext,
   pub segmentation: &'a SegmentationState,
+  pub segment_classes: &'a SegmentClasses,
   pub restoration: TileRestorationStateMut<'a>,
   pub half_res_pmvs: &'a mut Vec<BlockPmv>,
   pub mvs: Vec<TileMotionVectorsMut<'a>>,
+  pub block_hashes: Option<&'a BlockHashes>,
   pub coded_block_info: MiTileState,
   pub integral_buffer: IntegralImageBuffer,
   pub inter_compound_buffers: InterCompoundBuffers,
@@ -137,17 +144,20 @@ impl<'a, T: Pixel> TileStateMut<'a, T> {
       "Tile width must be a multiple of MI_SIZE"
     );
 
-    let sb_rounded_width = width.align_power_of_two(sb_size_log2);
-    let sb_rounded_height = height.align_power_of_two(sb_size_log2);
+    let sb_rounded_width = width.align_power_of_two(SUPERBLOCK_TO_PLANE_SHIFT);
+    let sb_rounded_height =
+      height.align_power_of_two(SUPERBLOCK_TO_PLANE_SHIFT);
 
     let luma_rect = TileRect {
-      x: sbo.0.x << sb_size_log2,
-      y: sbo.0.y << sb_size_log2,
+      x: sbo.0.x << SUPERBLOCK_TO_PLANE_SHIFT,
+      y: sbo.0.y << SUPERBLOCK_TO_PLANE_SHIFT,
       width: sb_rounded_width,
       height: sb_rounded_height,
     };
-    let sb_width = width.align_power_of_two_and_shift(sb_size_log2);
-    let sb_height = height.align_power_of_two_and_shift(sb_size_log2);
+    let sb_width =
+      width.align_power_of_two_and_shift(SUPERBLOCK_TO_PLANE_SHIFT);
+    let sb_height =
+      height.align_power_of_two_and_shift(SUPERBLOCK_TO_PLANE_SHIFT);
     if !fs.half_res_pmvs.iter().any(|&(key, _)| key == sbo) {
       // Initialize a blank array in the slot for this tile in the FrameState.
       // This will immediately be overridden with the half_res_pmvs
@@ -172,6 +182,7 @@ impl<'a, T: Pixel> TileStateMut<'a, T> {
       rec: TileMut::new(Arc::make_mut(&mut fs.rec), luma_rect),
       qc: Default::default(),
       segmentation: &fs.segmentation,
+      segment_classes: &fs.segment_classes,
       restoration: TileRestorationStateMut::new(
         &mut fs.restoration,
         sbo,
@@ -189,13 +200,14 @@ impl<'a, T: Pixel> TileStateMut<'a, T> {
         .map(|fmvs| {
           TileMotionVectorsMut::new(
             fmvs,
-            sbo.0.x << (sb_size_log2 - MI_SIZE_LOG2),
-            sbo.0.y << (sb_size_log2 - MI_SIZE_LOG2),
+            sbo.0.x << SUPERBLOCK_TO_BLOCK_SHIFT,
+            sbo.0.y << SUPERBLOCK_TO_BLOCK_SHIFT,
             width >> MI_SIZE_LOG2,
             height >> MI_SIZE_LOG2,
           )
         })
         .collect(),
+      block_hashes: fs.block_hashes.as_ref(),
       coded_block_info: MiTileState::new(
         width >> MI_SIZE_LOG2,
         height >> MI_SIZE_LOG2,
@@ -209,8 +221,8 @@ impl<'a, T: Pixel> TileStateMut<'a, T> {
   #[inline(always)]
   pub fn tile_rect(&self) -> TileRect {
     TileRect {
-      x: self.sbo.0.x << self.sb_size_log2,
-      y: self.sbo.0.y << self.sb_size_log2,
+      x: self.sbo.0.x << SUPERBLOCK_TO_PLANE_SHIFT,
+      y: self.sbo.0.y << SUPERBLOCK_TO_PLANE_SHIFT,
       width: self.width,
       height: self.height,
     }
@@ -220,8 +232,8 @@ impl<'a, T: Pixel> TileStateMut<'a, T> {
   pub fn to_frame_block_offset(
     &self, tile_bo: TileBlockOffset,
   ) -> PlaneBlockOffset {
-    let bx = self.sbo.0.x << (self.sb_size_log2 - MI_SIZE_LOG2);
-    let by = self.sbo.0.y << (self.sb_size_log2 - MI_SIZE_LOG2);
+    let bx = self.sbo.0.x << SUPERBLOCK_TO_BLOCK_SHIFT;
+    let by = self.sbo.0.y << SUPERBLOCK_TO_BLOCK_SHIFT;
     PlaneBlockOffset(BlockOffset { x: bx + tile_bo.0.x, y: by + tile_bo.0.y })
   }
 
@@ -110,6 +110,11 @@ impl TilingInfo {
       tile_width_sb_pre
     };
 
+    let cols = (frame_width_sb + tile_width_sb - 1) / tile_width_sb;
+    // Widening the tiles may have dropped a tile column, in which case the
+    // bitstream derives the log2 tile column count from the actual count.
+    let tile_cols_log2 = Self::tile_log2(1, cols).unwrap();
+
     let min_tile_rows_log2 = if min_tiles_log2 > tile_cols_log2 {
       min_tiles_log2 - tile_cols_log2
     } else {
@@ -127,7 +132,6 @@ impl TilingInfo {
       .min(max_tile_rows_log2);
     let tile_height_sb = sb_rows.align_power_of_two_and_shift(tile_rows_log2);
 
-    let cols = (frame_width_sb + tile_width_sb - 1) / tile_width_sb;
     let rows = (frame_height_sb + tile_height_sb - 1) / tile_height_sb;
 
     Self {
@@ -200,14 +204,16 @@ impl<'a, 'b, T: Pixel> Iterator for TileContextIterMut<'a, 'b, T> {
       let ctx = TileContextMut {
         ts: {
           let fs = unsafe { &mut *self.fs };
-          let sbo = PlaneSuperBlockOffset(SuperBlockOffset {
-            x: tile_col * self.ti.tile_width_sb,
-            y: tile_row * self.ti.tile_height_sb,
-          });
-          let x = sbo.0.x << self.ti.sb_size_log2;
-          let y = sbo.0.y << self.ti.sb_size_log2;
           let tile_width = self.ti.tile_width_sb << self.ti.sb_size_log2;
           let tile_height = self.ti.tile_height_sb << self.ti.sb_size_log2;
+          let x = tile_col * tile_width;
+          let y = tile_row * tile_height;
+          // The tile state is addressed in 64x64 superblocks, whatever the
+          // coded superblock size.
+          let sbo = PlaneSuperBlockOffset(SuperBlockOffset {
+            x: x >> SUPERBLOCK_TO_PLANE_SHIFT,
+            y: y >> SUPERBLOCK_TO_PLANE_SHIFT,
+          });
           let width = tile_width.min(self.ti.frame_width - x);
           let height = tile_height.min(self.ti.frame_height - y);
           TileStateMut::new(fs, sbo, self.ti.sb_size_log2, width, height)
@@ -327,6 +333,20 @@ pub mod test {
     assert_eq!(16, ti.tile_height_sb);
   }
 
+  #[test]
+  fn test_tiling_info_422_widened_tiles() {
+    // 3 superblocks of 128x128 split in 4 tiles of 1, widened to 2 in 4:2:2
+    let ti = TilingInfo::from_target_tiles(7, 384, 128, 25f64, 2, 0, true);
+    assert_eq!(2, ti.tile_width_sb);
+    assert_eq!(2, ti.cols);
+    assert_eq!(1, ti.tile_cols_log2);
+
+    let ti = TilingInfo::from_target_tiles(7, 384, 128, 25f64, 2, 0, false);
+    assert_eq!(1, ti.tile_width_sb);
+    assert_eq!(3, ti.cols);
+    assert_eq!(2, ti.tile_cols_log2);
+  }
+
   fn create_frame_invariants(
     width: usize, height: usize, chroma_sampling: ChromaSampling,
   ) -> FrameInvariants<u16> {
@@ -184,3 +184,45 @@ pub mod rust {
     }
   }
 }
+
+fn fwht4(x: [i32; 4]) -> [i32; 4] {
+  let [mut a, mut b, mut c, mut d] = x;
+  a += b;
+  d -= c;
+  let e = (a - d) >> 1;
+  b = e - b;
+  c = e - c;
+  a -= c;
+  d += b;
+  [a, c, d, b]
+}
+
+/// Forward 4x4 Walsh-Hadamard transform of lossless blocks.
+///
+/// The output is scaled by the lossless quantizer, 4, so that quantizing
+/// it is exact.
+pub fn forward_wht4x4<T: Coefficient>(
+  input: &[i16], output: &mut [T], stride: usize,
+) {
+  let mut buf = [[0i32; 4]; 4];
+
+  // Columns
+  for c in 0..4 {
+    let col = fwht4([
+      input[c].into(),
+      input[stride + c].into(),
+      input[2 * stride + c].into(),
+      input[3 * stride + c].into(),
+    ]);
+    for r in 0..4 {
+      buf[r][c] = col[r];
+    }
+  }
+
+  // Rows, stored column by column like the other transforms
+  for (r, row) in buf.iter().enumerate() {
+    for (c, &coeff) in fwht4(*row).iter().enumerate() {
+      output[c * 4 + r] = T::cast_from(coeff * 4);
+    }
+  }
+}
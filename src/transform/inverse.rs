@@ -119,6 +119,24 @@ pub fn av1_iadst4(input: &[i32], output: &mut [i32], _range: usize) {
   output[3] = round_shift(x3, bit);
 }
 
+pub fn av1_iwht4(input: &[i32], output: &mut [i32], shift: usize) {
+  let mut a = input[0] >> shift;
+  let mut c = input[1] >> shift;
+  let mut d = input[2] >> shift;
+  let mut b = input[3] >> shift;
+  a += c;
+  d -= b;
+  let e = (a - d) >> 1;
+  b = e - b;
+  c = e - c;
+  a -= b;
+  d += c;
+  output[0] = a;
+  output[1] = b;
+  output[2] = c;
+  output[3] = d;
+}
+
 pub fn av1_iidentity4(input: &[i32], output: &mut [i32], _range: usize) {
   output[..4]
     .iter_mut()
@@ -1664,3 +1682,37 @@ pub(crate) mod rust {
   const INV_INTERMEDIATE_SHIFTS: [usize; TxSize::TX_SIZES_ALL] =
     [0, 1, 2, 2, 2, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2];
 }
+
+/// Inverse 4x4 Walsh-Hadamard transform of lossless blocks, added to the
+/// prediction in `output`.
+pub fn inverse_wht4x4_add<T: Pixel>(
+  input: &[T::Coeff], output: &mut PlaneRegionMut<'_, T>, bd: usize,
+) {
+  let mut buffer = [0i32; 4 * 4];
+
+  // perform inv txfm on every row
+  for (r, buffer_slice) in buffer.chunks_mut(4).enumerate() {
+    let mut temp_in: [i32; 4] = [0; 4];
+    for (c, t) in temp_in.iter_mut().enumerate() {
+      *t = i32::cast_from(input[c * 4 + r]);
+    }
+    av1_iwht4(&temp_in, buffer_slice, 2);
+  }
+
+  // perform inv txfm on every col
+  for c in 0..4 {
+    let mut temp_in: [i32; 4] = [0; 4];
+    let mut temp_out: [i32; 4] = [0; 4];
+    for (raw, t) in buffer[c..].iter().step_by(4).zip(temp_in.iter_mut()) {
+      *t = *raw;
+    }
+    av1_iwht4(&temp_in, &mut temp_out, 0);
+    for (temp, out) in temp_out
+      .iter()
+      .zip(output.rows_iter_mut().map(|row| &mut row[c]).take(4))
+    {
+      let v = i32::cast_from(*out) + *temp;
+      *out = T::cast_from(v.max(0).min((1 << bd) - 1));
+    }
+  }
+}
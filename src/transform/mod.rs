@@ -13,8 +13,8 @@
 #[macro_use]
 pub mod forward_shared;
 
-pub use self::forward::forward_transform;
-pub use self::inverse::inverse_transform_add;
+pub use self::forward::{forward_transform, forward_wht4x4};
+pub use self::inverse::{inverse_transform_add, inverse_wht4x4_add};
 
 use crate::context::MI_SIZE_LOG2;
 use crate::partition::{BlockSize, BlockSize::*};
@@ -567,4 +567,36 @@ mod test {
   fn roundtrips_u16() {
     roundtrips::<u16>();
   }
+
+  fn wht_roundtrip<T: Pixel>(bd: usize) {
+    for _ in 0..100 {
+      let pixels = || {
+        (0..16)
+          .map(|_| T::cast_from(random::<u16>() >> (16 - bd)))
+          .collect::<Vec<T>>()
+      };
+      let src = pixels();
+      let mut dst = Plane::from_slice(&pixels(), 4);
+      let res: Vec<i16> = src
+        .iter()
+        .zip(dst.data.iter())
+        .map(|(&s, &d)| i16::cast_from(s) - i16::cast_from(d))
+        .collect();
+      let mut freq = [T::Coeff::cast_from(0); 16];
+      forward_wht4x4(&res, &mut freq, 4);
+
+      // The lossless quantizer, 4, must divide every coefficient.
+      assert!(freq.iter().all(|&c| i32::cast_from(c) % 4 == 0));
+
+      inverse_wht4x4_add(&freq, &mut dst.as_region_mut(), bd);
+      assert_eq!(&src[..], &dst.data[..16]);
+    }
+  }
+
+  #[test]
+  fn wht_roundtrips() {
+    wht_roundtrip::<u8>(8);
+    wht_roundtrip::<u16>(10);
+    wht_roundtrip::<u16>(12);
+  }
 }
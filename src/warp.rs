@@ -0,0 +1,364 @@
+// Copyright (c) 2020, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use crate::context::{PlaneBlockOffset, MI_SIZE};
+use crate::mc::MotionVector;
+use crate::partition::{BlockSize, GlobalMVMode};
+
+pub const WARPEDMODEL_PREC_BITS: u32 = 16;
+pub const WARP_PARAM_REDUCE_BITS: u32 = 6;
+const WARPEDMODEL_NONDIAGAFFINE_CLAMP: i64 = 1 << 13;
+const WARPEDMODEL_TRANS_CLAMP: i64 = 1 << 23;
+
+const DIV_LUT_BITS: u32 = 8;
+const DIV_LUT_PREC_BITS: u32 = 14;
+
+const LS_MV_MAX: i32 = 256;
+pub const LEAST_SQUARES_SAMPLES_MAX: usize = 8;
+
+pub const GM_ABS_ALPHA_BITS: u32 = 12;
+pub const GM_ALPHA_PREC_BITS: u32 = 15;
+pub const GM_ABS_TRANS_ONLY_BITS: u32 = 9;
+pub const GM_TRANS_ONLY_PREC_BITS: u32 = 3;
+pub const GM_ABS_TRANS_BITS: u32 = 12;
+pub const GM_TRANS_PREC_BITS: u32 = 6;
+
+/// A sample for the local warp fit: the center of a neighbouring block and
+/// the position it points to, as `[y, x, y + mv.row, x + mv.col]` in 1/8 pel.
+pub type WarpSample = [i32; 4];
+
+/// Affine model mapping a position in the current frame to a position in
+/// the reference frame, with `WARPEDMODEL_PREC_BITS` of precision:
+///
+/// x' = wmmat[2] * x + wmmat[3] * y + wmmat[0]
+/// y' = wmmat[4] * x + wmmat[5] * y + wmmat[1]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WarpedMotionParams {
+  pub wmtype: GlobalMVMode,
+  pub wmmat: [i32; 6],
+}
+
+impl Default for WarpedMotionParams {
+  fn default() -> Self {
+    WarpedMotionParams {
+      wmtype: GlobalMVMode::IDENTITY,
+      wmmat: [
+        0,
+        0,
+        1 << WARPEDMODEL_PREC_BITS,
+        0,
+        0,
+        1 << WARPEDMODEL_PREC_BITS,
+      ],
+    }
+  }
+}
+
+/// Shear decomposition of the non-translational part of a warp, used by
+/// the warp filter.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShearParams {
+  pub alpha: i32,
+  pub beta: i32,
+  pub gamma: i32,
+  pub delta: i32,
+}
+
+#[inline]
+fn round2(x: i64, n: u32) -> i64 {
+  if n == 0 {
+    x
+  } else {
+    (x + (1 << (n - 1))) >> n
+  }
+}
+
+#[inline]
+fn round2signed(x: i64, n: u32) -> i64 {
+  if x >= 0 {
+    round2(x, n)
+  } else {
+    -round2(-x, n)
+  }
+}
+
+/// Entry `i` of the spec's `Div_Lut`, i.e. 2^14 * 256 / (256 + i) rounded
+/// to the nearest integer.
+#[inline]
+fn div_lut(i: i64) -> i64 {
+  const NUM: i64 = 1 << (DIV_LUT_PREC_BITS + DIV_LUT_BITS + 1);
+  (NUM / ((1 << DIV_LUT_BITS) + i) + 1) >> 1
+}
+
+/// Approximates 1 / d as `factor / 2^shift` (spec 7.11.3.7).
+fn resolve_divisor(d: i64) -> (u32, i64) {
+  debug_assert!(d != 0);
+  let a = d.abs();
+  let n = 63 - a.leading_zeros();
+  let e = a - (1 << n);
+  let f = if n > DIV_LUT_BITS {
+    round2(e, n - DIV_LUT_BITS)
+  } else {
+    e << (DIV_LUT_BITS - n)
+  };
+  let factor = div_lut(f);
+  (n + DIV_LUT_PREC_BITS, if d < 0 { -factor } else { factor })
+}
+
+impl WarpedMotionParams {
+  /// Derives the shear parameters of the warp filter, returning `None` if
+  /// the model cannot be used for prediction (spec 7.11.3.6).
+  pub fn shear(&self) -> Option<ShearParams> {
+    let mat = &self.wmmat;
+    if mat[2] <= 0 {
+      return None;
+    }
+    let clamp16 = |v: i64| v.max(-32768).min(32767);
+    let reduce = |v: i64| {
+      (round2signed(v, WARP_PARAM_REDUCE_BITS) << WARP_PARAM_REDUCE_BITS)
+        as i32
+    };
+
+    let alpha = clamp16(mat[2] as i64 - (1 << WARPEDMODEL_PREC_BITS));
+    let beta = clamp16(mat[3] as i64);
+    let (shift, factor) = resolve_divisor(mat[2] as i64);
+    let v = (mat[4] as i64) << WARPEDMODEL_PREC_BITS;
+    let gamma = clamp16(round2signed(v * factor, shift));
+    let w = mat[3] as i64 * mat[4] as i64;
+    let delta = clamp16(
+      mat[5] as i64
+        - round2signed(w * factor, shift)
+        - (1 << WARPEDMODEL_PREC_BITS),
+    );
+
+    let shear = ShearParams {
+      alpha: reduce(alpha),
+      beta: reduce(beta),
+      gamma: reduce(gamma),
+      delta: reduce(delta),
+    };
+
+    if 4 * shear.alpha.abs() + 7 * shear.beta.abs()
+      >= (1 << WARPEDMODEL_PREC_BITS)
+      || 4 * shear.gamma.abs() + 4 * shear.delta.abs()
+        >= (1 << WARPEDMODEL_PREC_BITS)
+    {
+      None
+    } else {
+      Some(shear)
+    }
+  }
+
+  /// Whether blocks coded with GLOBALMV are predicted with the warp filter
+  /// rather than by translation.
+  pub fn is_warp(&self) -> bool {
+    self.wmtype > GlobalMVMode::TRANSLATION
+  }
+
+  /// Motion vector of the model at the center of the block, used as the
+  /// GLOBALMV candidate (spec 7.10.2.1).
+  pub fn global_mv(
+    &self, frame_bo: PlaneBlockOffset, bsize: BlockSize,
+    allow_high_precision_mv: bool,
+  ) -> MotionVector {
+    let mat = &self.wmmat;
+    match self.wmtype {
+      GlobalMVMode::IDENTITY => MotionVector::default(),
+      GlobalMVMode::TRANSLATION => {
+        // The spec assigns the horizontal translation to the row and the
+        // vertical one to the column; decoders follow it.
+        let shift = WARPEDMODEL_PREC_BITS - 3;
+        MotionVector {
+          row: (mat[0] >> shift) as i16,
+          col: (mat[1] >> shift) as i16,
+        }
+      }
+      _ => {
+        let x = (frame_bo.0.x * MI_SIZE + bsize.width() / 2) as i64 - 1;
+        let y = (frame_bo.0.y * MI_SIZE + bsize.height() / 2) as i64 - 1;
+        let xc = (mat[2] as i64 - (1 << WARPEDMODEL_PREC_BITS)) * x
+          + mat[3] as i64 * y
+          + mat[0] as i64;
+        let yc = mat[4] as i64 * x
+          + (mat[5] as i64 - (1 << WARPEDMODEL_PREC_BITS)) * y
+          + mat[1] as i64;
+        if allow_high_precision_mv {
+          MotionVector {
+            row: round2signed(yc, WARPEDMODEL_PREC_BITS - 3) as i16,
+            col: round2signed(xc, WARPEDMODEL_PREC_BITS - 3) as i16,
+          }
+        } else {
+          MotionVector {
+            row: round2signed(yc, WARPEDMODEL_PREC_BITS - 2) as i16 * 2,
+            col: round2signed(xc, WARPEDMODEL_PREC_BITS - 2) as i16 * 2,
+          }
+        }
+      }
+    }
+  }
+
+  /// Fits a local warp for a block from the samples of its neighbours
+  /// (spec 7.11.3.8). Returns `None` when the fit fails or the resulting
+  /// model is not usable for prediction.
+  pub fn from_samples(
+    samples: &[WarpSample], frame_bo: PlaneBlockOffset, bsize: BlockSize,
+    mv: MotionVector,
+  ) -> Option<Self> {
+    let mut a = [[0i64; 2]; 2];
+    let mut bx = [0i64; 2];
+    let mut by = [0i64; 2];
+
+    let mid_y = (frame_bo.0.y * MI_SIZE + bsize.height() / 2) as i32 - 1;
+    let mid_x = (frame_bo.0.x * MI_SIZE + bsize.width() / 2) as i32 - 1;
+    let suy = mid_y * 8;
+    let sux = mid_x * 8;
+    let duy = suy + mv.row as i32;
+    let dux = sux + mv.col as i32;
+
+    let ls_product = |a: i32, b: i32| (((a * b) >> 2) + (a + b)) as i64;
+
+    for s in samples {
+      let sy = s[0] - suy;
+      let sx = s[1] - sux;
+      let dy = s[2] - duy;
+      let dx = s[3] - dux;
+      if (sx - dx).abs() < LS_MV_MAX && (sy - dy).abs() < LS_MV_MAX {
+        a[0][0] += ls_product(sx, sx) + 8;
+        a[0][1] += ls_product(sx, sy) + 4;
+        a[1][1] += ls_product(sy, sy) + 8;
+        bx[0] += ls_product(sx, dx) + 8;
+        bx[1] += ls_product(sy, dx) + 4;
+        by[0] += ls_product(sx, dy) + 4;
+        by[1] += ls_product(sy, dy) + 8;
+      }
+    }
+
+    let det = a[0][0] * a[1][1] - a[0][1] * a[0][1];
+    if det == 0 {
+      return None;
+    }
+
+    let (shift, mut factor) = resolve_divisor(det);
+    let mut shift = shift as i32 - WARPEDMODEL_PREC_BITS as i32;
+    if shift < 0 {
+      factor <<= -shift;
+      shift = 0;
+    }
+    let shift = shift as u32;
+
+    let nondiag = |v: i64| {
+      round2signed(v * factor, shift)
+        .max(-WARPEDMODEL_NONDIAGAFFINE_CLAMP + 1)
+        .min(WARPEDMODEL_NONDIAGAFFINE_CLAMP - 1)
+    };
+    let diag = |v: i64| {
+      round2signed(v * factor, shift)
+        .max(
+          (1 << WARPEDMODEL_PREC_BITS) - WARPEDMODEL_NONDIAGAFFINE_CLAMP + 1,
+        )
+        .min(
+          (1 << WARPEDMODEL_PREC_BITS) + WARPEDMODEL_NONDIAGAFFINE_CLAMP - 1,
+        )
+    };
+
+    let mut mat = [0i64; 6];
+    mat[2] = diag(a[1][1] * bx[0] - a[0][1] * bx[1]);
+    mat[3] = nondiag(-a[0][1] * bx[0] + a[0][0] * bx[1]);
+    mat[4] = nondiag(a[1][1] * by[0] - a[0][1] * by[1]);
+    mat[5] = diag(-a[0][1] * by[0] + a[0][0] * by[1]);
+
+    let vx = mv.col as i64 * (1 << (WARPEDMODEL_PREC_BITS - 3))
+      - (mid_x as i64 * (mat[2] - (1 << WARPEDMODEL_PREC_BITS))
+        + mid_y as i64 * mat[3]);
+    let vy = mv.row as i64 * (1 << (WARPEDMODEL_PREC_BITS - 3))
+      - (mid_x as i64 * mat[4]
+        + mid_y as i64 * (mat[5] - (1 << WARPEDMODEL_PREC_BITS)));
+    mat[0] = vx.max(-WARPEDMODEL_TRANS_CLAMP).min(WARPEDMODEL_TRANS_CLAMP - 1);
+    mat[1] = vy.max(-WARPEDMODEL_TRANS_CLAMP).min(WARPEDMODEL_TRANS_CLAMP - 1);
+
+    let params = WarpedMotionParams {
+      wmtype: GlobalMVMode::AFFINE,
+      wmmat: [
+        mat[0] as i32,
+        mat[1] as i32,
+        mat[2] as i32,
+        mat[3] as i32,
+        mat[4] as i32,
+        mat[5] as i32,
+      ],
+    };
+
+    params.shear().map(|_| params)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::context::BlockOffset;
+
+  #[test]
+  fn div_lut_matches_spec() {
+    assert_eq!(div_lut(0), 16384);
+    assert_eq!(div_lut(1), 16320);
+    assert_eq!(div_lut(2), 16257);
+    assert_eq!(div_lut(3), 16194);
+    assert_eq!(div_lut(128), 10923);
+    assert_eq!(div_lut(255), 8208);
+    assert_eq!(div_lut(256), 8192);
+  }
+
+  #[test]
+  fn identity_shear() {
+    let params = WarpedMotionParams::default();
+    assert_eq!(params.shear(), Some(ShearParams::default()));
+  }
+
+  #[test]
+  fn rotzoom_global_mv() {
+    // Zoom in by 1/64 around the origin
+    let params = WarpedMotionParams {
+      wmtype: GlobalMVMode::ROTZOOM,
+      wmmat: [0, 0, 65536 + 1024, 0, 0, 65536 + 1024],
+    };
+    assert!(params.shear().is_some());
+    let frame_bo = PlaneBlockOffset(BlockOffset { x: 16, y: 32 });
+    let mv = params.global_mv(frame_bo, BlockSize::BLOCK_16X16, true);
+    // Block center is at (71, 135)
+    assert_eq!(mv, MotionVector { row: 17, col: 9 });
+    let mv = params.global_mv(frame_bo, BlockSize::BLOCK_16X16, false);
+    assert_eq!(mv, MotionVector { row: 16, col: 8 });
+  }
+
+  #[test]
+  fn local_warp_from_translation() {
+    // Neighbours moving exactly like the block give back a translation.
+    let frame_bo = PlaneBlockOffset(BlockOffset { x: 8, y: 8 });
+    let bsize = BlockSize::BLOCK_16X16;
+    let mv = MotionVector { row: -12, col: 20 };
+    let samples: Vec<WarpSample> = [(27, 39), (43, 23), (23, 23), (47, 27)]
+      .iter()
+      .map(|&(y, x)| {
+        [y * 8, x * 8, y * 8 + mv.row as i32, x * 8 + mv.col as i32]
+      })
+      .collect();
+    let params =
+      WarpedMotionParams::from_samples(&samples, frame_bo, bsize, mv).unwrap();
+    assert_eq!(params.wmmat[3], 0);
+    assert_eq!(params.wmmat[4], 0);
+    assert_eq!(params.shear(), Some(ShearParams::default()));
+    // The block center is displaced by exactly the block motion vector.
+    let (x, y) = (39i64, 39i64);
+    let mat = params.wmmat;
+    let dst_x = mat[2] as i64 * x + mat[3] as i64 * y + mat[0] as i64;
+    let dst_y = mat[4] as i64 * x + mat[5] as i64 * y + mat[1] as i64;
+    assert_eq!(dst_x, (x << 16) + (20 << 13));
+    assert_eq!(dst_y, (y << 16) - (12 << 13));
+  }
+}
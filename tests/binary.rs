@@ -19,6 +19,17 @@ mod binary {
     data
   }
 
+  /// The frames of the Y4M input, without their headers.
+  fn get_raw_input() -> Vec<u8> {
+    let data = get_y4m_input();
+    let header_len = data.iter().position(|&b| b == b'\n').unwrap() + 1;
+    let frame_len = 64 * 64 * 3 / 2;
+    data[header_len..]
+      .chunks(b"FRAME\n".len() + frame_len)
+      .flat_map(|frame| frame[b"FRAME\n".len()..].iter().cloned())
+      .collect()
+  }
+
   fn get_tempfile_path(extension: &str) -> PathBuf {
     let mut path = temp_dir();
     let filename =
@@ -115,4 +126,217 @@ mod binary {
       .assert()
       .success();
   }
+
+  fn matroska_output(extension: &str, doc_type: &[u8]) {
+    let outfile = get_tempfile_path(extension);
+
+    get_common_cmd(&outfile)
+      .arg("-")
+      .write_stdin(get_y4m_input())
+      .assert()
+      .success();
+
+    let mut data = Vec::new();
+    File::open(&outfile).unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(&data[..4], &[0x1A, 0x45, 0xDF, 0xA3]);
+    assert!(data.windows(doc_type.len()).any(|w| w == doc_type));
+  }
+
+  #[test]
+  fn webm_output() {
+    matroska_output("webm", b"webm");
+  }
+
+  #[test]
+  fn mkv_output() {
+    matroska_output("mkv", b"matroska");
+  }
+
+  fn encode_mp4(fragmented: bool) -> Vec<u8> {
+    let outfile = get_tempfile_path("mp4");
+
+    let mut cmd = get_common_cmd(&outfile);
+    if fragmented {
+      cmd.arg("--fragmented");
+    }
+    cmd.arg("-").write_stdin(get_y4m_input()).assert().success();
+
+    let mut data = Vec::new();
+    File::open(&outfile).unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(&data[4..8], b"ftyp");
+    assert!(data.windows(4).any(|w| w == b"av1C"));
+    data
+  }
+
+  #[test]
+  fn mp4_output() {
+    let data = encode_mp4(false);
+    assert!(data.windows(4).any(|w| w == b"stss"));
+  }
+
+  #[test]
+  fn fragmented_mp4_output() {
+    let data = encode_mp4(true);
+    assert!(data.windows(4).any(|w| w == b"moof"));
+  }
+
+  #[test]
+  fn avif_output() {
+    let outfile = get_tempfile_path("avif");
+
+    get_common_cmd(&outfile)
+      .arg("-")
+      .write_stdin(get_y4m_input())
+      .assert()
+      .success();
+
+    let mut data = Vec::new();
+    File::open(&outfile).unwrap().read_to_end(&mut data).unwrap();
+    assert_eq!(&data[4..12], b"ftypavif");
+  }
+
+  #[test]
+  fn annex_b_obu_output() {
+    let outfile = get_tempfile_path("obu");
+
+    get_common_cmd(&outfile)
+      .arg("--annex-b")
+      .arg("-")
+      .write_stdin(get_y4m_input())
+      .assert()
+      .success();
+
+    let mut data = Vec::new();
+    File::open(&outfile).unwrap().read_to_end(&mut data).unwrap();
+    // The file is a sequence of temporal units prefixed by their size.
+    let mut pos = 0;
+    while pos < data.len() {
+      let mut temporal_unit_size = 0;
+      let mut i = 0;
+      loop {
+        let byte = data[pos + i];
+        temporal_unit_size |= ((byte & 0x7f) as usize) << (7 * i);
+        i += 1;
+        if byte & 0x80 == 0 {
+          break;
+        }
+      }
+      pos += i + temporal_unit_size;
+    }
+    assert_eq!(pos, data.len());
+  }
+
+  #[test]
+  fn annex_b_requires_obu_output() {
+    let outfile = get_tempfile_path("ivf");
+
+    get_common_cmd(&outfile)
+      .arg("--annex-b")
+      .arg("-")
+      .write_stdin(get_y4m_input())
+      .assert()
+      .failure();
+  }
+
+  fn encode_raw(input_args: &[&str], input: Vec<u8>) -> Vec<u8> {
+    let outfile = get_tempfile_path("ivf");
+    let reconfile = get_tempfile_path("y4m");
+
+    get_rav1e_command()
+      .args(["--quantizer", "0", "--speed", "10"])
+      .args(input_args)
+      .args(["--width", "64", "--height", "64"])
+      .arg("-o")
+      .arg(&outfile)
+      .arg("-r")
+      .arg(&reconfile)
+      .arg("-")
+      .write_stdin(input)
+      .assert()
+      .success();
+
+    let mut data = Vec::new();
+    File::open(&reconfile).unwrap().read_to_end(&mut data).unwrap();
+    data
+  }
+
+  #[test]
+  fn raw_input() {
+    let planar = get_raw_input();
+    let luma_len = 64 * 64;
+    let chroma_len = luma_len / 4;
+    let mut semi_planar = Vec::new();
+    for frame in planar.chunks(luma_len + 2 * chroma_len) {
+      let (y, uv) = frame.split_at(luma_len);
+      let (u, v) = uv.split_at(chroma_len);
+      semi_planar.extend_from_slice(y);
+      for (&u, &v) in u.iter().zip(v.iter()) {
+        semi_planar.push(u);
+        semi_planar.push(v);
+      }
+    }
+
+    // Lossless encodes of both layouts reconstruct the same frames.
+    assert_eq!(
+      encode_raw(&["--input-format", "yuv"], planar),
+      encode_raw(&["--input-format", "nv12"], semi_planar)
+    );
+  }
+
+  #[test]
+  fn raw_input_high_bit_depth() {
+    let luma_len = 64 * 64;
+    let chroma_len = luma_len / 4;
+    let mut planar = Vec::new();
+    let mut semi_planar = Vec::new();
+    // 10-bit samples are little-endian words, in the low bits for planar
+    // input and in the high bits for P010.
+    for frame in get_raw_input().chunks(luma_len + 2 * chroma_len) {
+      for &s in frame {
+        planar.extend_from_slice(&(u16::from(s) << 2).to_le_bytes());
+      }
+      let (y, uv) = frame.split_at(luma_len);
+      let (u, v) = uv.split_at(chroma_len);
+      for &s in y {
+        semi_planar.extend_from_slice(&(u16::from(s) << 8).to_le_bytes());
+      }
+      for (&u, &v) in u.iter().zip(v.iter()) {
+        semi_planar.extend_from_slice(&(u16::from(u) << 8).to_le_bytes());
+        semi_planar.extend_from_slice(&(u16::from(v) << 8).to_le_bytes());
+      }
+    }
+
+    assert_eq!(
+      encode_raw(&["--input-format", "yuv", "--input-depth", "10"], planar),
+      encode_raw(&["--input-format", "p010"], semi_planar)
+    );
+  }
+
+  #[test]
+  fn raw_input_requires_dimensions() {
+    let outfile = get_tempfile_path("ivf");
+
+    get_common_cmd(&outfile)
+      .args(["--input-format", "yuv", "--width", "64"])
+      .arg("-")
+      .write_stdin(get_raw_input())
+      .assert()
+      .failure();
+  }
+
+  #[test]
+  fn raw_input_high_bit_depth_mono_reconstruction() {
+    let outfile = get_tempfile_path("ivf");
+    let reconfile = get_tempfile_path("y4m");
+
+    get_common_cmd(&outfile)
+      .args(["--input-format", "yuv", "--input-chroma", "400"])
+      .args(["--input-depth", "10", "--width", "64", "--height", "64"])
+      .arg("-r")
+      .arg(&reconfile)
+      .arg("-")
+      .write_stdin(vec![0; 64 * 64 * 2])
+      .assert()
+      .failure();
+  }
 }